@@ -0,0 +1,557 @@
+//! Stores backups as chunks, so that a backup only takes up room for what
+//! changed since the last one.
+//!
+//! Each export the backend sends is a full copy of its data, most of which is
+//! the same as in the export before. So a backup is cut into chunks where its
+//! content says to, rather than every so many bytes. A message added near the
+//! start of an export then only changes the chunk it lands in, instead of
+//! moving every chunk boundary after it. Where to cut is found with a rolling
+//! Gear hash, as in `FastCDC`.
+//!
+//! Each chunk is sealed on its own, as described in
+//! [`crate::backup_encryption`], and kept in a `chunks` folder next to the
+//! backups under a name worked out from its content. A chunk that is already
+//! there is not written again. In place of the backup itself goes a recipe,
+//! listing the chunks that make it up in order, which is all that is needed to
+//! put the backup back together.
+//!
+//! The recipe is not encrypted, since thinning out old backups and sending
+//! them off-site both need to know which chunks it uses, without the key. It
+//! carries an HMAC of its chunk list under the backup key instead, so a recipe
+//! with its chunks reordered, swapped or left out is refused when the backup
+//! is put back together, rather than giving back something else.
+//!
+//! A chunk is removed once no recipe in its folder needs it, but never one that
+//! was written or found again in the last day, since a backup that is still
+//! arriving may need it before its recipe is written.
+
+use crate::backup_encryption::BackupKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+/// The folder chunks are kept in, inside a backups folder.
+pub const CHUNKS_DIR: &str = "chunks/";
+
+/// What a recipe starts with, to tell it apart from a backup stored whole.
+const RECIPE_MAGIC: &[u8] = b"ATCHATRC\n";
+
+const MIN_CHUNK_BYTES: usize = 64 * 1024;
+const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// A chunk is cut where the top this many bits of the hash are all 0, which
+/// makes chunks 256 KiB on average, on top of the minimum.
+const BOUNDARY_BITS: u32 = 18;
+
+/// How many bytes the Gear hash depends on, each byte being shifted out of it
+/// after 64 more.
+const WINDOW_BYTES: usize = 64;
+
+/// How long a chunk no recipe needs is kept, in case a backup that is still
+/// arriving needs it.
+const UNUSED_CHUNK_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A random number for each byte, for the Gear hash. Changing these moves
+/// every chunk boundary, so nothing stored before the change would be reused.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut index = 0;
+    // SplitMix64, which needs no crate and can run at compile time.
+    while index < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut x = state;
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[index] = x ^ (x >> 31);
+        index += 1;
+    }
+    table
+};
+
+/// Cuts bytes into chunks as they are given to it. Where it cuts only depends
+/// on the bytes, and not on how they were split up when given.
+#[derive(Default)]
+pub struct Chunker {
+    pending: Vec<u8>,
+    /// How much of `pending` has been hashed.
+    scanned: usize,
+    hash: u64,
+}
+
+impl Chunker {
+    /// Adds bytes, and calls `on_chunk` with each chunk that is now complete.
+    pub fn push(
+        &mut self,
+        bytes: &[u8],
+        mut on_chunk: impl FnMut(&[u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        self.pending.extend_from_slice(bytes);
+
+        while let Some(cut) = self.next_cut() {
+            on_chunk(&self.pending[..cut])?;
+            self.pending.drain(..cut);
+            self.scanned = 0;
+            self.hash = 0;
+        }
+        Ok(())
+    }
+
+    /// Calls `on_chunk` with whatever is left, once there are no more bytes.
+    pub fn finish(
+        self,
+        mut on_chunk: impl FnMut(&[u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            on_chunk(&self.pending)
+        }
+    }
+
+    fn next_cut(&mut self) -> Option<usize> {
+        let end = self.pending.len().min(MAX_CHUNK_BYTES);
+
+        // Nothing before the minimum can be cut at, so hashing starts just
+        // early enough for the hash to be complete by then.
+        let mut index = self.scanned.max(MIN_CHUNK_BYTES - WINDOW_BYTES);
+        while index < end {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[self.pending[index] as usize]);
+            index += 1;
+
+            if index >= MIN_CHUNK_BYTES && self.hash >> (64 - BOUNDARY_BITS) == 0 {
+                return Some(index);
+            }
+        }
+        self.scanned = index;
+
+        (end == MAX_CHUNK_BYTES).then_some(MAX_CHUNK_BYTES)
+    }
+}
+
+/// The chunks a backup is made of, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recipe {
+    pub chunks: Vec<ChunkRef>,
+    /// The key's [`BackupKey::recipe_mac`] of `chunks`, vouching that the list
+    /// is the one the server wrote.
+    #[serde(default)]
+    pub mac: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub id: String,
+    pub size: u64,
+}
+
+impl Recipe {
+    /// What the recipe's MAC is worked out from.
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.chunks).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = RECIPE_MAGIC.to_vec();
+        bytes.extend_from_slice(serde_json::to_string(self).unwrap().as_bytes());
+        bytes
+    }
+
+    /// The recipe in a stored backup, if it is one rather than a backup stored
+    /// whole.
+    pub fn parse(stored: &[u8]) -> Option<Self> {
+        serde_json::from_slice(stored.strip_prefix(RECIPE_MAGIC)?).ok()
+    }
+
+    /// The recipe stored at `path`, or `None` if it holds a backup stored
+    /// whole. Only the start of a backup stored whole is read to tell.
+    pub fn read(path: &str) -> std::io::Result<Option<Self>> {
+        let mut file = fs::File::open(path)?;
+        let mut start = [0u8; RECIPE_MAGIC.len()];
+        match std::io::Read::read_exact(&mut file, &mut start) {
+            Ok(()) if start == RECIPE_MAGIC => {}
+            Err(error) if error.kind() != std::io::ErrorKind::UnexpectedEof => return Err(error),
+            _ => return Ok(None),
+        }
+
+        Self::parse(&fs::read(path)?).map(Some).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{path} is not a readable recipe"),
+            )
+        })
+    }
+}
+
+/// Where a chunk is kept in a backups folder.
+pub fn chunk_path(dir: &str, id: &str) -> String {
+    format!("{dir}{CHUNKS_DIR}{id}")
+}
+
+/// Cuts a backup into chunks as it is written, storing each chunk that is not
+/// in the folder yet.
+pub struct ChunkWriter<'a> {
+    dir: &'a str,
+    key: &'a BackupKey,
+    chunker: Chunker,
+    recipe: Recipe,
+    /// How many of the chunks had to be written, rather than being there
+    /// already.
+    pub written: usize,
+}
+
+impl<'a> ChunkWriter<'a> {
+    /// `dir` is the backups folder, which the chunks folder is made in if it
+    /// is not there yet.
+    pub fn new(dir: &'a str, key: &'a BackupKey) -> std::io::Result<Self> {
+        let chunks_dir = format!("{dir}{CHUNKS_DIR}");
+        if !fs::exists(&chunks_dir)? {
+            fs::create_dir(&chunks_dir)?;
+        }
+
+        Ok(Self {
+            dir,
+            key,
+            chunker: Chunker::default(),
+            recipe: Recipe::default(),
+            written: 0,
+        })
+    }
+
+    /// Stores what is left, and gives back the recipe for the whole backup.
+    pub fn finish(mut self) -> std::io::Result<Recipe> {
+        let chunker = std::mem::take(&mut self.chunker);
+        chunker.finish(|chunk| self.store(chunk))?;
+        self.recipe.mac = self.key.recipe_mac(&self.recipe.signed_bytes())?;
+        Ok(self.recipe)
+    }
+
+    fn store(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        let id = self.key.chunk_id(chunk)?;
+        let path = chunk_path(self.dir, &id);
+
+        if fs::exists(&path)? {
+            touch(&path)?;
+        } else {
+            crate::write_atomically(&path, &self.key.seal(chunk)?)?;
+            self.written += 1;
+        }
+
+        self.recipe.chunks.push(ChunkRef {
+            id,
+            size: chunk.len() as u64,
+        });
+        Ok(())
+    }
+}
+
+impl Write for ChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut chunker = std::mem::take(&mut self.chunker);
+        let result = chunker.push(buf, |chunk| self.store(chunk));
+        self.chunker = chunker;
+        result.map(|()| buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Marks a chunk as just used, so it is not removed while the backup that
+/// found it is still being written.
+pub fn touch(path: &str) -> std::io::Result<()> {
+    fs::File::options()
+        .append(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Puts a backup back together from its chunks in `dir`, writing it into
+/// `writer`. The recipe is checked to be one the server wrote, and each chunk
+/// to be the one the recipe asks for.
+pub fn open_to<W: Write>(
+    dir: &str,
+    recipe: &Recipe,
+    key: &BackupKey,
+    writer: W,
+) -> Result<W, String> {
+    if !key.is_recipe_mac(&recipe.signed_bytes(), &recipe.mac) {
+        return Err(String::from(
+            "The recipe has been altered or was written with a different key",
+        ));
+    }
+
+    let mut writer2 = writer;
+
+    for chunk in &recipe.chunks {
+        let stored = fs::read(chunk_path(dir, &chunk.id))
+            .map_err(|error| format!("Chunk {} could not be read: {error}", chunk.id))?;
+        let opened = key
+            .open(&stored)
+            .map_err(|error| format!("Chunk {}: {error}", chunk.id))?;
+
+        if opened.len() as u64 != chunk.size
+            || key.chunk_id(&opened).map_err(|error| error.to_string())? != chunk.id
+        {
+            return Err(format!("Chunk {} holds a different chunk", chunk.id));
+        }
+
+        writer2
+            .write_all(&opened)
+            .map_err(|error| error.to_string())?;
+    }
+
+    Ok(writer2)
+}
+
+/// The chunks a recipe needs that are not in `dir`.
+pub fn missing_chunks(dir: &str, recipe: &Recipe) -> Vec<String> {
+    recipe
+        .chunks
+        .iter()
+        .filter(|chunk| !fs::exists(chunk_path(dir, &chunk.id)).unwrap_or(false))
+        .map(|chunk| chunk.id.clone())
+        .collect()
+}
+
+/// Removes the chunks in `dir` that none of `recipes` need and that have not
+/// been used in a while, and says how many it removed.
+pub fn remove_unused(dir: &str, recipes: &[Recipe]) -> usize {
+    let Ok(entries) = fs::read_dir(format!("{dir}{CHUNKS_DIR}")) else {
+        return 0;
+    };
+
+    let needed: HashSet<&str> = recipes
+        .iter()
+        .flat_map(|recipe| recipe.chunks.iter().map(|chunk| chunk.id.as_str()))
+        .collect();
+    let now = SystemTime::now();
+
+    entries
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|id| !needed.contains(id))
+        })
+        .filter(|entry| {
+            entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified).unwrap_or_default() > UNUSED_CHUNK_GRACE
+                })
+        })
+        .filter(|entry| fs::remove_file(entry.path()).is_ok())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that look random but are the same every run, so where they are
+    /// cut is too.
+    fn random_bytes(length: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn chunk_sizes(pieces: &[&[u8]]) -> Vec<usize> {
+        let mut chunker = Chunker::default();
+        let mut sizes: Vec<usize> = Vec::new();
+        for piece in pieces {
+            chunker
+                .push(piece, |chunk| {
+                    sizes.push(chunk.len());
+                    Ok(())
+                })
+                .unwrap();
+        }
+        chunker
+            .finish(|chunk| {
+                sizes.push(chunk.len());
+                Ok(())
+            })
+            .unwrap();
+        sizes
+    }
+
+    #[test]
+    fn cuts_in_the_same_places_however_the_bytes_arrive() {
+        let bytes = random_bytes(3 * 1024 * 1024);
+
+        let whole = chunk_sizes(&[&bytes]);
+        let pieces: Vec<&[u8]> = bytes.chunks(7919).collect();
+
+        assert_eq!(
+            chunk_sizes(&pieces),
+            whole,
+            "the chunks should not depend on how the bytes were split up"
+        );
+        assert_eq!(
+            whole.iter().sum::<usize>(),
+            bytes.len(),
+            "every byte should be in a chunk"
+        );
+        assert!(
+            whole[..whole.len() - 1]
+                .iter()
+                .all(|size| (MIN_CHUNK_BYTES..=MAX_CHUNK_BYTES).contains(size)),
+            "every chunk but the last should be within the limits: {whole:?}"
+        );
+        assert_eq!(
+            chunk_sizes(&[&vec![0u8; 2 * MAX_CHUNK_BYTES + 1]]),
+            vec![MAX_CHUNK_BYTES, MAX_CHUNK_BYTES, 1],
+            "bytes with nowhere to cut should be cut at the maximum"
+        );
+    }
+
+    // This is what makes chunking worth it. Something added to the start of a
+    // backup should leave the chunks after it as they were.
+    #[test]
+    fn an_insertion_only_changes_the_chunks_around_it() {
+        let bytes = random_bytes(4 * 1024 * 1024);
+        let mut inserted = bytes[..1000].to_vec();
+        inserted.extend_from_slice(b"a new message");
+        inserted.extend_from_slice(&bytes[1000..]);
+
+        let before = chunk_sizes(&[&bytes]);
+        let after = chunk_sizes(&[&inserted]);
+
+        assert_eq!(
+            before[1..],
+            after[1..],
+            "only the first chunk should have changed"
+        );
+        assert_eq!(
+            after[0],
+            before[0] + b"a new message".len(),
+            "the first chunk should have grown by what was added"
+        );
+    }
+
+    #[test]
+    fn reads_back_a_recipe() {
+        let recipe = Recipe {
+            chunks: vec![ChunkRef {
+                id: String::from("abc"),
+                size: 10,
+            }],
+            mac: String::from("def"),
+        };
+        let bytes = recipe.to_bytes();
+
+        assert_eq!(Recipe::parse(&bytes), Some(recipe), "it should round trip");
+        assert_eq!(
+            Recipe::parse(b"ATCHATBK\x01sealed"),
+            None,
+            "a backup stored whole is not a recipe"
+        );
+    }
+
+    #[test]
+    fn stores_each_chunk_once_and_puts_the_backup_back_together() {
+        let dir = format!(
+            "{}/atchat-chunks-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+        let key = BackupKey::new([7; 32]);
+        let backup = random_bytes(2 * 1024 * 1024);
+
+        let store = |backup2: &[u8]| {
+            let mut writer = ChunkWriter::new(&dir, &key).unwrap();
+            writer.write_all(backup2).unwrap();
+            let written = writer.written;
+            (writer.finish().unwrap(), written)
+        };
+
+        let (recipe, _) = store(&backup);
+        let (recipe2, written) = store(&backup);
+        let reopened = open_to(&dir, &recipe, &key, Vec::new());
+        let chunk_count = fs::read_dir(format!("{dir}{CHUNKS_DIR}")).unwrap().count();
+
+        let removed_too_soon = remove_unused(&dir, &[]);
+        let first = recipe.chunks[0].id.clone();
+        fs::File::options()
+            .append(true)
+            .open(chunk_path(&dir, &first))
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * UNUSED_CHUNK_GRACE)
+            .unwrap();
+        let removed = remove_unused(&dir, &[]);
+        let missing = missing_chunks(&dir, &recipe);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            recipe2, recipe,
+            "the same backup should get the same recipe"
+        );
+        assert_eq!(written, 0, "no chunk should be written a second time");
+        assert_eq!(
+            chunk_count,
+            recipe.chunks.len(),
+            "each chunk should be stored once"
+        );
+        assert_eq!(
+            reopened,
+            Ok(backup),
+            "the backup should be put back together"
+        );
+        assert_eq!(removed_too_soon, 0, "recent chunks should be kept");
+        assert_eq!(removed, 1, "an old chunk no recipe needs should be removed");
+        assert_eq!(missing, vec![first], "its recipe should miss it");
+    }
+
+    // The recipe sits in the bucket for anyone who can write there to change,
+    // and its chunks alone cannot tell whether they are in the right order.
+    #[test]
+    fn refuses_a_recipe_that_has_been_changed() {
+        let dir = format!(
+            "{}/atchat-chunks-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+        let key = BackupKey::new([7; 32]);
+
+        let mut writer = ChunkWriter::new(&dir, &key).unwrap();
+        writer.write_all(&random_bytes(2 * 1024 * 1024)).unwrap();
+        let recipe = writer.finish().unwrap();
+        let mut reordered = recipe.clone();
+        reordered.chunks.reverse();
+        let mut shortened = recipe.clone();
+        shortened.chunks.pop();
+
+        let results = [
+            open_to(&dir, &recipe, &key, Vec::new()).is_ok(),
+            open_to(&dir, &reordered, &key, Vec::new()).is_ok(),
+            open_to(&dir, &shortened, &key, Vec::new()).is_ok(),
+            open_to(&dir, &recipe, &BackupKey::new([8; 32]), Vec::new()).is_ok(),
+        ];
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(
+            recipe.chunks.len() > 1,
+            "the backup should need several chunks to be reordered"
+        );
+        assert_eq!(
+            results,
+            [true, false, false, false],
+            "only the recipe as written, opened with its own key, should be put back together"
+        );
+    }
+}
@@ -0,0 +1,612 @@
+//! Compresses and encrypts backups before they are written anywhere.
+//!
+//! A backup holds every message anyone has sent, and one of its two copies
+//! sits in an S3 bucket that more people and programs can read than the
+//! server. So it is compressed with zstd and then encrypted with AES-256-GCM
+//! under a key of its own. That is deliberately not the server secret, which
+//! the backend knows and which is rotated whenever it might have leaked.
+//! Rotating it should not leave every older backup unreadable.
+//!
+//! The key is never made by the server itself. A key that only existed on the
+//! server's disk would go with it, and with it every backup in the bucket,
+//! which is the one thing the bucket is there to survive. So the key is made
+//! once, kept somewhere safe that is neither the server nor the bucket, such
+//! as a password manager, and given to the server as 64 hex digits in
+//! `BACKUP_KEY`, or in a file named by `BACKUP_KEY_FILE`. Until it is given
+//! one, the server takes no backups at all. A key can be made with:
+//!
+//! ```text
+//! openssl rand -hex 32
+//! ```
+//!
+//! To get the backups back after losing the server, set up a new one with the
+//! same key and the bucket mounted as before, and restore from
+//! `/file/internal/backups/restore/{filename}`, which falls back on the
+//! bucket's copy when the server has none. Without the key nothing in the
+//! bucket can be read, by us or by anyone else.
+//!
+//! A sealed backup is laid out as:
+//!
+//! ```text
+//! "ATCHATBK" | version (1 byte) | nonce (12 bytes) | ciphertext | tag (16 bytes)
+//! ```
+//!
+//! GCM's tag covers the header as well as the ciphertext, so a file that has
+//! been altered anywhere, or sealed under a different key, fails to open
+//! rather than decompressing into something wrong.
+//!
+//! Backups are sealed and opened a piece at a time as they are read and
+//! written, so one is never held in memory all at once however large it gets.
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{Cipher, Crypter, Mode};
+use std::fs;
+use std::io::{Read, Write};
+use subtle::ConstantTimeEq;
+
+const MAGIC: &[u8; 8] = b"ATCHATBK";
+const VERSION: u8 = 1;
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+const HEADER_BYTES: usize = MAGIC.len() + 1 + NONCE_BYTES;
+
+/// zstd's own default, which compresses JSON well without making a large
+/// backup noticeably slower to write.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// How much of a sealed backup is read at a time when opening it.
+const OPEN_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Why a backup could not be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// It was sealed by a newer version of the server than this one.
+    UnknownVersion(u8),
+    /// It has been altered or cut short, or was sealed under another key.
+    NotAuthentic,
+    Decompression,
+    /// Reading it failed partway through.
+    Unreadable,
+    /// It was never sealed, and is not a backup from before backups were, so
+    /// it cannot be told apart from one put there by someone without the key.
+    NotSealed,
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownVersion(version) => write!(f, "Unknown backup format version {version}"),
+            Self::NotAuthentic => write!(
+                f,
+                "The backup has been altered or was encrypted with a different key"
+            ),
+            Self::Decompression => write!(f, "The backup could not be decompressed"),
+            Self::Unreadable => write!(f, "The backup could not be read"),
+            Self::NotSealed => write!(f, "The backup should be sealed and is not"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BackupKey {
+    key: [u8; 32],
+}
+
+impl BackupKey {
+    pub const fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// The key given in `BACKUP_KEY`, or in the file `BACKUP_KEY_FILE` names.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_settings(
+            std::env::var("BACKUP_KEY").ok(),
+            std::env::var("BACKUP_KEY_FILE").ok(),
+        )
+    }
+
+    fn from_settings(key: Option<String>, key_file: Option<String>) -> Result<Self, String> {
+        let text: String = match (key, key_file) {
+            (Some(key2), _) if !key2.trim().is_empty() => key2,
+            (_, Some(key_file2)) if !key_file2.trim().is_empty() => {
+                fs::read_to_string(key_file2.trim())
+                    .map_err(|error| format!("{} could not be read: {error}", key_file2.trim()))?
+            }
+            _ => {
+                return Err(String::from(
+                    "No backup key is set. Make one with `openssl rand -hex 32`, keep a copy of it somewhere safe off this server, and give it to the server in BACKUP_KEY or a file named by BACKUP_KEY_FILE.",
+                ));
+            }
+        };
+
+        parse_hex_key(text.trim())
+            .map(Self::new)
+            .ok_or_else(|| String::from("The backup key should be 64 hex digits"))
+    }
+
+    /// A backup compressed and encrypted, ready to write.
+    pub fn seal(&self, backup: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut sealer = self.sealer(Vec::new())?;
+        sealer.write_all(backup)?;
+        sealer.finish()
+    }
+
+    /// Compresses and encrypts whatever is written to it into `writer` as it
+    /// goes, so a backup never has to be held in memory all at once.
+    pub fn sealer<W: Write>(&self, writer: W) -> std::io::Result<Sealer<W>> {
+        let nonce: [u8; NONCE_BYTES] = rand::random();
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_BYTES);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&nonce);
+
+        let mut crypter = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Encrypt,
+            &self.key,
+            Some(&nonce),
+        )
+        .map_err(std::io::Error::other)?;
+        crypter.aad_update(&header).map_err(std::io::Error::other)?;
+
+        let mut writer2 = writer;
+        writer2.write_all(&header)?;
+
+        let encrypter = Encrypter {
+            crypter,
+            writer: writer2,
+            buffer: Vec::new(),
+        };
+        Ok(Sealer {
+            encoder: zstd::stream::write::Encoder::new(encrypter, COMPRESSION_LEVEL)?,
+        })
+    }
+
+    /// A name for a chunk of a backup that is the same whenever the chunk is,
+    /// so that a chunk already stored can be found again. It is keyed, so that
+    /// someone with the bucket and without the key cannot check whether a
+    /// chunk holds something they guessed.
+    pub fn chunk_id(&self, chunk: &[u8]) -> std::io::Result<String> {
+        self.mac(&[b"atchat backup chunk\0", chunk])
+    }
+
+    /// A mark vouching that the backup `filename` with this Sha256 was stored
+    /// unsealed by a server from before backups were sealed, rather than put
+    /// there since by someone without the key. Only the key can make one.
+    pub fn legacy_mark(&self, filename: &str, sha256: &str) -> std::io::Result<String> {
+        self.mac(&[
+            b"atchat legacy backup\0",
+            filename.as_bytes(),
+            b"\0",
+            sha256.as_bytes(),
+        ])
+    }
+
+    pub fn is_legacy_mark(&self, filename: &str, sha256: &str, mark: &str) -> bool {
+        self.legacy_mark(filename, sha256)
+            .is_ok_and(|expected| bool::from(expected.as_bytes().ct_eq(mark.as_bytes())))
+    }
+
+    /// A MAC of a recipe's list of chunks, so that a recipe cannot be changed
+    /// by someone without the key, who could otherwise put the chunks of a
+    /// backup together in another order.
+    pub fn recipe_mac(&self, chunks: &[u8]) -> std::io::Result<String> {
+        self.mac(&[b"atchat backup recipe\0", chunks])
+    }
+
+    pub fn is_recipe_mac(&self, chunks: &[u8], mac: &str) -> bool {
+        self.recipe_mac(chunks)
+            .is_ok_and(|expected| bool::from(expected.as_bytes().ct_eq(mac.as_bytes())))
+    }
+
+    /// An HMAC-Sha256 of `parts` one after another, in lowercase hex.
+    fn mac(&self, parts: &[&[u8]]) -> std::io::Result<String> {
+        let key = PKey::hmac(&self.key).map_err(std::io::Error::other)?;
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &key).map_err(std::io::Error::other)?;
+        for part in parts {
+            signer.update(part).map_err(std::io::Error::other)?;
+        }
+        signer
+            .sign_to_vec()
+            .map(|mac| hex(&mac))
+            .map_err(std::io::Error::other)
+    }
+
+    /// A sealed backup as it was before it was sealed.
+    pub fn open(&self, stored: &[u8]) -> Result<Vec<u8>, OpenError> {
+        self.open_to(stored, Vec::new())
+    }
+
+    /// Opens a sealed backup a piece at a time, writing it into `writer` as it
+    /// goes. Whether it is authentic is only known once all of it has been
+    /// read, so nothing written to `writer` can be trusted unless this returns
+    /// `Ok`.
+    pub fn open_to<R: Read, W: Write>(&self, stored: R, writer: W) -> Result<W, OpenError> {
+        self.open_inner(stored, writer, false)
+    }
+
+    /// Like [`Self::open_to`], except that a backup that was never sealed is
+    /// given back as it is. Only for a copy known to have been written before
+    /// backups were sealed, by its [`Self::legacy_mark`], since anyone can
+    /// write one that is not.
+    pub fn open_legacy_to<R: Read, W: Write>(&self, stored: R, writer: W) -> Result<W, OpenError> {
+        self.open_inner(stored, writer, true)
+    }
+
+    fn open_inner<R: Read, W: Write>(
+        &self,
+        stored: R,
+        writer: W,
+        allow_unsealed: bool,
+    ) -> Result<W, OpenError> {
+        let mut stored2 = stored;
+        let mut writer2 = writer;
+
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_BYTES);
+        (&mut stored2)
+            .take(HEADER_BYTES as u64)
+            .read_to_end(&mut header)
+            .map_err(|_error| OpenError::Unreadable)?;
+
+        if !is_sealed(&header) {
+            if !allow_unsealed {
+                return Err(OpenError::NotSealed);
+            }
+            writer2
+                .write_all(&header)
+                .and_then(|()| std::io::copy(&mut stored2, &mut writer2))
+                .map_err(|_error| OpenError::Unreadable)?;
+            return Ok(writer2);
+        }
+
+        if header[MAGIC.len()] != VERSION {
+            return Err(OpenError::UnknownVersion(header[MAGIC.len()]));
+        }
+
+        if header.len() < HEADER_BYTES {
+            return Err(OpenError::NotAuthentic);
+        }
+
+        let mut crypter = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Decrypt,
+            &self.key,
+            Some(&header[MAGIC.len() + 1..]),
+        )
+        .map_err(|_error| OpenError::NotAuthentic)?;
+        crypter
+            .aad_update(&header)
+            .map_err(|_error| OpenError::NotAuthentic)?;
+
+        let mut decoder = zstd::stream::write::Decoder::new(writer2)
+            .map_err(|_error| OpenError::Decompression)?;
+        // Decompressing is given up on once it fails, but decrypting carries
+        // on, since a backup that was tampered with should say so rather than
+        // that it could not be decompressed.
+        let mut decompressed = true;
+
+        // The tag is the last few bytes, so that many are always held back
+        // until it is known there are more after them.
+        let mut pending: Vec<u8> = Vec::with_capacity(OPEN_CHUNK_BYTES + TAG_BYTES);
+        let mut chunk = vec![0u8; OPEN_CHUNK_BYTES];
+        let mut plaintext = vec![0u8; OPEN_CHUNK_BYTES + TAG_BYTES + 16];
+        loop {
+            let read = stored2
+                .read(&mut chunk)
+                .map_err(|_error| OpenError::Unreadable)?;
+            if read == 0 {
+                break;
+            }
+            pending.extend_from_slice(&chunk[..read]);
+
+            if pending.len() > TAG_BYTES {
+                let ready = pending.len() - TAG_BYTES;
+                plaintext.resize(ready + 16, 0);
+                let decrypted = crypter
+                    .update(&pending[..ready], &mut plaintext)
+                    .map_err(|_error| OpenError::NotAuthentic)?;
+                if decompressed {
+                    decompressed = decoder.write_all(&plaintext[..decrypted]).is_ok();
+                }
+                pending.drain(..ready);
+            }
+        }
+
+        if pending.len() < TAG_BYTES {
+            return Err(OpenError::NotAuthentic);
+        }
+        crypter
+            .set_tag(&pending)
+            .map_err(|_error| OpenError::NotAuthentic)?;
+        let finalized = crypter
+            .finalize(&mut plaintext)
+            .map_err(|_error| OpenError::NotAuthentic)?;
+        if decompressed {
+            decompressed =
+                decoder.write_all(&plaintext[..finalized]).is_ok() && decoder.flush().is_ok();
+        }
+
+        if decompressed {
+            Ok(decoder.into_inner())
+        } else {
+            Err(OpenError::Decompression)
+        }
+    }
+}
+
+/// A backup being compressed and encrypted as it is written. [`Sealer::finish`]
+/// has to be called once all of it has been written, to add the tag that
+/// proves it is authentic.
+pub struct Sealer<W: Write> {
+    encoder: zstd::stream::write::Encoder<'static, Encrypter<W>>,
+}
+
+impl<W: Write> Write for Sealer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl<W: Write> Sealer<W> {
+    /// Writes the end of the backup and its tag, and gives back the writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        self.encoder.finish()?.finish()
+    }
+}
+
+/// Encrypts compressed bytes as zstd writes them.
+struct Encrypter<W: Write> {
+    crypter: Crypter,
+    writer: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Write for Encrypter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // OpenSSL asks for a block's worth of room more than it is given.
+        self.buffer.resize(buf.len() + 16, 0);
+        let encrypted = self
+            .crypter
+            .update(buf, &mut self.buffer)
+            .map_err(std::io::Error::other)?;
+        self.writer.write_all(&self.buffer[..encrypted])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Encrypter<W> {
+    fn finish(mut self) -> std::io::Result<W> {
+        self.buffer.resize(16, 0);
+        let encrypted = self
+            .crypter
+            .finalize(&mut self.buffer)
+            .map_err(std::io::Error::other)?;
+        self.writer.write_all(&self.buffer[..encrypted])?;
+
+        let mut tag = [0u8; TAG_BYTES];
+        self.crypter
+            .get_tag(&mut tag)
+            .map_err(std::io::Error::other)?;
+        self.writer.write_all(&tag)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Whether a stored backup was sealed, rather than written as it arrived by an
+/// older version of the server.
+pub fn is_sealed(stored: &[u8]) -> bool {
+    stored.len() > MAGIC.len() && stored.starts_with(MAGIC)
+}
+
+fn parse_hex_key(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_a_backup() {
+        let key = BackupKey::new([7; 32]);
+        let backup = b"{\"messages\":[\"hello\",\"hello\",\"hello\",\"hello\"]}".repeat(100);
+
+        let sealed = key.seal(&backup).unwrap();
+        assert!(is_sealed(&sealed), "a sealed backup should say so");
+        assert!(
+            sealed.len() < backup.len() / 4,
+            "a repetitive backup should be compressed, {} bytes from {}",
+            sealed.len(),
+            backup.len()
+        );
+        assert!(
+            !sealed.windows(5).any(|window| window == b"hello"),
+            "nothing of the backup should be readable once sealed"
+        );
+        assert_eq!(
+            key.open(&sealed),
+            Ok(backup.clone()),
+            "opening should give back the backup"
+        );
+        assert_ne!(
+            key.seal(&backup).unwrap(),
+            sealed,
+            "each backup should be sealed with a nonce of its own"
+        );
+    }
+
+    #[test]
+    fn refuses_a_tampered_backup_or_the_wrong_key() {
+        let key = BackupKey::new([7; 32]);
+        let sealed = key.seal(b"secret messages").unwrap();
+
+        assert_eq!(
+            BackupKey::new([8; 32]).open(&sealed),
+            Err(OpenError::NotAuthentic),
+            "a different key should not open it"
+        );
+
+        let mut flipped = sealed.clone();
+        let last = flipped.len() - TAG_BYTES - 1;
+        flipped[last] ^= 1;
+        assert_eq!(
+            key.open(&flipped),
+            Err(OpenError::NotAuthentic),
+            "a changed byte should be noticed"
+        );
+
+        assert_eq!(
+            key.open(&sealed[..sealed.len() - 1]),
+            Err(OpenError::NotAuthentic),
+            "a cut short backup should be noticed"
+        );
+    }
+
+    // A large export is sealed and opened a piece at a time, and has to come
+    // out the same as if it had been sealed in one go.
+    #[test]
+    fn seals_and_opens_a_piece_at_a_time() {
+        let key = BackupKey::new([7; 32]);
+        let backup: Vec<u8> = (0..300_000u32).flat_map(u32::to_le_bytes).collect();
+
+        let mut sealer = key.sealer(Vec::new()).unwrap();
+        for chunk in backup.chunks(1000) {
+            sealer.write_all(chunk).unwrap();
+        }
+        let sealed = sealer.finish().unwrap();
+
+        assert_eq!(
+            key.open_to(std::io::Cursor::new(&sealed), Vec::new()),
+            Ok(backup.clone()),
+            "opening a piece at a time should give back the backup"
+        );
+        assert_eq!(
+            key.open(&sealed),
+            Ok(backup),
+            "opening all at once should too"
+        );
+    }
+
+    // Anyone who can write to the bucket can put a backup there that was never
+    // sealed, so one is only let through where it is known to be old.
+    #[test]
+    fn gives_back_backups_from_before_sealing_only_when_asked_to() {
+        let key = BackupKey::new([7; 32]);
+        assert_eq!(
+            key.open_legacy_to(&b"{\"plain\":true}"[..], Vec::new()),
+            Ok(b"{\"plain\":true}".to_vec()),
+            "an unsealed backup from before sealing should be returned unchanged"
+        );
+        assert_eq!(
+            key.open(b"{\"plain\":true}"),
+            Err(OpenError::NotSealed),
+            "an unsealed backup should otherwise be refused"
+        );
+    }
+
+    // A key made up on the server would be lost along with it, so there is no
+    // key unless one is given.
+    #[test]
+    fn only_uses_a_key_it_is_given() {
+        let hex_key = hex(&[7; 32]);
+        let path = format!(
+            "{}/atchat-backup-key-{}",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::write(&path, format!("{}\n", hex(&[8; 32]))).unwrap();
+
+        let given = BackupKey::from_settings(Some(hex_key.clone()), Some(path.clone()));
+        let from_file = BackupKey::from_settings(None, Some(path.clone()));
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            given.map(|key| key.key),
+            Ok([7; 32]),
+            "a key given directly should be used"
+        );
+        assert_eq!(
+            from_file.map(|key| key.key),
+            Ok([8; 32]),
+            "a key in a file should be read from it"
+        );
+        assert!(
+            BackupKey::from_settings(None, None).is_err(),
+            "with no key given there should be none"
+        );
+        assert!(
+            BackupKey::from_settings(Some(String::from("not hex")), None).is_err(),
+            "a key that is not 64 hex digits should be refused"
+        );
+    }
+
+    #[test]
+    fn only_the_key_can_vouch_for_a_backup_from_before_sealing() {
+        let key = BackupKey::new([7; 32]);
+        let mark = key.legacy_mark("backend-export", "abc").unwrap();
+
+        assert!(
+            key.is_legacy_mark("backend-export", "abc", &mark),
+            "the key's own mark should be accepted"
+        );
+        assert!(
+            !key.is_legacy_mark("backend-export", "abd", &mark),
+            "the mark should not vouch for other contents"
+        );
+        assert!(
+            !key.is_legacy_mark("backend-export-2", "abc", &mark),
+            "the mark should not vouch for another backup"
+        );
+        assert!(
+            !BackupKey::new([8; 32]).is_legacy_mark("backend-export", "abc", &mark),
+            "another key's mark should not be accepted"
+        );
+    }
+
+    #[test]
+    fn names_chunks_by_their_content_and_key() {
+        let key = BackupKey::new([7; 32]);
+        let id = key.chunk_id(b"chunk").unwrap();
+
+        assert_eq!(id.len(), 64, "an id should be a hex Sha256");
+        assert_eq!(
+            key.chunk_id(b"chunk").unwrap(),
+            id,
+            "the same chunk should always have the same id"
+        );
+        assert_ne!(
+            key.chunk_id(b"chunk2").unwrap(),
+            id,
+            "another chunk should have another id"
+        );
+        assert_ne!(
+            BackupKey::new([8; 32]).chunk_id(b"chunk").unwrap(),
+            id,
+            "another key should give another id"
+        );
+    }
+}
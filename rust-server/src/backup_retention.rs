@@ -0,0 +1,289 @@
+//! Decides which backups to keep, grandfather-father-son style.
+//!
+//! The backend sends a backup every so often, and keeping all of them forever
+//! fills the disk, while keeping only the last month loses anything older for
+//! good. Instead the newest backup in each of the last 24 hours is kept, then
+//! the newest in each of the last 31 days, then the newest in each of the last
+//! 12 months. That is a little over 60 backups, going back a year.
+//!
+//! The counts are of hours, days and months that have a backup, not of hours,
+//! days and months on the calendar. So if backups stop arriving, what is there
+//! stops being thinned out rather than aging away to nothing.
+//!
+//! When a backup was taken is read from its name, which the backend gives as
+//! `backend-export-2026-05-13-03:56:33.bin` in UTC. Timestamps on the files
+//! themselves are no use, since not every filesystem records when a file was
+//! created and a copy made by hand is created when it was copied. A file
+//! whose name has no timestamp is never removed.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const FILENAME_PREFIX: &str = "backend-export-";
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d-%H:%M:%S";
+const TIMESTAMP_LENGTH: usize = "2026-05-13-03:56:33".len();
+
+/// How many hours, days and months to keep a backup from, each of which can
+/// be changed with `BACKUP_KEEP_HOURLY`, `BACKUP_KEEP_DAILY` and
+/// `BACKUP_KEEP_MONTHLY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub hourly: u64,
+    pub daily: u64,
+    pub monthly: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            hourly: 24,
+            daily: 31,
+            monthly: 12,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            hourly: crate::env_u64("BACKUP_KEEP_HOURLY", default.hourly),
+            daily: crate::env_u64("BACKUP_KEEP_DAILY", default.daily),
+            monthly: crate::env_u64("BACKUP_KEEP_MONTHLY", default.monthly),
+        }
+    }
+}
+
+/// Why a backup is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// It is the newest backup there is, which is kept whatever the policy
+    /// says.
+    Latest,
+    Hourly,
+    Daily,
+    Monthly,
+    /// Its name has no timestamp, so there is no telling how old it is.
+    Undated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeptBackup {
+    pub filename: String,
+    pub reasons: Vec<Reason>,
+}
+
+/// Which backups in a folder to keep, newest first, and which to remove.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPlan {
+    pub keep: Vec<KeptBackup>,
+    pub remove: Vec<String>,
+}
+
+/// When a backup was taken, going by its name.
+pub fn backup_time(filename: &str) -> Option<NaiveDateTime> {
+    let rest = filename.strip_prefix(FILENAME_PREFIX)?;
+    let timestamp = rest.get(..TIMESTAMP_LENGTH)?;
+    let extension = &rest[TIMESTAMP_LENGTH..];
+
+    // Half written files have a `_` and a number after the name, and are left
+    // for their writer to finish or clean up.
+    if !(extension.is_empty() || extension.starts_with('.')) || extension.contains('_') {
+        return None;
+    }
+
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()
+}
+
+pub fn plan(filenames: &[String], policy: RetentionPolicy) -> RetentionPlan {
+    let mut dated: Vec<(NaiveDateTime, &str)> = filenames
+        .iter()
+        .filter_map(|filename| Some((backup_time(filename)?, filename.as_str())))
+        .collect();
+    dated.sort_by(|a, b| b.cmp(a));
+
+    let mut reasons: HashMap<&str, Vec<Reason>> = HashMap::new();
+    if let Some((_, latest)) = dated.first() {
+        reasons.entry(latest).or_default().push(Reason::Latest);
+    }
+
+    for (reason, count, period_format) in [
+        (Reason::Hourly, policy.hourly, "%Y-%m-%d-%H"),
+        (Reason::Daily, policy.daily, "%Y-%m-%d"),
+        (Reason::Monthly, policy.monthly, "%Y-%m"),
+    ] {
+        let mut periods: u64 = 0;
+        let mut last_period: Option<String> = None;
+
+        for (time, filename) in &dated {
+            let period = time.format(period_format).to_string();
+            if last_period.as_ref() == Some(&period) {
+                continue;
+            }
+            if periods == count {
+                break;
+            }
+
+            periods += 1;
+            last_period = Some(period);
+            reasons.entry(filename).or_default().push(reason);
+        }
+    }
+
+    let mut result = RetentionPlan::default();
+    for (_, filename) in &dated {
+        match reasons.remove(filename) {
+            Some(reasons2) => result.keep.push(KeptBackup {
+                filename: (*filename).to_owned(),
+                reasons: reasons2,
+            }),
+            None => result.remove.push((*filename).to_owned()),
+        }
+    }
+
+    let mut undated: Vec<&String> = filenames
+        .iter()
+        .filter(|filename| backup_time(filename).is_none())
+        .collect();
+    undated.sort();
+    result
+        .keep
+        .extend(undated.into_iter().map(|filename| KeptBackup {
+            filename: filename.clone(),
+            reasons: vec![Reason::Undated],
+        }));
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(timestamps: &[&str]) -> Vec<String> {
+        timestamps
+            .iter()
+            .map(|timestamp| format!("{FILENAME_PREFIX}{timestamp}.bin"))
+            .collect()
+    }
+
+    fn kept(plan: &RetentionPlan) -> Vec<(&str, Vec<Reason>)> {
+        plan.keep
+            .iter()
+            .map(|backup| {
+                (
+                    backup
+                        .filename
+                        .trim_start_matches(FILENAME_PREFIX)
+                        .trim_end_matches(".bin"),
+                    backup.reasons.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_time_from_the_name() {
+        assert_eq!(
+            backup_time("backend-export-2026-05-13-03:56:33.bin").map(|time| time.to_string()),
+            Some(String::from("2026-05-13 03:56:33")),
+            "the backend's own names should be understood"
+        );
+        for filename in [
+            "backend-export.bin",
+            "backend-export-subset.bin",
+            "backend-export-2026-05-13-03:56:33.bin_123456",
+            "backend-export-2026-13-13-03:56:33.bin",
+            "other-2026-05-13-03:56:33.bin",
+        ] {
+            assert_eq!(backup_time(filename), None, "{filename} has no timestamp");
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_in_each_hour_day_and_month() {
+        let policy = RetentionPolicy {
+            hourly: 2,
+            daily: 2,
+            monthly: 2,
+        };
+        let plan = plan(
+            &names(&[
+                "2026-03-10-12:00:00",
+                "2026-03-10-12:30:00",
+                "2026-03-10-11:30:00",
+                "2026-03-10-10:30:00",
+                "2026-03-09-23:00:00",
+                "2026-03-09-22:00:00",
+                "2026-03-08-12:00:00",
+                "2026-02-20-12:00:00",
+                "2026-02-10-12:00:00",
+                "2026-01-31-12:00:00",
+            ]),
+            policy,
+        );
+
+        assert_eq!(
+            kept(&plan),
+            vec![
+                (
+                    "2026-03-10-12:30:00",
+                    vec![
+                        Reason::Latest,
+                        Reason::Hourly,
+                        Reason::Daily,
+                        Reason::Monthly
+                    ]
+                ),
+                ("2026-03-10-11:30:00", vec![Reason::Hourly]),
+                ("2026-03-09-23:00:00", vec![Reason::Daily]),
+                ("2026-02-20-12:00:00", vec![Reason::Monthly]),
+            ],
+            "the newest backup of each of the last few periods should be kept"
+        );
+        assert_eq!(
+            plan.remove.len(),
+            6,
+            "everything else should be removed: {:?}",
+            plan.remove
+        );
+    }
+
+    // Backups that stopped arriving months ago are as valuable as ever, so
+    // nothing is thinned out just for being old.
+    #[test]
+    fn counts_periods_that_have_a_backup() {
+        let plan = plan(
+            &names(&["2020-01-01-00:00:00", "2020-01-02-00:00:00"]),
+            RetentionPolicy::default(),
+        );
+
+        assert!(plan.remove.is_empty(), "nothing should be removed");
+    }
+
+    #[test]
+    fn keeps_what_it_cannot_date_and_always_the_latest() {
+        let mut filenames = names(&["2026-03-10-12:00:00", "2026-03-09-12:00:00"]);
+        filenames.push(String::from("backend-export-subset.bin"));
+
+        let plan = plan(
+            &filenames,
+            RetentionPolicy {
+                hourly: 0,
+                daily: 0,
+                monthly: 0,
+            },
+        );
+
+        assert_eq!(
+            kept(&plan),
+            vec![
+                ("2026-03-10-12:00:00", vec![Reason::Latest]),
+                ("subset", vec![Reason::Undated]),
+            ],
+            "the latest and the undated should be kept with nothing else"
+        );
+    }
+}
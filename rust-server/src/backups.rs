@@ -0,0 +1,2566 @@
+//! Keeps the backups the backend sends of its data.
+//!
+//! Each backup is written twice, once to the server's own disk and once to the
+//! S3 bucket mounted under storage, to improve the odds that we don't lose all
+//! of them at once. A backup is only as good as the bytes that reached the
+//! disk though, so its Sha256 is worked out as it arrives, checked against the
+//! one the backend says it sent if it says, and checked again by reading back
+//! each copy once it is written. The checksum is kept next to each copy in a
+//! small manifest, so a copy that has since rotted on disk can be found long
+//! after it was written.
+//!
+//! A backup is written to the server's disk as it arrives, rather than held in
+//! memory and written in one go, which used to hold up everything else the
+//! server was doing. Once it is all there the backend is answered with a job
+//! id, and reading the copy back and writing the second one happen in the
+//! background. The backend can poll the job to find out how that went.
+//!
+//! What is written is compressed and encrypted first, as described in
+//! [`crate::backup_encryption`]. The manifest has checksums of both, so the
+//! copies can be checked without the key and the backup inside them with it.
+//! Backups from before then are stored as they arrived. The first time the
+//! server has a key, it marks each of those with it, so that they can still
+//! be restored without letting through any unsealed copy put there since.
+//!
+//! With `BACKUP_INCREMENTAL=1`, a backup is instead cut into chunks and only
+//! the chunks that neither folder has yet are written, as described in
+//! [`crate::backup_chunks`]. Backups stored either way can sit side by side.
+//!
+//! Each new backup thins out the older ones in its folder, as described in
+//! [`crate::backup_retention`], along with any chunks only they needed.
+//!
+//! Getting a backup back out needs no shell on the server. Backups can be
+//! listed, restored to what the backend sent, downloaded as they are stored,
+//! and the two folders compared to find copies that went missing or differ.
+
+use crate::backup_chunks::{self, CHUNKS_DIR, ChunkWriter, Recipe, chunk_path};
+use crate::backup_encryption::{BackupKey, hex};
+use crate::backup_replication::{BUCKET_TARGET, Replicator};
+use crate::backup_retention::{self, RetentionPlan, RetentionPolicy};
+use crate::byte_range::ByteRange;
+use crate::{
+    AppState, create_dir_if_missing, json_response_with_headers, options_endpoint,
+    response_with_headers, write_atomically,
+};
+use axum::routing::{get, post};
+use axum::{
+    Extension, Router,
+    body::{Body, Bytes},
+    extract::{Path, Query},
+    http::StatusCode,
+    response::Response,
+};
+use futures_util::StreamExt;
+use http::HeaderMap;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+pub const SERVER_BACKUPS_PATH: &str = "./var/lib/atchat/backups/";
+pub const BUCKET_BACKUPS_PATH: &str = "./var/lib/atchat/storage/backups/";
+
+/// Kept on the server once the backups from before sealing have been marked,
+/// so that they are only ever marked the once.
+const LEGACY_MARKED_PATH: &str = "./var/lib/atchat/backups-legacy-marked.txt";
+
+/// The header the backend can give a backup's Sha256 in, as lowercase hex.
+pub const DIGEST_HEADER: &str = "x-backup-sha256";
+
+/// What is added to a backup's filename to get its manifest's.
+pub const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// The longest name a backup can have. The backend's own are 38 characters.
+const MAX_FILENAME_LENGTH: usize = 128;
+
+/// How much of a backup is read from disk at a time when downloading it.
+const DOWNLOAD_CHUNK_BYTES: usize = 256 * 1024;
+
+/// How many pieces of a backup that has arrived can be waiting to be written
+/// before the rest of it is left to wait for the disk.
+const RECEIVE_QUEUE_LENGTH: usize = 8;
+
+/// How many backups' jobs are remembered for the backend to ask about.
+const MAX_JOBS: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// The folder in the server's backups folder that restored backups are kept
+/// in for a while, so that restoring one in many ranges only decrypts it once.
+const RESTORED_DIR: &str = "restored/";
+
+/// How long a restored backup is kept after it was restored. Long enough to
+/// download it in as many pieces as it takes, and no longer, since it sits on
+/// the server's disk unencrypted.
+const RESTORED_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// How many restored backups are kept at once.
+const MAX_RESTORED: usize = 2;
+
+/// What is added to a restored backup's filename to get the name of the file
+/// saying which copy it was restored from.
+const RESTORED_FROM_SUFFIX: &str = ".from.json";
+
+/// The backup endpoints, with the folders they keep backups in.
+pub fn routes() -> Router<Arc<Mutex<AppState>>> {
+    Router::new()
+        .route(
+            "/file/internal/upload-backup/{filename}",
+            post(post_backup_endpoint).options(options_endpoint),
+        )
+        .route("/file/internal/backups", get(list_backups_endpoint))
+        .route(
+            "/file/internal/backups/retention",
+            get(retention_preview_endpoint),
+        )
+        .route(
+            "/file/internal/backups/copies",
+            get(compare_copies_endpoint),
+        )
+        .route(
+            "/file/internal/backups/restore/{filename}",
+            get(restore_backup_endpoint),
+        )
+        .route(
+            "/file/internal/backups/download/{location}/{filename}",
+            get(download_backup_endpoint),
+        )
+        .route(
+            "/file/internal/backups/jobs/{job_id}",
+            get(backup_job_endpoint),
+        )
+        .route(
+            "/file/internal/backups/replication",
+            get(replication_status_endpoint),
+        )
+        .layer(Extension(Arc::new(Backups::load())))
+}
+
+/// The two folders backups are written to, and the key they are encrypted
+/// with. Without a key nothing is written, rather than writing backups anyone
+/// with the bucket can read.
+pub struct Backups {
+    server_dir: String,
+    bucket_dir: String,
+    key: Option<BackupKey>,
+    retention: RetentionPolicy,
+    /// Whether new backups are stored as chunks rather than whole.
+    incremental: bool,
+    max_upload_bytes: usize,
+    jobs: Mutex<LruCache<String, BackupJob>>,
+    /// Sends backups off-site, and to the bucket when copying there failed.
+    replicator: Arc<Replicator>,
+    /// Held while a backup is restored, so that the ranges of one asked for
+    /// together wait for it to be decrypted once rather than each doing it.
+    restoring: tokio::sync::Mutex<()>,
+}
+
+/// Why a backup's name was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilenameError {
+    Empty,
+    TooLong,
+    /// It names a folder, `..` or a path, which would put the backup
+    /// somewhere other than the backups folder.
+    Path,
+    /// It has something other than letters, digits, `.`, `-` and `:` in it.
+    Character(char),
+    /// It is the name of another backup's manifest.
+    Manifest,
+}
+
+impl std::fmt::Display for FilenameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "A backup needs a filename"),
+            Self::TooLong => write!(
+                f,
+                "A backup's filename can be at most {MAX_FILENAME_LENGTH} characters long"
+            ),
+            Self::Path => write!(
+                f,
+                "A backup's filename cannot be a path, start with a dot or contain .."
+            ),
+            Self::Character(character) => write!(
+                f,
+                "A backup's filename can only contain letters, digits, '.', '-' and ':', not {character:?}"
+            ),
+            Self::Manifest => write!(
+                f,
+                "A backup's filename cannot end in {MANIFEST_SUFFIX}, which is kept for manifests"
+            ),
+        }
+    }
+}
+
+/// Checks that a backup's name, which comes straight from the url, can only
+/// ever name a file directly inside the backups folder. Anything that could
+/// lead elsewhere, like `..`, `/etc/passwd` or `a\b`, is refused outright
+/// rather than cleaned up, since a name that needed cleaning was never sent by
+/// the backend.
+pub fn validate_filename(filename: &str) -> Result<(), FilenameError> {
+    if filename.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if filename.len() > MAX_FILENAME_LENGTH {
+        return Err(FilenameError::TooLong);
+    }
+    if filename.starts_with('.') || filename.contains("..") || filename.contains(['/', '\\']) {
+        return Err(FilenameError::Path);
+    }
+    if let Some(character) = filename
+        .chars()
+        .find(|x| !(x.is_ascii_alphanumeric() || matches!(x, '.' | '-' | ':')))
+    {
+        return Err(FilenameError::Character(character));
+    }
+    if filename.ends_with(MANIFEST_SUFFIX) {
+        return Err(FilenameError::Manifest);
+    }
+
+    Ok(())
+}
+
+fn invalid_filename_response(error: FilenameError) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(error.to_string()))
+        .unwrap()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadOptions {
+    /// Replace a backup that already has this name. Without it, a second
+    /// backup under the same name is refused so the first is never lost.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// Which of the two folders a copy of a backup is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Location {
+    Server,
+    Bucket,
+}
+
+/// How far along a job has got with one copy of a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyProgress {
+    Pending,
+    /// It was written, read back and found to match, and its manifest is
+    /// next to it.
+    Verified,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    /// Both copies were written and verified.
+    Succeeded,
+    /// At least one copy could not be written. The other may still have been.
+    Failed,
+}
+
+/// What became of a backup after it arrived.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupJob {
+    pub id: String,
+    pub filename: String,
+    pub sha256: String,
+    pub state: JobState,
+    pub server: CopyProgress,
+    pub bucket: CopyProgress,
+    pub errors: Vec<String>,
+    pub received_at: String,
+    pub finished_at: Option<String>,
+}
+
+impl BackupJob {
+    fn new(filename: &str, manifest: &Manifest) -> Self {
+        Self {
+            id: hex(&rand::random::<[u8; 16]>()),
+            filename: filename.to_owned(),
+            sha256: manifest.sha256.clone(),
+            state: JobState::Running,
+            server: CopyProgress::Pending,
+            bucket: CopyProgress::Pending,
+            errors: Vec::new(),
+            received_at: manifest.received_at.clone(),
+            finished_at: None,
+        }
+    }
+
+    fn record(&mut self, location: Location, result: &std::io::Result<()>) {
+        let progress = match result {
+            Ok(()) => CopyProgress::Verified,
+            Err(error) => {
+                self.errors.push(format!("{location:?} copy: {error}"));
+                CopyProgress::Failed
+            }
+        };
+
+        match location {
+            Location::Server => self.server = progress,
+            Location::Bucket => self.bucket = progress,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.state =
+            if self.server == CopyProgress::Verified && self.bucket == CopyProgress::Verified {
+                JobState::Succeeded
+            } else {
+                JobState::Failed
+            };
+        self.finished_at = Some(chrono::Utc::now().to_rfc3339());
+    }
+}
+
+/// What the backend is answered with once a backup has arrived.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadReceipt {
+    pub job_id: String,
+    pub sha256: String,
+}
+
+/// What is kept next to each copy of a backup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The Sha256 of the backup as it arrived, in lowercase hex.
+    pub sha256: String,
+    /// The Sha256 of what was written, once compressed and encrypted. Missing
+    /// from backups written before they were, where it is the same as
+    /// `sha256`.
+    #[serde(default)]
+    pub stored_sha256: Option<String>,
+    /// The size of the backup as it arrived.
+    pub size: u64,
+    pub received_at: String,
+    /// Whether the backend sent a checksum of its own, which this matched.
+    pub checked_against_sender: bool,
+    /// For a backup stored unsealed, from before backups were sealed, the
+    /// key's [`BackupKey::legacy_mark`] vouching for it. The manifest sits in
+    /// the bucket for anyone to rewrite, so nothing else in it can say a copy
+    /// is allowed to be unsealed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub legacy_mark: Option<String>,
+}
+
+/// Whether a copy still holds what was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumStatus {
+    Verified,
+    /// The copy no longer matches its manifest.
+    Mismatch,
+    /// There is no manifest to check against, as with backups written before
+    /// manifests were.
+    Unrecorded,
+    Unreadable,
+    /// The copy is a recipe, and some of the chunks it needs are gone.
+    MissingChunks,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub filename: String,
+    pub size: u64,
+    /// When the copy was last written, in RFC 3339.
+    pub modified: Option<String>,
+    pub sha256: Option<String>,
+    pub status: ChecksumStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupListing {
+    pub server: Vec<BackupInfo>,
+    pub bucket: Vec<BackupInfo>,
+}
+
+impl Backups {
+    /// Both folders end in a `/`.
+    pub fn new(server_dir: &str, bucket_dir: &str, key: Option<BackupKey>) -> Self {
+        Self {
+            server_dir: server_dir.to_owned(),
+            bucket_dir: bucket_dir.to_owned(),
+            key,
+            retention: RetentionPolicy::default(),
+            incremental: false,
+            max_upload_bytes: crate::MAX_UPLOAD_BYTES,
+            jobs: Mutex::new(LruCache::new(MAX_JOBS)),
+            replicator: Arc::new(Replicator::new(
+                server_dir,
+                vec![crate::backup_replication::Target::local(
+                    BUCKET_TARGET,
+                    bucket_dir,
+                )],
+                None,
+            )),
+            restoring: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// The usual folders, and the key the server was given, as described in
+    /// [`crate::backup_encryption`].
+    fn load() -> Self {
+        let key = match BackupKey::from_env() {
+            Ok(key2) => Some(key2),
+            Err(error) => {
+                println!("Backups are disabled. {error}");
+                None
+            }
+        };
+
+        let replicator = Arc::new(Replicator::load(SERVER_BACKUPS_PATH, BUCKET_BACKUPS_PATH));
+        Replicator::spawn_retries(replicator.clone());
+
+        let backups = Self {
+            retention: RetentionPolicy::from_env(),
+            incremental: crate::env_u64("BACKUP_INCREMENTAL", 0) != 0,
+            replicator,
+            ..Self::new(SERVER_BACKUPS_PATH, BUCKET_BACKUPS_PATH, key)
+        };
+
+        // The first time the server has a key, every unsealed backup there is
+        // has to be from before sealing, since nothing has been sealed yet.
+        // Any that turn up later cannot be, so this is never done again.
+        if backups.key.is_some() && !fs::exists(LEGACY_MARKED_PATH).unwrap_or(true) {
+            let backups2 = Self::new(
+                SERVER_BACKUPS_PATH,
+                BUCKET_BACKUPS_PATH,
+                backups.key.clone(),
+            );
+            tokio::task::spawn_blocking(move || {
+                let marked = backups2.mark_legacy_copies();
+                println!("Marked {marked} backups from before backups were encrypted");
+                let _ = write_atomically(LEGACY_MARKED_PATH, marked.to_string().as_bytes());
+            });
+        }
+
+        backups
+    }
+
+    /// Gives each unsealed copy of a backup in either folder a manifest with a
+    /// [`Manifest::legacy_mark`], so that it can still be restored, and returns
+    /// how many were marked. A copy whose manifest says it was sealed is left
+    /// unmarked, since it has been swapped for something else since.
+    fn mark_legacy_copies(&self) -> usize {
+        let Some(key) = &self.key else {
+            return 0;
+        };
+
+        let mut marked = 0;
+        for dir in [&self.server_dir, &self.bucket_dir] {
+            for filename in backup_filenames(dir) {
+                let path = format!("{dir}{filename}");
+                if !matches!(Recipe::read(&path), Ok(None)) || is_sealed_file(&path) {
+                    continue;
+                }
+
+                let existing: Option<Manifest> = read_manifest(&path);
+                if existing
+                    .as_ref()
+                    .is_some_and(|manifest| manifest.stored_sha256.is_some())
+                {
+                    continue;
+                }
+
+                let (Ok(sha256), Ok(metadata)) = (sha256_file(&path), fs::metadata(&path)) else {
+                    continue;
+                };
+                let Ok(mark) = key.legacy_mark(&filename, &sha256) else {
+                    continue;
+                };
+                let manifest = Manifest {
+                    stored_sha256: None,
+                    legacy_mark: Some(mark),
+                    ..existing
+                        .filter(|manifest| manifest.sha256 == sha256)
+                        .unwrap_or_else(|| Manifest {
+                            sha256: sha256.clone(),
+                            stored_sha256: None,
+                            size: metadata.len(),
+                            received_at: metadata
+                                .modified()
+                                .map(|time| {
+                                    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()
+                                })
+                                .unwrap_or_default(),
+                            checked_against_sender: false,
+                            legacy_mark: None,
+                        })
+                };
+
+                if write_atomically(
+                    &format!("{path}{MANIFEST_SUFFIX}"),
+                    serde_json::to_string(&manifest).unwrap().as_bytes(),
+                )
+                .is_ok()
+                {
+                    marked += 1;
+                }
+            }
+        }
+        marked
+    }
+
+    /// Reads back the server's copy of a backup that has just arrived, copies
+    /// it to the bucket and reads that back too, keeping the backup's job up
+    /// to date as it goes. This is the slow part, which the backend is not
+    /// kept waiting for.
+    fn finish_backup(&self, job_id: &str, filename: &str, key: &BackupKey, manifest: &Manifest) {
+        let server = verify_copy(&self.server_dir, filename, key, manifest);
+        self.update_job(job_id, |job| job.record(Location::Server, &server));
+
+        let bucket = match &server {
+            Ok(()) => {
+                create_dir_if_missing(self.bucket_dir.clone());
+                copy_backup(&self.server_dir, &self.bucket_dir, filename)
+                    .and_then(|()| verify_copy(&self.bucket_dir, filename, key, manifest))
+            }
+            Err(_) => Err(std::io::Error::other(
+                "There was no intact copy on the server to copy",
+            )),
+        };
+        self.update_job(job_id, |job| job.record(Location::Bucket, &bucket));
+
+        // The bucket is tried again later if it could not be written, and
+        // every backup is sent off-site.
+        if server.is_ok() {
+            let mut targets = self.replicator.configured_targets();
+            if bucket.is_err() {
+                targets.push(BUCKET_TARGET.to_owned());
+            }
+            self.replicator.enqueue(filename, &targets);
+        }
+
+        // Older backups are only thinned out once a newer one is safely in
+        // place next to them.
+        if server.is_ok() {
+            self.apply_retention(&self.server_dir);
+        }
+        if bucket.is_ok() {
+            self.apply_retention(&self.bucket_dir);
+        }
+
+        self.update_job(job_id, BackupJob::finish);
+    }
+
+    fn update_job(&self, job_id: &str, update: impl FnOnce(&mut BackupJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            update(job);
+        }
+    }
+
+    /// The backup decrypted and decompressed into a file in [`RESTORED_DIR`],
+    /// with its Sha256. It is only decrypted if it has not been lately, or the
+    /// copy it was restored from has changed since, from the server's copy if
+    /// that is intact and the bucket's otherwise.
+    fn restored(&self, filename: &str, key: &BackupKey) -> Result<(String, String), Vec<String>> {
+        let restored_dir = format!("{}{RESTORED_DIR}", self.server_dir);
+        remove_old_restored(&restored_dir, RESTORED_TTL, MAX_RESTORED);
+
+        let path = format!("{restored_dir}{filename}");
+        let from_path = format!("{path}{RESTORED_FROM_SUFFIX}");
+        if let Some(from) = fs::read(&from_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<RestoredFrom>(&bytes).ok())
+            && fs::exists(&path).unwrap_or(false)
+            && RestoredFrom::copy(&from.copy_path, &from.sha256).as_ref() == Some(&from)
+        {
+            return Ok((path, from.sha256));
+        }
+
+        create_dir_if_missing(restored_dir.clone());
+        remove_old_restored(&restored_dir, RESTORED_TTL, MAX_RESTORED - 1);
+        let partial_path = format!("{path}_{}", rand::random::<u64>());
+        let mut errors: Vec<String> = Vec::new();
+        for dir in [&self.server_dir, &self.bucket_dir] {
+            let copy_path = format!("{dir}{filename}");
+            let copy_before = fs::metadata(&copy_path).ok();
+            match restore_copy(dir, filename, key, &partial_path) {
+                Ok(sha256) => {
+                    // Recorded as the copy was before it was read, so that a
+                    // copy replaced while it was being read is read again.
+                    let from = copy_before.map(|metadata| {
+                        RestoredFrom::from_metadata(&copy_path, &sha256, &metadata)
+                    });
+                    return write_atomically(
+                        &from_path,
+                        serde_json::to_string(&from).unwrap().as_bytes(),
+                    )
+                    .and_then(|()| fs::rename(&partial_path, &path))
+                    .map(|()| (path, sha256))
+                    .map_err(|error| {
+                        let _ = fs::remove_file(&partial_path);
+                        vec![format!("Keeping the restored backup failed: {error}")]
+                    });
+                }
+                Err(error) => errors.push(format!("{dir}: {error}")),
+            }
+        }
+        Err(errors)
+    }
+
+    /// Forgets a restored backup, as when the backup is replaced.
+    fn forget_restored(&self, filename: &str) {
+        let path = format!("{}{RESTORED_DIR}{filename}", self.server_dir);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{path}{RESTORED_FROM_SUFFIX}"));
+    }
+
+    fn dir(&self, location: Location) -> &str {
+        match location {
+            Location::Server => &self.server_dir,
+            Location::Bucket => &self.bucket_dir,
+        }
+    }
+
+    fn retention_plan(&self, dir: &str) -> RetentionPlan {
+        backup_retention::plan(&backup_filenames(dir), self.retention)
+    }
+
+    /// Removes the backups in a folder the retention policy has no use for,
+    /// along with their manifests and any chunks no other backup needs.
+    fn apply_retention(&self, dir: &str) {
+        for filename in self.retention_plan(dir).remove {
+            let _ = fs::remove_file(format!("{dir}{filename}"));
+            let _ = fs::remove_file(format!("{dir}{filename}{MANIFEST_SUFFIX}"));
+        }
+
+        // A recipe that could not be read may still need any of the chunks,
+        // so none are removed until it can be.
+        let recipes: std::io::Result<Vec<Option<Recipe>>> = backup_filenames(dir)
+            .iter()
+            .map(|filename| Recipe::read(&format!("{dir}{filename}")))
+            .collect();
+        if let Ok(recipes2) = recipes {
+            let recipes3: Vec<Recipe> = recipes2.into_iter().flatten().collect();
+            backup_chunks::remove_unused(dir, &recipes3);
+        }
+    }
+
+    fn list(&self) -> BackupListing {
+        BackupListing {
+            server: list_dir(&self.server_dir),
+            bucket: list_dir(&self.bucket_dir),
+        }
+    }
+}
+
+/// Writes a backup to the server's disk as it arrives, and answers with the
+/// id of a job that reads it back and writes the second copy.
+pub async fn post_backup_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+    Path(filename): Path<String>,
+    Query(options): Query<UploadOptions>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response<String> {
+    if let Err(error) = validate_filename(&filename) {
+        return response_with_headers(StatusCode::BAD_REQUEST, error.to_string());
+    }
+
+    let Some(key) = backups.key.clone() else {
+        return response_with_headers(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No backup key has been given to the server, so the backup cannot be encrypted",
+        );
+    };
+
+    let exists = [&backups.server_dir, &backups.bucket_dir]
+        .iter()
+        .any(|dir| fs::exists(format!("{dir}{filename}")).unwrap_or(true));
+    if exists && !options.overwrite {
+        return response_with_headers(
+            StatusCode::CONFLICT,
+            format!("A backup named {filename} already exists. Add ?overwrite=true to replace it."),
+        );
+    }
+
+    create_dir_if_missing(backups.server_dir.clone());
+    create_dir_if_missing(backups.bucket_dir.clone());
+
+    let server_path = format!("{}{filename}", backups.server_dir);
+    let partial_path = format!("{server_path}_{}", rand::random::<u64>());
+
+    let received = receive(&backups, &key, &partial_path, body).await;
+    let received2 = match received {
+        Ok(received3) => received3,
+        Err(response) => {
+            let _ = fs::remove_file(&partial_path);
+            return response;
+        }
+    };
+
+    let claimed: Option<String> = headers
+        .get(DIGEST_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+
+    if let Some(claimed2) = &claimed
+        && *claimed2 != received2.sha256
+    {
+        let _ = fs::remove_file(&partial_path);
+        return response_with_headers(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Checksum mismatch, received {} but expected {claimed2}",
+                received2.sha256
+            ),
+        );
+    }
+
+    // A manifest left from a backup this one replaces would no longer match,
+    // and nor would the backup restored from it.
+    let _ = fs::remove_file(format!("{server_path}{MANIFEST_SUFFIX}"));
+    backups.forget_restored(&filename);
+    if let Err(error) = fs::rename(&partial_path, &server_path) {
+        let _ = fs::remove_file(&partial_path);
+        return response_with_headers(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Writing the backup failed\n{error:?}"),
+        );
+    }
+
+    let manifest = Manifest {
+        sha256: received2.sha256,
+        stored_sha256: Some(received2.stored_sha256),
+        size: received2.size,
+        received_at: chrono::Utc::now().to_rfc3339(),
+        checked_against_sender: claimed.is_some(),
+        legacy_mark: None,
+    };
+
+    let job = BackupJob::new(&filename, &manifest);
+    let receipt = UploadReceipt {
+        job_id: job.id.clone(),
+        sha256: manifest.sha256.clone(),
+    };
+    backups.jobs.lock().unwrap().put(job.id.clone(), job);
+
+    let job_id = receipt.job_id.clone();
+    tokio::task::spawn_blocking(move || {
+        backups.finish_backup(&job_id, &filename, &key, &manifest);
+    });
+
+    json_response_with_headers(
+        StatusCode::ACCEPTED,
+        serde_json::to_string(&receipt).unwrap(),
+    )
+}
+
+/// How a backup's job is getting on, for the backend to poll until both
+/// copies are written.
+pub async fn backup_job_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+    Path(job_id): Path<String>,
+) -> Response<String> {
+    let job: Option<BackupJob> = backups.jobs.lock().unwrap().get(&job_id).cloned();
+
+    match job {
+        Some(job2) => {
+            json_response_with_headers(StatusCode::OK, serde_json::to_string(&job2).unwrap())
+        }
+        None => response_with_headers(
+            StatusCode::NOT_FOUND,
+            format!("No such job. Only the last {MAX_JOBS} backups' jobs are remembered."),
+        ),
+    }
+}
+
+/// How sending backups to each target is going. Answers 503 when one has
+/// been failing for too long, for an uptime monitor to alert on.
+pub async fn replication_status_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+) -> Response<String> {
+    let status = backups.replicator.status();
+    let code = if status.alerting {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    json_response_with_headers(code, serde_json::to_string(&status).unwrap())
+}
+
+/// Writes a backup into `partial_path` as it arrives, refusing it if it turns
+/// out to be too large or never all arrives.
+async fn receive(
+    backups: &Backups,
+    key: &BackupKey,
+    partial_path: &str,
+    body: Body,
+) -> Result<Received, Response<String>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Bytes>(RECEIVE_QUEUE_LENGTH);
+    let writer = {
+        let key2 = key.clone();
+        let partial_path2 = partial_path.to_owned();
+        let chunks_dir = backups.incremental.then(|| backups.server_dir.clone());
+        tokio::task::spawn_blocking(move || {
+            write_received(&partial_path2, &key2, chunks_dir.as_deref(), receiver)
+        })
+    };
+
+    let mut refusal: Option<Response<String>> = None;
+    let mut size: usize = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk2 = match chunk {
+            Ok(chunk2) => chunk2,
+            Err(error) => {
+                refusal = Some(response_with_headers(
+                    StatusCode::BAD_REQUEST,
+                    format!("The backup was cut off before it all arrived\n{error}"),
+                ));
+                break;
+            }
+        };
+
+        size += chunk2.len();
+        if size > backups.max_upload_bytes {
+            refusal = Some(response_with_headers(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("A backup can be at most {} bytes", backups.max_upload_bytes),
+            ));
+            break;
+        }
+
+        // The writer only stops early if writing failed, which it reports
+        // once it is waited for.
+        if sender.send(chunk2).await.is_err() {
+            break;
+        }
+    }
+    drop(sender);
+
+    let written = writer.await;
+    match (refusal, written) {
+        (Some(refusal2), _) => Err(refusal2),
+        (None, Ok(Ok(received2))) => Ok(received2),
+        (None, Ok(Err(error))) => Err(response_with_headers(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Writing the backup failed\n{error:?}"),
+        )),
+        (None, Err(error)) => Err(response_with_headers(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Writing the backup failed\n{error:?}"),
+        )),
+    }
+}
+
+/// What was worked out about a backup as it was written.
+struct Received {
+    sha256: String,
+    stored_sha256: String,
+    size: u64,
+}
+
+/// Writes the pieces of a backup as they arrive, until there are no more. It
+/// is sealed whole into `path`, or, given a backups folder to keep chunks in,
+/// cut into chunks there with its recipe in `path`.
+fn write_received(
+    path: &str,
+    key: &BackupKey,
+    chunks_dir: Option<&str>,
+    receiver: tokio::sync::mpsc::Receiver<Bytes>,
+) -> std::io::Result<Received> {
+    match chunks_dir {
+        None => {
+            let file = fs::File::create(path)?;
+            let mut sealer = key.sealer(HashingWriter {
+                writer: std::io::BufWriter::new(file),
+                hasher: Sha256::new(),
+            })?;
+            let (sha256, size) = drain_into(receiver, &mut sealer)?;
+
+            let stored = sealer.finish()?;
+            stored
+                .writer
+                .into_inner()
+                .map_err(std::io::IntoInnerError::into_error)?
+                .sync_all()?;
+
+            Ok(Received {
+                sha256,
+                stored_sha256: hex(&stored.hasher.finalize()),
+                size,
+            })
+        }
+        Some(dir) => {
+            let mut chunks = ChunkWriter::new(dir, key)?;
+            let (sha256, size) = drain_into(receiver, &mut chunks)?;
+
+            let recipe = chunks.finish()?.to_bytes();
+            fs::write(path, &recipe)?;
+
+            Ok(Received {
+                sha256,
+                stored_sha256: hex(&Sha256::digest(&recipe)),
+                size,
+            })
+        }
+    }
+}
+
+/// Writes each piece of a backup into `writer` as it arrives, and gives back
+/// the backup's Sha256 and size.
+fn drain_into(
+    mut receiver: tokio::sync::mpsc::Receiver<Bytes>,
+    writer: &mut impl Write,
+) -> std::io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    while let Some(chunk) = receiver.blocking_recv() {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        writer.write_all(&chunk)?;
+    }
+    Ok((hex(&hasher.finalize()), size))
+}
+
+/// Works out the Sha256 of what is written through it.
+struct HashingWriter<W: Write> {
+    writer: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads a copy back to make sure it is what was sent, and only then writes
+/// its manifest. A copy without a manifest was never confirmed, and one that
+/// does not match is removed.
+fn verify_copy(
+    dir: &str,
+    filename: &str,
+    key: &BackupKey,
+    manifest: &Manifest,
+) -> std::io::Result<()> {
+    let path = format!("{dir}{filename}");
+    let opened_sha256: Option<String> =
+        open_copy_to(dir, filename, key, Some(manifest), Sha256::new())
+            .ok()
+            .map(|hasher| hex(&hasher.finalize()));
+
+    if opened_sha256.as_ref() != Some(&manifest.sha256) {
+        let _ = fs::remove_file(&path);
+        return Err(std::io::Error::other(format!(
+            "The copy read back does not match the backup's checksum {}",
+            manifest.sha256
+        )));
+    }
+
+    write_atomically(
+        &format!("{path}{MANIFEST_SUFFIX}"),
+        serde_json::to_string(manifest).unwrap().as_bytes(),
+    )
+}
+
+/// Opens one copy of a backup into `writer`, whether it was stored whole or as
+/// a recipe for chunks in its folder. A copy stored as it arrived, unsealed,
+/// is only opened if its manifest has the key's mark vouching that it is from
+/// before backups were sealed. Whether it still holds what the mark was made
+/// for is up to the caller to check against the manifest's `sha256`.
+fn open_copy_to<W: Write>(
+    dir: &str,
+    filename: &str,
+    key: &BackupKey,
+    manifest: Option<&Manifest>,
+    writer: W,
+) -> Result<W, String> {
+    let path = format!("{dir}{filename}");
+
+    match Recipe::read(&path) {
+        Ok(Some(recipe)) => backup_chunks::open_to(dir, &recipe, key, writer),
+        Ok(None) => {
+            let file =
+                std::io::BufReader::new(fs::File::open(&path).map_err(|error| error.to_string())?);
+            let is_legacy = manifest.is_some_and(|manifest2| {
+                manifest2
+                    .legacy_mark
+                    .as_ref()
+                    .is_some_and(|mark| key.is_legacy_mark(filename, &manifest2.sha256, mark))
+            });
+            let opened = if is_legacy {
+                key.open_legacy_to(file, writer)
+            } else {
+                key.open_to(file, writer)
+            };
+            opened.map_err(|error| error.to_string())
+        }
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Copies a backup from one folder to another, along with whichever of its
+/// chunks the other folder does not have yet.
+fn copy_backup(from_dir: &str, to_dir: &str, filename: &str) -> std::io::Result<()> {
+    if let Some(recipe) = Recipe::read(&format!("{from_dir}{filename}"))? {
+        create_dir_if_missing(format!("{to_dir}{CHUNKS_DIR}"));
+
+        for chunk in &recipe.chunks {
+            let to = chunk_path(to_dir, &chunk.id);
+            if fs::exists(&to)? {
+                backup_chunks::touch(&to)?;
+            } else {
+                copy_atomically(&chunk_path(from_dir, &chunk.id), &to)?;
+            }
+        }
+    }
+
+    copy_atomically(
+        &format!("{from_dir}{filename}"),
+        &format!("{to_dir}{filename}"),
+    )
+}
+
+/// Copies a file under a temporary name and then renames it into place, so
+/// there is never a half copied file under its real name.
+pub fn copy_atomically(from: &str, to: &str) -> std::io::Result<()> {
+    let partial_path: String = format!("{to}_{}", rand::random::<u64>());
+
+    let result = fs::copy(from, &partial_path).and_then(|_| {
+        let _ = fs::remove_file(format!("{to}{MANIFEST_SUFFIX}"));
+        fs::rename(&partial_path, to)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial_path);
+    }
+    result
+}
+
+/// Every backup in both folders, each checked against its manifest. Every copy
+/// is read in full to do that, so this is slow with many large backups.
+pub async fn list_backups_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+) -> Response<String> {
+    match tokio::task::spawn_blocking(move || backups.list()).await {
+        Ok(listing) => {
+            json_response_with_headers(StatusCode::OK, serde_json::to_string(&listing).unwrap())
+        }
+        Err(error) => response_with_headers(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Listing backups failed\n{error:?}"),
+        ),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPreview {
+    pub server: RetentionPlan,
+    pub bucket: RetentionPlan,
+}
+
+/// What the retention policy would keep and remove in each folder, without
+/// removing anything. The next backup to arrive removes it for real.
+pub async fn retention_preview_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+) -> Response<String> {
+    let preview = RetentionPreview {
+        server: backups.retention_plan(&backups.server_dir),
+        bucket: backups.retention_plan(&backups.bucket_dir),
+    };
+
+    json_response_with_headers(StatusCode::OK, serde_json::to_string(&preview).unwrap())
+}
+
+/// A backup as the backend sent it, decrypted and decompressed. The server's
+/// copy is used if it is intact, and the bucket's otherwise. A `Range` header
+/// is honoured. The backup is decrypted into a file the first time it is
+/// asked for and sent from there, so a backup downloaded in many pieces is
+/// only decrypted once, and never held in memory all at once.
+pub async fn restore_backup_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+    Path(filename): Path<String>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    if let Err(error) = validate_filename(&filename) {
+        return invalid_filename_response(error);
+    }
+
+    let Some(key) = backups.key.clone() else {
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(
+                "No backup key, so the backup cannot be decrypted",
+            ))
+            .unwrap();
+    };
+
+    let restored = {
+        let _restoring = backups.restoring.lock().await;
+        let backups2 = backups.clone();
+        tokio::task::spawn_blocking(move || backups2.restored(&filename, &key)).await
+    };
+
+    match restored {
+        Ok(Ok((path, sha256))) => {
+            let (file, length) = match fs::File::open(&path).and_then(|file| {
+                let length = file.metadata()?.len();
+                Ok((file, length))
+            }) {
+                Ok(opened) => opened,
+                Err(error) => {
+                    return Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from(format!(
+                            "The restored backup could not be read\n{error}"
+                        )))
+                        .unwrap();
+                }
+            };
+
+            let range = ByteRange::parse(range_header(&headers), length);
+            let builder =
+                range_response(range, length, Some(&sha256)).header(DIGEST_HEADER, &sha256);
+            match range {
+                ByteRange::Unsatisfiable => builder.body(Body::empty()).unwrap(),
+                ByteRange::Whole | ByteRange::Part { .. } => {
+                    let (start, count) = range.span(length);
+                    builder
+                        .header("Content-Length", count)
+                        .body(stream_file(file, start, count))
+                        .unwrap()
+                }
+            }
+        }
+        Ok(Err(errors)) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(format!(
+                "No intact copy of the backup was found\n{}",
+                errors.join("\n")
+            )))
+            .unwrap(),
+        Err(error) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!(
+                "Restoring the backup failed\n{error:?}"
+            )))
+            .unwrap(),
+    }
+}
+
+/// A copy of a backup exactly as it is stored, compressed and encrypted, for
+/// keeping somewhere else. It is read from disk as it is sent, so any size of
+/// backup can be downloaded, and a `Range` header lets a dropped download pick
+/// up where it left off. A backup stored as chunks is downloaded as its recipe,
+/// which is no use without the chunks, so those are better restored instead.
+pub async fn download_backup_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+    Path((location, filename)): Path<(Location, String)>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    if let Err(error) = validate_filename(&filename) {
+        return invalid_filename_response(error);
+    }
+
+    let path = format!("{}{filename}", backups.dir(location));
+
+    let (file, length) = match fs::File::open(&path).and_then(|file| {
+        let length = file.metadata()?.len();
+        Ok((file, length))
+    }) {
+        Ok(opened) => opened,
+        Err(error) => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from(format!("No such backup\n{error}")))
+                .unwrap();
+        }
+    };
+
+    let manifest = read_manifest(&path);
+    let stored_sha256: Option<&String> = manifest.as_ref().map(|manifest2| {
+        manifest2
+            .stored_sha256
+            .as_ref()
+            .unwrap_or(&manifest2.sha256)
+    });
+
+    let range = ByteRange::parse(range_header(&headers), length);
+    let builder = range_response(range, length, stored_sha256.map(String::as_str));
+
+    match range {
+        ByteRange::Unsatisfiable => builder.body(Body::empty()).unwrap(),
+        ByteRange::Whole | ByteRange::Part { .. } => {
+            let (start, count) = range.span(length);
+            builder
+                .header("Content-Length", count)
+                .body(stream_file(file, start, count))
+                .unwrap()
+        }
+    }
+}
+
+fn range_header(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+}
+
+/// The start of a response sending the part of a backup a `Range` header asked
+/// for. The backup's checksum makes a good `ETag`, since it changes exactly
+/// when the bytes do.
+fn range_response(range: ByteRange, length: u64, sha256: Option<&str>) -> http::response::Builder {
+    let status = match range {
+        ByteRange::Whole => StatusCode::OK,
+        ByteRange::Part { .. } => StatusCode::PARTIAL_CONTENT,
+        ByteRange::Unsatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+    };
+
+    let mut builder = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/octet-stream")
+        .header("Accept-Ranges", "bytes");
+    if let Some(sha256_2) = sha256 {
+        builder = builder.header("ETag", format!("\"{sha256_2}\""));
+    }
+    if let Some(content_range) = range.content_range(length) {
+        builder = builder.header("Content-Range", content_range);
+    }
+    builder
+}
+
+/// `count` bytes of a file from `start`, read a chunk at a time as the client
+/// takes them. Reading stops as soon as the client goes away.
+fn stream_file(mut file: fs::File, start: u64, count: u64) -> Body {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(4);
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(error) = file.seek(SeekFrom::Start(start)) {
+            let _ = sender.blocking_send(Err(error));
+            return;
+        }
+
+        let mut reader = file.take(count);
+        let mut buffer = vec![0u8; DOWNLOAD_CHUNK_BYTES];
+        loop {
+            let chunk = match reader.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(read) => Ok(Bytes::copy_from_slice(&buffer[..read])),
+                Err(error) => Err(error),
+            };
+            let failed = chunk.is_err();
+            if sender.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    Body::from_stream(futures_util::stream::unfold(
+        receiver,
+        |mut receiver| async move { receiver.recv().await.map(|chunk| (chunk, receiver)) },
+    ))
+}
+
+/// How the two copies of a backup compare.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyStatus {
+    Matching,
+    MissingFromServer,
+    MissingFromBucket,
+    /// Both copies are there, but they are not the same bytes, or one of them
+    /// could not be read to tell.
+    Different,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CopyReport {
+    pub filename: String,
+    pub status: CopyStatus,
+    /// The Sha256 of each copy as it is stored now.
+    pub server_sha256: Option<String>,
+    pub bucket_sha256: Option<String>,
+}
+
+/// Every backup in either folder and how its two copies compare, so a copy
+/// that went missing or was damaged can be put back from the other.
+pub async fn compare_copies_endpoint(
+    Extension(backups): Extension<Arc<Backups>>,
+) -> Response<String> {
+    match tokio::task::spawn_blocking(move || compare_copies(&backups)).await {
+        Ok(reports) => {
+            json_response_with_headers(StatusCode::OK, serde_json::to_string(&reports).unwrap())
+        }
+        Err(error) => response_with_headers(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Comparing backups failed\n{error:?}"),
+        ),
+    }
+}
+
+fn compare_copies(backups: &Backups) -> Vec<CopyReport> {
+    let on_server = backup_filenames(&backups.server_dir);
+    let on_bucket = backup_filenames(&backups.bucket_dir);
+
+    let mut filenames: Vec<&String> = on_server.iter().chain(&on_bucket).collect();
+    filenames.sort();
+    filenames.dedup();
+
+    filenames
+        .into_iter()
+        .map(|filename| {
+            let sha256_in = |dir: &str, present: &[String]| {
+                present
+                    .contains(filename)
+                    .then(|| sha256_file(&format!("{dir}{filename}")).ok())
+            };
+            let server_sha256 = sha256_in(&backups.server_dir, &on_server);
+            let bucket_sha256 = sha256_in(&backups.bucket_dir, &on_bucket);
+
+            let status = match (&server_sha256, &bucket_sha256) {
+                (None, _) => CopyStatus::MissingFromServer,
+                (_, None) => CopyStatus::MissingFromBucket,
+                (Some(Some(server)), Some(Some(bucket))) if server == bucket => {
+                    CopyStatus::Matching
+                }
+                (Some(_), Some(_)) => CopyStatus::Different,
+            };
+
+            CopyReport {
+                filename: filename.clone(),
+                status,
+                server_sha256: server_sha256.flatten(),
+                bucket_sha256: bucket_sha256.flatten(),
+            }
+        })
+        .collect()
+}
+
+fn read_manifest(path: &str) -> Option<Manifest> {
+    fs::read(format!("{path}{MANIFEST_SUFFIX}"))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+}
+
+/// One copy of a backup opened into a new file at `to`, and its checksum,
+/// which is checked against its manifest if it has one. Nothing is left at
+/// `to` unless it is.
+fn restore_copy(dir: &str, filename: &str, key: &BackupKey, to: &str) -> Result<String, String> {
+    let manifest: Option<Manifest> = read_manifest(&format!("{dir}{filename}"));
+
+    let opened = fs::File::create(to)
+        .map_err(|error| error.to_string())
+        .and_then(|file| {
+            let writer = HashingWriter {
+                writer: std::io::BufWriter::new(file),
+                hasher: Sha256::new(),
+            };
+            open_copy_to(dir, filename, key, manifest.as_ref(), writer)
+        })
+        .and_then(|mut writer| {
+            writer.flush().map_err(|error| error.to_string())?;
+            Ok(hex(&writer.hasher.finalize()))
+        });
+
+    let result = match (opened, manifest) {
+        (Ok(sha256), Some(manifest2)) if manifest2.sha256 != sha256 => Err(format!(
+            "Opened as {sha256} rather than {}",
+            manifest2.sha256
+        )),
+        (opened2, _) => opened2,
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    result
+}
+
+/// Which copy a restored backup was restored from, and how that copy was at
+/// the time, so that a restored backup is only used while its copy is
+/// unchanged. A copy that has been damaged or removed since is no longer
+/// vouched for by a backup restored from it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RestoredFrom {
+    copy_path: String,
+    copy_size: u64,
+    copy_modified: Option<std::time::SystemTime>,
+    sha256: String,
+}
+
+impl RestoredFrom {
+    fn from_metadata(copy_path: &str, sha256: &str, metadata: &fs::Metadata) -> Self {
+        Self {
+            copy_path: copy_path.to_owned(),
+            copy_size: metadata.len(),
+            copy_modified: metadata.modified().ok(),
+            sha256: sha256.to_owned(),
+        }
+    }
+
+    /// How the copy at `copy_path` is now.
+    fn copy(copy_path: &str, sha256: &str) -> Option<Self> {
+        fs::metadata(copy_path)
+            .ok()
+            .map(|metadata| Self::from_metadata(copy_path, sha256, &metadata))
+    }
+}
+
+/// Removes the restored backups in `dir` that were restored more than `ttl`
+/// ago, and then the oldest of the rest until only `keep` are left.
+fn remove_old_restored(dir: &str, ttl: std::time::Duration, keep: usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut restored: Vec<(std::time::SystemTime, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let filename = entry.file_name().into_string().ok()?;
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            (!filename.ends_with(RESTORED_FROM_SUFFIX)).then_some((modified, filename))
+        })
+        .collect();
+    restored.sort();
+    restored.reverse();
+
+    for (index, (modified, filename)) in restored.into_iter().enumerate() {
+        let expired = modified.elapsed().is_ok_and(|age| age > ttl);
+        if expired || index >= keep {
+            let _ = fs::remove_file(format!("{dir}{filename}"));
+            let _ = fs::remove_file(format!("{dir}{filename}{RESTORED_FROM_SUFFIX}"));
+        }
+    }
+}
+
+fn list_dir(dir: &str) -> Vec<BackupInfo> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut backups: Vec<BackupInfo> = entries
+        .filter_map(|entry| {
+            let entry2 = entry.ok()?;
+            let metadata = entry2.metadata().ok()?;
+            let filename = entry2.file_name().to_str()?.to_owned();
+            if !metadata.is_file() || filename.ends_with(MANIFEST_SUFFIX) {
+                return None;
+            }
+
+            let path = format!("{dir}{filename}");
+            let manifest: Option<Manifest> = read_manifest(&path);
+
+            let status = match (&manifest, sha256_file(&path)) {
+                (_, Err(_)) => ChecksumStatus::Unreadable,
+                (None, Ok(_)) => ChecksumStatus::Unrecorded,
+                (Some(manifest2), Ok(sha256))
+                    if *manifest2
+                        .stored_sha256
+                        .as_ref()
+                        .unwrap_or(&manifest2.sha256)
+                        == sha256 =>
+                {
+                    ChecksumStatus::Verified
+                }
+                (Some(_), Ok(_)) => ChecksumStatus::Mismatch,
+            };
+
+            let status2 = match Recipe::read(&path) {
+                Ok(Some(recipe))
+                    if status == ChecksumStatus::Verified
+                        && !backup_chunks::missing_chunks(dir, &recipe).is_empty() =>
+                {
+                    ChecksumStatus::MissingChunks
+                }
+                _ => status,
+            };
+
+            Some(BackupInfo {
+                filename,
+                size: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()),
+                sha256: manifest.map(|manifest2| manifest2.sha256),
+                status: status2,
+            })
+        })
+        .collect();
+
+    backups.sort_by(|a, b| a.filename.cmp(&b.filename));
+    backups
+}
+
+/// Whether a stored backup starts the way a sealed one does.
+fn is_sealed_file(path: &str) -> bool {
+    use std::io::Read;
+
+    let mut start: Vec<u8> = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| file.take(16).read_to_end(&mut start))
+        .is_ok()
+        && crate::backup_encryption::is_sealed(&start)
+}
+
+/// The Sha256 of a file in lowercase hex, read a piece at a time so a large
+/// backup is never held in memory all at once.
+fn sha256_file(path: &str) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// The names of the backups in a folder, leaving out their manifests.
+fn backup_filenames(dir: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| entry.metadata().is_ok_and(|metadata| metadata.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|filename| !filename.ends_with(MANIFEST_SUFFIX))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_replication::ReplicationStatus;
+
+    /// A pair of folders of its own for each test, removed when it is done.
+    struct TestDirs {
+        root: String,
+        backups: Arc<Backups>,
+    }
+
+    impl TestDirs {
+        fn new(name: &str) -> Self {
+            let root = format!(
+                "{}/atchat-backups-{name}-{}/",
+                std::env::temp_dir().display(),
+                rand::random::<u64>()
+            );
+            let server_dir = format!("{root}server/");
+            let bucket_dir = format!("{root}bucket/");
+            fs::create_dir_all(&server_dir).unwrap();
+            fs::create_dir_all(&bucket_dir).unwrap();
+            Self {
+                root,
+                backups: Arc::new(Backups::new(
+                    &server_dir,
+                    &bucket_dir,
+                    Some(BackupKey::new([7; 32])),
+                )),
+            }
+        }
+    }
+
+    impl Drop for TestDirs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    async fn post(
+        dirs: &TestDirs,
+        filename: &str,
+        digest: Option<&str>,
+        body: &[u8],
+    ) -> Response<String> {
+        post_with(dirs, filename, digest, UploadOptions::default(), body).await
+    }
+
+    async fn post_with(
+        dirs: &TestDirs,
+        filename: &str,
+        digest: Option<&str>,
+        options: UploadOptions,
+        body: &[u8],
+    ) -> Response<String> {
+        let mut headers = HeaderMap::new();
+        if let Some(digest2) = digest {
+            headers.insert(DIGEST_HEADER, digest2.parse().unwrap());
+        }
+        let response = post_backup_endpoint(
+            Extension(dirs.backups.clone()),
+            Path(filename.to_owned()),
+            Query(options),
+            headers,
+            Body::from(body.to_vec()),
+        )
+        .await;
+
+        // Both copies are only there to look at once the job is done.
+        if let Ok(receipt) = serde_json::from_str::<UploadReceipt>(response.body()) {
+            wait_for_job(dirs, &receipt.job_id).await;
+        }
+        response
+    }
+
+    async fn wait_for_job(dirs: &TestDirs, job_id: &str) -> BackupJob {
+        for _ in 0..1000 {
+            let response =
+                backup_job_endpoint(Extension(dirs.backups.clone()), Path(job_id.to_owned())).await;
+            let job: BackupJob = serde_json::from_str(response.body()).unwrap();
+            if job.state != JobState::Running {
+                return job;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("The job {job_id} never finished");
+    }
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[tokio::test]
+    async fn records_a_checksum_next_to_each_copy() {
+        let dirs = TestDirs::new("records");
+
+        let response = post(&dirs, "backend-export-1", None, b"hello").await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+        let receipt: UploadReceipt = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(
+            receipt.sha256, HELLO_SHA256,
+            "the checksum should be sent back"
+        );
+
+        for dir in [&dirs.backups.server_dir, &dirs.backups.bucket_dir] {
+            let manifest: Manifest = serde_json::from_slice(
+                &fs::read(format!("{dir}backend-export-1{MANIFEST_SUFFIX}")).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(manifest.sha256, HELLO_SHA256, "manifest in {dir}");
+            assert_eq!(manifest.size, 5, "manifest in {dir}");
+            assert!(!manifest.checked_against_sender, "no checksum was sent");
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_the_checksum_the_backend_sends() {
+        let dirs = TestDirs::new("sender");
+
+        let response = post(
+            &dirs,
+            "backend-export-1",
+            Some(&HELLO_SHA256.to_ascii_uppercase()),
+            b"hello",
+        )
+        .await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "a matching checksum in any case should be accepted"
+        );
+
+        let response2 = post(&dirs, "backend-export-2", Some(HELLO_SHA256), b"hellp").await;
+        assert_eq!(
+            response2.status(),
+            StatusCode::BAD_REQUEST,
+            "a backup that arrived damaged should be refused"
+        );
+        assert!(
+            !fs::exists(format!("{}backend-export-2", dirs.backups.server_dir)).unwrap(),
+            "nothing should be written of a damaged backup"
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_backups_with_their_checksum_status() {
+        let dirs = TestDirs::new("list");
+        let server_dir = dirs.backups.server_dir.clone();
+
+        for filename in ["backend-export-1", "backend-export-2"] {
+            let response = post(&dirs, filename, None, b"hello").await;
+            assert_eq!(
+                response.status(),
+                StatusCode::ACCEPTED,
+                "{}",
+                response.body()
+            );
+        }
+        let stored_size = fs::metadata(format!("{server_dir}backend-export-1"))
+            .unwrap()
+            .len();
+        fs::write(format!("{server_dir}backend-export-2"), b"rotted").unwrap();
+        fs::write(format!("{server_dir}older-backup"), b"old").unwrap();
+
+        let response = list_backups_endpoint(Extension(dirs.backups.clone())).await;
+        let listing: BackupListing = serde_json::from_str(response.body()).unwrap();
+
+        let summary: Vec<(&str, u64, ChecksumStatus)> = listing
+            .server
+            .iter()
+            .map(|backup| (backup.filename.as_str(), backup.size, backup.status))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("backend-export-1", stored_size, ChecksumStatus::Verified),
+                ("backend-export-2", 6, ChecksumStatus::Mismatch),
+                ("older-backup", 3, ChecksumStatus::Unrecorded),
+            ],
+            "each copy on the server should be checked against its manifest"
+        );
+        assert!(
+            listing.bucket.iter().all(
+                |backup| backup.status == ChecksumStatus::Verified && backup.modified.is_some()
+            ),
+            "the bucket's copies are untouched: {:?}",
+            listing.bucket
+        );
+    }
+
+    fn range(range: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(range2) = range {
+            headers.insert(http::header::RANGE, range2.parse().unwrap());
+        }
+        headers
+    }
+
+    async fn status_and_body(response: Response<Body>) -> (StatusCode, Vec<u8>) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body.to_vec())
+    }
+
+    async fn restore(dirs: &TestDirs, filename: &str) -> (StatusCode, Vec<u8>) {
+        status_and_body(
+            restore_backup_endpoint(
+                Extension(dirs.backups.clone()),
+                Path(filename.to_owned()),
+                range(None),
+            )
+            .await,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_backup_through_encryption() {
+        let dirs = TestDirs::new("round-trip");
+        let backup = br#"{"messages":["a private message"]}"#.repeat(50);
+
+        let response = post(&dirs, "backend-export-1", None, &backup).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+
+        for dir in [&dirs.backups.server_dir, &dirs.backups.bucket_dir] {
+            let stored = fs::read(format!("{dir}backend-export-1")).unwrap();
+            assert!(
+                crate::backup_encryption::is_sealed(&stored)
+                    && !stored.windows(7).any(|window| window == b"private"),
+                "the copy in {dir} should be encrypted"
+            );
+            assert!(
+                stored.len() < backup.len(),
+                "the copy in {dir} should be compressed"
+            );
+        }
+
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await,
+            (StatusCode::OK, backup.clone()),
+            "restoring should give back exactly what was sent"
+        );
+
+        // With the server's copy damaged, the bucket's is used instead.
+        fs::write(
+            format!("{}backend-export-1", dirs.backups.server_dir),
+            b"ATCHATBK\x01damaged beyond repair",
+        )
+        .unwrap();
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await,
+            (StatusCode::OK, backup),
+            "the bucket's copy should be restored when the server's is damaged"
+        );
+
+        fs::remove_file(format!("{}backend-export-1", dirs.backups.bucket_dir)).unwrap();
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await.0,
+            StatusCode::NOT_FOUND,
+            "with no intact copy left there is nothing to restore"
+        );
+    }
+
+    // Backups from before sealing, with or without a manifest, are marked by
+    // the key the first time the server has one, and can be restored from
+    // then on. An unsealed copy that turns up later has no mark, and whoever
+    // put it there cannot make one without the key.
+    #[tokio::test]
+    async fn restores_backups_from_before_encryption() {
+        let dirs = TestDirs::new("plain");
+        let plain: &[u8] = b"{\"plain\":true}";
+        let manifest = |stored_sha256: Option<String>, legacy_mark: Option<String>| Manifest {
+            sha256: hex(&Sha256::digest(plain)),
+            stored_sha256,
+            size: plain.len() as u64,
+            received_at: String::from("2024-01-01T00:00:00Z"),
+            checked_against_sender: false,
+            legacy_mark,
+        };
+        let write = |filename: &str, manifest2: Option<Manifest>| {
+            let path = format!("{}{filename}", dirs.backups.server_dir);
+            fs::write(&path, plain).unwrap();
+            if let Some(manifest3) = manifest2 {
+                fs::write(
+                    format!("{path}{MANIFEST_SUFFIX}"),
+                    serde_json::to_string(&manifest3).unwrap(),
+                )
+                .unwrap();
+            }
+        };
+        write("backend-export-old", Some(manifest(None, None)));
+        write("backend-export-unrecorded", None);
+        write(
+            "backend-export-new",
+            Some(manifest(Some(hex(&Sha256::digest(plain))), None)),
+        );
+
+        assert_ne!(
+            restore(&dirs, "backend-export-old").await.0,
+            StatusCode::OK,
+            "an unsealed copy should be refused until it has been marked"
+        );
+        assert_eq!(
+            dirs.backups.mark_legacy_copies(),
+            2,
+            "the two copies from before sealing should be marked"
+        );
+        write(
+            "backend-export-planted",
+            Some(manifest(None, Some(hex(&[0; 32])))),
+        );
+
+        assert_eq!(
+            restore(&dirs, "backend-export-old").await,
+            (StatusCode::OK, plain.to_vec()),
+            "an old unencrypted backup should come back as it is"
+        );
+        assert_eq!(
+            restore(&dirs, "backend-export-unrecorded").await,
+            (StatusCode::OK, plain.to_vec()),
+            "an old backup from before manifests should come back too"
+        );
+        assert_ne!(
+            restore(&dirs, "backend-export-new").await.0,
+            StatusCode::OK,
+            "an unsealed copy of a backup recorded as sealed should be refused"
+        );
+        assert_ne!(
+            restore(&dirs, "backend-export-planted").await.0,
+            StatusCode::OK,
+            "an unsealed copy with a mark the key did not make should be refused"
+        );
+    }
+
+    #[tokio::test]
+    async fn thins_out_old_backups_once_a_new_one_is_written() {
+        let mut dirs = TestDirs::new("retention");
+        dirs.backups = Arc::new(Backups {
+            retention: RetentionPolicy {
+                hourly: 1,
+                daily: 2,
+                monthly: 3,
+            },
+            ..Backups::new(
+                &dirs.backups.server_dir,
+                &dirs.backups.bucket_dir,
+                dirs.backups.key.clone(),
+            )
+        });
+        let server_dir = dirs.backups.server_dir.clone();
+
+        for filename in [
+            "backend-export-2024-06-01-00:00:00.bin",
+            "backend-export-2024-06-02-00:00:00.bin",
+        ] {
+            let response = post(&dirs, filename, None, b"hello").await;
+            assert_eq!(
+                response.status(),
+                StatusCode::ACCEPTED,
+                "{}",
+                response.body()
+            );
+        }
+        fs::write(format!("{server_dir}backend-export.bin"), b"by hand").unwrap();
+
+        let preview_response = retention_preview_endpoint(Extension(dirs.backups.clone())).await;
+        let preview: RetentionPreview = serde_json::from_str(preview_response.body()).unwrap();
+        assert!(
+            preview.server.remove.is_empty(),
+            "two days of backups are both kept: {preview:?}"
+        );
+
+        for month in ["07", "08", "09"] {
+            let filename = format!("backend-export-2024-{month}-01-00:00:00.bin");
+            let response = post(&dirs, &filename, None, b"hello").await;
+            assert_eq!(
+                response.status(),
+                StatusCode::ACCEPTED,
+                "{}",
+                response.body()
+            );
+        }
+
+        let mut remaining = backup_filenames(&server_dir);
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                "backend-export-2024-07-01-00:00:00.bin",
+                "backend-export-2024-08-01-00:00:00.bin",
+                "backend-export-2024-09-01-00:00:00.bin",
+                "backend-export.bin",
+            ],
+            "only the last three months and the undated backup should be left"
+        );
+        assert!(
+            !fs::exists(format!(
+                "{server_dir}backend-export-2024-06-02-00:00:00.bin{MANIFEST_SUFFIX}"
+            ))
+            .unwrap(),
+            "a removed backup's manifest should go with it"
+        );
+        assert_eq!(
+            backup_filenames(&dirs.backups.bucket_dir).len(),
+            3,
+            "the bucket should be thinned out the same way"
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_part_of_a_backup() {
+        let dirs = TestDirs::new("restore-range");
+        let response = post(&dirs, "backend-export-1", None, b"0123456789").await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+
+        let response2 = restore_backup_endpoint(
+            Extension(dirs.backups.clone()),
+            Path(String::from("backend-export-1")),
+            range(Some("bytes=2-4")),
+        )
+        .await;
+        assert_eq!(
+            response2.headers().get("content-range").unwrap(),
+            "bytes 2-4/10",
+            "the part sent should be named"
+        );
+        assert_eq!(
+            status_and_body(response2).await,
+            (StatusCode::PARTIAL_CONTENT, b"234".to_vec()),
+            "only the bytes asked for should be sent"
+        );
+    }
+
+    // Each range of a backup asked for should not decrypt the whole backup
+    // again, so the later ones are sent from what the first restored.
+    #[tokio::test]
+    async fn decrypts_a_backup_once_for_all_its_ranges() {
+        let dirs = TestDirs::new("restore-once");
+        let response = post(&dirs, "backend-export-1", None, b"0123456789").await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+
+        let restore_range = |range_header: &'static str| {
+            let backups = dirs.backups.clone();
+            async move {
+                status_and_body(
+                    restore_backup_endpoint(
+                        Extension(backups),
+                        Path(String::from("backend-export-1")),
+                        range(Some(range_header)),
+                    )
+                    .await,
+                )
+                .await
+            }
+        };
+
+        assert_eq!(
+            restore_range("bytes=0-4").await,
+            (StatusCode::PARTIAL_CONTENT, b"01234".to_vec()),
+            "the first range should be restored"
+        );
+        // Changing what was restored shows whether the next range is sent
+        // from it or decrypted all over again.
+        fs::write(
+            format!("{}{RESTORED_DIR}backend-export-1", dirs.backups.server_dir),
+            b"ABCDEFGHIJ",
+        )
+        .unwrap();
+        assert_eq!(
+            restore_range("bytes=5-").await,
+            (StatusCode::PARTIAL_CONTENT, b"FGHIJ".to_vec()),
+            "the next range should come from the backup already restored"
+        );
+    }
+
+    #[test]
+    fn keeps_restored_backups_for_a_while_and_only_a_few() {
+        let dirs = TestDirs::new("restored");
+        let dir = format!("{}{RESTORED_DIR}", dirs.backups.server_dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let now = std::time::SystemTime::now();
+        for (filename, age_secs) in [
+            ("expired", 7200),
+            ("older", 60),
+            ("newer", 30),
+            ("newest", 0),
+        ] {
+            for path in [
+                format!("{dir}{filename}"),
+                format!("{dir}{filename}{RESTORED_FROM_SUFFIX}"),
+            ] {
+                fs::File::create(&path)
+                    .unwrap()
+                    .set_modified(now - std::time::Duration::from_secs(age_secs))
+                    .unwrap();
+            }
+        }
+
+        remove_old_restored(&dir, std::time::Duration::from_secs(3600), 2);
+
+        let mut left = backup_filenames(&dir);
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["newer", "newer.from.json", "newest", "newest.from.json"],
+            "only the newest restored backups should be kept, with what they came from"
+        );
+    }
+
+    // A large export is downloaded as it is stored, and a download that was
+    // cut off can carry on from where it got to.
+    #[tokio::test]
+    async fn downloads_a_stored_copy_in_pieces() {
+        let dirs = TestDirs::new("download");
+        let backup: Vec<u8> = (0..200_000u32).flat_map(u32::to_le_bytes).collect();
+        let response = post(&dirs, "backend-export-1", None, &backup).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+
+        let stored = fs::read(format!("{}backend-export-1", dirs.backups.bucket_dir)).unwrap();
+        let download = |range_header: Option<&'static str>| {
+            let backups = dirs.backups.clone();
+            async move {
+                download_backup_endpoint(
+                    Extension(backups),
+                    Path((Location::Bucket, String::from("backend-export-1"))),
+                    range(range_header),
+                )
+                .await
+            }
+        };
+
+        let whole = download(None).await;
+        assert!(
+            whole.headers().get("etag").is_some(),
+            "the stored checksum should be offered as a tag"
+        );
+        assert_eq!(
+            status_and_body(whole).await,
+            (StatusCode::OK, stored.clone()),
+            "the whole copy should be sent as it is stored"
+        );
+
+        assert_eq!(
+            status_and_body(download(Some("bytes=1000-")).await).await,
+            (StatusCode::PARTIAL_CONTENT, stored[1000..].to_vec()),
+            "the rest of the copy should be sent from where it was asked for"
+        );
+        assert_eq!(
+            download(Some("bytes=999999999-")).await.status(),
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "a range past the end cannot be sent"
+        );
+
+        let missing = download_backup_endpoint(
+            Extension(dirs.backups.clone()),
+            Path((Location::Server, String::from("backend-export-2"))),
+            range(None),
+        )
+        .await;
+        assert_eq!(
+            missing.status(),
+            StatusCode::NOT_FOUND,
+            "a backup that is not there cannot be downloaded"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_copies_that_are_missing_or_differ() {
+        let dirs = TestDirs::new("compare");
+        for filename in ["backend-export-1", "backend-export-2", "backend-export-3"] {
+            let response = post(&dirs, filename, None, filename.as_bytes()).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::ACCEPTED,
+                "{}",
+                response.body()
+            );
+        }
+        fs::remove_file(format!("{}backend-export-1", dirs.backups.server_dir)).unwrap();
+        fs::remove_file(format!("{}backend-export-2", dirs.backups.bucket_dir)).unwrap();
+        fs::write(
+            format!("{}backend-export-3", dirs.backups.bucket_dir),
+            b"damaged",
+        )
+        .unwrap();
+        fs::write(format!("{}backend-export-4", dirs.backups.server_dir), b"4").unwrap();
+        fs::write(format!("{}backend-export-4", dirs.backups.bucket_dir), b"4").unwrap();
+
+        let response = compare_copies_endpoint(Extension(dirs.backups.clone())).await;
+        let reports: Vec<CopyReport> = serde_json::from_str(response.body()).unwrap();
+
+        assert_eq!(
+            reports
+                .iter()
+                .map(|report| (report.filename.as_str(), report.status))
+                .collect::<Vec<(&str, CopyStatus)>>(),
+            vec![
+                ("backend-export-1", CopyStatus::MissingFromServer),
+                ("backend-export-2", CopyStatus::MissingFromBucket),
+                ("backend-export-3", CopyStatus::Different),
+                ("backend-export-4", CopyStatus::Matching),
+            ],
+            "each backup's copies should be compared"
+        );
+    }
+
+    #[test]
+    fn accepts_the_backends_own_names() {
+        for filename in [
+            "backend-export-2026-05-13-03:56:33.bin",
+            "backend-export.bin",
+            "backend-export-subset.bin",
+        ] {
+            assert_eq!(validate_filename(filename), Ok(()), "{filename}");
+        }
+    }
+
+    #[test]
+    fn refuses_names_that_lead_out_of_the_folder() {
+        for (filename, expected) in [
+            ("", FilenameError::Empty),
+            ("..", FilenameError::Path),
+            ("../secret.txt", FilenameError::Path),
+            ("../../../etc/passwd", FilenameError::Path),
+            ("backups..bin", FilenameError::Path),
+            ("/etc/passwd", FilenameError::Path),
+            ("C:\\Windows", FilenameError::Path),
+            ("sub/backup.bin", FilenameError::Path),
+            (".hidden", FilenameError::Path),
+            ("backup\0.bin", FilenameError::Character('\0')),
+            ("backup .bin", FilenameError::Character(' ')),
+            ("backup_123.bin", FilenameError::Character('_')),
+            ("backup.bin.manifest.json", FilenameError::Manifest),
+        ] {
+            assert_eq!(validate_filename(filename), Err(expected), "{filename:?}");
+        }
+
+        assert_eq!(
+            validate_filename(&"a".repeat(MAX_FILENAME_LENGTH + 1)),
+            Err(FilenameError::TooLong),
+            "an overlong name should be refused"
+        );
+    }
+
+    // The names come from the url already percent-decoded, so `%2F` arrives
+    // as a `/` and has to be refused here rather than by the router.
+    #[tokio::test]
+    async fn writes_nothing_outside_the_backups_folder() {
+        let dirs = TestDirs::new("traversal");
+
+        for filename in ["../escaped.bin", "..", "/tmp/escaped.bin"] {
+            let response = post(&dirs, filename, None, b"hello").await;
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_REQUEST,
+                "{filename} should be refused"
+            );
+            assert!(
+                response.body().contains("cannot be a path"),
+                "the error should say why: {}",
+                response.body()
+            );
+        }
+
+        assert!(
+            !fs::exists(format!("{}escaped.bin", dirs.root)).unwrap(),
+            "nothing should have been written next to the backups folder"
+        );
+
+        let download = download_backup_endpoint(
+            Extension(dirs.backups.clone()),
+            Path((Location::Server, String::from("../../secret.txt"))),
+            range(None),
+        )
+        .await;
+        assert_eq!(
+            download.status(),
+            StatusCode::BAD_REQUEST,
+            "nothing outside the folder should be downloadable"
+        );
+        let restored = restore(&dirs, "../bucket/backend-export-1").await;
+        assert_eq!(
+            restored.0,
+            StatusCode::BAD_REQUEST,
+            "nothing outside the folder should be restorable"
+        );
+    }
+
+    #[tokio::test]
+    async fn overwrites_a_backup_only_when_asked() {
+        let dirs = TestDirs::new("overwrite");
+
+        let first = post(&dirs, "backend-export-1", None, b"first").await;
+        assert_eq!(first.status(), StatusCode::ACCEPTED, "{}", first.body());
+
+        let second = post(&dirs, "backend-export-1", None, b"second").await;
+        assert_eq!(
+            second.status(),
+            StatusCode::CONFLICT,
+            "a second backup with the same name should be refused"
+        );
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await.1,
+            b"first",
+            "the first backup should be untouched"
+        );
+
+        let third = post_with(
+            &dirs,
+            "backend-export-1",
+            None,
+            UploadOptions { overwrite: true },
+            b"third",
+        )
+        .await;
+        assert_eq!(
+            third.status(),
+            StatusCode::ACCEPTED,
+            "replacing should be allowed when asked for"
+        );
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await.1,
+            b"third",
+            "the backup should have been replaced"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_how_a_backups_job_went() {
+        let dirs = TestDirs::new("job");
+
+        let response = post(&dirs, "backend-export-1", None, b"hello").await;
+        let receipt: UploadReceipt = serde_json::from_str(response.body()).unwrap();
+        let job = wait_for_job(&dirs, &receipt.job_id).await;
+
+        assert_eq!(
+            (job.state, job.server, job.bucket),
+            (
+                JobState::Succeeded,
+                CopyProgress::Verified,
+                CopyProgress::Verified
+            ),
+            "both copies should have been written and checked: {job:?}"
+        );
+        assert_eq!(job.sha256, HELLO_SHA256, "the job should name the backup");
+        assert!(job.finished_at.is_some(), "a finished job should say when");
+
+        let missing =
+            backup_job_endpoint(Extension(dirs.backups.clone()), Path(String::from("nope"))).await;
+        assert_eq!(
+            missing.status(),
+            StatusCode::NOT_FOUND,
+            "an unknown job should not be found"
+        );
+    }
+
+    // With the bucket missing, the backend still gets its answer straight
+    // away and the job is what says the second copy failed.
+    #[tokio::test]
+    async fn reports_a_second_copy_that_failed() {
+        let mut dirs = TestDirs::new("job-failed");
+        dirs.backups = Arc::new(Backups::new(
+            &dirs.backups.server_dir,
+            &format!("{}unmounted/backups/", dirs.root),
+            dirs.backups.key.clone(),
+        ));
+
+        let response = post(&dirs, "backend-export-1", None, b"hello").await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+
+        let receipt: UploadReceipt = serde_json::from_str(response.body()).unwrap();
+        let job = wait_for_job(&dirs, &receipt.job_id).await;
+        assert_eq!(
+            (job.state, job.server, job.bucket),
+            (
+                JobState::Failed,
+                CopyProgress::Verified,
+                CopyProgress::Failed
+            ),
+            "only the second copy should have failed: {job:?}"
+        );
+        assert_eq!(job.errors.len(), 1, "the failure should be explained");
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await,
+            (StatusCode::OK, b"hello".to_vec()),
+            "the server's copy should still be restorable"
+        );
+
+        // Once the bucket is mounted again, the copy is retried.
+        let status = replication_status(&dirs).await;
+        assert_eq!(
+            status.targets[0].queued, 1,
+            "the bucket should be waiting for the backup: {status:?}"
+        );
+        fs::create_dir_all(&dirs.backups.bucket_dir).unwrap();
+        dirs.backups.replicator.run_due().await;
+
+        let status2 = replication_status(&dirs).await;
+        assert_eq!(
+            (status2.targets[0].queued, status2.alerting),
+            (0, false),
+            "nothing should be left to retry: {status2:?}"
+        );
+        let listing = dirs.backups.list();
+        assert_eq!(
+            listing.bucket.first().map(|backup| backup.status),
+            Some(ChecksumStatus::Verified),
+            "the retried copy should match its manifest: {listing:?}"
+        );
+    }
+
+    async fn replication_status(dirs: &TestDirs) -> ReplicationStatus {
+        let response = replication_status_endpoint(Extension(dirs.backups.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", response.body());
+        serde_json::from_str(response.body()).unwrap()
+    }
+
+    // The backend sends large exports in many pieces, which are written as
+    // they arrive until there are too many of them.
+    #[tokio::test]
+    async fn writes_a_backup_as_it_arrives_up_to_a_limit() {
+        let mut dirs = TestDirs::new("stream");
+        dirs.backups = Arc::new(Backups {
+            max_upload_bytes: 1000,
+            ..Backups::new(
+                &dirs.backups.server_dir,
+                &dirs.backups.bucket_dir,
+                dirs.backups.key.clone(),
+            )
+        });
+
+        let send = |filename: &'static str, pieces: usize| {
+            let backups = dirs.backups.clone();
+            async move {
+                let chunks = (0..pieces).map(|index| {
+                    Ok::<Bytes, std::io::Error>(Bytes::from(vec![b'a' + index as u8; 300]))
+                });
+                post_backup_endpoint(
+                    Extension(backups),
+                    Path(filename.to_owned()),
+                    Query(UploadOptions::default()),
+                    HeaderMap::new(),
+                    Body::from_stream(futures_util::stream::iter(chunks)),
+                )
+                .await
+            }
+        };
+
+        let response = send("backend-export-1", 3).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+        let receipt: UploadReceipt = serde_json::from_str(response.body()).unwrap();
+        wait_for_job(&dirs, &receipt.job_id).await;
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await.1,
+            [[b'a'; 300], [b'b'; 300], [b'c'; 300]].concat(),
+            "every piece should have been written in order"
+        );
+
+        let too_large = send("backend-export-2", 4).await;
+        assert_eq!(
+            too_large.status(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "a backup over the limit should be refused"
+        );
+        assert_eq!(
+            backup_filenames(&dirs.backups.server_dir),
+            vec![String::from("backend-export-1")],
+            "nothing of the refused backup should be left behind"
+        );
+    }
+
+    /// An export of `count` messages, the way the backend's look.
+    fn export(count: u32, changed: Option<u32>) -> Vec<u8> {
+        (0..count)
+            .map(|index| {
+                let text = if Some(index) == changed {
+                    "edited"
+                } else {
+                    "hello"
+                };
+                format!("{{\"id\":{index},\"text\":\"{text} {index}\"}}\n")
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn chunk_count(dir: &str) -> usize {
+        fs::read_dir(format!("{dir}{CHUNKS_DIR}")).map_or(0, Iterator::count)
+    }
+
+    // Two exports a message apart should take up little more room than one,
+    // and each should still come back exactly as it was sent.
+    #[tokio::test]
+    async fn stores_only_the_chunks_that_changed() {
+        let mut dirs = TestDirs::new("incremental");
+        dirs.backups = Arc::new(Backups {
+            incremental: true,
+            ..Backups::new(
+                &dirs.backups.server_dir,
+                &dirs.backups.bucket_dir,
+                dirs.backups.key.clone(),
+            )
+        });
+        let server_dir = dirs.backups.server_dir.clone();
+
+        let first = export(80_000, None);
+        let second = export(80_000, Some(40_000));
+
+        let response = post(&dirs, "backend-export-1", None, &first).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response.body()
+        );
+        let first_chunks = chunk_count(&server_dir);
+        assert!(
+            first_chunks > 4,
+            "a large export should be cut into several chunks, not {first_chunks}"
+        );
+
+        let response2 = post(&dirs, "backend-export-2", None, &second).await;
+        assert_eq!(
+            response2.status(),
+            StatusCode::ACCEPTED,
+            "{}",
+            response2.body()
+        );
+        let receipt: UploadReceipt = serde_json::from_str(response2.body()).unwrap();
+        assert_eq!(
+            wait_for_job(&dirs, &receipt.job_id).await.state,
+            JobState::Succeeded,
+            "both copies of the second export should be written"
+        );
+
+        let all_chunks = chunk_count(&server_dir);
+        assert!(
+            all_chunks <= first_chunks + 2,
+            "only the chunks around the change should be new, {all_chunks} from {first_chunks}"
+        );
+        assert_eq!(
+            chunk_count(&dirs.backups.bucket_dir),
+            all_chunks,
+            "the bucket should get the same chunks"
+        );
+        assert!(
+            fs::metadata(format!("{server_dir}backend-export-2"))
+                .unwrap()
+                .len()
+                < 4096,
+            "only a recipe should be stored in place of the export"
+        );
+
+        assert_eq!(
+            restore(&dirs, "backend-export-1").await,
+            (StatusCode::OK, first),
+            "the first export should be put back together"
+        );
+        assert_eq!(
+            restore(&dirs, "backend-export-2").await,
+            (StatusCode::OK, second.clone()),
+            "the second export should be put back together"
+        );
+
+        let listing = dirs.backups.list();
+        assert!(
+            listing
+                .server
+                .iter()
+                .chain(&listing.bucket)
+                .all(|backup| backup.status == ChecksumStatus::Verified),
+            "every copy should check out: {listing:?}"
+        );
+
+        // With one of its chunks gone from the server, the bucket's copy is
+        // used instead.
+        let recipe = Recipe::read(&format!("{server_dir}backend-export-2"))
+            .unwrap()
+            .unwrap();
+        fs::remove_file(chunk_path(&server_dir, &recipe.chunks[0].id)).unwrap();
+        let listing2 = dirs.backups.list();
+        assert!(
+            listing2
+                .server
+                .iter()
+                .all(|backup| backup.status == ChecksumStatus::MissingChunks),
+            "both exports share the missing chunk: {:?}",
+            listing2.server
+        );
+        assert_eq!(
+            restore(&dirs, "backend-export-2").await,
+            (StatusCode::OK, second),
+            "the bucket's chunks should be used when the server's are gone"
+        );
+    }
+}
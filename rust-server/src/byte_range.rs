@@ -0,0 +1,146 @@
+//! Reads the `Range` header, for endpoints that send large files a client may
+//! want to fetch a piece at a time or pick up where a dropped download left
+//! off.
+//!
+//! Only a single range is understood. A request for several at once is
+//! answered with the whole file, which RFC 9110 allows and which is all any
+//! download tool ends up needing.
+
+/// Which bytes of a file of a known length to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    Whole,
+    /// From `start` up to and including `end`.
+    Part {
+        start: u64,
+        end: u64,
+    },
+    /// The range asked for starts past the end of the file.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// The range a `Range` header asks for. A header that is missing, or that
+    /// cannot be read, asks for the whole file.
+    pub fn parse(header: Option<&str>, length: u64) -> Self {
+        let Some(spec) = header.and_then(|header2| header2.trim().strip_prefix("bytes=")) else {
+            return Self::Whole;
+        };
+        if spec.contains(',') {
+            return Self::Whole;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return Self::Whole;
+        };
+
+        let number = |text: &str| text.trim().parse::<u64>().ok();
+
+        match (first.trim().is_empty(), number(first), number(last)) {
+            // `bytes=-500` is the last 500 bytes.
+            (true, _, Some(suffix)) => {
+                if suffix == 0 || length == 0 {
+                    Self::Unsatisfiable
+                } else {
+                    Self::Part {
+                        start: length.saturating_sub(suffix),
+                        end: length - 1,
+                    }
+                }
+            }
+            (false, Some(start), end) if last.trim().is_empty() || end.is_some() => {
+                if start >= length {
+                    Self::Unsatisfiable
+                } else {
+                    match end {
+                        Some(end2) if end2 < start => Self::Whole,
+                        Some(end2) => Self::Part {
+                            start,
+                            end: end2.min(length - 1),
+                        },
+                        None => Self::Part {
+                            start,
+                            end: length - 1,
+                        },
+                    }
+                }
+            }
+            _ => Self::Whole,
+        }
+    }
+
+    /// Where to start reading and how many bytes to read.
+    pub const fn span(self, length: u64) -> (u64, u64) {
+        match self {
+            Self::Whole | Self::Unsatisfiable => (0, length),
+            Self::Part { start, end } => (start, end - start + 1),
+        }
+    }
+
+    /// The `Content-Range` to answer with, if any.
+    pub fn content_range(self, length: u64) -> Option<String> {
+        match self {
+            Self::Whole => None,
+            Self::Part { start, end } => Some(format!("bytes {start}-{end}/{length}")),
+            Self::Unsatisfiable => Some(format!("bytes */{length}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_single_range() {
+        for (header, expected) in [
+            (None, ByteRange::Whole),
+            (Some("bytes=0-99"), ByteRange::Part { start: 0, end: 99 }),
+            (
+                Some("bytes=900-"),
+                ByteRange::Part {
+                    start: 900,
+                    end: 999,
+                },
+            ),
+            (
+                Some("bytes=-100"),
+                ByteRange::Part {
+                    start: 900,
+                    end: 999,
+                },
+            ),
+            (Some("bytes=-5000"), ByteRange::Part { start: 0, end: 999 }),
+            (
+                Some("bytes=500-5000"),
+                ByteRange::Part {
+                    start: 500,
+                    end: 999,
+                },
+            ),
+            (Some("bytes=1000-"), ByteRange::Unsatisfiable),
+            (Some("bytes=-0"), ByteRange::Unsatisfiable),
+            (Some("bytes=0-1,5-6"), ByteRange::Whole),
+            (Some("bytes=9-1"), ByteRange::Whole),
+            (Some("bytes=a-b"), ByteRange::Whole),
+            (Some("items=0-1"), ByteRange::Whole),
+        ] {
+            assert_eq!(ByteRange::parse(header, 1000), expected, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn describes_what_is_sent() {
+        let part = ByteRange::Part { start: 10, end: 19 };
+        assert_eq!(part.span(100), (10, 10), "ten bytes from the tenth");
+        assert_eq!(
+            part.content_range(100).as_deref(),
+            Some("bytes 10-19/100"),
+            "a part names itself"
+        );
+        assert_eq!(
+            ByteRange::Unsatisfiable.content_range(100).as_deref(),
+            Some("bytes */100"),
+            "an unsatisfiable range gives the length"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Works out how a page's text is encoded, and decodes it.
+//!
+//! Most of the web is UTF-8 by now, but plenty of older and regional sites are
+//! not: Japanese sites in `Shift_JIS` or EUC-JP, older European ones in
+//! Windows-1252. Reading those as UTF-8 turns every character outside ASCII
+//! into a replacement character, and the embed's title comes out as noise.
+//!
+//! The encoding is looked for where a browser looks for it and in the same
+//! order: a byte order mark, then the `Content-Type` header, then a `<meta>` tag
+//! near the start of the page, with UTF-8 when none of them say.
+
+use crate::link_tags;
+use encoding_rs::{Encoding, UTF_8};
+
+/// How far into a page a `<meta>` tag naming its encoding is looked for. This is
+/// as far as browsers look, so a page that puts it any later already shows up
+/// wrong for its own visitors.
+const META_PRESCAN_BYTES: usize = 1024;
+
+/// The text of a page served with the given `Content-Type`.
+pub fn decode(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding: &'static Encoding = content_type
+        .and_then(charset_parameter)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .or_else(|| meta_charset(bytes))
+        .unwrap_or(UTF_8);
+
+    // A byte order mark outranks everything else, and `decode` goes by one
+    // whenever it finds one.
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// The `charset` a `Content-Type` value names, as in
+/// `text/html; charset="Shift_JIS"`.
+fn charset_parameter(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']))
+    })
+}
+
+/// The encoding named by a `<meta charset>` tag, or by the older
+/// `<meta http-equiv="Content-Type">`, in the first bytes of the page.
+fn meta_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    // Every encoding a page can name in a tag writes ASCII as ASCII, so the tag
+    // reads the same whatever the rest of the page turns out to be.
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(META_PRESCAN_BYTES)]);
+    let lowercase = start.to_ascii_lowercase();
+    let mut position: usize = 0;
+
+    while let Some(offset) = lowercase[position..].find('<') {
+        let tag_start = position + offset;
+        let rest = &lowercase[tag_start..];
+
+        if rest.starts_with("<!--") {
+            match rest.find("-->") {
+                Some(end) => position = tag_start + end + 3,
+                None => return None,
+            }
+        } else if rest.starts_with("<meta")
+            && rest[5..].starts_with(|x: char| x.is_ascii_whitespace() || x == '/')
+        {
+            let (attributes, length) = link_tags::attributes(&start[tag_start + 5..])?;
+            position = tag_start + 5 + length;
+
+            if let Some(encoding) = meta_tag_charset(&attributes) {
+                // A page cannot really be UTF-16 if this tag could be read as
+                // ASCII, so browsers take such a tag to mean UTF-8.
+                return Some(encoding.output_encoding());
+            }
+        } else {
+            position = tag_start + 1;
+        }
+    }
+
+    None
+}
+
+fn meta_tag_charset(attributes: &[(String, String)]) -> Option<&'static Encoding> {
+    let attribute = |name: &str| {
+        attributes
+            .iter()
+            .find(|(name2, _)| name2 == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let label: &str = match attribute("charset") {
+        Some(charset) => charset,
+        None if attribute("http-equiv")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("content-type")) =>
+        {
+            charset_parameter(attribute("content")?)?
+        }
+        None => return None,
+    };
+
+    Encoding::for_label(label.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_by_the_content_type_header() {
+        let (bytes, _, _) = encoding_rs::SHIFT_JIS.encode("<title>日本語</title>");
+
+        assert_eq!(
+            decode(&bytes, Some("text/html; charset=\"Shift_JIS\"")),
+            "<title>日本語</title>",
+            "the header names the encoding"
+        );
+        assert_eq!(
+            decode("café".as_bytes(), Some("text/html")),
+            "café",
+            "a header without a charset leaves it to the page"
+        );
+    }
+
+    #[test]
+    fn goes_by_a_meta_tag_near_the_start() {
+        let (shift_jis, _, _) =
+            encoding_rs::SHIFT_JIS.encode(r#"<head><meta charset=shift_jis><title>日本語</title>"#);
+        assert_eq!(
+            decode(&shift_jis, Some("text/html")),
+            "<head><meta charset=shift_jis><title>日本語</title>",
+            "<meta charset> should be read"
+        );
+
+        let windows_1252: &[u8] =
+            b"<!-- <meta charset=\"utf-8\"> --><META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=windows-1252\">caf\xe9";
+        assert!(
+            decode(windows_1252, None).ends_with("café"),
+            "http-equiv should be read, and a commented out tag ignored"
+        );
+
+        let late: Vec<u8> = [
+            vec![b' '; META_PRESCAN_BYTES],
+            b"<meta charset=windows-1252>caf\xe9".to_vec(),
+        ]
+        .concat();
+        assert!(
+            decode(&late, None).ends_with("caf\u{fffd}"),
+            "a tag too far in should be ignored, leaving UTF-8"
+        );
+    }
+
+    #[test]
+    fn header_and_byte_order_mark_outrank_the_page() {
+        assert_eq!(
+            decode(
+                b"<meta charset=shift_jis>caf\xe9",
+                Some("text/html; charset=windows-1252")
+            ),
+            "<meta charset=shift_jis>café",
+            "the header outranks the tag"
+        );
+        assert_eq!(
+            decode(
+                b"\xef\xbb\xbfcaf\xc3\xa9",
+                Some("text/html; charset=windows-1252")
+            ),
+            "café",
+            "a byte order mark outranks the header"
+        );
+        assert_eq!(
+            decode(b"<meta charset=utf-16>caf\xc3\xa9", None),
+            "<meta charset=utf-16>café",
+            "a tag naming UTF-16 means UTF-8"
+        );
+    }
+}
@@ -0,0 +1,575 @@
+//! Compresses text-like files on their way out.
+//!
+//! Logs, CSVs, JSON and source files shrink to a fraction of their size when
+//! compressed, whereas images, video and archives are compressed already and
+//! only grow from being squeezed a second time. So only the types that are text
+//! underneath are ever offered compressed, and everything else goes out exactly
+//! as it was stored.
+//!
+//! Stored files never change, so each compressed copy is made once, by the first
+//! request that asks for it, and kept on disk beside the original. From then on
+//! serving it costs no more than serving the original does.
+//!
+//! The content type comes from the url, which whoever asks can change, so a
+//! file is only compressed if it is no larger than [`MAX_COMPRESSIBLE_SIZE`]
+//! and its first few KiB really do look like text. The copies together are
+//! kept under `COMPRESSED_COPIES_BUDGET_BYTES` (512 MiB by default), by
+//! removing the oldest whenever a new one takes them over it.
+
+use http::HeaderMap;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// How the encoding is named in `Accept-Encoding` and `Content-Encoding`,
+    /// which is also the suffix its copy is stored under.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// Below this the headers outweigh anything compression could save.
+pub const MIN_COMPRESSIBLE_SIZE: u64 = 1024;
+
+/// Above this a file goes out as it is, rather than holding up a request
+/// while the whole of it is compressed.
+pub const MAX_COMPRESSIBLE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How much of a file is looked at to tell whether it is text.
+const TEXT_SNIFF_BYTES: usize = 8 * 1024;
+
+const DEFAULT_BUDGET_BYTES: u64 = 512 * 1024 * 1024;
+
+/// How much space the compressed copies in each folder take up, as of when it
+/// was last counted plus whatever has been written since. A folder is only
+/// looked through again once this says it has gone over budget.
+static COPIES_TOTALS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Types outside `text/` that are text all the same. Anything ending in `+xml`
+/// or `+json` is caught by its suffix and does not need listing.
+const COMPRESSIBLE_APPLICATION_TYPES: &[&str] = &[
+    "application/ecmascript",
+    "application/javascript",
+    "application/json",
+    "application/postscript",
+    "application/rtf",
+    "application/x-csh",
+    "application/x-latex",
+    "application/x-sh",
+    "application/x-shellscript",
+    "application/x-tex",
+    "application/x-texinfo",
+    "application/xml",
+    "application/xml-dtd",
+    "application/yaml",
+];
+
+/// Whether a file served as this content type is worth compressing. Parameters
+/// such as `charset` have no bearing on it and are ignored.
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type: &str = content_type.split(';').next().unwrap_or_default().trim();
+
+    media_type.starts_with("text/")
+        || media_type.ends_with("+xml")
+        || media_type.ends_with("+json")
+        || COMPRESSIBLE_APPLICATION_TYPES.contains(&media_type)
+}
+
+/// Which encoding the client would rather have, going by its `Accept-Encoding`,
+/// or `None` if it takes neither. Brotli comes out smaller, so it wins whenever
+/// the client rates the two the same.
+pub fn preferred_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let accept_encoding: &str = headers.get(http::header::ACCEPT_ENCODING)?.to_str().ok()?;
+
+    let brotli = quality(accept_encoding, Encoding::Brotli);
+    let gzip = quality(accept_encoding, Encoding::Gzip);
+
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// How much the client wants an encoding, from 0 for not at all to 1. An
+/// encoding it does not name gets whatever `*` was given, and one it does not
+/// name with no `*` either is not acceptable.
+fn quality(accept_encoding: &str, encoding: Encoding) -> f32 {
+    let mut wildcard: f32 = 0.0;
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality: f32 = parts
+            .find_map(|parameter| parameter.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |value| value.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+
+        if name == encoding.name() {
+            return quality;
+        } else if name == "*" {
+            wildcard = quality;
+        }
+    }
+
+    wildcard
+}
+
+/// Where the compressed copy of the stored file at `path` lives.
+fn compressed_filepath(path: &str, encoding: Encoding) -> String {
+    format!("{path}_{}", encoding.name())
+}
+
+/// The path of a compressed copy of the stored file at `path`, which is made
+/// now if nobody has asked for one before. `None` means it could not be made,
+/// and the file should go out uncompressed instead.
+///
+/// Compressing a large file takes long enough to hold up everything else on
+/// the thread, so it happens on one set aside for blocking work.
+pub async fn compressed_copy(path: &str, encoding: Encoding) -> Option<String> {
+    let compressed_path = compressed_filepath(path, encoding);
+
+    if matches!(fs::exists(&compressed_path), Ok(true)) {
+        return Some(compressed_path);
+    }
+
+    let path2: String = path.to_owned();
+    let compressed_path2: String = compressed_path.clone();
+    tokio::task::spawn_blocking(move || write_compressed_copy(&path2, &compressed_path2, encoding))
+        .await
+        .ok()?
+        .ok()?;
+
+    Some(compressed_path)
+}
+
+fn write_compressed_copy(
+    path: &str,
+    compressed_path: &str,
+    encoding: Encoding,
+) -> std::io::Result<()> {
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() > MAX_COMPRESSIBLE_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Too large to compress",
+        ));
+    }
+
+    let mut start: Vec<u8> = Vec::new();
+    (&mut file)
+        .take(TEXT_SNIFF_BYTES as u64)
+        .read_to_end(&mut start)?;
+    if !looks_like_text(&start) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Not text, whatever the url says",
+        ));
+    }
+
+    // The file could have grown since its size was checked, so the rest is
+    // read no further than the limit either.
+    let mut bytes: Vec<u8> = start;
+    file.take(MAX_COMPRESSIBLE_SIZE + 1 - bytes.len() as u64)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_COMPRESSIBLE_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Too large to compress",
+        ));
+    }
+    let compressed: Vec<u8> = compress(&bytes, encoding)?;
+
+    crate::write_atomically(compressed_path, &compressed)?;
+
+    if let Some((dir, _)) = compressed_path.rsplit_once('/') {
+        note_copy_written(
+            &COPIES_TOTALS,
+            &format!("{dir}/"),
+            compressed.len() as u64,
+            crate::env_u64("COMPRESSED_COPIES_BUDGET_BYTES", DEFAULT_BUDGET_BYTES),
+        );
+    }
+    Ok(())
+}
+
+/// Adds a new copy to the running total for `dir`, and only looks through the
+/// folder for copies to remove once that takes it over `budget_bytes`, or
+/// when nothing has been counted there yet since the server started.
+fn note_copy_written(
+    totals: &Mutex<BTreeMap<String, u64>>,
+    dir: &str,
+    size: u64,
+    budget_bytes: u64,
+) {
+    let mut totals2 = totals.lock().unwrap();
+    let total = match totals2.get(dir) {
+        Some(total) if total + size <= budget_bytes => total + size,
+        _ => remove_oldest_copies(dir, budget_bytes),
+    };
+    totals2.insert(dir.to_owned(), total);
+}
+
+/// Text has no NUL bytes in it, whereas images, video and archives are all
+/// but certain to have some in their first few KiB.
+fn looks_like_text(start: &[u8]) -> bool {
+    !start.contains(&0)
+}
+
+/// Removes the compressed copies in `dir` that were made longest ago, until
+/// those left fit in `budget_bytes`, and returns how much those left take up.
+/// Every file in the folder is looked at, so this is only done when the
+/// running total says it is needed.
+fn remove_oldest_copies(dir: &str, budget_bytes: u64) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let mut copies: Vec<(std::time::SystemTime, u64, std::path::PathBuf)> = entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name2 = name.to_string_lossy();
+            [Encoding::Brotli, Encoding::Gzip]
+                .iter()
+                .any(|encoding| name2.ends_with(&format!("_{}", encoding.name())))
+        })
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+
+    let mut total: u64 = copies.iter().map(|(_, size, _)| size).sum();
+    copies.sort();
+
+    for (_, size, path) in copies {
+        if total <= budget_bytes {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= size;
+        }
+    }
+    total
+}
+
+/// Brotli at 9 rather than its maximum of 11, which is several times slower for
+/// a file a few percent smaller. Each copy is only made once, but the request
+/// that makes it still has to wait.
+const BROTLI_QUALITY: u32 = 9;
+
+/// The window brotli's own tools default to.
+const BROTLI_WINDOW: u32 = 22;
+
+fn compress(bytes: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut compressed: Vec<u8> = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(
+                    &mut compressed,
+                    4096,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW,
+                );
+                writer.write_all(bytes)?;
+            }
+            Ok(compressed)
+        }
+        Encoding::Gzip => {
+            let mut writer = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            writer.write_all(bytes)?;
+            writer.finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepting(accept_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::ACCEPT_ENCODING,
+            accept_encoding.parse().expect("bad header value"),
+        );
+        headers
+    }
+
+    #[test]
+    fn compresses_text_but_not_media() {
+        for content_type in [
+            "text/plain; charset=UTF-8",
+            "text/csv; charset=UTF-8",
+            "application/json",
+            "application/rss+xml",
+            "image/svg+xml",
+        ] {
+            assert!(
+                is_compressible(content_type),
+                "{content_type} is text and should be compressed"
+            );
+        }
+
+        for content_type in [
+            "image/png",
+            "video/mp4",
+            "audio/aac",
+            "application/zip",
+            "application/pdf",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        ] {
+            assert!(
+                !is_compressible(content_type),
+                "{content_type} is compressed already and should be sent as it is"
+            );
+        }
+    }
+
+    #[test]
+    fn prefers_brotli_when_both_are_accepted() {
+        assert_eq!(
+            preferred_encoding(&accepting("gzip, deflate, br, zstd")),
+            Some(Encoding::Brotli),
+            "what browsers send should get brotli"
+        );
+    }
+
+    #[test]
+    fn follows_the_weights_the_client_gives() {
+        assert_eq!(
+            preferred_encoding(&accepting("br;q=0.5, gzip")),
+            Some(Encoding::Gzip),
+            "gzip is rated higher, so it should be chosen"
+        );
+        assert_eq!(
+            preferred_encoding(&accepting("br;q=0, gzip;q=0.1")),
+            Some(Encoding::Gzip),
+            "q=0 rules brotli out however low gzip is rated"
+        );
+    }
+
+    #[test]
+    fn sends_nothing_compressed_to_a_client_that_takes_neither() {
+        for accept_encoding in ["identity", "deflate", "br;q=0, gzip;q=0", "*;q=0"] {
+            assert_eq!(
+                preferred_encoding(&accepting(accept_encoding)),
+                None,
+                "Accept-Encoding: {accept_encoding} accepts neither encoding"
+            );
+        }
+        assert_eq!(
+            preferred_encoding(&HeaderMap::new()),
+            None,
+            "a client that says nothing should get the file as it is"
+        );
+    }
+
+    #[test]
+    fn a_wildcard_accepts_whatever_is_not_named() {
+        assert_eq!(
+            preferred_encoding(&accepting("*")),
+            Some(Encoding::Brotli),
+            "* accepts brotli"
+        );
+        assert_eq!(
+            preferred_encoding(&accepting("br;q=0, *")),
+            Some(Encoding::Gzip),
+            "* should not override brotli being ruled out by name"
+        );
+    }
+
+    #[test]
+    fn both_encodings_come_back_as_they_went_in() {
+        let text: Vec<u8> = "a line of a log file\n".repeat(500).into_bytes();
+
+        let brotli: Vec<u8> = compress(&text, Encoding::Brotli).expect("brotli failed");
+        let mut from_brotli: Vec<u8> = Vec::new();
+        brotli::Decompressor::new(brotli.as_slice(), 4096)
+            .read_to_end(&mut from_brotli)
+            .expect("brotli output should decompress");
+
+        let gzip: Vec<u8> = compress(&text, Encoding::Gzip).expect("gzip failed");
+        let mut from_gzip: Vec<u8> = Vec::new();
+        flate2::read::GzDecoder::new(gzip.as_slice())
+            .read_to_end(&mut from_gzip)
+            .expect("gzip output should decompress");
+
+        assert_eq!(from_brotli, text, "brotli should round trip");
+        assert_eq!(from_gzip, text, "gzip should round trip");
+        assert!(
+            brotli.len() < text.len() && gzip.len() < text.len(),
+            "repetitive text should come out smaller"
+        );
+    }
+
+    // A video asked for under a text type's index should not be compressed
+    // just because the url says so.
+    #[test]
+    fn only_compresses_what_really_is_text() {
+        let dir = format!(
+            "{}/atchat-compression-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(
+            format!("{dir}video"),
+            [0, 0, 0, 0x18, b'f', b't', b'y', b'p'],
+        )
+        .unwrap();
+        assert!(
+            write_compressed_copy(
+                &format!("{dir}video"),
+                &format!("{dir}video_br"),
+                Encoding::Brotli
+            )
+            .is_err(),
+            "binary data should not be compressed"
+        );
+        fs::write(format!("{dir}log"), "a line of a log file\n".repeat(500)).unwrap();
+        assert!(
+            write_compressed_copy(
+                &format!("{dir}log"),
+                &format!("{dir}log_br"),
+                Encoding::Brotli
+            )
+            .is_ok(),
+            "text should be compressed"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // A file over the limit should be turned down before any of it is read.
+    #[test]
+    fn does_not_read_files_too_large_to_compress() {
+        let dir = format!(
+            "{}/atchat-compression-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = fs::File::create(format!("{dir}large")).unwrap();
+        file.set_len(MAX_COMPRESSIBLE_SIZE + 1).unwrap();
+        let error = write_compressed_copy(
+            &format!("{dir}large"),
+            &format!("{dir}large_br"),
+            Encoding::Brotli,
+        )
+        .expect_err("a file over the limit should not be compressed");
+        assert_eq!(
+            error.to_string(),
+            "Too large to compress",
+            "the size should be what turns it down, not its contents"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // The folder is only looked through when the running total goes over
+    // budget, so a copy nobody counted is left alone until then.
+    #[test]
+    fn only_looks_for_copies_to_remove_once_the_total_is_over_budget() {
+        let dir = format!(
+            "{}/atchat-compression-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+        let totals: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+        let create = |name: &str, size: u64, age_secs: u64| {
+            let file = fs::File::create(format!("{dir}{name}")).unwrap();
+            file.set_len(size).unwrap();
+            file.set_modified(
+                std::time::SystemTime::now() - std::time::Duration::from_secs(age_secs),
+            )
+            .unwrap();
+        };
+
+        create("a_br", 100, 60);
+        note_copy_written(&totals, &dir, 100, 250);
+        create("uncounted_gzip", 1000, 120);
+        create("b_br", 100, 30);
+        note_copy_written(&totals, &dir, 100, 250);
+        assert!(
+            fs::exists(format!("{dir}uncounted_gzip")).unwrap(),
+            "the folder should not be looked through while the total is under budget"
+        );
+
+        create("c_br", 100, 0);
+        note_copy_written(&totals, &dir, 100, 250);
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["b_br", "c_br"],
+            "going over budget should remove the oldest copies, counted or not"
+        );
+        assert_eq!(
+            totals.lock().unwrap().get(&dir),
+            Some(&200),
+            "the total should be what is left after removing them"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn removes_the_oldest_copies_once_over_budget() {
+        let dir = format!(
+            "{}/atchat-compression-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        for (name, modified) in [
+            ("a_br", old),
+            ("b_gzip", std::time::SystemTime::now()),
+            ("c", old),
+        ] {
+            let file = fs::File::create(format!("{dir}{name}")).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+
+        remove_oldest_copies(&dir, 150);
+
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["b_gzip", "c"],
+            "only the oldest copy should go, and never an original"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1027 @@
+//! Serves media from Discord's CDN through our own origin.
+//!
+//! Avatars, guild icons, emoji and attachments bridged from Discord would
+//! otherwise be loaded by every viewer's browser straight from Discord. That
+//! shows Discord the address of everyone reading the conversation, and stops
+//! working for attachments once the signature in their url expires. Here they
+//! are fetched once, kept on disk, and served like any other file from then on.
+//!
+//! The url is the one on Discord's CDN with the host moved into the path, so
+//! `https://cdn.discordapp.com/avatars/1/2.png?size=64` is served at
+//! `/file/discord-cdn/cdn.discordapp.com/avatars/1/2.png?size=64`. Only
+//! Discord's own hosts and the kinds of media the app shows are let through,
+//! which is what stops this being used to fetch anything else.
+//!
+//! Only the query parameters Discord's CDN understands are passed on, or play
+//! any part in which copy is which, so a url cannot be varied endlessly to
+//! make a new copy each time. The copies together are kept under
+//! `DISCORD_CDN_BUDGET_BYTES` (4 GiB by default), by removing those served
+//! longest ago, and Discord is asked to refresh attachment urls at most
+//! [`MAX_REFRESHES_PER_MINUTE`] times a minute.
+//!
+//! Works the same way as `discord_sticker`, which came first and only needed
+//! the one kind of media.
+
+use crate::file_cache::FileCache;
+use crate::outbound::{OutboundClient, ReadError, read_capped};
+use crate::{
+    CustomRequest, Header, IMMUTABLE_CACHE_CONTROL, NOSNIFF, SANDBOX_CSP, base64_encode,
+    create_dir_if_missing, send_custom_request, write_atomically,
+};
+use axum::Extension;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, RawQuery};
+use axum::http::StatusCode;
+use axum::response::Response;
+use image::{GenericImageView, ImageFormat, ImageReader};
+use serde::Deserialize;
+use sha2::{Digest, Sha224};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The hosts Discord serves media from. Nothing is fetched from anywhere else.
+const DISCORD_CDN_HOSTS: [&str; 2] = ["cdn.discordapp.com", "media.discordapp.net"];
+
+/// The first part of the path of every kind of media the app shows.
+const DISCORD_CDN_ROOTS: [&str; 12] = [
+    "app-icons",
+    "attachments",
+    "avatars",
+    "banners",
+    "embed",
+    "emojis",
+    "ephemeral-attachments",
+    "guilds",
+    "icons",
+    "role-icons",
+    "splashes",
+    "stickers",
+];
+
+const DISCORD_API_URL: &str = "https://discord.com/api/v10";
+
+const DISCORD_CDN_PATH: &str = "./var/lib/atchat/storage/discord-cdn/";
+
+/// Matches the largest upload we accept ourselves. Attachments can be bigger
+/// than this on Discord, and those are left to load from Discord directly.
+const MAX_MEDIA_BYTES: u64 = 100 * 1024 * 1024;
+
+/// The largest size an image can be asked to be shrunk to.
+const MAX_RESIZE: u32 = 4096;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The query parameters that sign an attachment url. Refreshing the url gives
+/// them new values without the file changing, so they play no part in which
+/// copy of a file is which.
+const SIGNATURE_PARAMETERS: [&str; 3] = ["ex", "is", "hm"];
+
+/// The other query parameters Discord's CDN understands. Anything else is
+/// dropped rather than passed on.
+const MEDIA_PARAMETERS: [&str; 6] = ["size", "format", "quality", "width", "height", "animated"];
+
+const DEFAULT_BUDGET_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Refreshing a url uses the bot's token against Discord's rate limits, and
+/// anyone can ask for an attachment with an expired url.
+const MAX_REFRESHES_PER_MINUTE: u32 = 30;
+
+/// How many refreshes have been asked for in the minute starting at
+/// `minute`, in minutes since the Unix epoch.
+struct RefreshWindow {
+    minute: i64,
+    count: u32,
+}
+
+static REFRESHES: Mutex<RefreshWindow> = Mutex::new(RefreshWindow {
+    minute: 0,
+    count: 0,
+});
+
+/// Counts a refresh against the limit, or says there is none left this minute.
+fn take_refresh(window: &Mutex<RefreshWindow>, now: i64) -> bool {
+    let mut window2 = window.lock().unwrap();
+    let minute = now.div_euclid(60);
+    if window2.minute != minute {
+        *window2 = RefreshWindow { minute, count: 0 };
+    }
+
+    if window2.count < MAX_REFRESHES_PER_MINUTE {
+        window2.count += 1;
+        true
+    } else {
+        false
+    }
+}
+
+/// Whether a value is one Discord could have given a parameter. Signatures
+/// are hex, sizes are numbers and the rest are short words.
+fn is_valid_parameter_value(key: &str, value: &str) -> bool {
+    match key {
+        "ex" | "is" | "hm" => {
+            !value.is_empty() && value.len() <= 128 && value.chars().all(|x| x.is_ascii_hexdigit())
+        }
+        "size" | "width" | "height" => value
+            .parse::<u32>()
+            .is_ok_and(|size| (1..=MAX_RESIZE).contains(&size)),
+        "animated" => value == "true" || value == "false",
+        _ => {
+            !value.is_empty()
+                && value.len() <= 16
+                && value.chars().all(|x| x.is_ascii_alphanumeric())
+        }
+    }
+}
+
+/// What was asked for, checked against what this proxy is willing to fetch.
+#[derive(Debug)]
+struct MediaRequest {
+    host: String,
+    path: String,
+    /// The query minus `resize`, which is ours rather than Discord's, still
+    /// percent encoded so it can be passed on exactly as it came.
+    upstream_query: Vec<(String, String)>,
+    /// Shrink an image to fit within a square this many pixels across.
+    resize: Option<u32>,
+}
+
+impl MediaRequest {
+    fn parse(host: &str, path: &str, query: Option<&str>) -> Result<Self, &'static str> {
+        if !DISCORD_CDN_HOSTS.contains(&host) {
+            return Err("Not a Discord CDN host");
+        }
+
+        let is_valid_path: bool = path.len() < 300
+            && path
+                .split('/')
+                .next()
+                .is_some_and(|root| DISCORD_CDN_ROOTS.contains(&root))
+            && path.split('/').all(|segment| {
+                !segment.is_empty()
+                    && segment != "."
+                    && segment != ".."
+                    && segment
+                        .chars()
+                        .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_' || x == '.')
+            });
+
+        if !is_valid_path {
+            return Err("Not a Discord CDN path");
+        }
+
+        let mut upstream_query: Vec<(String, String)> = Vec::new();
+        let mut resize: Option<u32> = None;
+
+        for pair in query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+        {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            let is_valid_pair: bool = !key.is_empty()
+                && format!("{key}{value}")
+                    .chars()
+                    .all(|x| x.is_ascii_alphanumeric() || "-_.~%".contains(x));
+
+            if !is_valid_pair {
+                return Err("Invalid query");
+            }
+
+            if key == "resize" {
+                match value.parse::<u32>() {
+                    Ok(size) if (1..=MAX_RESIZE).contains(&size) => resize = Some(size),
+                    _ => return Err("Invalid resize value"),
+                }
+            } else if SIGNATURE_PARAMETERS.contains(&key) || MEDIA_PARAMETERS.contains(&key) {
+                if !is_valid_parameter_value(key, value)
+                    || upstream_query.iter().any(|(key2, _)| key2 == key)
+                {
+                    return Err("Invalid query");
+                }
+                upstream_query.push((key.to_owned(), value.to_owned()));
+            }
+        }
+
+        Ok(Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            upstream_query,
+            resize,
+        })
+    }
+
+    /// Attachments are the only media whose urls are signed, and so the only
+    /// media whose urls expire.
+    fn is_attachment(&self) -> bool {
+        self.path.starts_with("attachments/") || self.path.starts_with("ephemeral-attachments/")
+    }
+
+    /// Where the media is on Discord, given the origin to find it at.
+    fn upstream_url(&self, origin: &str) -> String {
+        let query: Vec<String> = self
+            .upstream_query
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+
+        if query.is_empty() {
+            format!("{origin}/{}", self.path)
+        } else {
+            format!("{origin}/{}?{}", self.path, query.join("&"))
+        }
+    }
+
+    /// Whether the signature on an attachment url has run out. Discord gives
+    /// the time it runs out in `ex`, as seconds since the Unix epoch in hex.
+    fn is_expired(&self, now: i64) -> bool {
+        self.upstream_query
+            .iter()
+            .find(|(key, _)| key == "ex")
+            .and_then(|(_, value)| i64::from_str_radix(value, 16).ok())
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// The name a copy is kept under. Made from everything that decides what
+    /// the bytes are and nothing that does not, so a refreshed signature finds
+    /// the copy made under the old one. Hashed because a url is not a filename.
+    fn cache_key(&self, resize: Option<u32>) -> String {
+        let mut query: Vec<String> = self
+            .upstream_query
+            .iter()
+            .filter(|(key, _)| !SIGNATURE_PARAMETERS.contains(&key.as_str()))
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        query.sort();
+
+        let key = match resize {
+            Some(size) => format!(
+                "{}/{}?{}#resize={size}",
+                self.host,
+                self.path,
+                query.join("&")
+            ),
+            None => format!("{}/{}?{}", self.host, self.path, query.join("&")),
+        };
+
+        base64_encode(&Sha224::digest(key.as_bytes()))
+    }
+}
+
+/// Where media and fresh attachment urls come from, which is always Discord
+/// outside of tests.
+struct Upstream {
+    client: OutboundClient,
+    cdn_origin: String,
+    api_url: String,
+    /// What to send as `Authorization` when asking Discord to refresh an
+    /// attachment url. Without it expired attachments cannot be refreshed.
+    authorization: Option<String>,
+}
+
+pub async fn discord_cdn_endpoint(
+    Path((host, path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    Extension(file_cache): Extension<Arc<FileCache>>,
+    Extension(client): Extension<OutboundClient>,
+) -> Response<Body> {
+    let request = match MediaRequest::parse(&host, &path, query.as_deref()) {
+        Ok(request2) => request2,
+        Err(error) => return plain_response(StatusCode::BAD_REQUEST, error),
+    };
+
+    // Refreshing an attachment url needs a token, which is given to the server
+    // in `DISCORD_BOT_TOKEN` so it never appears in a url or a log.
+    let upstream = Upstream {
+        client,
+        cdn_origin: format!("https://{}", request.host),
+        api_url: DISCORD_API_URL.to_owned(),
+        authorization: std::env::var("DISCORD_BOT_TOKEN")
+            .ok()
+            .map(|token| format!("Bot {}", token.trim())),
+    };
+
+    match media(&upstream, &request, &file_cache).await {
+        Ok((bytes, content_type)) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .header("Cache-Control", IMMUTABLE_CACHE_CONTROL)
+            .header("Content-Security-Policy", SANDBOX_CSP)
+            .header("X-Content-Type-Options", NOSNIFF)
+            .body(Body::from(bytes))
+            .unwrap(),
+        Err((status, message)) => plain_response(status, message),
+    }
+}
+
+fn plain_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// The media and the content type to serve it as, shrunk if that was asked for
+/// and the media is an image that can be.
+async fn media(
+    upstream: &Upstream,
+    request: &MediaRequest,
+    file_cache: &FileCache,
+) -> Result<(Bytes, String), (StatusCode, &'static str)> {
+    if let Some(stored) = request
+        .resize
+        .and_then(|size| stored_media(&request.cache_key(Some(size)), file_cache))
+    {
+        return Ok(stored);
+    }
+
+    let original = original_media(upstream, request, file_cache).await?;
+
+    // Decoding and encoding a large image keeps the thread busy for seconds,
+    // so it is done on one set aside for blocking work.
+    let resized_media: Option<(u32, Vec<u8>)> = match request.resize {
+        Some(size) => {
+            let bytes: Bytes = original.0.clone();
+            tokio::task::spawn_blocking(move || Some((size, resized(&bytes, size)?)))
+                .await
+                .ok()
+                .flatten()
+        }
+        None => None,
+    };
+
+    match resized_media {
+        Some((size, webp)) => {
+            store_media(&request.cache_key(Some(size)), &webp, "image/webp");
+            Ok((Bytes::from(webp), String::from("image/webp")))
+        }
+        None => Ok(original),
+    }
+}
+
+/// The media as Discord has it, from our copy if there is one.
+///
+/// An attachment whose signature has run out is refreshed before it is
+/// fetched, and one Discord turns away is refreshed and tried again, since a
+/// clock that disagrees with Discord's would otherwise leave it unreachable.
+async fn original_media(
+    upstream: &Upstream,
+    request: &MediaRequest,
+    file_cache: &FileCache,
+) -> Result<(Bytes, String), (StatusCode, &'static str)> {
+    let key = request.cache_key(None);
+
+    if let Some(stored) = stored_media(&key, file_cache) {
+        return Ok(stored);
+    }
+
+    let url = request.upstream_url(&upstream.cdn_origin);
+    let now = chrono::Utc::now().timestamp();
+
+    let fetched = if request.is_attachment() && request.is_expired(now) {
+        match refreshed_url(upstream, &url).await {
+            Some(refreshed) => fetch(&upstream.client, &refreshed).await,
+            None => fetch(&upstream.client, &url).await,
+        }
+    } else {
+        match fetch(&upstream.client, &url).await {
+            Err((StatusCode::FORBIDDEN | StatusCode::NOT_FOUND, _)) if request.is_attachment() => {
+                match refreshed_url(upstream, &url).await {
+                    Some(refreshed) => fetch(&upstream.client, &refreshed).await,
+                    None => Err((StatusCode::NOT_FOUND, "Media does not exist")),
+                }
+            }
+            result => result,
+        }
+    }?;
+
+    store_media(&key, &fetched.0, &fetched.1);
+    Ok(fetched)
+}
+
+async fn fetch(
+    client: &OutboundClient,
+    url: &str,
+) -> Result<(Bytes, String), (StatusCode, &'static str)> {
+    let request = match client.get(url) {
+        Ok(request2) => request2,
+        Err(_) => return Err((StatusCode::BAD_GATEWAY, "Could not fetch media")),
+    };
+
+    let response = match request.timeout(FETCH_TIMEOUT).send().await {
+        Ok(response2) => response2,
+        Err(_) => return Err((StatusCode::BAD_GATEWAY, "Could not reach Discord")),
+    };
+
+    match response.status() {
+        status if status.is_success() => {}
+        StatusCode::NOT_FOUND => return Err((StatusCode::NOT_FOUND, "Media does not exist")),
+        status => return Err((status, "Discord could not provide the media")),
+    }
+
+    // Served sandboxed and unsniffed whatever the type, so Discord's word for
+    // it can be passed on as it is.
+    let content_type: String = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+
+    match read_capped(response, MAX_MEDIA_BYTES).await {
+        Ok(bytes) => Ok((bytes, content_type)),
+        Err(ReadError::TooLarge) => Err((StatusCode::BAD_GATEWAY, "Media is too large")),
+        Err(ReadError::Interrupted) => {
+            Err((StatusCode::BAD_GATEWAY, "Media download was interrupted"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RefreshedUrls {
+    refreshed_urls: Vec<RefreshedUrl>,
+}
+
+#[derive(Deserialize)]
+struct RefreshedUrl {
+    refreshed: String,
+}
+
+/// Asks Discord for a freshly signed url for an attachment, by the same route
+/// the backend sends its own Discord API calls.
+///
+/// The answer is only used if it points back at Discord's CDN, so that a
+/// compromised or mistaken response cannot send this proxy anywhere else.
+async fn refreshed_url(upstream: &Upstream, url: &str) -> Option<String> {
+    let authorization = upstream.authorization.clone()?;
+
+    if !take_refresh(&REFRESHES, chrono::Utc::now().timestamp()) {
+        return None;
+    }
+
+    let (status, text) = send_custom_request(
+        &upstream.client,
+        CustomRequest {
+            method: String::from("POST"),
+            url: format!("{}/attachments/refresh-urls", upstream.api_url),
+            headers: vec![
+                Header {
+                    key: String::from("Authorization"),
+                    value: authorization,
+                },
+                Header {
+                    key: String::from("Content-Type"),
+                    value: String::from("application/json"),
+                },
+            ],
+            body: Some(serde_json::json!({ "attachment_urls": [url] }).to_string()),
+        },
+    )
+    .await
+    .ok()?;
+
+    if !status.is_success() {
+        return None;
+    }
+
+    let refreshed: String = serde_json::from_str::<RefreshedUrls>(&text)
+        .ok()?
+        .refreshed_urls
+        .into_iter()
+        .next()?
+        .refreshed;
+
+    let is_discord: bool = refreshed.starts_with(&format!("{}/", upstream.cdn_origin))
+        || DISCORD_CDN_HOSTS
+            .iter()
+            .any(|host| refreshed.starts_with(&format!("https://{host}/")));
+
+    is_discord.then_some(refreshed)
+}
+
+fn media_filepath(key: &str) -> String {
+    format!("{DISCORD_CDN_PATH}{key}")
+}
+
+/// Discord's content type is kept beside each copy, since unlike a sticker an
+/// attachment can be any kind of file and cannot be told apart by its bytes.
+fn content_type_filepath(key: &str) -> String {
+    format!("{DISCORD_CDN_PATH}{key}_content_type")
+}
+
+/// A copy's modified time is moved on each time it is served, so that the
+/// copies removed to stay under budget are the ones nobody is looking at.
+fn stored_media(key: &str, file_cache: &FileCache) -> Option<(Bytes, String)> {
+    let path = media_filepath(key);
+    let content_type = fs::read_to_string(content_type_filepath(key)).ok()?;
+    let metadata = fs::metadata(&path).ok()?;
+    let bytes = file_cache.read(&path, metadata.len()).ok()?;
+
+    if let Ok(file) = fs::File::options().write(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+
+    Some((bytes, content_type))
+}
+
+/// The content type is written first, so a copy that can be found always has
+/// one. Failing to keep a copy only costs fetching it again next time.
+fn store_media(key: &str, bytes: &[u8], content_type: &str) {
+    create_dir_if_missing(DISCORD_CDN_PATH.to_owned());
+
+    if write_atomically(&content_type_filepath(key), content_type.as_bytes()).is_ok() {
+        let _ = write_atomically(&media_filepath(key), bytes);
+    }
+
+    remove_least_recently_served(
+        DISCORD_CDN_PATH,
+        crate::env_u64("DISCORD_CDN_BUDGET_BYTES", DEFAULT_BUDGET_BYTES),
+    );
+}
+
+/// Removes the copies in `dir` served longest ago, along with their content
+/// types, until those left fit in `budget_bytes`.
+fn remove_least_recently_served(dir: &str, budget_bytes: u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut copies: Vec<(SystemTime, u64, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if name.ends_with("_content_type") {
+                return None;
+            }
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), name))
+        })
+        .collect();
+
+    let mut total: u64 = copies.iter().map(|(_, size, _)| size).sum();
+    copies.sort();
+
+    for (_, size, name) in copies {
+        if total <= budget_bytes {
+            break;
+        }
+        if fs::remove_file(format!("{dir}{name}")).is_ok() {
+            let _ = fs::remove_file(format!("{dir}{name}_content_type"));
+            total -= size;
+        }
+    }
+}
+
+/// The widest or tallest image that is decoded to be resized. A small file can
+/// claim to be enormous, and decoding it would take gigabytes.
+const MAX_DECODED_SIDE: u32 = 16384;
+
+/// An image shrunk to fit within `size` pixels square, as a WebP the way
+/// thumbnails are kept. `None` leaves the media as it is, which is what happens
+/// to anything that is not an image, is already small enough, is larger than
+/// [`MAX_DECODED_SIDE`], or is animated: decoding gives only its first frame,
+/// and a resized copy would stand still.
+fn resized(bytes: &[u8], size: u32) -> Option<Vec<u8>> {
+    let mut reader = ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?;
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_SIDE);
+    limits.max_image_height = Some(MAX_DECODED_SIDE);
+    reader.limits(limits);
+
+    let is_animated: bool = match reader.format()? {
+        ImageFormat::Gif => true,
+        ImageFormat::Png => contains(bytes, b"acTL"),
+        ImageFormat::WebP => contains(bytes, b"ANIM"),
+        _ => false,
+    };
+
+    if is_animated {
+        return None;
+    }
+
+    let image = reader.decode().ok()?;
+    let (width, height) = image.dimensions();
+
+    if width <= size && height <= size {
+        return None;
+    }
+
+    let mut webp: Vec<u8> = Vec::new();
+    image
+        .resize(size, size, image::imageops::FilterType::Triangle)
+        .write_to(&mut std::io::Cursor::new(&mut webp), ImageFormat::WebP)
+        .ok()?;
+    Some(webp)
+}
+
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    bytes.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_storage_dir, make_png, spawn_test_server, test_outbound_client};
+    use axum::routing::{get, post};
+
+    fn test_file_cache() -> FileCache {
+        FileCache::new(1024 * 1024, 1024 * 1024)
+    }
+
+    fn parse(path: &str, query: Option<&str>) -> Result<MediaRequest, &'static str> {
+        MediaRequest::parse("cdn.discordapp.com", path, query)
+    }
+
+    fn remove_stored(request: &MediaRequest) {
+        for key in [request.cache_key(None), request.cache_key(request.resize)] {
+            let _ = fs::remove_file(media_filepath(&key));
+            let _ = fs::remove_file(content_type_filepath(&key));
+        }
+    }
+
+    #[test]
+    fn accepts_the_media_the_app_shows() {
+        for (path, query) in [
+            ("avatars/1/a_abc123.png", Some("size=64")),
+            ("icons/1/abc.webp", None),
+            ("emojis/123.gif", Some("size=48&quality=lossless")),
+            ("attachments/1/2/holiday.mp4", Some("ex=66&is=65&hm=abc&")),
+            (
+                "attachments/1/2/photo.png",
+                Some("format=webp&quality=lossless&width=400&height=300&animated=true"),
+            ),
+            ("embed/avatars/0.png", None),
+        ] {
+            assert!(
+                parse(path, query).is_ok(),
+                "{path} is media the app shows and should be let through"
+            );
+        }
+    }
+
+    // Anything but Discord's media would make this an open proxy onto whatever
+    // the server can reach.
+    #[test]
+    fn refuses_anything_that_is_not_discord_media() {
+        assert!(
+            MediaRequest::parse("evil.example", "avatars/1/abc.png", None).is_err(),
+            "only Discord's CDN hosts should be fetched from"
+        );
+        assert!(
+            MediaRequest::parse("localhost:8000", "avatars/1/abc.png", None).is_err(),
+            "only Discord's CDN hosts should be fetched from"
+        );
+
+        for path in [
+            "api/v10/users/@me",
+            "avatars/../../etc/passwd",
+            "avatars//abc.png",
+            "avatars/1/abc.png#x",
+            "",
+        ] {
+            assert!(
+                parse(path, None).is_err(),
+                "{path} is not Discord media and should be refused"
+            );
+        }
+
+        assert!(
+            parse("avatars/1/abc.png", Some("size=64&next=http://x")).is_err(),
+            "a query that is not plain parameters should be refused"
+        );
+    }
+
+    // Each different query is a different copy on disk, so one that could be
+    // varied freely would let anyone fill the disk with the same file.
+    #[test]
+    fn only_discords_own_parameters_make_a_new_copy() {
+        let plain = parse("attachments/1/2/a.png", Some("ex=1&is=2&hm=3")).expect("valid");
+        let padded = parse(
+            "attachments/1/2/a.png",
+            Some("ex=1&is=2&hm=3&a=1&cachebust=2"),
+        )
+        .expect("valid");
+
+        assert_eq!(
+            plain.cache_key(None),
+            padded.cache_key(None),
+            "parameters Discord does not know should not make a new copy"
+        );
+        assert_eq!(
+            padded.upstream_url("https://cdn.discordapp.com"),
+            "https://cdn.discordapp.com/attachments/1/2/a.png?ex=1&is=2&hm=3",
+            "nor be passed on"
+        );
+
+        for query in [
+            "size=abc",
+            "size=100000",
+            "width=0",
+            "animated=maybe",
+            "ex=not-hex",
+            "format=a%2Fb",
+            "size=64&size=128",
+        ] {
+            assert!(
+                parse("avatars/1/abc.png", Some(query)).is_err(),
+                "{query} is not a value Discord would give"
+            );
+        }
+    }
+
+    #[test]
+    fn refreshes_only_so_often() {
+        let window = Mutex::new(RefreshWindow {
+            minute: 0,
+            count: 0,
+        });
+
+        let allowed = (0..MAX_REFRESHES_PER_MINUTE + 5)
+            .filter(|_| take_refresh(&window, 600))
+            .count();
+        assert_eq!(
+            allowed, MAX_REFRESHES_PER_MINUTE as usize,
+            "only so many refreshes should be allowed in a minute"
+        );
+        assert!(take_refresh(&window, 660), "a new minute should allow more");
+    }
+
+    #[test]
+    fn removes_the_copies_served_longest_ago_once_over_budget() {
+        let dir = format!(
+            "{}/atchat-discord-cdn-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+
+        let old = SystemTime::now() - Duration::from_secs(60);
+        for (name, modified) in [("old", old), ("new", SystemTime::now())] {
+            let file = fs::File::create(format!("{dir}{name}")).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(modified).unwrap();
+            fs::write(format!("{dir}{name}_content_type"), "image/png").unwrap();
+        }
+
+        remove_least_recently_served(&dir, 150);
+
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            left,
+            vec!["new", "new_content_type"],
+            "the copy served longest ago should go, along with its content type"
+        );
+    }
+
+    #[test]
+    fn resize_is_ours_and_is_not_passed_on() {
+        let request = parse("avatars/1/abc.png", Some("size=64&resize=32"))
+            .expect("should be a valid request");
+
+        assert_eq!(request.resize, Some(32), "resize should be read");
+        assert_eq!(
+            request.upstream_url("https://cdn.discordapp.com"),
+            "https://cdn.discordapp.com/avatars/1/abc.png?size=64",
+            "resize should not be sent to Discord"
+        );
+        assert!(
+            parse("avatars/1/abc.png", Some("resize=0")).is_err()
+                && parse("avatars/1/abc.png", Some("resize=100000")).is_err(),
+            "a resize outside what is sensible should be refused"
+        );
+    }
+
+    // A refreshed url has a new signature for the same file, and should find
+    // the copy kept under the old one rather than fetch the file again.
+    #[test]
+    fn a_new_signature_is_the_same_file() {
+        let old = parse("attachments/1/2/a.png", Some("ex=1&is=2&hm=3")).expect("valid");
+        let new = parse("attachments/1/2/a.png", Some("hm=6&ex=4&is=5")).expect("valid");
+        let other = parse("attachments/1/2/a.png", Some("ex=1&is=2&hm=3&size=64")).expect("valid");
+
+        assert_eq!(
+            old.cache_key(None),
+            new.cache_key(None),
+            "signature parameters should not change which copy is used"
+        );
+        assert_ne!(
+            old.cache_key(None),
+            other.cache_key(None),
+            "other parameters can change the bytes and should be kept apart"
+        );
+        assert_ne!(
+            old.cache_key(None),
+            old.cache_key(Some(64)),
+            "a resized copy is not the original"
+        );
+    }
+
+    #[test]
+    fn reads_when_an_attachment_url_expires() {
+        let request = parse("attachments/1/2/a.png", Some("ex=66000000")).expect("valid");
+        assert!(request.is_expired(0x6600_0000), "the url expires at ex");
+        assert!(!request.is_expired(0x6500_0000), "the url is good until ex");
+        assert!(
+            !parse("attachments/1/2/a.png", None)
+                .expect("valid")
+                .is_expired(i64::MAX),
+            "a url with no expiry never expires"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_media_once_fetched() {
+        create_storage_dir();
+        let png = make_png(4, 4);
+        let expected = png.clone();
+        let base =
+            spawn_test_server(move |_base| vec![("/avatars/1/kept.png", "image/png", png)]).await;
+        let request = parse("avatars/1/kept.png", Some("size=64")).expect("valid");
+        let upstream = Upstream {
+            client: test_outbound_client(),
+            cdn_origin: base,
+            api_url: String::from("http://127.0.0.1:9"),
+            authorization: None,
+        };
+
+        let fetched = media(&upstream, &request, &test_file_cache()).await;
+        let stored = stored_media(&request.cache_key(None), &test_file_cache());
+        remove_stored(&request);
+
+        let (bytes, content_type) = fetched.expect("the avatar should be fetched");
+        assert_eq!(
+            bytes.to_vec(),
+            expected,
+            "the avatar should be passed on as sent"
+        );
+        assert_eq!(
+            content_type, "image/png",
+            "Discord's content type should be kept"
+        );
+        assert_eq!(
+            stored.map(|(bytes2, content_type2)| (bytes2.to_vec(), content_type2)),
+            Some((expected, String::from("image/png"))),
+            "the avatar should have been kept for next time"
+        );
+    }
+
+    #[tokio::test]
+    async fn shrinks_an_image_when_asked() {
+        create_storage_dir();
+        let png = make_png(200, 100);
+        let base =
+            spawn_test_server(move |_base| vec![("/icons/1/large.png", "image/png", png)]).await;
+        let request = parse("icons/1/large.png", Some("resize=50")).expect("valid");
+        let upstream = Upstream {
+            client: test_outbound_client(),
+            cdn_origin: base,
+            api_url: String::from("http://127.0.0.1:9"),
+            authorization: None,
+        };
+
+        let fetched = media(&upstream, &request, &test_file_cache()).await;
+        remove_stored(&request);
+
+        let (bytes, content_type) = fetched.expect("the icon should be fetched");
+        assert_eq!(
+            content_type, "image/webp",
+            "a resized image is kept as webp"
+        );
+        let image = image::load_from_memory(&bytes).expect("should be an image");
+        assert_eq!(
+            image.dimensions(),
+            (50, 25),
+            "the image should fit in the size asked for and keep its shape"
+        );
+    }
+
+    // Discord answers an expired attachment url with a 404. With a token to
+    // ask for a new signature, the attachment is still reachable.
+    #[tokio::test]
+    async fn refreshes_an_attachment_url_discord_turns_away() {
+        create_storage_dir();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let base = format!("http://{}", listener.local_addr().unwrap());
+
+        let png = make_png(3, 3);
+        let expected = png.clone();
+        let refreshed = format!("{base}/attachments/1/2/refreshed.png?ex=7fffffff&is=1&hm=new");
+        let router = axum::Router::new()
+            .route(
+                "/attachments/1/2/refreshed.png",
+                get(
+                    move || async move { ([(axum::http::header::CONTENT_TYPE, "image/png")], png) },
+                ),
+            )
+            .route(
+                "/api/attachments/refresh-urls",
+                post(move |headers: http::HeaderMap| async move {
+                    match headers.get("authorization").and_then(|v| v.to_str().ok()) {
+                        Some("Bot the-token") => (
+                            StatusCode::OK,
+                            serde_json::json!({
+                                "refreshed_urls": [{ "original": "", "refreshed": refreshed }]
+                            })
+                            .to_string(),
+                        ),
+                        _ => (StatusCode::UNAUTHORIZED, String::new()),
+                    }
+                }),
+            );
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, router).await;
+        });
+
+        let request = parse(
+            "attachments/1/2/expired.png",
+            Some("ex=7fffffff&is=1&hm=01d"),
+        )
+        .expect("valid");
+
+        let without_token = media(
+            &Upstream {
+                client: test_outbound_client(),
+                cdn_origin: base.clone(),
+                api_url: format!("{base}/api"),
+                authorization: None,
+            },
+            &request,
+            &test_file_cache(),
+        )
+        .await;
+
+        let with_token = media(
+            &Upstream {
+                client: test_outbound_client(),
+                cdn_origin: base.clone(),
+                api_url: format!("{base}/api"),
+                authorization: Some(String::from("Bot the-token")),
+            },
+            &request,
+            &test_file_cache(),
+        )
+        .await;
+        remove_stored(&request);
+
+        assert_eq!(
+            without_token.map(|_| ()).map_err(|(status, _)| status),
+            Err(StatusCode::NOT_FOUND),
+            "with no token there is no way to refresh the url"
+        );
+        assert_eq!(
+            with_token.map(|(bytes, _)| bytes.to_vec()),
+            Ok(expected),
+            "the attachment should be fetched from its refreshed url"
+        );
+    }
+
+    // A few bytes can claim an image of ten billion pixels, which should be
+    // turned down from its header rather than decoded.
+    #[test]
+    fn does_not_decode_an_image_claiming_to_be_enormous() {
+        let mut png = make_png(20, 20);
+        png[16..20].copy_from_slice(&100_000u32.to_be_bytes());
+        png[20..24].copy_from_slice(&100_000u32.to_be_bytes());
+        let mut crc = flate2::Crc::new();
+        crc.update(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.sum().to_be_bytes());
+
+        assert!(
+            resized(&png, 50).is_none(),
+            "an image larger than the limit should be left as it is"
+        );
+    }
+
+    #[test]
+    fn leaves_animated_and_small_images_alone() {
+        assert!(
+            resized(&make_png(10, 10), 50).is_none(),
+            "an image already small enough should be left as it is"
+        );
+        assert!(
+            resized(b"not an image", 50).is_none(),
+            "something that is not an image should be left as it is"
+        );
+
+        let mut gif: Vec<u8> = Vec::new();
+        image::DynamicImage::new_rgba8(100, 100)
+            .write_to(&mut std::io::Cursor::new(&mut gif), ImageFormat::Gif)
+            .expect("failed to encode test gif");
+        assert!(
+            resized(&gif, 50).is_none(),
+            "a gif may be animated and should be left as it is"
+        );
+    }
+}
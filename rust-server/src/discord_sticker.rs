@@ -0,0 +1,696 @@
+//! Serves Discord stickers from our own origin.
+//!
+//! Messages bridged from Discord can carry stickers, and those live on Discord's
+//! servers. Fetching them through here keeps viewers' addresses away from
+//! Discord, and keeping a copy means each sticker is fetched from Discord once
+//! rather than once per view. A sticker id is never reused for a different
+//! image, so a copy never needs refreshing.
+//!
+//! Stickers come as PNG (animated or not), GIF, or Lottie. Lottie is a JSON
+//! description of a vector animation, which the app draws itself; it is served
+//! as it comes, and only read here for the size it declares.
+//!
+//! Anything that cannot draw Lottie can ask for the sticker as `{id}.webp`
+//! instead, and get it drawn as an animated WebP. This is off unless
+//! `LOTTIE_RENDERER` names a program to do the drawing, which is run as
+//! `program <input.json> <output.webp>`, the shape `lottie_convert.py` from
+//! python-lottie takes. Each sticker is drawn once and the WebP kept like any
+//! other sticker.
+
+use crate::file_cache::FileCache;
+use crate::outbound::{OutboundClient, ReadError, read_capped};
+use crate::{
+    IMMUTABLE_CACHE_CONTROL, ImageData, NOSNIFF, SANDBOX_CSP, create_dir_if_missing,
+    image_data_from_bytes, json_response_with_headers, response_with_headers, write_atomically,
+};
+use axum::Extension;
+use axum::body::{Body, Bytes};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::Response;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+const DISCORD_STICKER_URL: &str = "https://discord.com/stickers";
+
+const STICKERS_PATH: &str = "./var/lib/atchat/storage/discord-stickers/";
+
+/// Discord caps stickers at 512 KB. Anything far past that is not a sticker,
+/// and is not worth holding on to.
+const MAX_STICKER_BYTES: u64 = 4 * 1024 * 1024;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A drawn animation can come out several times the size of the JSON it was
+/// drawn from.
+const MAX_RENDERED_BYTES: u64 = 16 * 1024 * 1024;
+
+const RENDER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Drawing an animation keeps a core busy for seconds, so only a couple are
+/// drawn at once and any more wait their turn.
+static RENDERS: Semaphore = Semaphore::const_new(2);
+
+/// A sticker is asked for as its id and the extension for its format, which is
+/// all the app ever builds. Holding to exactly that shape is what keeps the path
+/// safe to use as a filename.
+fn is_valid_sticker_path(sticker_path: &str) -> bool {
+    match sticker_path.split_once('.') {
+        Some((id, extension)) => {
+            !id.is_empty()
+                && id.len() < 30
+                && id.chars().all(|x| x.is_ascii_digit())
+                && ["png", "gif", "json", "webp"].contains(&extension)
+        }
+        None => false,
+    }
+}
+
+fn sticker_filepath(sticker_path: &str) -> String {
+    format!("{STICKERS_PATH}{sticker_path}")
+}
+
+/// The program that draws Lottie stickers as WebP, if one has been set up.
+fn lottie_renderer() -> Option<String> {
+    std::env::var("LOTTIE_RENDERER")
+        .ok()
+        .filter(|renderer| !renderer.is_empty())
+}
+
+pub async fn discord_sticker_endpoint(
+    Path(sticker_path): Path<String>,
+    Extension(file_cache): Extension<Arc<FileCache>>,
+    Extension(client): Extension<OutboundClient>,
+) -> Response<Body> {
+    if !is_valid_sticker_path(&sticker_path) {
+        return plain_response(StatusCode::BAD_REQUEST, "Invalid sticker format");
+    }
+
+    match sticker(
+        &client,
+        DISCORD_STICKER_URL,
+        &sticker_path,
+        &file_cache,
+        lottie_renderer().as_deref(),
+    )
+    .await
+    {
+        Ok((bytes, content_type)) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .header("Cache-Control", IMMUTABLE_CACHE_CONTROL)
+            .header("Content-Security-Policy", SANDBOX_CSP)
+            .header("X-Content-Type-Options", NOSNIFF)
+            .body(Body::from(bytes))
+            .unwrap(),
+        Err((status, message)) => plain_response(status, message),
+    }
+}
+
+/// The size and format of a sticker. Not every sticker is square, and the app
+/// needs to know the shape of one to lay it out before it has loaded.
+pub async fn discord_sticker_info_endpoint(
+    Path(sticker_path): Path<String>,
+    Extension(file_cache): Extension<Arc<FileCache>>,
+    Extension(client): Extension<OutboundClient>,
+) -> Response<String> {
+    if !is_valid_sticker_path(&sticker_path) {
+        return response_with_headers(StatusCode::BAD_REQUEST, "Invalid sticker format");
+    }
+
+    match sticker(
+        &client,
+        DISCORD_STICKER_URL,
+        &sticker_path,
+        &file_cache,
+        lottie_renderer().as_deref(),
+    )
+    .await
+    {
+        Ok((bytes, _)) => {
+            let url = format!("/file/discord-sticker/{sticker_path}");
+            match sticker_image_data(&url, &bytes) {
+                Some(image_data) => json_response_with_headers(
+                    StatusCode::OK,
+                    serde_json::to_string(&image_data).unwrap(),
+                ),
+                None => response_with_headers(
+                    StatusCode::BAD_GATEWAY,
+                    "Sticker is not in a format we can read",
+                ),
+            }
+        }
+        Err((status, message)) => response_with_headers(status, message),
+    }
+}
+
+fn plain_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// A sticker and the content type to serve it as, from our copy if there is one
+/// and from Discord otherwise. A sticker fetched from Discord is kept, so it is
+/// only ever fetched the once.
+///
+/// When Discord cannot provide the sticker, the status it answered with is
+/// passed on rather than papered over with a success, so that a sticker that
+/// has been deleted reads as missing.
+///
+/// A `.webp` is drawn from the sticker's Lottie with `lottie_renderer`, and
+/// is missing if there is none.
+async fn sticker(
+    client: &OutboundClient,
+    base_url: &str,
+    sticker_path: &str,
+    file_cache: &FileCache,
+    lottie_renderer: Option<&str>,
+) -> Result<(Bytes, String), (StatusCode, &'static str)> {
+    let path = sticker_filepath(sticker_path);
+
+    let stored: Option<(Bytes, &'static str)> = fs::metadata(&path)
+        .ok()
+        .and_then(|metadata| file_cache.read(&path, metadata.len()).ok())
+        .and_then(|bytes| {
+            let content_type = sticker_content_type(&bytes)?;
+            Some((bytes, content_type))
+        });
+
+    if let Some((bytes, content_type)) = stored {
+        return Ok((bytes, content_type.to_owned()));
+    }
+
+    if let Some(id) = sticker_path.strip_suffix(".webp") {
+        let Some(lottie_renderer2) = lottie_renderer else {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Stickers are not drawn as WebP on this server",
+            ));
+        };
+        let (lottie, content_type) = Box::pin(sticker(
+            client,
+            base_url,
+            &format!("{id}.json"),
+            file_cache,
+            None,
+        ))
+        .await?;
+        if content_type != "application/json" || lottie_size(&lottie).is_none() {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Only Lottie stickers are drawn as WebP",
+            ));
+        }
+
+        let webp = render_lottie(lottie_renderer2, &lottie).await?;
+        let _ = write_atomically(&path, &webp);
+        return Ok((webp, String::from("image/webp")));
+    }
+
+    let request = match client.get(&format!("{base_url}/{sticker_path}")) {
+        Ok(request2) => request2,
+        Err(_) => return Err((StatusCode::BAD_GATEWAY, "Could not fetch the sticker")),
+    };
+
+    let response = match request.timeout(FETCH_TIMEOUT).send().await {
+        Ok(response2) => response2,
+        Err(_) => return Err((StatusCode::BAD_GATEWAY, "Could not reach Discord")),
+    };
+
+    match response.status() {
+        status if status.is_success() => {}
+        StatusCode::NOT_FOUND => return Err((StatusCode::NOT_FOUND, "Sticker does not exist")),
+        status => return Err((status, "Discord could not provide the sticker")),
+    }
+
+    // Served from our own origin, so the type is held to the ones a sticker
+    // can be. Anything else, such as an html error page sent with a success
+    // status, is not passed on.
+    let content_type: String = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with("image/") || v.starts_with("application/json"))
+        .ok_or((
+            StatusCode::BAD_GATEWAY,
+            "Discord sent something that is not a sticker",
+        ))?
+        .to_owned();
+
+    let bytes: Bytes = match read_capped(response, MAX_STICKER_BYTES).await {
+        Ok(bytes2) => bytes2,
+        Err(ReadError::TooLarge) => return Err((StatusCode::BAD_GATEWAY, "Sticker is too large")),
+        Err(ReadError::Interrupted) => {
+            return Err((StatusCode::BAD_GATEWAY, "Sticker download was interrupted"));
+        }
+    };
+
+    // Failing to keep a copy only costs fetching it again next time.
+    create_dir_if_missing(STICKERS_PATH.to_owned());
+    let _ = write_atomically(&path, &bytes);
+
+    Ok((bytes, content_type))
+}
+
+/// Draws a Lottie animation as an animated WebP, with the program set up to do
+/// it. The program is stopped if it has not finished by [`RENDER_TIMEOUT`].
+async fn render_lottie(
+    lottie_renderer: &str,
+    lottie: &[u8],
+) -> Result<Bytes, (StatusCode, &'static str)> {
+    const FAILED: (StatusCode, &str) = (StatusCode::BAD_GATEWAY, "Could not draw the sticker");
+
+    let _permit = RENDERS.acquire().await.map_err(|_closed| FAILED)?;
+
+    let prefix = format!(
+        "{}/atchat-lottie-{}",
+        std::env::temp_dir().display(),
+        rand::random::<u64>()
+    );
+    let input = format!("{prefix}.json");
+    let output = format!("{prefix}.webp");
+    fs::write(&input, lottie).map_err(|_error| FAILED)?;
+
+    // Giving up drops the child, which kills it.
+    let status = tokio::time::timeout(
+        RENDER_TIMEOUT,
+        tokio::process::Command::new(lottie_renderer)
+            .arg(&input)
+            .arg(&output)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .status(),
+    )
+    .await;
+
+    let webp: Option<Vec<u8>> = match status {
+        Ok(Ok(status2)) if status2.success() => fs::metadata(&output)
+            .ok()
+            .filter(|metadata| metadata.len() <= MAX_RENDERED_BYTES)
+            .and_then(|_| fs::read(&output).ok())
+            .filter(|webp2| image::guess_format(webp2).ok() == Some(image::ImageFormat::WebP)),
+        _ => None,
+    };
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&output);
+
+    webp.map(Bytes::from).ok_or(FAILED)
+}
+
+/// The content type of a sticker we have a copy of, judged from its bytes
+/// since the copy is kept without Discord's headers.
+fn sticker_content_type(bytes: &[u8]) -> Option<&'static str> {
+    match image::guess_format(bytes) {
+        Ok(format) => Some(format.to_mime_type()),
+        Err(_) => lottie_size(bytes).map(|_| "application/json"),
+    }
+}
+
+fn sticker_image_data(url: &str, bytes: &[u8]) -> Option<ImageData> {
+    match lottie_size(bytes) {
+        Some((width, height)) => Some(ImageData {
+            url: url.to_owned(),
+            width,
+            height,
+            format: Some(String::from("Lottie")),
+            thumbnail_url: None,
+        }),
+        None => image_data_from_bytes(url, bytes),
+    }
+}
+
+/// The size a Lottie animation declares, or `None` if these bytes are not
+/// one. Lottie files are JSON objects that give their size in `w` and `h`
+/// and their content in `layers`.
+fn lottie_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let lottie: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+
+    lottie.get("layers")?.as_array()?;
+
+    let dimension = |name: &str| -> Option<u32> {
+        let value = lottie.get(name)?.as_f64()?;
+        (value >= 1.0 && value <= f64::from(u32::MAX)).then_some(value.round() as u32)
+    };
+
+    Some((dimension("w")?, dimension("h")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_storage_dir, make_png, spawn_test_server, test_outbound_client};
+
+    fn test_file_cache() -> FileCache {
+        FileCache::new(1024 * 1024, 1024 * 1024)
+    }
+
+    const LOTTIE: &str = r#"{"v":"5.5.2","fr":60,"w":320,"h":160,"layers":[]}"#;
+
+    #[test]
+    fn accepts_only_an_id_and_a_sticker_extension() {
+        for sticker_path in ["1490556070756618301.png", "1.gif", "22.json", "3.webp"] {
+            assert!(
+                is_valid_sticker_path(sticker_path),
+                "{sticker_path} is what the app asks for"
+            );
+        }
+
+        for sticker_path in [
+            "..",
+            "../secret.txt",
+            ".png",
+            "abc.png",
+            "1.html",
+            "1",
+            "1.png.png",
+        ] {
+            assert!(
+                !is_valid_sticker_path(sticker_path),
+                "{sticker_path} is not a sticker and must not reach the filesystem"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_a_copy_of_a_sticker_once_fetched() {
+        create_storage_dir();
+        let png = make_png(8, 8);
+        let expected = png.clone();
+        let base =
+            spawn_test_server(move |_base| vec![("/900000000000000001.png", "image/png", png)])
+                .await;
+
+        let (bytes, content_type) = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000001.png",
+            &test_file_cache(),
+            None,
+        )
+        .await
+        .expect("the sticker should be fetched");
+        let stored = fs::read(sticker_filepath("900000000000000001.png"));
+        let _ = fs::remove_file(sticker_filepath("900000000000000001.png"));
+
+        assert_eq!(
+            bytes.to_vec(),
+            expected,
+            "the sticker should be passed on as sent"
+        );
+        assert_eq!(
+            content_type, "image/png",
+            "Discord's content type should be passed on"
+        );
+        assert_eq!(
+            stored.ok(),
+            Some(expected),
+            "the sticker should have been kept for next time"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_a_kept_sticker_without_asking_discord() {
+        create_storage_dir();
+        create_dir_if_missing(STICKERS_PATH.to_owned());
+        fs::write(sticker_filepath("900000000000000002.json"), LOTTIE)
+            .expect("failed to keep the sticker");
+
+        // Nothing is listening here, so only the kept copy can answer.
+        let result = sticker(
+            &test_outbound_client(),
+            "http://127.0.0.1:9",
+            "900000000000000002.json",
+            &test_file_cache(),
+            None,
+        )
+        .await;
+        let _ = fs::remove_file(sticker_filepath("900000000000000002.json"));
+
+        let (bytes, content_type) = result.expect("the kept copy should be served");
+        assert_eq!(
+            bytes.as_ref(),
+            LOTTIE.as_bytes(),
+            "the kept copy should be served"
+        );
+        assert_eq!(
+            content_type, "application/json",
+            "a kept Lottie sticker should still be served as json"
+        );
+    }
+
+    // Discord answering 404 means the sticker is gone, and the app should be
+    // told as much rather than handed an empty image with a 200.
+    #[tokio::test]
+    async fn passes_on_a_sticker_that_does_not_exist() {
+        let base = spawn_test_server(|_base| Vec::new()).await;
+
+        let result = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000003.png",
+            &test_file_cache(),
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            result.map(|_| ()),
+            Err((StatusCode::NOT_FOUND, "Sticker does not exist")),
+            "a missing sticker should be reported as missing"
+        );
+        assert!(
+            !fs::exists(sticker_filepath("900000000000000003.png")).unwrap_or(false),
+            "nothing should be kept for a sticker that was not found"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_something_that_is_not_a_sticker() {
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/900000000000000004.png",
+                "text/html; charset=utf-8",
+                b"<script>alert(1)</script>".to_vec(),
+            )]
+        })
+        .await;
+
+        let result = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000004.png",
+            &test_file_cache(),
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            result.map(|_| ()).map_err(|(status, _)| status),
+            Err(StatusCode::BAD_GATEWAY),
+            "html must never be served from our origin as a sticker"
+        );
+    }
+
+    // A link to something enormous should not be read into memory in full
+    // before it is found to be too large.
+    #[tokio::test]
+    async fn refuses_a_sticker_that_is_too_large() {
+        create_storage_dir();
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/900000000000000005.png",
+                "image/png",
+                vec![0; MAX_STICKER_BYTES as usize + 1],
+            )]
+        })
+        .await;
+
+        let result = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000005.png",
+            &test_file_cache(),
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            result.map(|_| ()),
+            Err((StatusCode::BAD_GATEWAY, "Sticker is too large")),
+            "a sticker over the limit should be refused"
+        );
+        assert!(
+            !fs::exists(sticker_filepath("900000000000000005.png")).unwrap_or(false),
+            "nothing should be kept for a sticker that was refused"
+        );
+    }
+
+    /// A stand-in for the renderer, which copies a WebP it was made with to
+    /// wherever it is told to write, as a real one would draw it there.
+    fn fake_renderer(webp: &[u8]) -> String {
+        let dir = format!(
+            "{}/atchat-renderer-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(format!("{dir}drawn.webp"), webp).unwrap();
+
+        let renderer = format!("{dir}render");
+        fs::write(
+            &renderer,
+            format!("#!/bin/sh\ncp \"{dir}drawn.webp\" \"$2\"\n"),
+        )
+        .unwrap();
+        let mut permissions = fs::metadata(&renderer).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o755);
+        fs::set_permissions(&renderer, permissions).unwrap();
+        renderer
+    }
+
+    fn make_webp() -> Vec<u8> {
+        let mut webp: Vec<u8> = Vec::new();
+        image::DynamicImage::new_rgba8(4, 2)
+            .write_to(
+                &mut std::io::Cursor::new(&mut webp),
+                image::ImageFormat::WebP,
+            )
+            .unwrap();
+        webp
+    }
+
+    #[tokio::test]
+    async fn draws_a_lottie_sticker_as_webp_when_a_renderer_is_set_up() {
+        create_storage_dir();
+        let webp = make_webp();
+        let renderer = fake_renderer(&webp);
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/900000000000000006.json",
+                "application/json",
+                LOTTIE.as_bytes().to_vec(),
+            )]
+        })
+        .await;
+
+        let result = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000006.webp",
+            &test_file_cache(),
+            Some(&renderer),
+        )
+        .await;
+        let stored = fs::read(sticker_filepath("900000000000000006.webp"));
+        let _ = fs::remove_file(sticker_filepath("900000000000000006.webp"));
+        let _ = fs::remove_file(sticker_filepath("900000000000000006.json"));
+
+        let (bytes, content_type) = result.expect("the sticker should be drawn");
+        assert_eq!(
+            (bytes.to_vec(), content_type.as_str()),
+            (webp.clone(), "image/webp"),
+            "what the renderer drew should be served as WebP"
+        );
+        assert_eq!(
+            stored.ok(),
+            Some(webp),
+            "the drawn sticker should have been kept for next time"
+        );
+    }
+
+    #[tokio::test]
+    async fn draws_nothing_without_a_renderer_or_a_lottie_sticker() {
+        create_storage_dir();
+        let renderer = fake_renderer(&make_webp());
+        let base = spawn_test_server(|_base| {
+            vec![
+                (
+                    "/900000000000000007.json",
+                    "application/json",
+                    LOTTIE.as_bytes().to_vec(),
+                ),
+                (
+                    "/900000000000000008.json",
+                    "application/json",
+                    b"[]".to_vec(),
+                ),
+            ]
+        })
+        .await;
+
+        let without_renderer = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000007.webp",
+            &test_file_cache(),
+            None,
+        )
+        .await;
+        let not_lottie = sticker(
+            &test_outbound_client(),
+            &base,
+            "900000000000000008.webp",
+            &test_file_cache(),
+            Some(&renderer),
+        )
+        .await;
+        let _ = fs::remove_file(sticker_filepath("900000000000000008.json"));
+
+        assert_eq!(
+            without_renderer.map(|_| ()).map_err(|(status, _)| status),
+            Err(StatusCode::NOT_FOUND),
+            "nothing should be drawn when no renderer is set up"
+        );
+        assert_eq!(
+            not_lottie.map(|_| ()),
+            Err((
+                StatusCode::NOT_FOUND,
+                "Only Lottie stickers are drawn as WebP"
+            )),
+            "only a Lottie sticker should be handed to the renderer"
+        );
+    }
+
+    #[test]
+    fn reports_the_shape_of_a_sticker_that_is_not_square() {
+        let image_data = sticker_image_data("/file/discord-sticker/1.png", &make_png(320, 160))
+            .expect("a png should be readable");
+        assert_eq!(
+            (image_data.width, image_data.height),
+            (320, 160),
+            "the real width and height should be reported"
+        );
+    }
+
+    #[test]
+    fn reads_the_size_a_lottie_sticker_declares() {
+        let image_data = sticker_image_data("/file/discord-sticker/1.json", LOTTIE.as_bytes())
+            .expect("a lottie file should be recognised");
+        assert_eq!(
+            (
+                image_data.width,
+                image_data.height,
+                image_data.format.as_deref()
+            ),
+            (320, 160, Some("Lottie")),
+            "a lottie sticker should report its declared size"
+        );
+    }
+
+    #[test]
+    fn json_that_is_not_lottie_is_not_mistaken_for_it() {
+        for json in [r#"{"w":1,"h":1}"#, r#"{"layers":[],"w":0,"h":10}"#, "[]"] {
+            assert_eq!(
+                lottie_size(json.as_bytes()),
+                None,
+                "{json} is not a usable lottie animation"
+            );
+        }
+    }
+}
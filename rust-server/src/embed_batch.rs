@@ -0,0 +1,397 @@
+//! Makes the embeds for every link in a message in one request.
+//!
+//! The backend otherwise asks for each link's embed in turn, so a message
+//! with five links waits for five pages one after another. Here they are all
+//! fetched at once, with two limits: no more than a few pages at a time from
+//! any one site, so that a message full of links to one site does not look
+//! like an attack on it, and a deadline for the whole batch, so that one slow
+//! site holds up only its own embed.
+
+use crate::embed_cache::{self, EmbedCache, EmbedJson};
+use crate::outbound::OutboundClient;
+use crate::{
+    EmbedOptions, FetchError, build_and_cache_embed, json_response_with_headers,
+    response_with_headers,
+};
+use axum::{Extension, Json, http::StatusCode, response::Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+/// The most links one batch can ask about. Far more than a message ever has.
+pub const MAX_BATCH_URLS: usize = 25;
+
+/// How many pages from the same host are fetched at once.
+const MAX_FETCHES_PER_HOST: usize = 2;
+
+/// How long the whole batch may take. Each page is already given 15 seconds on
+/// its own, so this leaves a little over that for its image and icon.
+const BATCH_DEADLINE: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbedBatchRequest {
+    pub urls: Vec<String>,
+}
+
+/// What became of one of the links, in the same order the links were given.
+/// Exactly one of `embed` and `error` is present.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbedBatchResult {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embed: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub async fn embed_batch_endpoint(
+    Extension(client): Extension<OutboundClient>,
+    Extension(embed_cache): Extension<Arc<EmbedCache>>,
+    Extension(options): Extension<EmbedOptions>,
+    Json(EmbedBatchRequest { urls }): Json<EmbedBatchRequest>,
+) -> Response<String> {
+    if urls.len() > MAX_BATCH_URLS {
+        return response_with_headers(
+            StatusCode::BAD_REQUEST,
+            format!("At most {MAX_BATCH_URLS} urls can be embedded at once"),
+        );
+    }
+
+    let results = embed_batch(
+        &client,
+        &embed_cache,
+        options,
+        &urls,
+        Instant::now() + BATCH_DEADLINE,
+    )
+    .await;
+
+    json_response_with_headers(StatusCode::OK, serde_json::to_string(&results).unwrap())
+}
+
+/// Why a link in a batch has no embed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BatchError {
+    InvalidUrl,
+    Failed,
+    TooLarge,
+    Disallowed,
+    TimedOut,
+}
+
+impl BatchError {
+    const fn message(self) -> &'static str {
+        match self {
+            Self::InvalidUrl => "Invalid url",
+            Self::Failed => "Nothing could be embedded from this url",
+            Self::TooLarge => "Too large to embed",
+            Self::Disallowed => "The site asks not to be fetched",
+            Self::TimedOut => "Timed out",
+        }
+    }
+}
+
+impl From<FetchError> for BatchError {
+    fn from(error: FetchError) -> Self {
+        match error {
+            FetchError::Failed => Self::Failed,
+            FetchError::TooLarge => Self::TooLarge,
+            FetchError::Disallowed => Self::Disallowed,
+        }
+    }
+}
+
+async fn embed_batch(
+    client: &OutboundClient,
+    embed_cache: &EmbedCache,
+    options: EmbedOptions,
+    urls: &[String],
+    deadline: Instant,
+) -> Vec<EmbedBatchResult> {
+    let mut done: HashMap<String, Result<EmbedJson, BatchError>> = HashMap::new();
+    let mut to_fetch: Vec<(String, &str, Arc<Semaphore>)> = Vec::new();
+    let mut hosts: HashMap<String, Arc<Semaphore>> = HashMap::new();
+
+    // Links already embedded are answered straight away, and a link given
+    // twice, in whatever form, is only fetched once.
+    for url in urls {
+        let key = embed_cache::normalize_url(url);
+        if done.contains_key(&key) || to_fetch.iter().any(|(key2, _, _)| *key2 == key) {
+            continue;
+        }
+
+        let Some(host) = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url2| url2.host_str().map(str::to_ascii_lowercase))
+        else {
+            done.insert(key, Err(BatchError::InvalidUrl));
+            continue;
+        };
+
+        if let Some(cached) = embed_cache.get(url) {
+            done.insert(key, Ok(cached));
+        } else {
+            let semaphore = hosts
+                .entry(host)
+                .or_insert_with(|| Arc::new(Semaphore::new(MAX_FETCHES_PER_HOST)))
+                .clone();
+            to_fetch.push((key, url, semaphore));
+        }
+    }
+
+    let fetched = futures_util::future::join_all(to_fetch.into_iter().map(
+        |(key, url, semaphore)| async move {
+            let embed = tokio::time::timeout_at(deadline, async {
+                // The semaphore is never closed, so waiting on it cannot fail.
+                let _permit = semaphore.acquire().await;
+                build_and_cache_embed(client, embed_cache, options, url).await
+            })
+            .await;
+
+            match embed {
+                Ok(embed2) => (key, Ok(embed2)),
+                Err(_) => (key, Err(BatchError::TimedOut)),
+            }
+        },
+    ))
+    .await;
+    done.extend(fetched);
+
+    urls.iter()
+        .map(|url| {
+            let result = done
+                .get(&embed_cache::normalize_url(url))
+                .cloned()
+                .unwrap_or(Err(BatchError::Failed));
+
+            match result {
+                Ok(EmbedJson { error: None, json }) => EmbedBatchResult {
+                    url: url.clone(),
+                    embed: serde_json::from_str(&json).ok(),
+                    error: None,
+                },
+                Ok(EmbedJson {
+                    error: Some(error), ..
+                }) => batch_error(url, BatchError::from(error)),
+                Err(error) => batch_error(url, error),
+            }
+        })
+        .collect()
+}
+
+fn batch_error(url: &str, error: BatchError) -> EmbedBatchResult {
+    EmbedBatchResult {
+        url: url.to_owned(),
+        embed: None,
+        error: Some(error.message().to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spawn_test_server, test_outbound_client};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache() -> EmbedCache {
+        EmbedCache::new(Duration::from_secs(60), Duration::from_secs(60), 100)
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(10)
+    }
+
+    #[tokio::test]
+    async fn answers_for_each_url_in_order() {
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/page",
+                "text/html",
+                br#"<meta property="og:title" content="A page">"#.to_vec(),
+            )]
+        })
+        .await;
+        let embed_cache = cache();
+
+        let urls = vec![
+            String::from("http://127.0.0.1:1/nothing-listens-here"),
+            format!("{base}/page"),
+            String::from("not a url"),
+            format!("{base}/page#again"),
+        ];
+        let results = embed_batch(
+            &test_outbound_client(),
+            &embed_cache,
+            EmbedOptions::default(),
+            &urls,
+            deadline(),
+        )
+        .await;
+
+        let summary: Vec<(&str, Option<&str>, Option<&str>)> = results
+            .iter()
+            .map(|result| {
+                (
+                    result.url.as_str(),
+                    result
+                        .embed
+                        .as_ref()
+                        .and_then(|embed| embed["title"].as_str()),
+                    result.error.as_deref(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    urls[0].as_str(),
+                    None,
+                    Some("Nothing could be embedded from this url")
+                ),
+                (urls[1].as_str(), Some("A page"), None),
+                (urls[2].as_str(), None, Some("Invalid url")),
+                (urls[3].as_str(), Some("A page"), None),
+            ],
+            "each url should get its own result, in the order given"
+        );
+        assert_eq!(
+            embed_cache.stats().misses,
+            2,
+            "the same page given twice should only be looked up once"
+        );
+    }
+
+    // Stands in for a slow site, counting how many of its pages are being
+    // fetched at any moment and remembering the most there ever were.
+    async fn spawn_slow_server(delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let current = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let most2 = most.clone();
+
+        let router = axum::Router::new().route(
+            "/{page}",
+            axum::routing::get(move || {
+                let current = current.clone();
+                let most = most2.clone();
+                async move {
+                    let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(delay).await;
+                    current.fetch_sub(1, Ordering::SeqCst);
+                    (
+                        [("content-type", "text/html")],
+                        r#"<meta property="og:title" content="Slow">"#,
+                    )
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+
+        (base, most)
+    }
+
+    #[tokio::test]
+    async fn fetches_only_a_few_pages_from_one_host_at_a_time() {
+        let (base, most) = spawn_slow_server(Duration::from_millis(100)).await;
+        let urls: Vec<String> = (0..6).map(|page| format!("{base}/{page}")).collect();
+
+        let results = embed_batch(
+            &test_outbound_client(),
+            &cache(),
+            EmbedOptions::default(),
+            &urls,
+            deadline(),
+        )
+        .await;
+
+        assert!(
+            results.iter().all(|result| result.embed.is_some()),
+            "every page should still be embedded: {results:?}"
+        );
+        assert_eq!(
+            most.load(Ordering::SeqCst),
+            MAX_FETCHES_PER_HOST,
+            "pages from one host should be fetched a few at a time"
+        );
+    }
+
+    // A slow site should cost the batch only its own embed, and only up to the
+    // deadline.
+    #[tokio::test]
+    async fn gives_up_on_what_is_not_done_by_the_deadline() {
+        let (slow_base, _) = spawn_slow_server(Duration::from_secs(5)).await;
+        let fast_base = spawn_test_server(|_base| {
+            vec![(
+                "/",
+                "text/html",
+                br#"<meta property="og:title" content="Fast">"#.to_vec(),
+            )]
+        })
+        .await;
+        let embed_cache = cache();
+
+        let started = Instant::now();
+        let results = embed_batch(
+            &test_outbound_client(),
+            &embed_cache,
+            EmbedOptions::default(),
+            &[format!("{slow_base}/page"), format!("{fast_base}/")],
+            Instant::now() + Duration::from_millis(500),
+        )
+        .await;
+
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "the batch should end at the deadline"
+        );
+        assert_eq!(
+            results[0].error.as_deref(),
+            Some("Timed out"),
+            "the slow page should time out"
+        );
+        assert_eq!(
+            results[1]
+                .embed
+                .as_ref()
+                .map(|embed| embed["title"].clone()),
+            Some(serde_json::json!("Fast")),
+            "the fast page should not be held up"
+        );
+        assert_eq!(
+            embed_cache.stats().entries,
+            1,
+            "a page that timed out should not be remembered as failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_too_many_urls() {
+        let response = embed_batch_endpoint(
+            Extension(test_outbound_client()),
+            Extension(Arc::new(cache())),
+            Extension(EmbedOptions::default()),
+            Json(EmbedBatchRequest {
+                urls: vec![String::from("https://example.com/"); MAX_BATCH_URLS + 1],
+            }),
+        )
+        .await;
+
+        assert_eq!(
+            response.status(),
+            StatusCode::BAD_REQUEST,
+            "a batch over the limit should be refused"
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! Remembers the embeds already made for each link.
+//!
+//! Building an embed fetches the page, whatever it redirects to, its image,
+//! its icon and sometimes its oEmbed description and video as well. The same
+//! link gets asked about every time it is posted and whenever the backend
+//! needs its embed again, and the answer rarely changes from one minute to
+//! the next, so each one is kept for a while instead.
+//!
+//! Links that could not be embedded are remembered too, for less time, so a
+//! dead or slow site is not asked again on every request while it stays down.
+
+use crate::{FetchError, env_u64};
+use lru::LruCache;
+use serde::Serialize;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long an embed is kept unless the server is told otherwise.
+pub const DEFAULT_TTL_SECONDS: u64 = 60 * 60;
+
+/// How long a link that could not be embedded is left alone unless the server
+/// is told otherwise. Short, since the site may only have been down briefly.
+pub const DEFAULT_NEGATIVE_TTL_SECONDS: u64 = 5 * 60;
+
+/// How many links are remembered at once unless the server is told otherwise.
+/// An embed is a few kilobytes of JSON at most.
+pub const DEFAULT_MAX_ENTRIES: u64 = 10_000;
+
+/// Query parameters that only say where a link was shared from. Two links that
+/// differ only in these lead to the same page.
+const TRACKING_PARAMETERS: [&str; 4] = ["fbclid", "gclid", "igshid", "mc_eid"];
+
+/// Sites whose share buttons add an `si` parameter saying who shared the link.
+/// Elsewhere `si` is as likely as not to choose the page, so it is kept.
+const SHARE_ID_HOSTS: [&str; 6] = [
+    "youtube.com",
+    "www.youtube.com",
+    "m.youtube.com",
+    "music.youtube.com",
+    "youtu.be",
+    "open.spotify.com",
+];
+
+pub struct EmbedCache {
+    entries: Mutex<LruCache<String, CachedEmbed>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CachedEmbed {
+    embed: EmbedJson,
+    expires_at: Instant,
+}
+
+/// An embed as it is sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmbedJson {
+    /// The response as it was sent, so a hit costs nothing to serve.
+    pub json: String,
+    /// Why this is the empty embed sent for a link that could not be
+    /// embedded, if it is.
+    pub error: Option<FetchError>,
+}
+
+/// How well the cache is doing, for `/file/internal/embed-cache-stats`.
+#[derive(Debug, Serialize)]
+pub struct EmbedCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Hits as a share of every lookup, from 0 to 1, or `None` before the
+    /// first lookup.
+    pub hit_rate: Option<f64>,
+    pub entries: usize,
+    pub failed_entries: usize,
+    pub ttl_seconds: u64,
+    pub negative_ttl_seconds: u64,
+}
+
+impl EmbedCache {
+    pub fn new(ttl: Duration, negative_ttl: Duration, max_entries: u64) -> Self {
+        let capacity = usize::try_from(max_entries)
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            negative_ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads `EMBED_CACHE_TTL_SECONDS`, `EMBED_CACHE_NEGATIVE_TTL_SECONDS` and
+    /// `EMBED_CACHE_MAX_ENTRIES`, falling back to the defaults above.
+    pub fn from_env() -> Self {
+        Self::new(
+            Duration::from_secs(env_u64("EMBED_CACHE_TTL_SECONDS", DEFAULT_TTL_SECONDS)),
+            Duration::from_secs(env_u64(
+                "EMBED_CACHE_NEGATIVE_TTL_SECONDS",
+                DEFAULT_NEGATIVE_TTL_SECONDS,
+            )),
+            env_u64("EMBED_CACHE_MAX_ENTRIES", DEFAULT_MAX_ENTRIES),
+        )
+    }
+
+    /// The embed made for this link, if one was made recently enough.
+    pub fn get(&self, url: &str) -> Option<EmbedJson> {
+        let key = normalize_url(url);
+        let mut entries = self.entries.lock().unwrap();
+
+        let embed: Option<EmbedJson> = match entries.get(&key) {
+            Some(cached) if cached.expires_at > Instant::now() => Some(cached.embed.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        };
+
+        match embed {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        embed
+    }
+
+    /// Remembers the embed made for a link. An `error` says nothing useful came
+    /// of it, which is remembered for less time.
+    pub fn insert(&self, url: &str, json: String, error: Option<FetchError>) {
+        let ttl = if error.is_some() {
+            self.negative_ttl
+        } else {
+            self.ttl
+        };
+
+        self.entries.lock().unwrap().put(
+            normalize_url(url),
+            CachedEmbed {
+                embed: EmbedJson { json, error },
+                expires_at: Instant::now() + ttl,
+            },
+        );
+    }
+
+    /// Forgets a link, so that the next request for it builds its embed
+    /// afresh. Returns whether there was anything to forget.
+    pub fn purge(&self, url: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .pop(&normalize_url(url))
+            .is_some()
+    }
+
+    pub fn stats(&self) -> EmbedCacheStats {
+        let entries = self.entries.lock().unwrap();
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+
+        EmbedCacheStats {
+            hits,
+            misses,
+            hit_rate: match hits + misses {
+                0 => None,
+                lookups => Some(hits as f64 / lookups as f64),
+            },
+            entries: entries.len(),
+            failed_entries: entries
+                .iter()
+                .filter(|(_, cached)| cached.embed.error.is_some())
+                .count(),
+            ttl_seconds: self.ttl.as_secs(),
+            negative_ttl_seconds: self.negative_ttl.as_secs(),
+        }
+    }
+}
+
+/// The form of a link that two links leading to the same page share. The
+/// scheme and host are lowercased and a default port dropped by parsing, and
+/// the fragment and any tracking parameters are dropped here, since none of
+/// them change what the server sends back.
+pub fn normalize_url(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url.trim()) else {
+        return url.trim().to_owned();
+    };
+
+    parsed.set_fragment(None);
+
+    let strip_share_id = parsed
+        .host_str()
+        .is_some_and(|host| SHARE_ID_HOSTS.contains(&host));
+    let query: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(key, _)| {
+            !(key.starts_with("utm_")
+                || TRACKING_PARAMETERS.contains(&key.as_ref())
+                || (strip_share_id && key == "si"))
+        })
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+
+    if query.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    parsed.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> EmbedCache {
+        EmbedCache::new(Duration::from_secs(60), Duration::from_secs(60), 100)
+    }
+
+    #[test]
+    fn serves_an_embed_until_it_expires() {
+        let cache = cache();
+        cache.insert("https://example.com/", String::from("{}"), None);
+
+        assert_eq!(
+            cache.get("https://example.com/"),
+            Some(EmbedJson {
+                json: String::from("{}"),
+                error: None
+            }),
+            "a fresh embed should be served"
+        );
+
+        let expiring = EmbedCache::new(Duration::ZERO, Duration::from_secs(60), 100);
+        expiring.insert("https://example.com/", String::from("{}"), None);
+        assert!(
+            expiring.get("https://example.com/").is_none(),
+            "an expired embed should be made again"
+        );
+        assert_eq!(
+            expiring.stats().entries,
+            0,
+            "an expired embed should be dropped once found"
+        );
+    }
+
+    #[test]
+    fn keeps_failures_for_their_own_time() {
+        let cache = EmbedCache::new(Duration::from_secs(60), Duration::ZERO, 100);
+        cache.insert("https://up.example/", String::from("{}"), None);
+        cache.insert(
+            "https://down.example/",
+            String::from("{}"),
+            Some(FetchError::Failed),
+        );
+
+        assert!(
+            cache.get("https://up.example/").is_some(),
+            "a working link follows the normal ttl"
+        );
+        assert!(
+            cache.get("https://down.example/").is_none(),
+            "a failed link follows the negative ttl"
+        );
+    }
+
+    #[test]
+    fn the_same_page_is_the_same_entry() {
+        assert_eq!(
+            normalize_url("HTTPS://Example.com:443/a?utm_source=x&id=1&fbclid=y#top"),
+            "https://example.com/a?id=1",
+            "case, default port, fragment and tracking should not matter"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/a?utm_campaign=z"),
+            "https://example.com/a",
+            "a query of only tracking parameters should go entirely"
+        );
+        assert_ne!(
+            normalize_url("https://example.com/a?id=1"),
+            normalize_url("https://example.com/a?id=2"),
+            "other parameters choose the page and should be kept"
+        );
+    }
+
+    // `si` is only known to be a share id on the sites that add one, and on
+    // any other site it may well be part of what picks the page.
+    #[test]
+    fn share_ids_are_dropped_only_where_they_are_share_ids() {
+        assert_eq!(
+            normalize_url("https://youtu.be/abc?si=xyz&t=10"),
+            "https://youtu.be/abc?t=10",
+            "a YouTube share id should not matter"
+        );
+        assert_eq!(
+            normalize_url("https://open.spotify.com/track/abc?si=xyz"),
+            "https://open.spotify.com/track/abc",
+            "a Spotify share id should not matter"
+        );
+        assert_ne!(
+            normalize_url("https://example.com/search?si=1"),
+            normalize_url("https://example.com/search?si=2"),
+            "si on another site should be kept"
+        );
+    }
+
+    #[test]
+    fn purging_forgets_a_link() {
+        let cache = cache();
+        cache.insert("https://example.com/page", String::from("{}"), None);
+
+        assert!(
+            cache.purge("https://example.com/page#section"),
+            "purging any form of the link should find it"
+        );
+        assert!(
+            cache.get("https://example.com/page").is_none(),
+            "a purged link should be made again"
+        );
+        assert!(
+            !cache.purge("https://example.com/page"),
+            "there is nothing left to purge"
+        );
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = cache();
+        cache.get("https://example.com/");
+        cache.insert(
+            "https://example.com/",
+            String::from("{}"),
+            Some(FetchError::Failed),
+        );
+        cache.get("https://example.com/");
+        cache.get("https://example.com/");
+
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.hits, stats.misses),
+            (2, 1),
+            "one miss, then two hits"
+        );
+        assert_eq!(stats.hit_rate, Some(2.0 / 3.0), "two of three lookups hit");
+        assert_eq!(stats.failed_entries, 1, "the entry is a failure");
+    }
+
+    #[test]
+    fn holds_no_more_than_it_is_allowed() {
+        let cache = EmbedCache::new(Duration::from_secs(60), Duration::from_secs(60), 2);
+        for page in ["a", "b", "c"] {
+            cache.insert(
+                &format!("https://example.com/{page}"),
+                String::from("{}"),
+                None,
+            );
+        }
+
+        assert_eq!(cache.stats().entries, 2, "the oldest entry should go");
+        assert!(
+            cache.get("https://example.com/a").is_none(),
+            "the least recently used link is the one dropped"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Keeps copies of the images embeds show.
+//!
+//! An upload is only written to storage once the backend has said it may be,
+//! and from then on the backend knows it is there. An embed's image has nobody
+//! to ask, since it is fetched for whatever link anyone posts, so it is kept
+//! apart from the uploads, in a folder of its own that is held under
+//! `EMBED_IMAGES_BUDGET_BYTES` (1 GiB by default) by removing the oldest
+//! images whenever a new one takes it over.
+//!
+//! The images are still served at `/file/{content_type}/{hash}` and
+//! `/file/t/{hash}` like uploads are, since those urls are already what an
+//! embed points at. The file endpoints look here when storage has no upload by
+//! that hash.
+
+use crate::{ImageData, create_dir_if_missing, write_atomically};
+use image::metadata::Orientation;
+use image::{GenericImageView, ImageFormat};
+use std::fs;
+use std::time::SystemTime;
+
+const EMBED_IMAGES_PATH: &str = "./var/lib/atchat/storage/embed-images/";
+
+const DEFAULT_BUDGET_BYTES: u64 = 1024 * 1024 * 1024;
+
+fn image_filepath(hash: &str) -> String {
+    format!("{EMBED_IMAGES_PATH}{hash}")
+}
+
+fn thumbnail_filepath(hash: &str) -> String {
+    format!("{EMBED_IMAGES_PATH}{hash}_thumbnail")
+}
+
+/// Where the file with this hash is kept: the upload if there is one, and
+/// otherwise the embed image, if there is one of those.
+pub fn stored_filepath(hash: &str) -> String {
+    preferring_uploads(crate::filepath(hash), image_filepath(hash))
+}
+
+pub fn stored_thumbnail_filepath(hash: &str) -> String {
+    preferring_uploads(crate::thumbnail_filepath(hash), thumbnail_filepath(hash))
+}
+
+fn preferring_uploads(upload_path: String, embed_path: String) -> String {
+    if !fs::exists(&upload_path).unwrap_or(false) && fs::exists(&embed_path).unwrap_or(false) {
+        embed_path
+    } else {
+        upload_path
+    }
+}
+
+/// Keeps a copy of an embed's image, so it is served from `/file/...` like any
+/// other file. Returns the url of the image and of its thumbnail, if it is big
+/// enough to have one. An image that was uploaded already is pointed at as it
+/// is, rather than kept twice.
+///
+/// Writing the image and making its thumbnail are slow enough to hold up the
+/// thread, so this is meant to be called from one set aside for blocking work.
+pub fn store(
+    bytes: &[u8],
+    format: ImageFormat,
+    image: &image::DynamicImage,
+) -> Option<(String, Option<String>)> {
+    let content_type_index: usize = crate::content_types::CONTENT_TYPES
+        .iter()
+        .position(|content_type| *content_type == format.to_mime_type())?;
+    let hash = crate::hash_bytes(bytes);
+
+    let has_thumbnail: bool = if fs::exists(crate::filepath(&hash)).unwrap_or(false) {
+        fs::exists(crate::thumbnail_filepath(&hash)).unwrap_or(false)
+    } else if fs::exists(image_filepath(&hash)).unwrap_or(false) {
+        fs::exists(thumbnail_filepath(&hash)).unwrap_or(false)
+    } else {
+        create_dir_if_missing(EMBED_IMAGES_PATH.to_owned());
+        write_atomically(&image_filepath(&hash), bytes).ok()?;
+
+        let (width, height) = image.dimensions();
+        let metadata = crate::image_metadata(width, height, format, bytes.to_vec());
+        let orientation = metadata
+            .orientation
+            .and_then(Orientation::from_exif)
+            .unwrap_or(Orientation::NoTransforms);
+        let has_thumbnail2 = crate::save_thumbnail(
+            &thumbnail_filepath(&hash),
+            image,
+            metadata.image_size,
+            orientation,
+        );
+
+        remove_oldest(
+            EMBED_IMAGES_PATH,
+            crate::env_u64("EMBED_IMAGES_BUDGET_BYTES", DEFAULT_BUDGET_BYTES),
+        );
+        has_thumbnail2
+    };
+
+    Some((
+        format!("/file/{content_type_index}/{hash}"),
+        has_thumbnail.then(|| format!("/file/t/{hash}")),
+    ))
+}
+
+/// Removes the images in `dir` kept longest ago, along with their thumbnails,
+/// until those left fit in `budget_bytes`. A thumbnail counts towards the
+/// budget but is only ever removed with its image.
+fn remove_oldest(dir: &str, budget_bytes: u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut total: u64 = 0;
+    let mut images: Vec<(SystemTime, u64, String)> = Vec::new();
+    for entry in entries.flatten() {
+        let (Ok(name), Ok(metadata)) = (entry.file_name().into_string(), entry.metadata()) else {
+            continue;
+        };
+        total += metadata.len();
+        if !name.ends_with("_thumbnail")
+            && let Ok(modified) = metadata.modified()
+        {
+            images.push((modified, metadata.len(), name));
+        }
+    }
+    images.sort();
+
+    for (_, size, name) in images {
+        if total <= budget_bytes {
+            break;
+        }
+        if fs::remove_file(format!("{dir}{name}")).is_ok() {
+            total -= size;
+            let thumbnail = format!("{dir}{name}_thumbnail");
+            if let Ok(metadata) = fs::metadata(&thumbnail)
+                && fs::remove_file(&thumbnail).is_ok()
+            {
+                total -= metadata.len();
+            }
+        }
+    }
+}
+
+/// What an embed says about an image it keeps a copy of, pointing at the copy
+/// if it could be made and at the site if not.
+pub fn image_data(url: &str, bytes: &[u8]) -> Option<ImageData> {
+    let reader = image::ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?;
+    let format = reader.format()?;
+    let image = reader.decode().ok()?;
+    let (width, height) = image.dimensions();
+
+    // Linking to the site is still better than having no image at all.
+    let (stored_url, thumbnail_url) = match store(bytes, format, &image) {
+        Some(stored) => stored,
+        None => (url.to_owned(), None),
+    };
+
+    Some(ImageData {
+        url: stored_url,
+        width,
+        height,
+        format: crate::image_format_name(format),
+        thumbnail_url,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An image and its thumbnail go together, and the budget counts both.
+    #[test]
+    fn removes_the_oldest_images_with_their_thumbnails() {
+        let dir = format!(
+            "{}/atchat-embed-images-{}/",
+            std::env::temp_dir().display(),
+            rand::random::<u64>()
+        );
+        fs::create_dir_all(&dir).unwrap();
+
+        let old = SystemTime::now() - std::time::Duration::from_secs(60);
+        for (name, modified) in [
+            ("a", old),
+            ("a_thumbnail", SystemTime::now()),
+            ("b", SystemTime::now()),
+        ] {
+            let file = fs::File::create(format!("{dir}{name}")).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+
+        remove_oldest(&dir, 150);
+
+        let left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(
+            left,
+            vec!["b"],
+            "the oldest image should go, and its thumbnail with it"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,848 @@
+//! Reads more out of the sites people link to most than their Open Graph tags
+//! say.
+//!
+//! The tags of a video page give its title and a thumbnail but not the channel
+//! or how long the video is, and a GitHub link's tags do not say how many stars a
+//! repository has or whether an issue is still open. Each extractor here knows
+//! one site: which links are to it, and where on its pages the rest is found.
+//! Whatever it finds goes in [`SiteEmbed`], alongside the embed that is built
+//! from the tags as usual, and a link no extractor knows gets that embed alone.
+//!
+//! Everything is read from the page already fetched for the embed, so knowing
+//! a site costs no extra requests to it.
+
+use crate::link_tags;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use webpage::HTML;
+
+/// What a site-specific extractor found, on top of the usual embed.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "site", rename_all = "snake_case")]
+pub enum SiteEmbed {
+    YouTube {
+        video_id: String,
+        /// Where the video can be played in a frame, starting wherever the
+        /// link said to.
+        embed_url: String,
+        channel: Option<String>,
+        duration_seconds: Option<u64>,
+        views: Option<u64>,
+        /// Unix timestamp.
+        published_at: Option<i64>,
+        /// From the `t` parameter of the link.
+        start_seconds: Option<u64>,
+    },
+    GitHubRepository {
+        owner: String,
+        repository: String,
+        stars: Option<u64>,
+        forks: Option<u64>,
+        /// The language most of the code is in.
+        language: Option<String>,
+    },
+    GitHubIssue {
+        owner: String,
+        repository: String,
+        number: u64,
+        pull_request: bool,
+        state: Option<IssueState>,
+        author: Option<String>,
+    },
+    Wikipedia {
+        /// The language edition, as in the `en` of `en.wikipedia.org`.
+        language: String,
+        article: String,
+        /// The opening paragraph, since Wikipedia gives no description tag.
+        summary: Option<String>,
+    },
+    Mastodon {
+        author_name: Option<String>,
+        /// With the server, as in `@user@mastodon.social`.
+        author_handle: String,
+        content: Option<String>,
+        /// Unix timestamp.
+        published_at: Option<i64>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueState {
+    Open,
+    Closed,
+    Merged,
+    Draft,
+}
+
+impl SiteEmbed {
+    /// Something to use as the embed's description when the page has none.
+    pub fn description(&self) -> Option<&str> {
+        match self {
+            Self::Wikipedia { summary, .. } => summary.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// A fetched page, along with the link it was fetched for.
+pub struct Page<'a> {
+    /// The link as it was posted, or where it redirected to. This is what
+    /// decides which extractor gets the page, so it is always the site's own
+    /// address even when the page itself came from somewhere else.
+    pub url: &'a Url,
+    pub body: &'a str,
+    pub html: &'a HTML,
+}
+
+pub struct Extractor {
+    /// Whether a link is one this extractor is for. Only the url is looked
+    /// at, so a page that turns out not to be what its url suggests is left
+    /// to `extract` to turn down.
+    pub matches: fn(&Url) -> bool,
+    pub extract: fn(&Page<'_>) -> Option<SiteEmbed>,
+}
+
+/// Every site there is an extractor for, tried in this order.
+pub const EXTRACTORS: [Extractor; 4] = [
+    Extractor {
+        matches: |url| youtube_video_id(url).is_some(),
+        extract: youtube,
+    },
+    Extractor {
+        matches: |url| github_path(url).is_some(),
+        extract: github,
+    },
+    Extractor {
+        matches: |url| wikipedia_article(url).is_some(),
+        extract: wikipedia,
+    },
+    Extractor {
+        matches: |url| mastodon_status_path(url),
+        extract: mastodon,
+    },
+];
+
+/// What the first extractor for this link that recognises the page makes of
+/// it, or `None` to leave the page to the generic embed.
+pub fn extract(page: &Page<'_>) -> Option<SiteEmbed> {
+    EXTRACTORS
+        .iter()
+        .filter(|extractor| (extractor.matches)(page.url))
+        .find_map(|extractor| (extractor.extract)(page))
+}
+
+fn host(url: &Url) -> Option<&str> {
+    let host = url.host_str()?;
+    Some(host.strip_prefix("www.").unwrap_or(host))
+}
+
+fn path_segments(url: &Url) -> Vec<&str> {
+    url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+fn query_parameter(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// YouTube
+
+fn youtube_video_id(url: &Url) -> Option<String> {
+    let segments = path_segments(url);
+
+    let id: String = match (host(url)?, segments.as_slice()) {
+        ("youtu.be", [id])
+        | ("youtube.com" | "m.youtube.com", ["shorts" | "live" | "embed", id]) => (*id).to_owned(),
+        ("youtube.com" | "m.youtube.com" | "music.youtube.com", ["watch"]) => {
+            query_parameter(url, "v")?
+        }
+        _ => return None,
+    };
+
+    let is_valid_id = id.len() == 11
+        && id
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_');
+    is_valid_id.then_some(id)
+}
+
+fn youtube(page: &Page<'_>) -> Option<SiteEmbed> {
+    let video_id = youtube_video_id(page.url)?;
+    let start_seconds: Option<u64> = query_parameter(page.url, "t")
+        .or_else(|| query_parameter(page.url, "start"))
+        .and_then(|t| parse_duration(&t));
+
+    // The details are in microdata in the body, which `HTML` does not read.
+    let metas = tags(page.body, "meta");
+    let links = tags(page.body, "link");
+    let itemprop = |tags2: &[Tag], name: &str| {
+        tags2
+            .iter()
+            .find(|tag| tag.get("itemprop") == Some(name))
+            .and_then(|tag| tag.get("content").or_else(|| tag.get("href")))
+            .map(str::to_owned)
+    };
+
+    let embed_url = match start_seconds {
+        Some(start) => format!("https://www.youtube-nocookie.com/embed/{video_id}?start={start}"),
+        None => format!("https://www.youtube-nocookie.com/embed/{video_id}"),
+    };
+
+    Some(SiteEmbed::YouTube {
+        embed_url,
+        video_id,
+        // The video's own name is a `<meta>`, and its channel's is a `<link>`
+        // inside the author.
+        channel: itemprop(&links, "name"),
+        duration_seconds: itemprop(&metas, "duration").and_then(|text| parse_duration(&text)),
+        views: itemprop(&metas, "interactionCount")
+            .or_else(|| itemprop(&metas, "userInteractionCount"))
+            .and_then(|text| text.trim().parse().ok()),
+        published_at: itemprop(&metas, "uploadDate")
+            .or_else(|| itemprop(&metas, "datePublished"))
+            .and_then(|text| parse_timestamp(&text)),
+        start_seconds,
+    })
+}
+
+/// Seconds from the ways a length of time is written on a video page: `PT1H2M3S`
+/// in its microdata, and `90`, `90s` or `1m30s` in a link's `t` parameter.
+fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let text = match text.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("pt") => &text[2..],
+        _ => text,
+    };
+
+    if text.is_empty() {
+        return None;
+    }
+
+    if text.chars().all(|x| x.is_ascii_digit()) {
+        return text.parse().ok();
+    }
+
+    let mut seconds: u64 = 0;
+    let mut number: Option<u64> = None;
+    for x in text.chars() {
+        match (x.to_digit(10), x.to_ascii_lowercase()) {
+            (Some(digit), _) => {
+                number = Some(number.unwrap_or(0).checked_mul(10)? + u64::from(digit));
+            }
+            (None, unit @ ('h' | 'm' | 's')) => {
+                let scale: u64 = match unit {
+                    'h' => 60 * 60,
+                    'm' => 60,
+                    _ => 1,
+                };
+                seconds = seconds.checked_add(number.take()?.checked_mul(scale)?)?;
+            }
+            _ => return None,
+        }
+    }
+
+    // A number left without a unit is not something YouTube writes.
+    number.is_none().then_some(seconds)
+}
+
+// GitHub
+
+/// Paths on github.com that belong to GitHub rather than to a user.
+const GITHUB_RESERVED_OWNERS: [&str; 16] = [
+    "about",
+    "apps",
+    "collections",
+    "enterprise",
+    "explore",
+    "features",
+    "login",
+    "marketplace",
+    "notifications",
+    "orgs",
+    "pricing",
+    "search",
+    "settings",
+    "sponsors",
+    "topics",
+    "users",
+];
+
+enum GitHubPath {
+    Repository,
+    Issue(u64),
+    PullRequest(u64),
+}
+
+fn github_path(url: &Url) -> Option<(String, String, GitHubPath)> {
+    if host(url)? != "github.com" {
+        return None;
+    }
+
+    let segments = path_segments(url);
+    let (owner, repository, rest) = match segments.as_slice() {
+        [owner, repository, rest @ ..] => (*owner, *repository, rest),
+        _ => return None,
+    };
+
+    if GITHUB_RESERVED_OWNERS.contains(&owner) {
+        return None;
+    }
+
+    let path = match rest {
+        [] => GitHubPath::Repository,
+        ["issues", number] => GitHubPath::Issue(number.parse().ok()?),
+        ["pull", number, ..] => GitHubPath::PullRequest(number.parse().ok()?),
+        _ => return None,
+    };
+
+    Some((owner.to_owned(), repository.to_owned(), path))
+}
+
+fn github(page: &Page<'_>) -> Option<SiteEmbed> {
+    let (owner, repository, path) = github_path(page.url)?;
+
+    match path {
+        GitHubPath::Repository => {
+            let spans = tags(page.body, "span");
+            let counter = |id: &str| {
+                spans
+                    .iter()
+                    .find(|span| span.get("id") == Some(id))
+                    .and_then(|span| span.get("title"))
+                    .and_then(|title| title.replace(',', "").trim().parse().ok())
+            };
+
+            // The languages bar links each language to a search of the
+            // repository, and names it in bold inside the link.
+            let language: Option<String> = tags(page.body, "a")
+                .iter()
+                .find(|a| {
+                    a.get("href")
+                        .is_some_and(|href| href.contains("/search?l="))
+                })
+                .and_then(|a| {
+                    spans
+                        .iter()
+                        .find(|span| span.start > a.end && span.has_class("text-bold"))
+                })
+                .map(|span| text_until(page.body, span.end, "</span"))
+                .filter(|text| !text.is_empty());
+
+            Some(SiteEmbed::GitHubRepository {
+                owner,
+                repository,
+                stars: counter("repo-stars-counter-star"),
+                forks: counter("repo-network-counter"),
+                language,
+            })
+        }
+        GitHubPath::Issue(number) | GitHubPath::PullRequest(number) => {
+            let state: Option<IssueState> = tags(page.body, "span").iter().find_map(|span| {
+                match span
+                    .classes()
+                    .find_map(|class| class.strip_prefix("State--"))?
+                {
+                    "open" => Some(IssueState::Open),
+                    "closed" => Some(IssueState::Closed),
+                    "merged" => Some(IssueState::Merged),
+                    "draft" => Some(IssueState::Draft),
+                    _ => None,
+                }
+            });
+
+            let author: Option<String> = tags(page.body, "a")
+                .iter()
+                .find(|a| a.has_class("author"))
+                .map(|a| text_until(page.body, a.end, "</a"))
+                .filter(|text| !text.is_empty());
+
+            Some(SiteEmbed::GitHubIssue {
+                owner,
+                repository,
+                number,
+                pull_request: matches!(path, GitHubPath::PullRequest(_)),
+                state,
+                author,
+            })
+        }
+    }
+}
+
+// Wikipedia
+
+/// The longest summary given, in characters. The opening paragraph of a long
+/// article can run to several hundred words.
+const MAX_SUMMARY_CHARS: usize = 500;
+
+fn wikipedia_article(url: &Url) -> Option<(String, String)> {
+    let language = url
+        .host_str()?
+        .strip_suffix(".wikipedia.org")?
+        .trim_end_matches(".m");
+
+    let is_valid_language =
+        !language.is_empty() && language.chars().all(|x| x.is_ascii_lowercase() || x == '-');
+
+    match path_segments(url).as_slice() {
+        ["wiki", article] if is_valid_language => Some((
+            language.to_owned(),
+            percent_decode(article).replace('_', " "),
+        )),
+        _ => None,
+    }
+}
+
+fn wikipedia(page: &Page<'_>) -> Option<SiteEmbed> {
+    let (language, article) = wikipedia_article(page.url)?;
+
+    // Paragraphs before the article text, like the coordinates box, are outside
+    // the article's content and skipped by starting from it.
+    let content_start: usize = page.body.find("mw-parser-output").unwrap_or(0);
+    let summary: Option<String> = tags(&page.body[content_start..], "p")
+        .iter()
+        .filter(|p| !p.has_class("mw-empty-elt"))
+        .map(|p| text_until(&page.body[content_start..], p.end, "</p"))
+        .find(|text| !text.is_empty())
+        .map(|text| truncate(&text, MAX_SUMMARY_CHARS));
+
+    Some(SiteEmbed::Wikipedia {
+        language,
+        article,
+        summary,
+    })
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index: usize = 0;
+
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| text.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => {
+            // Cut at the last space that fits, so no word is left in half.
+            let cut = text[..end].rfind(' ').unwrap_or(end);
+            format!("{}…", text[..cut].trim_end())
+        }
+        None => text.to_owned(),
+    }
+}
+
+// Mastodon
+
+/// Mastodon runs on thousands of servers with nothing in common in their
+/// names, so a post is recognised by its path alone, as in `/@user/1234`, and
+/// the page is then checked for the tags Mastodon writes.
+fn mastodon_status_path(url: &Url) -> bool {
+    match path_segments(url).as_slice() {
+        [user, id] => user.starts_with('@') && is_status_id(id),
+        ["users", _, "statuses", id] => is_status_id(id),
+        _ => false,
+    }
+}
+
+fn is_status_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|x| x.is_ascii_digit())
+}
+
+fn mastodon(page: &Page<'_>) -> Option<SiteEmbed> {
+    let meta = |name: &str| {
+        page.html
+            .meta
+            .get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+
+    let username = meta("profile:username")?;
+
+    // The title is written as "Display Name (@user@server)".
+    let author_name: Option<String> = meta("og:title")
+        .and_then(|title| title.rsplit_once(" (@"))
+        .map(|(name, _)| name.trim().to_owned())
+        .filter(|name| !name.is_empty());
+
+    Some(SiteEmbed::Mastodon {
+        author_name,
+        author_handle: format!("@{}", username.trim_start_matches('@')),
+        content: meta("og:description").map(String::from),
+        published_at: meta("og:published_time").and_then(parse_timestamp),
+    })
+}
+
+fn parse_timestamp(text: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(text.trim())
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+// Reading the page
+
+/// An opening tag somewhere in a page.
+struct Tag {
+    attributes: Vec<(String, String)>,
+    /// Where the `<` that starts it is.
+    start: usize,
+    /// Just past the `>` that ends it, which is where what it contains starts.
+    end: usize,
+}
+
+impl Tag {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(name2, _)| name2 == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn classes(&self) -> impl Iterator<Item = &str> {
+        self.get("class")
+            .unwrap_or_default()
+            .split_ascii_whitespace()
+    }
+
+    fn has_class(&self, class: &str) -> bool {
+        self.classes().any(|class2| class2 == class)
+    }
+}
+
+/// Every `name` tag in the page, in order, outside of comments. The body is
+/// read as well as the head, unlike [`link_tags::link_tags`], since that is
+/// where the details these extractors want are kept.
+fn tags(html: &str, name: &str) -> Vec<Tag> {
+    let lowercase = html.to_ascii_lowercase();
+    let opening = format!("<{name}");
+    let mut tags: Vec<Tag> = Vec::new();
+    let mut position: usize = 0;
+
+    while let Some(offset) = lowercase[position..].find('<') {
+        let start = position + offset;
+        let rest = &lowercase[start..];
+
+        if rest.starts_with("<!--") {
+            match rest.find("-->") {
+                Some(end) => position = start + end + 3,
+                None => break,
+            }
+        } else if rest.starts_with(&opening)
+            && rest[opening.len()..]
+                .starts_with(|x: char| x.is_ascii_whitespace() || x == '/' || x == '>')
+        {
+            match link_tags::attributes(&html[start + opening.len()..]) {
+                Some((attributes, length)) => {
+                    let end = start + opening.len() + length;
+                    tags.push(Tag {
+                        attributes,
+                        start,
+                        end,
+                    });
+                    position = end;
+                }
+                None => break,
+            }
+        } else {
+            position = start + 1;
+        }
+    }
+
+    tags
+}
+
+/// The text from `start` up to the first `closing` tag after it, as it would
+/// read on the page: tags taken out, references decoded and runs of spaces
+/// made one. Footnote markers and inline styles are dropped along with their
+/// tags, since they are not part of the sentence they sit in.
+fn text_until(html: &str, start: usize, closing: &str) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let end = lowercase[start..]
+        .find(closing)
+        .map_or(html.len(), |end2| start + end2);
+
+    let mut text = String::new();
+    let mut position = start;
+
+    while let Some(offset) = lowercase[position..end].find('<') {
+        text.push_str(&html[position..position + offset]);
+        let tag_start = position + offset;
+
+        let skipped_until = ["sup", "style"].iter().find_map(|name| {
+            let rest = &lowercase[tag_start..end];
+            let is_tag = rest.starts_with(&format!("<{name}"))
+                && rest[name.len() + 1..].starts_with([' ', '>']);
+            is_tag.then(|| {
+                rest.find(&format!("</{name}>"))
+                    .map(|close| close + name.len() + 3)
+            })
+        });
+
+        position = match skipped_until {
+            Some(Some(length)) => tag_start + length,
+            Some(None) => end,
+            None => lowercase[tag_start..end]
+                .find('>')
+                .map_or(end, |close| tag_start + close + 1),
+        };
+    }
+    text.push_str(&html[position..end]);
+
+    link_tags::decode_entities(&text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spawn_test_server, test_outbound_client};
+    use crate::{FetchedContent, fetch_content, parse_html_safe};
+
+    fn url(text: &str) -> Url {
+        Url::parse(text).unwrap()
+    }
+
+    /// Serves a saved page the way a site would, fetches it the way an embed
+    /// does, and hands it to the extractors as though it came from `link`.
+    async fn extract_fixture(name: &str, link: &str) -> Option<SiteEmbed> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/samples/embeds")
+            .join(name);
+        let fixture: Vec<u8> =
+            std::fs::read(&path).unwrap_or_else(|error| panic!("could not read {path:?}: {error}"));
+        let base =
+            spawn_test_server(move |_base| vec![("/", "text/html; charset=utf-8", fixture)]).await;
+
+        let Ok(FetchedContent::Html {
+            url: page_url,
+            text,
+        }) = fetch_content(&test_outbound_client(), &format!("{base}/")).await
+        else {
+            panic!("the fixture should be served as a page");
+        };
+        let html = parse_html_safe(text.clone(), page_url).expect("the fixture should parse");
+
+        extract(&Page {
+            url: &url(link),
+            body: &text,
+            html: &html,
+        })
+    }
+
+    #[test]
+    fn recognises_links_to_each_site() {
+        // In the order of `EXTRACTORS`.
+        let names = ["youtube", "github", "wikipedia", "mastodon"];
+        let name = |link: &str| {
+            EXTRACTORS
+                .iter()
+                .position(|extractor| (extractor.matches)(&url(link)))
+                .map(|index| names[index])
+        };
+
+        for (link, expected) in [
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+                Some("youtube"),
+            ),
+            ("https://youtu.be/dQw4w9WgXcQ?t=42", Some("youtube")),
+            ("https://m.youtube.com/shorts/dQw4w9WgXcQ", Some("youtube")),
+            ("https://www.youtube.com/watch?v=tooshort", None),
+            ("https://github.com/rust-lang/rust", Some("github")),
+            ("https://github.com/rust-lang/rust/issues/1", Some("github")),
+            (
+                "https://github.com/rust-lang/rust/pull/2/files",
+                Some("github"),
+            ),
+            (
+                "https://github.com/rust-lang/rust/blob/main/README.md",
+                None,
+            ),
+            ("https://github.com/settings/profile", None),
+            ("https://en.wikipedia.org/wiki/Rust", Some("wikipedia")),
+            ("https://de.m.wikipedia.org/wiki/Rust", Some("wikipedia")),
+            ("https://en.wikipedia.org/w/index.php?title=Rust", None),
+            ("https://mastodon.social/@Gargron/1", Some("mastodon")),
+            ("https://example.com/users/a/statuses/2", Some("mastodon")),
+            ("https://example.com/@user/about", None),
+            ("https://example.com/", None),
+        ] {
+            assert_eq!(name(link), expected, "{link} should go to {expected:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_a_youtube_video() {
+        assert_eq!(
+            extract_fixture("youtube_watch.html", "https://youtu.be/dQw4w9WgXcQ?t=1m30s").await,
+            Some(SiteEmbed::YouTube {
+                video_id: String::from("dQw4w9WgXcQ"),
+                embed_url: String::from(
+                    "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?start=90"
+                ),
+                channel: Some(String::from("Rick Astley")),
+                duration_seconds: Some(213),
+                views: Some(1_234_567_890),
+                published_at: Some(1_256_453_853),
+                start_seconds: Some(90),
+            }),
+            "the channel, length, views and start should all be found"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_github_repository() {
+        assert_eq!(
+            extract_fixture(
+                "github_repository.html",
+                "https://github.com/rust-lang/rust"
+            )
+            .await,
+            Some(SiteEmbed::GitHubRepository {
+                owner: String::from("rust-lang"),
+                repository: String::from("rust"),
+                stars: Some(98_765),
+                forks: Some(12_345),
+                language: Some(String::from("Rust")),
+            }),
+            "stars, forks and the main language should be found"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_github_issues_and_pull_requests() {
+        assert_eq!(
+            extract_fixture(
+                "github_issue.html",
+                "https://github.com/rust-lang/rust/issues/12345"
+            )
+            .await,
+            Some(SiteEmbed::GitHubIssue {
+                owner: String::from("rust-lang"),
+                repository: String::from("rust"),
+                number: 12345,
+                pull_request: false,
+                state: Some(IssueState::Closed),
+                author: Some(String::from("octocat")),
+            }),
+            "the issue's state and author should be found"
+        );
+        assert_eq!(
+            extract_fixture(
+                "github_pull_request.html",
+                "https://github.com/rust-lang/rust/pull/67890"
+            )
+            .await,
+            Some(SiteEmbed::GitHubIssue {
+                owner: String::from("rust-lang"),
+                repository: String::from("rust"),
+                number: 67890,
+                pull_request: true,
+                state: Some(IssueState::Merged),
+                author: Some(String::from("ferris")),
+            }),
+            "a merged pull request should say so"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_wikipedia_article() {
+        assert_eq!(
+            extract_fixture(
+                "wikipedia_article.html",
+                "https://en.wikipedia.org/wiki/Rust_(programming_language)"
+            )
+            .await,
+            Some(SiteEmbed::Wikipedia {
+                language: String::from("en"),
+                article: String::from("Rust (programming language)"),
+                summary: Some(String::from(
+                    "Rust is a general-purpose programming language emphasizing \
+                     performance, type safety, and concurrency. It enforces memory \
+                     safety without a garbage collector."
+                )),
+            }),
+            "the first real paragraph should be the summary, without footnotes"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_mastodon_post() {
+        assert_eq!(
+            extract_fixture(
+                "mastodon_status.html",
+                "https://mastodon.example/@ferris/109876543210"
+            )
+            .await,
+            Some(SiteEmbed::Mastodon {
+                author_name: Some(String::from("Ferris the Crab")),
+                author_handle: String::from("@ferris@mastodon.example"),
+                content: Some(String::from("Just shipped a new release! 🦀")),
+                published_at: Some(1_700_000_000),
+            }),
+            "the author and post should be found"
+        );
+    }
+
+    // A path that looks like a post on a site that is not Mastodon should get
+    // the generic embed rather than an empty Mastodon one.
+    #[tokio::test]
+    async fn leaves_other_pages_to_the_generic_embed() {
+        assert_eq!(
+            extract_fixture(
+                "wikipedia_article.html",
+                "https://blog.example/@someone/2024"
+            )
+            .await,
+            None,
+            "a page without Mastodon's tags is not a Mastodon post"
+        );
+    }
+
+    #[test]
+    fn reads_lengths_of_time() {
+        for (text, expected) in [
+            ("PT3M33S", Some(213)),
+            ("PT1H0M1S", Some(3601)),
+            ("90", Some(90)),
+            ("1m30s", Some(90)),
+            ("2h", Some(7200)),
+            ("1m30", None),
+            ("soon", None),
+            ("", None),
+        ] {
+            assert_eq!(
+                parse_duration(text),
+                expected,
+                "{text} should be {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn shortens_long_summaries_between_words() {
+        assert_eq!(truncate("one two three", 9), "one two…", "cut at a space");
+        assert_eq!(truncate("short", 9), "short", "short text is left alone");
+    }
+}
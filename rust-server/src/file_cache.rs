@@ -0,0 +1,236 @@
+//! Keeps the most requested stored files in memory.
+//!
+//! Avatars, guild icons and stickers are asked for on every page load, and
+//! without this each of those requests reads the same few kilobytes off disk
+//! again. Stored files never change, so a copy held in memory can never go
+//! stale, and the only question is which files are worth the space.
+//!
+//! The answer is whichever were asked for most recently, within a fixed budget
+//! of bytes. Large files are never held at all: a single video would push out
+//! hundreds of avatars, and a file that size spends far longer on the wire than
+//! it ever spent being read.
+
+use crate::env_u64;
+use axum::body::Bytes;
+use lru::LruCache;
+use serde::Serialize;
+use std::fs;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How much memory the cache may hold unless the server is told otherwise.
+pub const DEFAULT_BUDGET_BYTES: u64 = 128 * 1024 * 1024;
+
+/// Files bigger than this are read from disk every time unless the server is
+/// told otherwise. Comfortably above any avatar, icon or sticker.
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 2 * 1024 * 1024;
+
+pub struct FileCache {
+    files: Mutex<CachedFiles>,
+    budget_bytes: u64,
+    max_file_bytes: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypasses: AtomicU64,
+}
+
+struct CachedFiles {
+    /// Keyed by path, so a thumbnail or a compressed copy is cached in its own
+    /// right alongside the file it was made from.
+    files: LruCache<String, Bytes>,
+    /// The total size of everything in `files`.
+    bytes: u64,
+}
+
+/// How well the cache is doing, for `/file/internal/file-cache-stats`.
+#[derive(Debug, Serialize)]
+pub struct FileCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Reads of files too large to be cached at all. Counted apart from misses
+    /// so that they do not make the cache look worse than it is.
+    pub bypasses: u64,
+    pub files: usize,
+    pub bytes: u64,
+    pub budget_bytes: u64,
+    pub max_file_bytes: u64,
+}
+
+impl FileCache {
+    /// Reads `FILE_CACHE_BUDGET_BYTES` and `FILE_CACHE_MAX_FILE_BYTES`, falling
+    /// back to the defaults above.
+    pub fn from_env() -> Self {
+        Self::new(
+            env_u64("FILE_CACHE_BUDGET_BYTES", DEFAULT_BUDGET_BYTES),
+            env_u64("FILE_CACHE_MAX_FILE_BYTES", DEFAULT_MAX_FILE_BYTES),
+        )
+    }
+
+    pub fn new(budget_bytes: u64, max_file_bytes: u64) -> Self {
+        Self {
+            files: Mutex::new(CachedFiles {
+                files: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            budget_bytes,
+            max_file_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypasses: AtomicU64::new(0),
+        }
+    }
+
+    /// The contents of the stored file at `path`, from memory if it is there and
+    /// from disk otherwise. `size` is the size the file's metadata gives, which
+    /// is how a file too large to cache is recognised before it is read.
+    pub fn read(&self, path: &str, size: u64) -> std::io::Result<Bytes> {
+        if size > self.max_file_bytes || size > self.budget_bytes {
+            self.bypasses.fetch_add(1, Ordering::Relaxed);
+            return fs::read(path).map(Bytes::from);
+        }
+
+        let cached: Option<Bytes> = self.files.lock().unwrap().files.get(path).cloned();
+
+        if let Some(bytes) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(bytes);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Read without the lock held, so a slow disk holds up this request and
+        // not every other one.
+        let bytes = Bytes::from(fs::read(path)?);
+        self.insert(path, bytes.clone());
+        Ok(bytes)
+    }
+
+    /// Adds a file, then drops whatever has gone longest unused until
+    /// everything fits in the budget again.
+    fn insert(&self, path: &str, bytes: Bytes) {
+        let mut cached = self.files.lock().unwrap();
+
+        let size = bytes.len() as u64;
+        if let Some(previous) = cached.files.put(path.to_owned(), bytes) {
+            cached.bytes -= previous.len() as u64;
+        }
+        cached.bytes += size;
+
+        while cached.bytes > self.budget_bytes {
+            match cached.files.pop_lru() {
+                Some((_, evicted)) => cached.bytes -= evicted.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    pub fn stats(&self) -> FileCacheStats {
+        let cached = self.files.lock().unwrap();
+
+        FileCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypasses: self.bypasses.load(Ordering::Relaxed),
+            files: cached.files.len(),
+            bytes: cached.bytes,
+            budget_bytes: self.budget_bytes,
+            max_file_bytes: self.max_file_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a file of the given size somewhere only this test looks, and return
+    // its path.
+    fn stored_file(name: &str, size: usize) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("file-cache-test-{name}"))
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&path, vec![b'x'; size]).expect("failed to write the test file");
+        path
+    }
+
+    #[test]
+    fn serves_a_file_from_memory_the_second_time() {
+        let cache = FileCache::new(1024, 1024);
+        let path = stored_file("second-time", 100);
+
+        let first = cache.read(&path, 100).expect("failed to read from disk");
+        // Gone from disk, so only the cache can answer now.
+        fs::remove_file(&path).expect("failed to remove the test file");
+        let second = cache.read(&path, 100).expect("should be read from memory");
+
+        assert_eq!(first, second, "the cached copy should be the file");
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.hits, stats.misses),
+            (1, 1),
+            "the first read misses and the second hits"
+        );
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_file_to_stay_in_budget() {
+        let cache = FileCache::new(250, 250);
+        let a = stored_file("lru-a", 100);
+        let b = stored_file("lru-b", 100);
+        let c = stored_file("lru-c", 100);
+
+        cache.read(&a, 100).expect("failed to read a");
+        cache.read(&b, 100).expect("failed to read b");
+        // Using a again leaves b as the one that has waited longest.
+        cache.read(&a, 100).expect("failed to read a");
+        cache.read(&c, 100).expect("failed to read c");
+
+        let cached = cache.files.lock().unwrap();
+        assert!(
+            cached.files.contains(&a),
+            "a was used recently and should stay"
+        );
+        assert!(
+            !cached.files.contains(&b),
+            "b was used least recently and should go"
+        );
+        assert!(cached.files.contains(&c), "c was just read and should stay");
+        assert_eq!(
+            cached.bytes, 200,
+            "the running total should match what is held"
+        );
+
+        for path in [a, b, c] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn reads_large_files_from_disk_every_time() {
+        let cache = FileCache::new(1024 * 1024, 1000);
+        let path = stored_file("large", 2000);
+
+        cache.read(&path, 2000).expect("failed to read");
+        cache.read(&path, 2000).expect("failed to read");
+        let _ = fs::remove_file(&path);
+
+        let stats = cache.stats();
+        assert_eq!(stats.bypasses, 2, "both reads should go to disk");
+        assert_eq!(
+            (stats.hits, stats.misses),
+            (0, 0),
+            "a file too large to cache is neither a hit nor a miss"
+        );
+        assert_eq!(stats.files, 0, "a file over the limit should never be held");
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error_and_is_not_cached() {
+        let cache = FileCache::new(1024, 1024);
+        let path = stored_file("missing", 10);
+        let _ = fs::remove_file(&path);
+
+        assert!(cache.read(&path, 10).is_err(), "there is nothing to read");
+        assert_eq!(cache.stats().files, 0, "nothing should have been cached");
+    }
+}
@@ -0,0 +1,266 @@
+//! Reads the `<link>` tags out of the head of a page.
+//!
+//! `webpage` keeps the canonical url and feeds from these and drops the rest,
+//! but a site's icon and its oEmbed endpoint are only ever given as `<link>`
+//! tags too. Rather than parse the page a second time to find them, they are
+//! picked out of the text directly. A `<link>` tag is nothing but attributes and
+//! never has anything inside it, so there is no tree to build to read one, and
+//! a page nested deep enough to trouble the parser costs nothing extra here.
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LinkTag {
+    /// Each of the space separated values in `rel`, in lowercase.
+    pub rel: Vec<String>,
+    /// As written, so relative to the page if it is relative at all.
+    pub href: String,
+    pub link_type: Option<String>,
+}
+
+impl LinkTag {
+    pub fn has_rel(&self, rel: &str) -> bool {
+        self.rel.iter().any(|value| value == rel)
+    }
+}
+
+/// Every `<link>` tag with an `href`, in the order they appear. Looking stops
+/// at the end of the head, since the body is far larger and never has any.
+pub fn link_tags(html: &str) -> Vec<LinkTag> {
+    // Lowercasing only ASCII leaves every byte where it was, so a position
+    // found in one is the same position in the other.
+    let lowercase = html.to_ascii_lowercase();
+    let mut tags: Vec<LinkTag> = Vec::new();
+    let mut position: usize = 0;
+
+    while let Some(offset) = lowercase[position..].find('<') {
+        let start = position + offset;
+        let rest = &lowercase[start..];
+
+        if rest.starts_with("<!--") {
+            match rest.find("-->") {
+                Some(end) => position = start + end + 3,
+                None => break,
+            }
+        } else if rest.starts_with("</head") || rest.starts_with("<body") {
+            break;
+        } else if rest.starts_with("<link")
+            && rest[5..].starts_with(|x: char| x.is_ascii_whitespace() || x == '/' || x == '>')
+        {
+            match attributes(&html[start + 5..]) {
+                Some((attributes2, length)) => {
+                    position = start + 5 + length;
+                    tags.extend(link_tag(attributes2));
+                }
+                None => break,
+            }
+        } else {
+            position = start + 1;
+        }
+    }
+
+    tags
+}
+
+fn link_tag(attributes: Vec<(String, String)>) -> Option<LinkTag> {
+    let mut rel: Vec<String> = Vec::new();
+    let mut href: Option<String> = None;
+    let mut link_type: Option<String> = None;
+
+    for (name, value) in attributes {
+        match name.as_str() {
+            "rel" => {
+                rel = value
+                    .split_ascii_whitespace()
+                    .map(str::to_ascii_lowercase)
+                    .collect();
+            }
+            "href" => href = Some(value.trim().to_owned()),
+            "type" => link_type = Some(value.trim().to_ascii_lowercase()),
+            _ => {}
+        }
+    }
+
+    Some(LinkTag {
+        rel,
+        href: href.filter(|href2| !href2.is_empty())?,
+        link_type,
+    })
+}
+
+/// The attributes of a tag, read from just after its name, along with how many
+/// bytes it took to reach the `>` that ends it. A tag the page ends in the
+/// middle of is no tag at all.
+pub fn attributes(text: &str) -> Option<(Vec<(String, String)>, usize)> {
+    let mut attributes: Vec<(String, String)> = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    loop {
+        while chars
+            .next_if(|(_, x)| x.is_ascii_whitespace() || *x == '/')
+            .is_some()
+        {}
+
+        match chars.peek() {
+            Some(&(end, '>')) => return Some((attributes, end + 1)),
+            Some(_) => {}
+            None => return None,
+        }
+
+        let mut name = String::new();
+        while let Some((_, x)) =
+            chars.next_if(|(_, x)| !x.is_ascii_whitespace() && !"=>/".contains(*x))
+        {
+            name.push(x.to_ascii_lowercase());
+        }
+
+        while chars.next_if(|(_, x)| x.is_ascii_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if(|(_, x)| *x == '=').is_some() {
+            while chars.next_if(|(_, x)| x.is_ascii_whitespace()).is_some() {}
+
+            match chars.peek() {
+                Some(&(_, quote @ ('"' | '\''))) => {
+                    chars.next();
+                    for (_, x) in chars.by_ref() {
+                        if x == quote {
+                            break;
+                        }
+                        value.push(x);
+                    }
+                }
+                _ => {
+                    while let Some((_, x)) =
+                        chars.next_if(|(_, x)| !x.is_ascii_whitespace() && *x != '>')
+                    {
+                        value.push(x);
+                    }
+                }
+            }
+        }
+
+        // An `=` with no name before it is not an attribute, and is skipped
+        // along with whatever value followed it.
+        if !name.is_empty() {
+            attributes.push((name, decode_entities(&value)));
+        }
+    }
+}
+
+/// Turns the character references an attribute value can contain back into
+/// the characters they stand for. Urls are where this matters, since any `&`
+/// in one is meant to be written `&amp;`.
+pub fn decode_entities(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| Some((entity_char(&rest[1..=end])?, end + 2)));
+
+        // An `&` that starts no reference it knows of is left as it is.
+        let (x, length) = entity.unwrap_or(('&', 1));
+        decoded.push(x);
+        rest = &rest[length..];
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+fn entity_char(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse::<u32>().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_links_however_they_are_written() {
+        let tags = link_tags(
+            r#"<!DOCTYPE html><html><HEAD>
+            <LINK REL="Shortcut Icon" HREF="/favicon.png">
+            <link rel=apple-touch-icon href=/touch.png />
+            <link href='/oembed?url=a&amp;format=json' type="application/json+oembed" rel="alternate">
+            <link rel="stylesheet">
+            </head><body><link rel="icon" href="/in-body.png"></body></html>"#,
+        );
+
+        assert_eq!(
+            tags,
+            vec![
+                LinkTag {
+                    rel: vec![String::from("shortcut"), String::from("icon")],
+                    href: String::from("/favicon.png"),
+                    link_type: None,
+                },
+                LinkTag {
+                    rel: vec![String::from("apple-touch-icon")],
+                    href: String::from("/touch.png"),
+                    link_type: None,
+                },
+                LinkTag {
+                    rel: vec![String::from("alternate")],
+                    href: String::from("/oembed?url=a&format=json"),
+                    link_type: Some(String::from("application/json+oembed")),
+                },
+            ],
+            "every link with an href in the head should be read, and nothing after it"
+        );
+    }
+
+    #[test]
+    fn ignores_links_that_are_commented_out_or_not_links() {
+        let tags = link_tags(
+            r#"<head><!-- <link rel="icon" href="/old.png"> -->
+            <linkage rel="icon" href="/not-a-link.png">
+            <link rel="icon" href="/new.png"></head>"#,
+        );
+
+        assert_eq!(
+            tags.iter().map(|tag| tag.href.as_str()).collect::<Vec<_>>(),
+            vec!["/new.png"],
+            "only the real link should be read"
+        );
+    }
+
+    #[test]
+    fn decodes_character_references() {
+        assert_eq!(
+            decode_entities("a&amp;b&#38;c&#x26;d &unknown; e & f&lt;"),
+            "a&b&c&d &unknown; e & f<",
+            "known references should be decoded and anything else left alone"
+        );
+    }
+
+    #[test]
+    fn survives_a_page_that_ends_mid_tag() {
+        assert!(
+            link_tags(r#"<head><link rel="icon" href="/a.png"#).is_empty(),
+            "a tag the page ends in the middle of should be dropped"
+        );
+        assert!(
+            link_tags("<head><!-- never closed <link rel=icon href=/a.png>").is_empty(),
+            "an unclosed comment hides everything after it"
+        );
+    }
+}
@@ -1,10 +1,10 @@
 use axum::body::Body;
 use axum::response::Response;
-use axum::{Json, RequestExt};
+use axum::{Extension, Json, RequestExt};
 use axum::{
     Router,
     body::Bytes,
-    extract::{DefaultBodyLimit, Path, Request, State},
+    extract::{DefaultBodyLimit, Path, Query, Request, State},
     http::{StatusCode, Uri},
     middleware::Next,
     routing::get,
@@ -12,48 +12,88 @@ use axum::{
 };
 
 use chrono;
-use http::HeaderMap;
+use embed_cache::{EmbedCache, EmbedJson};
+use extractors::SiteEmbed;
+use file_cache::FileCache;
+use http::{HeaderMap, Method};
 use image::metadata::Orientation;
 use image::{self, GenericImageView, ImageFormat, ImageReader};
+use link_tags::LinkTag;
+use outbound::OutboundClient;
 use serde::{Deserialize, Serialize};
+use server_secret::ServerSecret;
 use sha2::{Digest, Sha224};
 use std::fs;
 use std::str::FromStr;
 use web_push::SubscriptionInfo;
 use webpage::HTML;
+mod backup_chunks;
+mod backup_encryption;
+mod backup_replication;
+mod backup_retention;
+mod backups;
+mod byte_range;
+mod charset;
+mod compression;
 mod content_types;
+mod discord_cdn;
+mod discord_sticker;
+mod embed_batch;
+mod embed_cache;
+mod embed_images;
+mod extractors;
+mod file_cache;
+mod link_tags;
+mod outbound;
+mod robots;
+mod server_secret;
 mod video;
 mod websocket;
-use rand::RngExt;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 use std::time::SystemTime;
-use subtle::ConstantTimeEq;
 #[tokio::main]
 async fn main() {
     // secret.txt should match Env.secretKey
-    match fs::read_to_string(SERVER_SECRET_PATH.to_string()) {
-        Ok(secret_key) => {
-            let state = Arc::new(Mutex::new(AppState {
-                secret_key: secret_key.trim().as_bytes().to_vec(),
-            }));
+    match ServerSecret::load() {
+        Ok(secret) => {
+            let state = Arc::new(Mutex::new(AppState { secret }));
 
             let rooms = websocket::rooms();
 
+            let file_cache = Arc::new(FileCache::from_env());
+
+            let outbound_client = match OutboundClient::from_env() {
+                Ok(client) => client,
+                Err(error) => {
+                    println!("Server didn't start, the outbound client failed to build: {error}");
+                    return;
+                }
+            };
+
+            let embed_cache = Arc::new(EmbedCache::from_env());
+            let embed_options = EmbedOptions::from_env();
+
             let app = Router::new()
                 .route(
                     "/file/internal/embed",
                     post(post_embed).options(options_endpoint),
                 )
                 .route(
-                    "/file/internal/upload-backup/{filename}",
-                    post(post_backup_endpoint).options(options_endpoint),
+                    "/file/internal/embed-batch",
+                    post(embed_batch::embed_batch_endpoint).options(options_endpoint),
+                )
+                .route(
+                    "/file/internal/embed-cache/purge",
+                    post(purge_embed_endpoint).options(options_endpoint),
                 )
                 .route(
-                    "/file/internal/regenerate-server-secret",
-                    post(regenerate_server_secret_endpoint).options(options_endpoint),
+                    "/file/internal/embed-cache-stats",
+                    get(embed_cache_stats_endpoint),
                 )
+                .merge(backups::routes())
+                .merge(server_secret::routes())
                 .route(
                     "/file/upload",
                     post(upload_endpoint).options(options_endpoint),
@@ -72,15 +112,31 @@ async fn main() {
                 )
                 .route(
                     "/file/discord-sticker/{sticker_id}",
-                    get(discord_sticker_endpoint).options(options_endpoint),
+                    get(discord_sticker::discord_sticker_endpoint).options(options_endpoint),
+                )
+                .route(
+                    "/file/discord-sticker/{sticker_id}/info",
+                    get(discord_sticker::discord_sticker_info_endpoint).options(options_endpoint),
+                )
+                .route(
+                    "/file/discord-cdn/{host}/{*path}",
+                    get(discord_cdn::discord_cdn_endpoint).options(options_endpoint),
                 )
                 .route("/file/internal/vapid", get(vapid_endpoint))
+                .route(
+                    "/file/internal/file-cache-stats",
+                    get(file_cache_stats_endpoint),
+                )
                 .route("/file/websocket", get(websocket::websocket_endpoint))
                 .route("/file/websocket/{room_id}", get(websocket::room_endpoint))
                 .route("/file/{content_type}/{filename}", get(get_file_endpoint))
                 .route("/file/t/{filename}", get(get_file_thumbnail_endpoint))
                 .layer(axum::Extension(rooms))
-                .layer(DefaultBodyLimit::max(100 * 1024 * 1024))
+                .layer(axum::Extension(file_cache))
+                .layer(axum::Extension(outbound_client))
+                .layer(axum::Extension(embed_cache))
+                .layer(axum::Extension(embed_options))
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
                 .layer(axum::middleware::from_fn_with_state(
                     state.clone(),
                     require_internal_secret,
@@ -106,9 +162,18 @@ async fn main() {
 
 const SERVER_SECRET_PATH: &str = "./var/lib/atchat/secret.txt";
 
+/// A number from the environment, for the few limits worth tuning on the server
+/// without a rebuild. Anything unset or unreadable falls back to `default`.
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    pub secret_key: Vec<u8>,
+    pub secret: ServerSecret,
 }
 
 async fn require_internal_secret(
@@ -128,11 +193,14 @@ async fn require_internal_secret(
 
         match provided {
             Some(token) => {
-                let authorized = token
-                    .as_bytes()
-                    .to_vec()
-                    .ct_eq(&state.lock().unwrap().secret_key)
-                    .into();
+                let authorized = {
+                    let secret = &state.lock().unwrap().secret;
+                    if server_secret::needs_current_secret(path) {
+                        secret.is_current(token.as_bytes())
+                    } else {
+                        secret.accepts(token.as_bytes())
+                    }
+                };
                 if authorized {
                     next.run(req).await
                 } else {
@@ -185,34 +253,93 @@ fn thumbnail_filepath(hash: &str) -> String {
 
 enum FetchedContent {
     Image(Vec<u8>),
-    Html(String),
+    /// Anything that is not an image, decoded to text. `url` is where it was
+    /// found after any redirects, which is what links in it are relative to.
+    Html {
+        url: String,
+        text: String,
+    },
+}
+
+/// Why nothing could be made of a link. Sent along with the empty embed, so
+/// a link too large to embed can be told apart from one that is broken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchError {
+    Failed,
+    /// The link is to something bigger than an embed is ever made from.
+    TooLarge,
+    /// The site's robots.txt asks us not to fetch it.
+    Disallowed,
 }
 
-async fn fetch_content(client: &reqwest::Client, url: &str) -> Option<FetchedContent> {
-    let response: reqwest::Response = client.get(url).send().await.ok()?;
+/// What an embed asks for. Sites that can answer a url in more than one way,
+/// as a page or as JSON for their own app, otherwise sometimes pick the one
+/// with no metadata in it. Images are for links straight to one.
+const EMBED_ACCEPT: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/*;q=0.8,*/*;q=0.5";
+
+/// The language an embed asks for. Without one, some sites guess from where
+/// the server is and give the title and description in whatever language is
+/// spoken there.
+const EMBED_ACCEPT_LANGUAGE: &str = "en-US,en;q=0.9,*;q=0.5";
+
+const MAX_EMBED_IMAGE_BYTES: u64 = 10 * 1024 * 1024;
+
+const MAX_EMBED_HTML_BYTES: u64 = 1024 * 1024;
+
+async fn fetch_content(client: &OutboundClient, url: &str) -> Result<FetchedContent, FetchError> {
+    let request = match client.get(url) {
+        Ok(request2) => request2,
+        Err(_) => return Err(FetchError::Failed),
+    };
+
+    if !client.robots_allow(url).await {
+        return Err(FetchError::Disallowed);
+    }
+
+    let response = match request
+        .header(reqwest::header::ACCEPT, EMBED_ACCEPT)
+        .header(reqwest::header::ACCEPT_LANGUAGE, EMBED_ACCEPT_LANGUAGE)
+        .timeout(Duration::from_secs(15))
+        .send()
+        .await
+    {
+        Ok(response2) => response2,
+        Err(_) => return Err(FetchError::Failed),
+    };
+
+    if !client.robots_allow_response(url, &response).await {
+        return Err(FetchError::Disallowed);
+    }
 
-    let is_image = response
+    let content_type: Option<String> = response
         .headers()
         .get(reqwest::header::CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
-        .map(|v| v.trim().to_ascii_lowercase().starts_with("image/"))
-        .unwrap_or(false);
+        .map(String::from);
 
-    let buf: Vec<u8> = response.bytes().await.ok()?.to_vec();
+    let is_image = content_type
+        .as_deref()
+        .is_some_and(|v| v.trim().to_ascii_lowercase().starts_with("image/"));
 
-    if is_image {
-        if buf.len() < 10 * 1024 * 1024 {
-            Some(FetchedContent::Image(buf))
-        } else {
-            None
-        }
-    } else if buf.len() < 1024 * 1024 {
-        Some(FetchedContent::Html(
-            String::from_utf8_lossy(&buf).into_owned(),
-        ))
-    }
-    else {
-        None
+    let final_url = response.url().to_string();
+
+    // Only as much is downloaded as an embed could use, so a link to something
+    // enormous costs no more than a link to something at the limit.
+    let limit = if is_image {
+        MAX_EMBED_IMAGE_BYTES
+    } else {
+        MAX_EMBED_HTML_BYTES
+    };
+
+    match outbound::read_capped(response, limit).await {
+        Ok(bytes) if is_image => Ok(FetchedContent::Image(bytes.to_vec())),
+        Ok(bytes) => Ok(FetchedContent::Html {
+            url: final_url,
+            text: charset::decode(&bytes, content_type.as_deref()),
+        }),
+        Err(outbound::ReadError::TooLarge) => Err(FetchError::TooLarge),
+        Err(outbound::ReadError::Interrupted) => Err(FetchError::Failed),
     }
 }
 
@@ -251,12 +378,48 @@ fn image_data_from_bytes(url: &str, bytes: &[u8]) -> Option<ImageData> {
                 width,
                 height,
                 format: image_format_name(format),
+                thumbnail_url: None,
             })
         }
         _ => None,
     }
 }
 
+/// Settings for how embeds are made, read once at startup.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EmbedOptions {
+    /// Keep a copy of each embed's image in storage and point the embed at that
+    /// instead of the site. Otherwise every viewer's browser fetches the image
+    /// from the site itself, telling it who they are, and the embed loses its
+    /// image whenever the site removes it. Turned on with `EMBED_STORE_IMAGES=1`.
+    pub store_images: bool,
+}
+
+impl EmbedOptions {
+    fn from_env() -> Self {
+        Self {
+            store_images: env_u64("EMBED_STORE_IMAGES", 0) != 0,
+        }
+    }
+}
+
+/// What an embed says about the image fetched from `url`, pointing at the copy
+/// kept of it if images are being kept. Decoding the image, and keeping it,
+/// take long enough to hold up everything else on the thread, so both happen
+/// on one set aside for blocking work.
+async fn embed_image(url: &str, bytes: Vec<u8>, options: EmbedOptions) -> Option<ImageData> {
+    let url2: String = url.to_owned();
+    tokio::task::spawn_blocking(move || {
+        if options.store_images {
+            embed_images::image_data(&url2, &bytes)
+        } else {
+            image_data_from_bytes(&url2, &bytes)
+        }
+    })
+    .await
+    .ok()?
+}
+
 // Parse already-fetched HTML without letting a parse failure take down the server.
 fn parse_html_safe(body: String, url: String) -> Option<HTML> {
     std::thread::Builder::new()
@@ -274,74 +437,493 @@ fn parse_html_safe(body: String, url: String) -> Option<HTML> {
         .flatten()
 }
 
-async fn build_embed(client: &reqwest::Client, url: &str) -> Option<EmbedResponse> {
-    let html = match fetch_content(client, url).await? {
+/// The first of these meta tags that the page gives a value, in the order
+/// given. Sites often fill in only some of the Open Graph, Twitter card and
+/// plain HTML versions of the same thing.
+fn first_meta<'a>(html: &'a HTML, names: &[&str]) -> Option<&'a str> {
+    names.iter().find_map(|name| {
+        html.meta
+            .get(*name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// Where `href` leads when found on the page at `page_url`, for the many tags
+/// that are allowed to give a url relative to the page.
+fn resolve_url(page_url: &str, href: &str) -> Option<String> {
+    let url = reqwest::Url::parse(page_url).ok()?.join(href).ok()?;
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+async fn build_embed(
+    client: &OutboundClient,
+    options: EmbedOptions,
+    url: &str,
+) -> Result<EmbedResponse, FetchError> {
+    let (page_url, body, html) = match fetch_content(client, url).await? {
         // The link points straight at an image: skip HTML parsing entirely and
         // build the embed from the image itself.
         FetchedContent::Image(bytes) => {
-            return Some(EmbedResponse {
-                title: None,
-                description: None,
-                image: image_data_from_bytes(url, &bytes),
-                created_at: None,
+            return Ok(EmbedResponse {
+                image: embed_image(url, bytes, options).await,
+                ..EmbedResponse::default()
             });
         }
-        FetchedContent::Html(body) => parse_html_safe(body, url.to_string())?,
+        FetchedContent::Html {
+            url: page_url,
+            text,
+        } => follow_meta_refresh(client, page_url, text)
+            .await
+            .ok_or(FetchError::Failed)?,
     };
 
-    // Follow a single meta-refresh redirect, matching the previous behaviour.
-    let html = match (html.meta.len(), html.meta.get("refresh")) {
-        (1, Some(refresh)) => {
-            let redirect_url = refresh.split('=').skip(1).collect::<Vec<_>>().join("=");
-            match fetch_content(client, &redirect_url).await {
-                Some(FetchedContent::Html(body)) => {
-                    parse_html_safe(body, redirect_url).unwrap_or(html)
-                }
-                _ => html,
-            }
-        }
-        _ => html,
-    };
+    let links = link_tags::link_tags(&body);
 
-    let image = match html.meta.get("og:image") {
-        Some(image_url) => match fetch_content(client, image_url).await {
-            Some(FetchedContent::Image(bytes)) => image_data_from_bytes(image_url, &bytes),
-            _ => None,
-        },
+    let site: Option<SiteEmbed> = reqwest::Url::parse(&page_url).ok().and_then(|page_url2| {
+        extractors::extract(&extractors::Page {
+            url: &page_url2,
+            body: &body,
+            html: &html,
+        })
+    });
+
+    let oembed: Option<OEmbed> = match oembed_url(&page_url, &links) {
+        Some(oembed_url2) => fetch_oembed(client, &oembed_url2).await,
         None => None,
     };
 
-    Some(EmbedResponse {
-        title: html.meta.get("og:title").cloned(),
-        description: html.meta.get("og:description").cloned(),
+    let image_url: Option<String> = first_meta(
+        &html,
+        &[
+            "og:image",
+            "og:image:url",
+            "og:image:secure_url",
+            "twitter:image",
+            "twitter:image:src",
+        ],
+    )
+    .and_then(|image_url2| resolve_url(&page_url, image_url2))
+    .or_else(|| oembed.as_ref()?.thumbnail_url.clone());
+
+    let (image, favicon, media) = tokio::join!(
+        async {
+            let image_url2 = image_url.as_deref()?;
+            match fetch_content(client, image_url2).await {
+                Ok(FetchedContent::Image(bytes)) => embed_image(image_url2, bytes, options).await,
+                _ => None,
+            }
+        },
+        favicon(client, &page_url, &links),
+        embed_media(client, &page_url, &html),
+    );
+
+    Ok(EmbedResponse {
+        title: first_meta(&html, &["og:title", "twitter:title"])
+            .or(html
+                .title
+                .as_deref()
+                .filter(|title| !title.trim().is_empty()))
+            .map(|title| title.trim().to_owned())
+            .or_else(|| oembed.as_ref()?.title.clone()),
+        description: first_meta(
+            &html,
+            &["og:description", "twitter:description", "description"],
+        )
+        .map(String::from)
+        .or_else(|| site.as_ref()?.description().map(String::from)),
         image,
         created_at: html
             .meta
             .get("article:published_time")
             .and_then(|text| chrono::DateTime::parse_from_rfc3339(text).ok())
             .map(|date| date.timestamp()),
+        site_name: first_meta(&html, &["og:site_name"])
+            .map(String::from)
+            .or_else(|| oembed.as_ref()?.provider_name.clone()),
+        theme_color: first_meta(&html, &["theme-color"]).map(String::from),
+        canonical_url: canonical_url(&page_url, &html, &links),
+        favicon,
+        oembed,
+        media,
+        site,
+        error: None,
     })
 }
 
-async fn post_embed(Json(EmbedRequest { url }): Json<EmbedRequest>) -> Response<String> {
-    let empty = EmbedResponse {
-        title: None,
-        description: None,
-        image: None,
-        created_at: None,
+/// How many meta refresh redirects are followed from a link before settling for
+/// the page reached. Enough for a link shortener in front of a site that has
+/// moved, and few enough that two pages sending the browser back and forth
+/// between them are soon given up on.
+const MAX_META_REFRESH_HOPS: usize = 5;
+
+/// Parses the page, and while it is only there to send the browser on somewhere
+/// else with a meta refresh, follows it there. Returns the page it ends on,
+/// along with its url and text.
+async fn follow_meta_refresh(
+    client: &OutboundClient,
+    mut page_url: String,
+    mut body: String,
+) -> Option<(String, String, HTML)> {
+    let mut html = parse_html_safe(body.clone(), page_url.clone())?;
+
+    for _ in 0..MAX_META_REFRESH_HOPS {
+        let Some(target) = meta_refresh_target(&page_url, &html) else {
+            break;
+        };
+
+        let Ok(FetchedContent::Html { url: url2, text }) = fetch_content(client, &target).await
+        else {
+            break;
+        };
+
+        match parse_html_safe(text.clone(), url2.clone()) {
+            Some(html2) => {
+                page_url = url2;
+                body = text;
+                html = html2;
+            }
+            None => break,
+        }
+    }
+
+    Some((page_url, body, html))
+}
+
+/// Where the page's meta refresh sends the browser, if the page has nothing of
+/// its own worth embedding. One that does have a title of its own is more
+/// likely to be refreshing itself now and then, like a news front page.
+fn meta_refresh_target(page_url: &str, html: &HTML) -> Option<String> {
+    if first_meta(html, &["og:title", "twitter:title"]).is_some() {
+        return None;
+    }
+
+    let target = refresh_url(html.meta.get("refresh")?)?;
+    resolve_url(page_url, target).filter(|target2| target2 != page_url)
+}
+
+/// The url in the content of a refresh tag, which is a delay in seconds
+/// followed by the url, as in `0; url=/elsewhere`. The `url=` and any quotes
+/// around the url are optional, and a delay on its own means to reload.
+fn refresh_url(content: &str) -> Option<&str> {
+    let rest = content
+        .trim_start()
+        .trim_start_matches(|x: char| x.is_ascii_digit() || x == '.')
+        .trim_start()
+        .strip_prefix([';', ','])?
+        .trim_start();
+
+    let rest2 = match rest.get(..3) {
+        Some(name) if name.eq_ignore_ascii_case("url") => rest[3..]
+            .trim_start()
+            .strip_prefix('=')
+            .map_or(rest, str::trim_start),
+        _ => rest,
+    };
+
+    let url = match rest2.chars().next() {
+        Some(quote @ ('"' | '\'')) => rest2[1..].split(quote).next()?,
+        _ => rest2,
     };
 
-    let response = match reqwest::Client::builder()
+    Some(url.trim()).filter(|url2| !url2.is_empty())
+}
+
+/// The address the page gives as its own, which leaves out whatever tracking
+/// parameters or alternate path the link was shared with.
+fn canonical_url(page_url: &str, html: &HTML, links: &[LinkTag]) -> Option<String> {
+    first_meta(html, &["og:url"])
+        .or_else(|| {
+            links
+                .iter()
+                .find(|link| link.has_rel("canonical"))
+                .map(|link| link.href.as_str())
+        })
+        .and_then(|url| resolve_url(page_url, url))
+}
+
+/// How much of a video or audio file is fetched to check it. Plenty for the
+/// header of a file prepared for streaming, which puts it first so that a
+/// browser can start playing before the rest arrives.
+const MEDIA_PROBE_BYTES: usize = 1024 * 1024;
+
+/// The page's video if it has one that plays, and otherwise its audio.
+async fn embed_media(client: &OutboundClient, page_url: &str, html: &HTML) -> Option<EmbedMedia> {
+    let video_url: Option<String> =
+        first_meta(html, &["og:video:secure_url", "og:video:url", "og:video"])
+            .and_then(|video_url2| resolve_url(page_url, video_url2));
+
+    if let Some(video_url2) = video_url {
+        let declared_size = (
+            first_meta(html, &["og:video:width"]).and_then(|width| width.parse::<u32>().ok()),
+            first_meta(html, &["og:video:height"]).and_then(|height| height.parse::<u32>().ok()),
+        );
+
+        if let Some(media) = probe_media(
+            client,
+            &video_url2,
+            first_meta(html, &["og:video:type"]),
+            declared_size,
+        )
+        .await
+        {
+            return Some(media);
+        }
+    }
+
+    let audio_url: String = first_meta(html, &["og:audio:secure_url", "og:audio:url", "og:audio"])
+        .and_then(|audio_url2| resolve_url(page_url, audio_url2))?;
+
+    probe_media(
+        client,
+        &audio_url,
+        first_meta(html, &["og:audio:type"]),
+        (None, None),
+    )
+    .await
+}
+
+/// Fetches the start of a media file to make sure it is there and is something
+/// a browser can play, and reads its size out of the header if it has one.
+///
+/// Plenty of pages give `og:video` as the url of an HTML player rather than of
+/// a video, which is no use to a `<video>` tag, so what the server says it is
+/// sending counts for more than what the page says it is.
+async fn probe_media(
+    client: &OutboundClient,
+    url: &str,
+    declared_type: Option<&str>,
+    (declared_width, declared_height): (Option<u32>, Option<u32>),
+) -> Option<EmbedMedia> {
+    let request = client.get(url).ok()?;
+    if !client.robots_allow(url).await {
+        return None;
+    }
+
+    let response = request
+        .header(
+            reqwest::header::RANGE,
+            format!("bytes=0-{}", MEDIA_PROBE_BYTES - 1),
+        )
         .timeout(Duration::from_secs(15))
-        .build()
+        .send()
+        .await
+        .ok()?;
+
+    if !response.status().is_success() || !client.robots_allow_response(url, &response).await {
+        return None;
+    }
+
+    let served_type: Option<String> = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+
+    let is_media = |content_type: &str| {
+        content_type.starts_with("video/") || content_type.starts_with("audio/")
+    };
+
+    // A server that only says it is sending bytes is taken at the page's word.
+    let content_type: String = match served_type {
+        Some(served_type2) if is_media(&served_type2) => served_type2,
+        Some(served_type2) if served_type2 != "application/octet-stream" => return None,
+        _ => declared_type
+            .map(str::to_ascii_lowercase)
+            .filter(|declared_type2| is_media(declared_type2))?,
+    };
+
+    let prefix = outbound::read_prefix(response, MEDIA_PROBE_BYTES).await?;
+    let metadata: Option<video::VideoMetadata> = video::video_metadata(&prefix);
+
+    let (width, height) = match &metadata {
+        Some(metadata2) => (Some(metadata2.video_size.0), Some(metadata2.video_size.1)),
+        None => (declared_width, declared_height),
+    };
+
+    Some(EmbedMedia {
+        url: url.to_owned(),
+        kind: if content_type.starts_with("audio/") {
+            MediaKind::Audio
+        } else {
+            MediaKind::Video
+        },
+        content_type,
+        width,
+        height,
+        codec: metadata.and_then(|metadata2| metadata2.codec),
+    })
+}
+
+/// Browsers ask for this when a page names no icon of its own.
+const DEFAULT_FAVICON_PATH: &str = "/favicon.ico";
+
+/// How many of a page's icons to try before giving up. Each one that fails to
+/// load or decode costs another fetch, and the first usually works.
+const MAX_FAVICON_ATTEMPTS: usize = 3;
+
+/// The site's icon, from the first of the icons it names that can be decoded,
+/// or from `/favicon.ico` if it names none that can be.
+async fn favicon(client: &OutboundClient, page_url: &str, links: &[LinkTag]) -> Option<ImageData> {
+    for favicon_url in favicon_urls(page_url, links)
+        .iter()
+        .take(MAX_FAVICON_ATTEMPTS)
     {
-        Ok(client) => build_embed(&client, &url).await.unwrap_or(empty),
-        Err(_) => empty,
+        if let Ok(FetchedContent::Image(bytes)) = fetch_content(client, favicon_url).await
+            && let Some(image) = image_data_from_bytes(favicon_url, &bytes)
+        {
+            return Some(image);
+        }
+    }
+
+    None
+}
+
+/// Every icon the page names, small ones before the larger touch icons, and
+/// then the default. SVG icons are left out, since the image crate cannot
+/// decode them to say how big they are.
+fn favicon_urls(page_url: &str, links: &[LinkTag]) -> Vec<String> {
+    let is_svg = |link: &&LinkTag| {
+        link.link_type.as_deref() == Some("image/svg+xml")
+            || link.href.to_ascii_lowercase().ends_with(".svg")
+    };
+
+    let mut favicon_urls: Vec<String> = Vec::new();
+
+    for favicon_url in links
+        .iter()
+        .filter(|link| link.has_rel("icon"))
+        .chain(links.iter().filter(|link| link.has_rel("apple-touch-icon")))
+        .filter(|link| !is_svg(link))
+        .filter_map(|link| resolve_url(page_url, &link.href))
+        .chain(resolve_url(page_url, DEFAULT_FAVICON_PATH))
+    {
+        if !favicon_urls.contains(&favicon_url) {
+            favicon_urls.push(favicon_url);
+        }
+    }
+
+    favicon_urls
+}
+
+/// Where the page says its oEmbed description can be had. Only the JSON
+/// flavour is used; providers that offer XML offer JSON as well.
+fn oembed_url(page_url: &str, links: &[LinkTag]) -> Option<String> {
+    links
+        .iter()
+        .find(|link| {
+            link.has_rel("alternate")
+                && link.link_type.as_deref() == Some("application/json+oembed")
+        })
+        .and_then(|link| resolve_url(page_url, &link.href))
+}
+
+async fn fetch_oembed(client: &OutboundClient, oembed_url: &str) -> Option<OEmbed> {
+    match fetch_content(client, oembed_url).await {
+        Ok(FetchedContent::Html { text, .. }) => parse_oembed(&text),
+        _ => None,
+    }
+}
+
+/// Reads an oEmbed response field by field rather than all at once, because
+/// providers are loose with the types: sizes turn up as numbers from some and
+/// as strings from others, and one odd field should not lose all the rest.
+fn parse_oembed(text: &str) -> Option<OEmbed> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let fields = value.as_object()?;
+
+    let text_field = |name: &str| {
+        fields
+            .get(name)?
+            .as_str()
+            .map(str::trim)
+            .filter(|text2| !text2.is_empty())
+            .map(String::from)
+    };
+    let size_field = |name: &str| {
+        let field = fields.get(name)?;
+        field
+            .as_u64()
+            .or_else(|| field.as_str()?.trim().parse::<u64>().ok())
+            .and_then(|size| u32::try_from(size).ok())
+    };
+
+    Some(OEmbed {
+        kind: text_field("type"),
+        title: text_field("title"),
+        author_name: text_field("author_name"),
+        author_url: text_field("author_url"),
+        provider_name: text_field("provider_name"),
+        provider_url: text_field("provider_url"),
+        thumbnail_url: text_field("thumbnail_url"),
+        thumbnail_width: size_field("thumbnail_width"),
+        thumbnail_height: size_field("thumbnail_height"),
+    })
+}
+
+async fn post_embed(
+    Extension(client): Extension<OutboundClient>,
+    Extension(embed_cache): Extension<Arc<EmbedCache>>,
+    Extension(options): Extension<EmbedOptions>,
+    Json(EmbedRequest { url }): Json<EmbedRequest>,
+) -> Response<String> {
+    let embed = match embed_cache.get(&url) {
+        Some(cached) => cached,
+        None => build_and_cache_embed(&client, &embed_cache, options, &url).await,
+    };
+
+    response_with_headers(StatusCode::OK, embed.json)
+}
+
+/// Makes the embed for a link as it is sent, and keeps it for the next time
+/// the link is asked about. A link nothing could be made of gets an empty embed
+/// saying why.
+async fn build_and_cache_embed(
+    client: &OutboundClient,
+    embed_cache: &EmbedCache,
+    options: EmbedOptions,
+    url: &str,
+) -> EmbedJson {
+    let (embed, error) = match build_embed(client, options, url).await {
+        Ok(embed2) => (embed2, None),
+        Err(error) => (
+            EmbedResponse {
+                error: Some(error),
+                ..EmbedResponse::default()
+            },
+            Some(error),
+        ),
     };
+    let json: String = serde_json::to_string::<EmbedResponse>(&embed).unwrap();
+
+    embed_cache.insert(url, json.clone(), error);
+    EmbedJson { json, error }
+}
+
+/// Lets the backend have a link's embed made again, for when a page is known
+/// to have changed since it was last fetched.
+async fn purge_embed_endpoint(
+    Extension(embed_cache): Extension<Arc<EmbedCache>>,
+    Json(EmbedRequest { url }): Json<EmbedRequest>,
+) -> Response<String> {
+    json_response_with_headers(
+        StatusCode::OK,
+        serde_json::json!({ "purged": embed_cache.purge(&url) }).to_string(),
+    )
+}
 
-    response_with_headers(
+async fn embed_cache_stats_endpoint(
+    Extension(embed_cache): Extension<Arc<EmbedCache>>,
+) -> Response<String> {
+    json_response_with_headers(
         StatusCode::OK,
-        serde_json::to_string::<EmbedResponse>(&response).unwrap(),
+        serde_json::to_string(&embed_cache.stats()).unwrap(),
     )
 }
 
@@ -373,8 +955,22 @@ fn vec_to_headermap(
     Ok(header_map)
 }
 
-const SERVER_BACKUPS_PATH: &str = "./var/lib/atchat/backups/";
-const BUCKET_BACKUPS_PATH: &str = "./var/lib/atchat/storage/backups/";
+/// Writes a file under a name of its own and then renames it into place, so a
+/// request arriving while it is still being written never finds half of one.
+/// Two writers racing for the same path both succeed, and whichever rename
+/// lands last wins.
+fn write_atomically(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let partial_path: String = format!("{path}_{}", rand::random::<u64>());
+    fs::write(&partial_path, bytes)?;
+
+    match fs::rename(&partial_path, path) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            let _ = fs::remove_file(&partial_path);
+            Err(error)
+        }
+    }
+}
 
 fn create_dir_if_missing(path: String) {
     match fs::exists(&path) {
@@ -385,119 +981,46 @@ fn create_dir_if_missing(path: String) {
     }
 }
 
-const BACKUP_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
-
-fn remove_old_backups(dir: String) {
-    let now = SystemTime::now();
-
-    match fs::read_dir(&dir) {
-        Ok(entries) => {
-            for entry in entries {
-                match entry {
-                    Ok(entry2) => match (entry2.metadata(), entry2.file_name().to_str()) {
-                        (Ok(metadata), Some(filename)) => {
-                            match (metadata.is_file(), metadata.created()) {
-                                (true, Ok(time)) => {
-                                    let should_delete = match now.duration_since(time) {
-                                        Ok(duration) => {
-                                            duration > BACKUP_MAX_AGE
-                                                && filename.starts_with("backend-export-")
-                                        }
-                                        Err(_) => false,
-                                    };
-                                    if should_delete {
-                                        let _ = fs::remove_file(entry2.path());
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        _ => {}
-                    },
-                    Err(_) => {}
-                }
-            }
-        }
-        Err(_) => {}
-    }
-}
-
-async fn post_backup_endpoint(Path(filename): Path<String>, body: Bytes) -> Response<String> {
-    create_dir_if_missing(SERVER_BACKUPS_PATH.to_string());
-    create_dir_if_missing(BUCKET_BACKUPS_PATH.to_string());
-
-    remove_old_backups(SERVER_BACKUPS_PATH.to_string());
-    remove_old_backups(BUCKET_BACKUPS_PATH.to_string());
-
-    // The first path is on the main server and the second path is the S3 bucket. We write to both to improve the odds that we don't lose all backups
-    let write_a = fs::write(String::from(SERVER_BACKUPS_PATH) + &filename, &body);
-    let write_b = fs::write(String::from(BUCKET_BACKUPS_PATH) + &filename, &body);
-
-    match (write_a, write_b) {
-        (Ok(_), Ok(_)) => response_with_headers(StatusCode::OK, ""),
-        (Err(error), Ok(_)) => response_with_headers(
-            StatusCode::BAD_REQUEST,
-            format!("First write failed\n{:?}", error),
-        ),
-        (Ok(_), Err(error)) => response_with_headers(
-            StatusCode::BAD_REQUEST,
-            format!("Second write failed\n{:?}", error),
-        ),
-        (Err(error_a), Err(error_b)) => response_with_headers(
-            StatusCode::BAD_REQUEST,
-            format!("Both file writes failed\n{:?}\n{:?}", error_a, error_b),
-        ),
-    }
-}
-
-async fn regenerate_server_secret_endpoint(state: State<Arc<Mutex<AppState>>>) -> Response<String> {
-    let mut rng = rand::rng();
-    let random_string: String = (0..64)
-        .map(|_| format!("{}", (rng.random_range(0..10))))
-        .collect();
-    match fs::write(String::from(SERVER_SECRET_PATH), &random_string) {
-        Ok(_) => {
-            let mut state2 = state.lock().unwrap();
-            state2.secret_key = random_string.clone().into();
-            response_with_headers(StatusCode::OK, random_string)
-        }
-        Err(error) => response_with_headers(
-            StatusCode::BAD_REQUEST,
-            format!("Write failed\n{:?}", error),
-        ),
+async fn custom_request_endpoint(
+    Extension(client): Extension<OutboundClient>,
+    Json(custom_request): Json<CustomRequest>,
+) -> Response<String> {
+    match send_custom_request(&client, custom_request).await {
+        Ok((status, response_text)) => response_with_headers(status, response_text),
+        Err(error) => response_with_headers(StatusCode::BAD_REQUEST, error),
     }
 }
 
-async fn custom_request_endpoint(
-    Json(CustomRequest {
+/// Sends a request on the backend's behalf and returns the status and body that
+/// came back, or a message saying why it could not be sent. The backend uses it
+/// for calls to Discord's API, which is also what `discord_cdn` uses it for.
+async fn send_custom_request(
+    client: &OutboundClient,
+    CustomRequest {
         method,
         url,
         headers,
         body,
-    }): Json<CustomRequest>,
-) -> Response<String> {
+    }: CustomRequest,
+) -> Result<(StatusCode, String), String> {
     let headers2 = match vec_to_headermap(headers) {
         Ok(ok) => ok,
-        Err(error) => {
-            return response_with_headers(StatusCode::BAD_REQUEST, format!("Error 1: {error:?}"));
-        }
+        Err(error) => return Err(format!("Error 1: {error:?}")),
     };
 
-    let client = reqwest::Client::new();
+    let method2 = match method.as_str() {
+        "GET" => Method::GET,
+        "POST" => Method::POST,
+        "PUT" => Method::PUT,
+        "PATCH" => Method::PATCH,
+        "DELETE" => Method::DELETE,
+        "HEAD" => Method::HEAD,
+        _ => return Err(format!("Invalid method: {method}")),
+    };
 
-    let request = match method.as_str() {
-        "GET" => client.get(url),
-        "POST" => client.post(url),
-        "PUT" => client.put(url),
-        "PATCH" => client.patch(url),
-        "DELETE" => client.delete(url),
-        "HEAD" => client.head(url),
-        _ => {
-            return response_with_headers(
-                StatusCode::BAD_REQUEST,
-                format!("Invalid method: {method}"),
-            );
-        }
+    let request = match client.request(method2, &url) {
+        Ok(request2) => request2,
+        Err(blocked) => return Err(format!("Blocked: {blocked}")),
     };
 
     let request2 = request.headers(headers2);
@@ -510,21 +1033,15 @@ async fn custom_request_endpoint(
     match request3.send().await {
         Ok(response) => {
             let status = response.status();
-            let response_text = match response.text().await {
-                Ok(text) => text,
-                Err(error) => {
-                    return response_with_headers(
-                        StatusCode::BAD_REQUEST,
-                        format!("Error 2: {error:?}"),
-                    );
-                }
-            };
-
-            response_with_headers(status, response_text)
-        }
-        Err(error) => {
-            response_with_headers(StatusCode::BAD_REQUEST, format!("Error 3:  {error:?}"))
+            match response.text().await {
+                Ok(text) => Ok((status, text)),
+                Err(error) => Err(format!("Error 2: {error:?}")),
+            }
         }
+        Err(error) => match outbound::blocked_by(&error) {
+            Some(blocked) => Err(format!("Blocked: {blocked}")),
+            None => Err(format!("Error 3:  {error:?}")),
+        },
     }
 }
 
@@ -666,11 +1183,7 @@ pub fn session_id_from_cookie(headers: &HeaderMap) -> Option<String> {
 
 fn uploader(state: &Mutex<AppState>, headers: &HeaderMap) -> Option<Uploader> {
     let is_backend: bool = match headers.get("x-secret-key").and_then(|v| v.to_str().ok()) {
-        Some(provided) => provided
-            .as_bytes()
-            .to_vec()
-            .ct_eq(&state.lock().unwrap().secret_key)
-            .into(),
+        Some(provided) => state.lock().unwrap().secret.accepts(provided.as_bytes()),
         None => false,
     };
 
@@ -686,7 +1199,7 @@ async fn upload_endpoint(
     request: Request,
 ) -> Response<String> {
     let uploader: Option<Uploader> = uploader(&state, request.headers());
-    let secret_key: Vec<u8> = state.lock().unwrap().secret_key.clone();
+    let secret_key: Vec<u8> = state.lock().unwrap().secret.outbound();
 
     match (uploader, request.extract::<Bytes, _>().await) {
         (Some(uploader2), Ok(bytes)) => file_upload_helper(&secret_key, &uploader2, bytes).await,
@@ -702,25 +1215,70 @@ async fn upload_endpoint(
 /// this runs.
 async fn upload_url_endpoint(
     State(state): State<Arc<Mutex<AppState>>>,
+    Extension(client): Extension<OutboundClient>,
     Json(UploadUrl { url }): Json<UploadUrl>,
 ) -> Response<String> {
-    let secret_key: Vec<u8> = state.lock().unwrap().secret_key.clone();
-
-    match reqwest::Client::new().get(url).send().await {
-        Ok(response) => match response.bytes().await {
-            Ok(bytes) => file_upload_helper(&secret_key, &Uploader::Backend, bytes).await,
-            Err(_) => response_with_headers(
-                StatusCode::UNAUTHORIZED,
-                String::from("Invalid permissions 2"),
-            ),
-        },
-        Err(_) => response_with_headers(
-            StatusCode::UNAUTHORIZED,
-            String::from("Invalid permissions 1"),
-        ),
+    let secret_key: Vec<u8> = state.lock().unwrap().secret.outbound();
+
+    match fetch_for_upload(&client, &url, UPLOAD_URL_TIMEOUT).await {
+        Ok(bytes) => file_upload_helper(&secret_key, &Uploader::Backend, bytes).await,
+        Err((status, message)) => response_with_headers(status, message),
     }
 }
 
+/// The backend gives up on an upload by url after 30 seconds, so there is no
+/// point carrying on for much longer than that.
+const UPLOAD_URL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Downloads a file to upload, giving up once `timeout` has gone by, however
+/// far it has got. A download that fails says so, rather than being mistaken
+/// for the backend not being allowed to upload.
+async fn fetch_for_upload(
+    client: &OutboundClient,
+    url: &str,
+    timeout: Duration,
+) -> Result<Bytes, (StatusCode, String)> {
+    let request = client
+        .get(url)
+        .map_err(|blocked| (StatusCode::FORBIDDEN, blocked.to_string()))?;
+
+    let download = async {
+        match request.send().await {
+            Ok(response) => match outbound::read_capped(response, MAX_UPLOAD_BYTES as u64).await {
+                Ok(bytes) => Ok(bytes),
+                Err(outbound::ReadError::TooLarge) => Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    String::from("File is too large"),
+                )),
+                Err(outbound::ReadError::Interrupted) => Err((
+                    StatusCode::BAD_REQUEST,
+                    String::from("The file's download was interrupted"),
+                )),
+            },
+            Err(error) => match outbound::blocked_by(&error) {
+                Some(blocked) => Err((StatusCode::FORBIDDEN, blocked.to_string())),
+                None => Err((
+                    StatusCode::BAD_REQUEST,
+                    String::from("Could not download the file"),
+                )),
+            },
+        }
+    };
+
+    tokio::time::timeout(timeout, download)
+        .await
+        .unwrap_or_else(|_elapsed| {
+            Err((
+                StatusCode::REQUEST_TIMEOUT,
+                String::from("The file took too long to download"),
+            ))
+        })
+}
+
+/// The largest file that can be uploaded, whether it is sent to us or we are
+/// sent a url to fetch it from.
+const MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
 /// Should match RichText.maxImageHeight
 const MAX_THUMBNAIL_HEIGHT: u32 = 600;
 
@@ -934,23 +1492,12 @@ async fn file_upload_helper(
                         Ok(true) => json_response_with_headers(StatusCode::OK, response),
                         _ => match fs::write(path, bytes) {
                             Ok(()) => {
-                                let (width2, height2) = image_size;
-                                if height2 > MAX_THUMBNAIL_HEIGHT
-                                    || width2 > MAX_THUMBNAIL_HEIGHT * 3
-                                {
-                                    let mut resized_image = image.resize(
-                                        MAX_THUMBNAIL_HEIGHT * 3,
-                                        MAX_THUMBNAIL_HEIGHT,
-                                        image::imageops::FilterType::Triangle,
-                                    );
-                                    resized_image.apply_orientation(orientation);
-
-                                    let _ = resized_image.save_with_format(
-                                        thumbnail_filepath(&hash),
-                                        image::ImageFormat::WebP,
-                                    );
-                                }
-
+                                save_thumbnail(
+                                    &thumbnail_filepath(&hash),
+                                    &image,
+                                    image_size,
+                                    orientation,
+                                );
                                 json_response_with_headers(StatusCode::OK, response)
                             }
                             Err(_) => response_with_headers(
@@ -1009,6 +1556,33 @@ async fn file_upload_helper(
     }
 }
 
+/// Saves the smaller copy of an image that the chat shows in place of the
+/// original to `path`, if the image is big enough to need one. Returns whether
+/// it has one now.
+fn save_thumbnail(
+    path: &str,
+    image: &image::DynamicImage,
+    (width, height): (u32, u32),
+    orientation: Orientation,
+) -> bool {
+    if height > MAX_THUMBNAIL_HEIGHT || width > MAX_THUMBNAIL_HEIGHT * 3 {
+        let mut resized_image = image.resize(
+            MAX_THUMBNAIL_HEIGHT * 3,
+            MAX_THUMBNAIL_HEIGHT,
+            image::imageops::FilterType::Triangle,
+        );
+        resized_image.apply_orientation(orientation);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        resized_image
+            .write_to(&mut std::io::Cursor::new(&mut bytes), ImageFormat::WebP)
+            .is_ok()
+            && write_atomically(path, &bytes).is_ok()
+    } else {
+        false
+    }
+}
+
 /// The page these endpoints are called from, and the only origin they answer
 /// for. It cannot be `*` any more: browsers reject a wildcard on a request that
 /// carries credentials, and uploads now rely on the `sid` cookie being sent.
@@ -1049,25 +1623,40 @@ fn json_response_with_headers(
         .unwrap()
 }
 
-fn hash_bytes(bytes: &Bytes) -> String {
+fn hash_bytes(bytes: &[u8]) -> String {
     base64_encode(&Sha224::digest(bytes))
 }
 
-async fn get_file_thumbnail_endpoint(Path(hash): Path<String>) -> http::Response<Body> {
+async fn get_file_thumbnail_endpoint(
+    Path(hash): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    Extension(file_cache): Extension<Arc<FileCache>>,
+) -> http::Response<Body> {
     let is_valid_hash: bool = hash
         .chars()
         .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_');
 
     if is_valid_hash {
-        match fs::read(thumbnail_filepath(&hash)) {
-            Result::Ok(data) => Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "image/webp")
-                .header("Content-Disposition", "inline")
-                .header("Cache-Control", IMMUTABLE_CACHE_CONTROL)
-                .body(Body::from(data))
-                .unwrap(),
-            Result::Err(_) => Response::builder()
+        let path = embed_images::stored_thumbnail_filepath(&hash);
+        match fs::metadata(&path) {
+            Result::Ok(metadata) => {
+                let builder = Response::builder()
+                    .header("Content-Type", "image/webp")
+                    .header("Content-Disposition", "inline")
+                    .header("Cache-Control", IMMUTABLE_CACHE_CONTROL);
+
+                send_stored_file(
+                    builder,
+                    &file_cache,
+                    &method,
+                    &headers,
+                    &path,
+                    &metadata,
+                    &thumbnail_entity_tag(&hash),
+                )
+            }
+            Result::Err(_) => Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Body::from("File not found"))
                 .unwrap(),
@@ -1080,32 +1669,6 @@ async fn get_file_thumbnail_endpoint(Path(hash): Path<String>) -> http::Response
     }
 }
 
-async fn discord_sticker_endpoint(sticker_path: Path<String>) -> http::Response<Body> {
-    let sticker_path2 = sticker_path.to_string();
-    if sticker_path2
-        .chars()
-        .all(|x| x.is_ascii_alphanumeric() || x == '.')
-        && sticker_path2.len() < 50
-    {
-        match reqwest::get(format!("https://discord.com/stickers/{}", sticker_path2)).await {
-            Ok(bytes) => Response::builder()
-                .status(StatusCode::OK)
-                .header("Cache-Control", IMMUTABLE_CACHE_CONTROL)
-                .body(Body::from(bytes.bytes().await.unwrap()))
-                .unwrap(),
-            Err(_) => Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(format!("Sticker does not exist")))
-                .unwrap(),
-        }
-    } else {
-        Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from(format!("Invalid sticker format")))
-            .unwrap()
-    }
-}
-
 /// Files are addressed by a hash of their contents, so the bytes behind a given
 /// URL can never change and the browser is free to keep them indefinitely
 /// without revalidating. Without this the browser has no expiry and no
@@ -1159,42 +1722,300 @@ fn sandbox_csp(content_type: &str) -> &'static str {
 /// choice was made for.
 const NOSNIFF: &str = "nosniff";
 
+/// What the url may say about how a file is handed over, on top of which file it
+/// is. Both are optional, and a link without either is shown in the tab the way
+/// it always has been.
+///
+/// `filename` is the name the file was uploaded with, so that saving it gives
+/// `holiday.mp4` rather than the hash. `download=1` asks the browser to save the
+/// file instead of opening it, which is the only way to get a file the browser
+/// would otherwise display, such as a text file, onto disk with a click.
+#[derive(Debug, Default, Deserialize)]
+pub struct FileQuery {
+    filename: Option<String>,
+    download: Option<String>,
+}
+
+/// Longer names than this are refused by most filesystems anyway.
+const MAX_DOWNLOAD_FILENAME_BYTES: usize = 255;
+
+/// The `Content-Disposition` a file is served with, or a message saying which
+/// part of the query could not be used.
+///
+/// The filename is chosen by whoever wrote the link, so it is held to what a
+/// filename can be rather than passed through. Separators are refused because a
+/// browser told to save `../../.bashrc` is being asked for more than a name, and
+/// control characters because they have no place in a header.
+fn content_disposition(query: &FileQuery) -> Result<String, String> {
+    let disposition: &str = match query.download.as_deref() {
+        None | Some("0") => "inline",
+        Some("1") => "attachment",
+        Some(other) => return Err(format!("{other} is not a valid download value")),
+    };
+
+    match &query.filename {
+        None => Ok(disposition.to_owned()),
+        Some(filename) => {
+            let is_valid_filename: bool = !filename.is_empty()
+                && filename.len() <= MAX_DOWNLOAD_FILENAME_BYTES
+                && filename != "."
+                && filename != ".."
+                && !filename
+                    .chars()
+                    .any(|x| x == '/' || x == '\\' || x.is_control());
+
+            if is_valid_filename {
+                Ok(format!("{disposition}; {}", filename_parameters(filename)))
+            } else {
+                Err(String::from("Invalid download filename"))
+            }
+        }
+    }
+}
+
+/// The `filename` parameter of `Content-Disposition` can only carry ASCII, and
+/// browsers disagree on what a backslash or percent sign inside it means. So it
+/// gets a plain ASCII stand-in, and whenever that is not the real name the real
+/// name goes alongside it in `filename*`, percent encoded as UTF-8 the way RFC
+/// 5987 describes. Every browser we support reads `filename*` in preference, so
+/// the stand-in only matters to clients that predate it.
+fn filename_parameters(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|x| {
+            if (x.is_ascii_graphic() || x == ' ') && !matches!(x, '"' | '\\' | '%') {
+                x
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if fallback == filename {
+        format!("filename=\"{fallback}\"")
+    } else {
+        format!(
+            "filename=\"{fallback}\"; filename*=UTF-8''{}",
+            rfc5987_encode(filename)
+        )
+    }
+}
+
+/// Percent encodes everything outside the characters RFC 5987 lets through
+/// as they are.
+fn rfc5987_encode(text: &str) -> String {
+    let mut result = String::new();
+
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    result
+}
+
+/// Files are stored under the hash of their contents, so the hash already names
+/// exactly one set of bytes and is a strong validator as it stands. The content
+/// type in the url changes the headers a file is sent with but never its bytes,
+/// which is why it plays no part here.
+fn entity_tag(hash: &str) -> String {
+    format!("\"{hash}\"")
+}
+
+/// A thumbnail is different bytes to the file it was made from, so it gets a tag
+/// of its own and a cache holding one is never told it holds the other.
+fn thumbnail_entity_tag(hash: &str) -> String {
+    format!("\"{hash}_thumbnail\"")
+}
+
+/// A compressed copy is different bytes again, and needs a tag that tells it
+/// apart from the file as stored and from the copy in the other encoding.
+fn compressed_entity_tag(hash: &str, encoding: compression::Encoding) -> String {
+    format!("\"{hash}_{}\"", encoding.name())
+}
+
+/// The IMF-fixdate form of a time, which is the one `Last-Modified` is sent in.
+fn http_date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Whether the client already holds what it would be sent.
+///
+/// `If-None-Match` decides it whenever it is present, as RFC 9110 requires, and
+/// is compared weakly so a tag a proxy has marked `W/` still counts.
+/// `If-Modified-Since` is only looked at by clients with no tag to offer, which
+/// in practice means ones that cached a file before tags were sent.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    let if_none_match: Option<&str> = headers
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+
+    let if_modified_since = headers
+        .get(http::header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok());
+
+    match (if_none_match, if_modified_since, last_modified) {
+        (Some(tags), _, _) => tags.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        }),
+        (None, Some(since), Some(last_modified2)) => {
+            chrono::DateTime::<chrono::Utc>::from(last_modified2).timestamp() <= since.timestamp()
+        }
+        _ => false,
+    }
+}
+
+/// Answers a request for a file that is known to exist, given the headers it is
+/// served with.
+///
+/// A client that already has the file is told so with a 304 and a HEAD request
+/// is told its size, and neither of them costs a read of the file itself. The
+/// ones that do need the file are served from memory when it is there.
+fn send_stored_file(
+    builder: http::response::Builder,
+    file_cache: &FileCache,
+    method: &Method,
+    headers: &HeaderMap,
+    path: &str,
+    metadata: &fs::Metadata,
+    etag: &str,
+) -> http::Response<Body> {
+    let last_modified: Option<SystemTime> = metadata.modified().ok();
+
+    let builder2 = match last_modified {
+        Some(last_modified2) => builder.header("Last-Modified", http_date(last_modified2)),
+        None => builder,
+    }
+    .header("ETag", etag);
+
+    if is_not_modified(headers, etag, last_modified) {
+        builder2
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap()
+    } else if method == Method::HEAD {
+        builder2
+            .status(StatusCode::OK)
+            .header("Content-Length", metadata.len())
+            .body(Body::empty())
+            .unwrap()
+    } else {
+        match file_cache.read(path, metadata.len()) {
+            Result::Ok(data) => builder2
+                .status(StatusCode::OK)
+                .body(Body::from(data))
+                .unwrap(),
+            Result::Err(_) => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("File not found"))
+                .unwrap(),
+        }
+    }
+}
+
 async fn get_file_endpoint(
     Path((content_type_index, hash)): Path<(String, String)>,
+    Query(query): Query<FileQuery>,
+    method: Method,
+    headers: HeaderMap,
+    Extension(file_cache): Extension<Arc<FileCache>>,
 ) -> http::Response<Body> {
     let is_valid_hash: bool = hash
         .chars()
         .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_');
 
+    let disposition: String = match content_disposition(&query) {
+        Ok(disposition2) => disposition2,
+        Err(error) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(error))
+                .unwrap();
+        }
+    };
+
     if is_valid_hash {
-        match fs::read(filepath(&hash)) {
-            Result::Ok(data) => {
+        let path = embed_images::stored_filepath(&hash);
+        match fs::metadata(&path) {
+            Result::Ok(metadata) => {
                 let content_type = match content_type_index.parse::<usize>() {
                     Ok(index) => content_types::CONTENT_TYPES.get(index),
                     Err(_) => None,
                 };
 
-                match content_type {
+                let builder = match content_type {
                     Some(content_type2) => Response::builder()
-                        .status(StatusCode::OK)
                         .header("Content-Type", *content_type2)
-                        .header("Content-Disposition", "inline")
+                        .header("Content-Disposition", disposition)
                         .header("Cache-Control", IMMUTABLE_CACHE_CONTROL)
                         .header("Content-Security-Policy", sandbox_csp(content_type2))
-                        .header("X-Content-Type-Options", NOSNIFF)
-                        .body(Body::from(data))
-                        .unwrap(),
+                        .header("X-Content-Type-Options", NOSNIFF),
                     // No content type to send, so the browser would otherwise
                     // sniff one out of the bytes and could land on html. This
                     // branch is only reached by a url with an index the app never
                     // generates, so refusing to guess costs nothing.
                     None => Response::builder()
-                        .status(StatusCode::OK)
+                        .header("Content-Disposition", disposition)
                         .header("Cache-Control", IMMUTABLE_CACHE_CONTROL)
                         .header("Content-Security-Policy", SANDBOX_CSP)
-                        .header("X-Content-Type-Options", NOSNIFF)
-                        .body(Body::from(data))
-                        .unwrap(),
+                        .header("X-Content-Type-Options", NOSNIFF),
+                };
+
+                let is_compressible: bool = content_type
+                    .is_some_and(|content_type2| compression::is_compressible(content_type2));
+
+                // Whether a response is compressed depends on the request, so
+                // a cache in between has to keep the two apart.
+                let builder2 = if is_compressible {
+                    builder.header("Vary", "Accept-Encoding")
+                } else {
+                    builder
+                };
+
+                let encoding: Option<compression::Encoding> = if is_compressible
+                    && (compression::MIN_COMPRESSIBLE_SIZE..=compression::MAX_COMPRESSIBLE_SIZE)
+                        .contains(&metadata.len())
+                {
+                    compression::preferred_encoding(&headers)
+                } else {
+                    None
+                };
+
+                let compressed = match encoding {
+                    Some(encoding2) => compression::compressed_copy(&path, encoding2)
+                        .await
+                        .and_then(|compressed_path| {
+                            let compressed_metadata = fs::metadata(&compressed_path).ok()?;
+                            Some((encoding2, compressed_path, compressed_metadata))
+                        }),
+                    None => None,
+                };
+
+                match compressed {
+                    Some((encoding2, compressed_path, compressed_metadata)) => send_stored_file(
+                        builder2.header("Content-Encoding", encoding2.name()),
+                        &file_cache,
+                        &method,
+                        &headers,
+                        &compressed_path,
+                        &compressed_metadata,
+                        &compressed_entity_tag(&hash, encoding2),
+                    ),
+                    None => send_stored_file(
+                        builder2,
+                        &file_cache,
+                        &method,
+                        &headers,
+                        &path,
+                        &metadata,
+                        &entity_tag(&hash),
+                    ),
                 }
             }
             Result::Err(_) => Response::builder()
@@ -1210,6 +2031,15 @@ async fn get_file_endpoint(
     }
 }
 
+async fn file_cache_stats_endpoint(
+    Extension(file_cache): Extension<Arc<FileCache>>,
+) -> Response<String> {
+    json_response_with_headers(
+        StatusCode::OK,
+        serde_json::to_string(&file_cache.stats()).unwrap(),
+    )
+}
+
 async fn fallback(uri: Uri) -> (StatusCode, String) {
     (StatusCode::NOT_FOUND, format!("No route for {uri}"))
 }
@@ -1391,12 +2221,64 @@ pub struct EmbedRequest {
     pub url: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct EmbedResponse {
     pub title: Option<String>,
     pub description: Option<String>,
     pub image: Option<ImageData>,
     pub created_at: Option<i64>,
+    pub site_name: Option<String>,
+    /// As the page gives it, which is usually but not always a hex colour.
+    pub theme_color: Option<String>,
+    /// The page's own address for itself, from `og:url` or
+    /// `<link rel="canonical">`.
+    pub canonical_url: Option<String>,
+    pub favicon: Option<ImageData>,
+    pub oembed: Option<OEmbed>,
+    pub media: Option<EmbedMedia>,
+    /// More about the page, for the sites there is an extractor for.
+    pub site: Option<SiteEmbed>,
+    /// Why the embed is empty, when nothing could be made of the link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<FetchError>,
+}
+
+/// A video or audio file the page offers that a browser can play directly.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbedMedia {
+    pub url: String,
+    pub kind: MediaKind,
+    pub content_type: String,
+    /// Read from the file when its header was near enough the start to be
+    /// fetched, and otherwise what the page says.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// How the container names the video codec, when the header was read.
+    pub codec: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Video,
+    Audio,
+}
+
+/// The parts of a provider's oEmbed response worth showing. The `html` it
+/// offers is left out on purpose: it is markup from a third party, and usually
+/// a script or an iframe.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OEmbed {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub title: Option<String>,
+    pub author_name: Option<String>,
+    pub author_url: Option<String>,
+    pub provider_name: Option<String>,
+    pub provider_url: Option<String>,
+    pub thumbnail_url: Option<String>,
+    pub thumbnail_width: Option<u32>,
+    pub thumbnail_height: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1405,6 +2287,9 @@ pub struct ImageData {
     pub width: u32,
     pub height: u32,
     pub format: Option<String>,
+    /// A smaller copy to show in its place, for an image kept in storage that
+    /// is big enough to have one.
+    pub thumbnail_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1427,7 +2312,7 @@ pub struct UploadUrl {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     // Regression test for the production crash where pasting a link to a binary
@@ -1439,7 +2324,13 @@ mod tests {
     #[tokio::test]
     async fn post_embed_does_not_crash_on_image_url() {
         let url = "https://at-chat.app/file/1/3SFn-guIRPHsr-z_L9bsJA9CCnWnDzWSKETXPA".to_owned();
-        let response = post_embed(Json(EmbedRequest { url })).await;
+        let response = post_embed(
+            Extension(OutboundClient::new(Vec::new()).expect("the client should build")),
+            test_embed_cache(),
+            Extension(EmbedOptions::default()),
+            Json(EmbedRequest { url }),
+        )
+        .await;
         assert_eq!(
             response.status(),
             StatusCode::OK,
@@ -1464,7 +2355,7 @@ mod tests {
     // ---- helpers for hermetic metadata tests ----
 
     // Encode a solid-colour PNG of the given size, for serving as test image data.
-    fn make_png(width: u32, height: u32) -> Vec<u8> {
+    pub(crate) fn make_png(width: u32, height: u32) -> Vec<u8> {
         let buffer =
             image::ImageBuffer::from_pixel(width, height, image::Rgba([200u8, 100, 50, 255]));
         let mut bytes: Vec<u8> = Vec::new();
@@ -1478,7 +2369,7 @@ mod tests {
     // the server's own base URL (handy for embedding absolute links in HTML) and
     // returns the (path, content-type, body) responses to serve. Returns the base
     // URL the server is listening on.
-    async fn spawn_test_server<F>(make_routes: F) -> String
+    pub(crate) async fn spawn_test_server<F>(make_routes: F) -> String
     where
         F: FnOnce(&str) -> Vec<(&'static str, &'static str, Vec<u8>)>,
     {
@@ -1505,6 +2396,191 @@ mod tests {
         base
     }
 
+    // The stand-ins above listen on this machine, which the real client refuses
+    // to fetch from, so tests that fetch from them allow it by name.
+    pub(crate) fn test_outbound_client() -> OutboundClient {
+        OutboundClient::new(vec![String::from("127.0.0.1")]).expect("the client should build")
+    }
+
+    fn test_embed_cache() -> Extension<Arc<EmbedCache>> {
+        Extension(Arc::new(EmbedCache::new(
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            100,
+        )))
+    }
+
+    // The second request for a link should not fetch anything. The stand-in
+    // answers the same every time, so it is the cache counting a hit that
+    // shows the page was not fetched again.
+    #[tokio::test]
+    async fn serves_a_repeated_embed_from_the_cache() {
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/",
+                "text/html; charset=utf-8",
+                br#"<meta property="og:title" content="Cached">"#.to_vec(),
+            )]
+        })
+        .await;
+        let Extension(embed_cache) = test_embed_cache();
+
+        let request = |url: String| {
+            post_embed(
+                Extension(test_outbound_client()),
+                Extension(embed_cache.clone()),
+                Extension(EmbedOptions::default()),
+                Json(EmbedRequest { url }),
+            )
+        };
+
+        let first = request(format!("{base}/")).await;
+        let second = request(format!("{base}/#fragment")).await;
+        let missing = request(format!("{base}/missing")).await;
+
+        assert_eq!(
+            first.body(),
+            second.body(),
+            "the same page should get the same embed"
+        );
+        assert!(first.body().contains("Cached"), "the embed should be made");
+        let stats = embed_cache.stats();
+        assert_eq!(
+            (stats.hits, stats.misses),
+            (1, 2),
+            "the second request should be a hit"
+        );
+        assert_eq!(
+            missing.status(),
+            StatusCode::OK,
+            "a failed embed is still an answer"
+        );
+
+        assert_eq!(
+            purge_embed_endpoint(
+                Extension(embed_cache.clone()),
+                Json(EmbedRequest {
+                    url: format!("{base}/")
+                })
+            )
+            .await
+            .body(),
+            r#"{"purged":true}"#,
+            "the cached embed should be purged"
+        );
+    }
+
+    // Each of these would otherwise have fetched from the stand-in, which is
+    // exactly where an attacker would point them: a service on this machine.
+    #[tokio::test]
+    async fn endpoints_refuse_to_fetch_from_this_machine() {
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/",
+                "text/html; charset=utf-8",
+                br#"<meta property="og:title" content="Internal">"#.to_vec(),
+            )]
+        })
+        .await;
+        let client = OutboundClient::new(Vec::new()).expect("the client should build");
+
+        let embed = post_embed(
+            Extension(client.clone()),
+            test_embed_cache(),
+            Extension(EmbedOptions::default()),
+            Json(EmbedRequest {
+                url: format!("{base}/"),
+            }),
+        )
+        .await;
+        assert!(
+            !embed.body().contains("Internal"),
+            "an embed should not be made of a page on this machine"
+        );
+
+        let upload = upload_url_endpoint(
+            State(Arc::new(test_state("secret"))),
+            Extension(client.clone()),
+            Json(UploadUrl {
+                url: format!("{base}/"),
+            }),
+        )
+        .await;
+        assert_eq!(
+            upload.status(),
+            StatusCode::FORBIDDEN,
+            "a file on this machine should not be uploaded by url"
+        );
+
+        let custom = send_custom_request(
+            &client,
+            CustomRequest {
+                method: String::from("GET"),
+                url: base.replace("127.0.0.1", "localhost"),
+                headers: Vec::new(),
+                body: None,
+            },
+        )
+        .await;
+        assert!(
+            custom.is_err_and(|error| error.starts_with("Blocked")),
+            "a custom request should not reach this machine, even by name"
+        );
+    }
+
+    // A server that sends part of a file and then goes quiet should not hold
+    // an upload open forever, nor be reported as the backend lacking
+    // permission.
+    #[tokio::test]
+    async fn gives_up_on_a_download_for_upload_that_stalls() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\npart of it")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let started = std::time::Instant::now();
+        let result = fetch_for_upload(
+            &test_outbound_client(),
+            &format!("http://{address}/"),
+            Duration::from_millis(500),
+        )
+        .await;
+
+        assert_eq!(
+            result.map_err(|(status, _)| status),
+            Err(StatusCode::REQUEST_TIMEOUT),
+            "a stalled download should be reported as timing out"
+        );
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "the download should have been given up on, it took {:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_a_download_that_fails_as_a_bad_request() {
+        let result = fetch_for_upload(
+            &test_outbound_client(),
+            "http://127.0.0.1:9/",
+            UPLOAD_URL_TIMEOUT,
+        )
+        .await;
+
+        assert_eq!(
+            result.map_err(|(status, _)| status),
+            Err(StatusCode::BAD_REQUEST),
+            "a download that cannot connect is not a permissions problem"
+        );
+    }
+
     #[tokio::test]
     async fn extracts_opengraph_metadata_from_html() {
         let base = spawn_test_server(|_base| {
@@ -1519,8 +2595,8 @@ mod tests {
         })
         .await;
 
-        let client = reqwest::Client::new();
-        let embed = build_embed(&client, &format!("{base}/"))
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
             .await
             .expect("expected an embed response");
 
@@ -1545,13 +2621,86 @@ mod tests {
         );
     }
 
+    // With images stored, the embed should point at our own copy so that no
+    // viewer's browser has to contact the site, and a big image should come
+    // with a thumbnail the same way an upload does.
+    #[tokio::test]
+    async fn stores_the_embed_image_when_asked_to() {
+        create_storage_dir();
+        let png = make_png(2000, 10);
+        let base = spawn_test_server(move |base| {
+            vec![
+                (
+                    "/",
+                    "text/html; charset=utf-8",
+                    format!(r#"<meta property="og:image" content="{base}/wide.png">"#).into_bytes(),
+                ),
+                ("/wide.png", "image/png", png),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let options = EmbedOptions { store_images: true };
+        let image = build_embed(&client, options, &format!("{base}/"))
+            .await
+            .ok()
+            .and_then(|embed| embed.image)
+            .expect("expected an image");
+
+        let hash = hash_bytes(&make_png(2000, 10));
+        assert_eq!(
+            image.url,
+            format!("/file/2/{hash}"),
+            "the image should be served from storage as a png"
+        );
+        assert_eq!(
+            image.thumbnail_url,
+            Some(format!("/file/t/{hash}")),
+            "a wide image should get a thumbnail"
+        );
+        assert!(
+            !fs::exists(filepath(&hash)).unwrap(),
+            "nobody uploaded the image, so it should not be among the uploads"
+        );
+        assert_eq!(
+            (image.width, image.height),
+            (2000, 10),
+            "the size should be the original's"
+        );
+
+        let file = get_file_endpoint(
+            Path((String::from("2"), hash.clone())),
+            Query(FileQuery::default()),
+            Method::GET,
+            HeaderMap::new(),
+            test_file_cache(),
+        )
+        .await;
+        let thumbnail = get_file_thumbnail_endpoint(
+            Path(hash.clone()),
+            Method::GET,
+            HeaderMap::new(),
+            test_file_cache(),
+        )
+        .await;
+        assert_eq!(
+            (file.status(), thumbnail.status()),
+            (StatusCode::OK, StatusCode::OK),
+            "both copies should be served from the urls the embed gives"
+        );
+
+        let _ = fs::remove_file(embed_images::stored_filepath(&hash));
+        let _ = fs::remove_file(embed_images::stored_thumbnail_filepath(&hash));
+    }
+
     #[tokio::test]
     async fn direct_image_url_returns_image_dimensions() {
         let png = make_png(7, 4);
         let base = spawn_test_server(move |_base| vec![("/pic.png", "image/png", png)]).await;
 
-        let client = reqwest::Client::new();
-        let embed = build_embed(&client, &format!("{base}/pic.png"))
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/pic.png"))
             .await
             .expect("expected an embed response");
 
@@ -1592,8 +2741,8 @@ mod tests {
         })
         .await;
 
-        let client = reqwest::Client::new();
-        let embed = build_embed(&client, &format!("{base}/"))
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
             .await
             .expect("expected an embed response");
 
@@ -1622,12 +2771,16 @@ mod tests {
         })
         .await;
 
-        let client = reqwest::Client::new();
-        let embed = build_embed(&client, &format!("{base}/"))
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
             .await
             .expect("expected an embed response");
 
-        assert!(embed.title.is_none(), "no og:title -> no title");
+        assert_eq!(
+            embed.title.as_deref(),
+            Some("Plain"),
+            "no og:title -> the <title> is used instead"
+        );
         assert!(
             embed.description.is_none(),
             "no og:description -> no description"
@@ -1637,28 +2790,645 @@ mod tests {
             embed.created_at.is_none(),
             "no article:published_time -> no created_at"
         );
+        assert!(
+            embed.site_name.is_none() && embed.theme_color.is_none(),
+            "no og:site_name or theme-color -> neither is set"
+        );
+        assert!(
+            embed.favicon.is_none() && embed.oembed.is_none(),
+            "no icon and no oEmbed link -> neither is set"
+        );
     }
 
+    // Plenty of sites only fill in Twitter's tags, or only the plain HTML ones,
+    // and should get an embed all the same.
     #[tokio::test]
-    async fn oversized_html_is_rejected() {
-        // Build an HTML document comfortably larger than the 1 MB cap.
-        let mut html = String::from(r#"<meta property="og:title" content="Too Big">"#);
-        html.push_str(&"<!-- padding -->".repeat(1024 * 1024 / 10));
+    async fn falls_back_to_twitter_and_plain_html_tags() {
+        let png = make_png(5, 6);
         let base = spawn_test_server(move |_base| {
-            vec![("/", "text/html; charset=utf-8", html.into_bytes())]
+            let html = br##"<html><head>
+                <title>Plain title</title>
+                <meta name="description" content="Plain description">
+                <meta name="twitter:title" content="Twitter title">
+                <meta name="twitter:image" content="/card.png">
+                <meta property="og:site_name" content="Example Site">
+                <meta name="theme-color" content="#336699">
+                </head><body></body></html>"##
+                .to_vec();
+            vec![
+                ("/", "text/html; charset=utf-8", html),
+                ("/card.png", "image/png", png),
+            ]
         })
         .await;
 
-        let client = reqwest::Client::new();
-        assert!(
-            build_embed(&client, &format!("{base}/")).await.is_none(),
-            "HTML larger than the size cap should be rejected rather than parsed"
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+            .await
+            .expect("expected an embed response");
+
+        assert_eq!(
+            embed.title.as_deref(),
+            Some("Twitter title"),
+            "twitter:title should be preferred to <title>"
+        );
+        assert_eq!(
+            embed.description.as_deref(),
+            Some("Plain description"),
+            "meta description should be used when nothing better is given"
+        );
+        assert_eq!(
+            embed
+                .image
+                .map(|image| (image.url, image.width, image.height)),
+            Some((format!("{base}/card.png"), 5, 6)),
+            "twitter:image should be used, resolved against the page"
+        );
+        assert_eq!(
+            embed.site_name.as_deref(),
+            Some("Example Site"),
+            "og:site_name should be extracted"
+        );
+        assert_eq!(
+            embed.theme_color.as_deref(),
+            Some("#336699"),
+            "theme-color should be extracted"
         );
     }
 
-    // --- who an upload is attributed to ---
-
-    fn headers_from(pairs: &[(&str, &str)]) -> HeaderMap {
+    #[tokio::test]
+    async fn finds_the_favicon_the_page_names() {
+        let icon = make_png(32, 32);
+        let base = spawn_test_server(move |_base| {
+            let html = br#"<html><head>
+                <link rel="icon" type="image/svg+xml" href="/icon.svg">
+                <link rel="shortcut icon" href="static/icon.png">
+                </head><body></body></html>"#
+                .to_vec();
+            vec![
+                ("/page/", "text/html; charset=utf-8", html),
+                ("/page/static/icon.png", "image/png", icon),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/page/"))
+            .await
+            .expect("expected an embed response");
+
+        let favicon = embed.favicon.expect("the named icon should be found");
+        assert_eq!(
+            (favicon.url, favicon.width, favicon.height),
+            (format!("{base}/page/static/icon.png"), 32, 32),
+            "the png icon should be used, resolved against the page, and the svg skipped"
+        );
+    }
+
+    // Shorteners and moved sites often send the browser on with a meta refresh
+    // rather than an HTTP redirect, and usually give the target relative to
+    // themselves, in any of several spellings.
+    #[tokio::test]
+    async fn follows_meta_refresh_to_the_real_page() {
+        let base = spawn_test_server(|_base| {
+            vec![
+                (
+                    "/",
+                    "text/html",
+                    br#"<meta http-equiv="refresh" content="0; URL='short/'">"#.to_vec(),
+                ),
+                (
+                    "/short/",
+                    "text/html",
+                    br#"<meta http-equiv="refresh" content="0;url=../final?id=1">"#.to_vec(),
+                ),
+                (
+                    "/final",
+                    "text/html",
+                    br#"<head><meta property="og:title" content="Arrived">
+                    <link rel="canonical" href="/article"></head>"#
+                        .to_vec(),
+                ),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+            .await
+            .expect("expected an embed response");
+
+        assert_eq!(
+            embed.title.as_deref(),
+            Some("Arrived"),
+            "both refreshes should be followed"
+        );
+        assert_eq!(
+            embed.canonical_url,
+            Some(format!("{base}/article")),
+            "the canonical link is relative to the page it was found on"
+        );
+    }
+
+    // Two pages sending the browser back and forth between them must not keep
+    // the embed fetching forever.
+    #[tokio::test]
+    async fn gives_up_on_a_meta_refresh_loop() {
+        let base = spawn_test_server(|_base| {
+            vec![
+                (
+                    "/a",
+                    "text/html",
+                    br#"<title>A</title><meta http-equiv="refresh" content="0; url=/b">"#.to_vec(),
+                ),
+                (
+                    "/b",
+                    "text/html",
+                    br#"<title>B</title><meta http-equiv="refresh" content="0; url=/a">"#.to_vec(),
+                ),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/a"))
+            .await
+            .expect("the page reached last should still be embedded");
+
+        assert_eq!(
+            embed.title.as_deref(),
+            Some("B"),
+            "it should stop after the fifth hop, on the other page"
+        );
+    }
+
+    #[test]
+    fn reads_every_way_of_writing_a_refresh() {
+        for (content, expected) in [
+            ("0; url=https://example.com/", Some("https://example.com/")),
+            ("5;URL='/next'", Some("/next")),
+            ("0, \"next page.html\"", Some("next page.html")),
+            ("0; /plain", Some("/plain")),
+            ("0;url= urlish", Some("urlish")),
+            ("300", None),
+            ("0; url=", None),
+        ] {
+            assert_eq!(
+                refresh_url(content),
+                expected,
+                "{content} should give {expected:?}"
+            );
+        }
+    }
+
+    // Titles from sites that are not in UTF-8 used to come out as replacement
+    // characters.
+    #[tokio::test]
+    async fn reads_pages_in_other_encodings() {
+        let (shift_jis, _, _) =
+            encoding_rs::SHIFT_JIS.encode(r#"<meta property="og:title" content="日本語のページ">"#);
+        let shift_jis = shift_jis.into_owned();
+        let base = spawn_test_server(move |_base| {
+            vec![
+                ("/jp", "text/html; charset=Shift_JIS", shift_jis),
+                (
+                    "/fr",
+                    "text/html",
+                    b"<meta charset=\"windows-1252\"><meta property=\"og:url\" content=\"https://example.fr/\"><title>Caf\xe9</title>"
+                        .to_vec(),
+                ),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let japanese = build_embed(&client, EmbedOptions::default(), &format!("{base}/jp"))
+            .await
+            .expect("expected an embed response");
+        let french = build_embed(&client, EmbedOptions::default(), &format!("{base}/fr"))
+            .await
+            .expect("expected an embed response");
+
+        assert_eq!(
+            japanese.title.as_deref(),
+            Some("日本語のページ"),
+            "the header's charset should be used"
+        );
+        assert_eq!(
+            french.title.as_deref(),
+            Some("Café"),
+            "the meta tag's charset should be used"
+        );
+        assert_eq!(
+            french.canonical_url.as_deref(),
+            Some("https://example.fr/"),
+            "og:url should be reported"
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_favicon_ico() {
+        let icon = make_png(16, 16);
+        let base = spawn_test_server(move |_base| {
+            vec![
+                (
+                    "/",
+                    "text/html; charset=utf-8",
+                    b"<title>No icon</title>".to_vec(),
+                ),
+                ("/favicon.ico", "image/x-icon", icon),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+            .await
+            .expect("expected an embed response");
+
+        assert_eq!(
+            embed.favicon.map(|favicon| favicon.url),
+            Some(format!("{base}/favicon.ico")),
+            "a page that names no icon should get /favicon.ico"
+        );
+    }
+
+    // YouTube, Vimeo, Flickr and the like say more through oEmbed than through
+    // their meta tags, and some say nothing through meta tags at all.
+    #[tokio::test]
+    async fn discovers_oembed() {
+        let thumbnail = make_png(12, 9);
+        let base = spawn_test_server(move |base| {
+            let html = format!(
+                r#"<html><head>
+                <link rel="alternate" type="application/json+oembed"
+                    href="{base}/oembed?url=video&amp;format=json">
+                </head><body></body></html>"#
+            )
+            .into_bytes();
+            let oembed = format!(
+                r#"{{"type": "video", "version": "1.0", "title": "A video",
+                    "author_name": "Someone", "provider_name": "VideoSite",
+                    "thumbnail_url": "{base}/thumbnail.png",
+                    "thumbnail_width": "12", "thumbnail_height": 9,
+                    "html": "<iframe src=\"https://videosite.example/embed\"></iframe>"}}"#
+            )
+            .into_bytes();
+            vec![
+                ("/video", "text/html; charset=utf-8", html),
+                ("/oembed", "application/json", oembed),
+                ("/thumbnail.png", "image/png", thumbnail),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/video"))
+            .await
+            .expect("expected an embed response");
+
+        let oembed = embed.oembed.expect("the oEmbed link should be followed");
+        assert_eq!(oembed.kind.as_deref(), Some("video"), "type should be read");
+        assert_eq!(
+            oembed.author_name.as_deref(),
+            Some("Someone"),
+            "author_name should be read"
+        );
+        assert_eq!(
+            (oembed.thumbnail_width, oembed.thumbnail_height),
+            (Some(12), Some(9)),
+            "sizes should be read whether given as numbers or strings"
+        );
+        assert_eq!(
+            embed.title.as_deref(),
+            Some("A video"),
+            "the oEmbed title should fill in for a missing og:title"
+        );
+        assert_eq!(
+            embed.site_name.as_deref(),
+            Some("VideoSite"),
+            "the provider should fill in for a missing og:site_name"
+        );
+        assert_eq!(
+            embed.image.map(|image| (image.width, image.height)),
+            Some((12, 9)),
+            "the oEmbed thumbnail should fill in for a missing og:image"
+        );
+    }
+
+    fn video_sample(name: &str) -> Vec<u8> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/samples")
+            .join(name);
+        fs::read(&path).unwrap_or_else(|error| panic!("could not read {path:?}: {error}"))
+    }
+
+    #[tokio::test]
+    async fn reads_the_video_a_page_offers() {
+        let mp4 = video_sample("landscape.mp4");
+        let base = spawn_test_server(move |_base| {
+            let html = br#"<html><head>
+                <meta property="og:video" content="/media/clip.mp4">
+                <meta property="og:video:type" content="video/mp4">
+                <meta property="og:video:width" content="1">
+                <meta property="og:video:height" content="1">
+                </head><body></body></html>"#
+                .to_vec();
+            vec![
+                ("/", "text/html; charset=utf-8", html),
+                ("/media/clip.mp4", "video/mp4", mp4),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+            .await
+            .expect("expected an embed response");
+
+        let media = embed.media.expect("the video should be found and probed");
+        assert_eq!(
+            media.url,
+            format!("{base}/media/clip.mp4"),
+            "the video url should be resolved against the page"
+        );
+        assert_eq!(media.kind, MediaKind::Video, "it is a video");
+        assert_eq!(
+            media.content_type, "video/mp4",
+            "the served type should be kept"
+        );
+        assert_eq!(
+            (media.width, media.height),
+            (Some(640), Some(360)),
+            "the size in the file's header should win over the page's"
+        );
+        assert_eq!(
+            media.codec.as_deref(),
+            Some("avc1.64001F"),
+            "the codec should be read from the header"
+        );
+    }
+
+    // YouTube and most other video sites give the url of their HTML player as
+    // og:video, which a <video> tag cannot play.
+    #[tokio::test]
+    async fn skips_a_video_that_is_a_player_page_and_uses_the_audio() {
+        let base = spawn_test_server(|_base| {
+            let html = br#"<html><head>
+                <meta property="og:video" content="/player">
+                <meta property="og:video:type" content="text/html">
+                <meta property="og:audio" content="/track">
+                <meta property="og:audio:type" content="audio/mpeg">
+                </head><body></body></html>"#
+                .to_vec();
+            vec![
+                ("/", "text/html; charset=utf-8", html),
+                ("/player", "text/html", b"<iframe></iframe>".to_vec()),
+                ("/track", "application/octet-stream", vec![0u8; 64]),
+            ]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+            .await
+            .expect("expected an embed response");
+
+        let media = embed.media.expect("the audio should be used instead");
+        assert_eq!(media.kind, MediaKind::Audio, "it is audio");
+        assert_eq!(
+            media.content_type, "audio/mpeg",
+            "the page's type should be used when the server does not say"
+        );
+        assert_eq!(
+            (media.width, media.height),
+            (None, None),
+            "audio has no size"
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_out_media_that_is_not_there() {
+        let base = spawn_test_server(|_base| {
+            let html = br#"<html><head>
+                <meta property="og:video" content="/missing.mp4">
+                <meta property="og:video:type" content="video/mp4">
+                </head><body></body></html>"#
+                .to_vec();
+            vec![("/", "text/html; charset=utf-8", html)]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        let embed = build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+            .await
+            .expect("expected an embed response");
+
+        assert!(
+            embed.media.is_none(),
+            "a video that cannot be fetched should not be offered"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_html_is_rejected() {
+        // Build an HTML document comfortably larger than the 1 MB cap.
+        let mut html = String::from(r#"<meta property="og:title" content="Too Big">"#);
+        html.push_str(&"<!-- padding -->".repeat(1024 * 1024 / 10));
+        let base = spawn_test_server(move |_base| {
+            vec![("/", "text/html; charset=utf-8", html.into_bytes())]
+        })
+        .await;
+
+        let client = test_outbound_client();
+        assert_eq!(
+            build_embed(&client, EmbedOptions::default(), &format!("{base}/"))
+                .await
+                .err(),
+            Some(FetchError::TooLarge),
+            "HTML larger than the size cap should be rejected rather than parsed"
+        );
+
+        // The empty embed sent back says why, and so does the cached copy.
+        let embed_cache = EmbedCache::new(Duration::from_secs(60), Duration::from_secs(60), 10);
+        let embed = build_and_cache_embed(
+            &client,
+            &embed_cache,
+            EmbedOptions::default(),
+            &format!("{base}/"),
+        )
+        .await;
+        assert_eq!(
+            embed.error,
+            Some(FetchError::TooLarge),
+            "the embed should be remembered as too large"
+        );
+        assert!(
+            embed.json.contains(r#""error":"too_large""#),
+            "the response should say the link was too large, got {}",
+            embed.json
+        );
+        assert_eq!(
+            embed_cache.get(&format!("{base}/")),
+            Some(embed),
+            "a hit should give the same answer as the miss"
+        );
+    }
+
+    // An image is allowed to be bigger than a page, but not without limit, and
+    // running into the limit is told apart from the link simply not working.
+    #[tokio::test]
+    async fn oversized_image_is_reported_as_too_large() {
+        let too_large = vec![0u8; MAX_EMBED_IMAGE_BYTES as usize + 1];
+        let base =
+            spawn_test_server(move |_base| vec![("/huge.png", "image/png", too_large)]).await;
+
+        let client = test_outbound_client();
+        assert!(
+            matches!(
+                fetch_content(&client, &format!("{base}/huge.png")).await,
+                Err(FetchError::TooLarge)
+            ),
+            "an image over the cap should be reported as too large"
+        );
+        assert!(
+            matches!(
+                fetch_content(&client, &format!("{base}/missing.png")).await,
+                Ok(FetchedContent::Html { .. })
+            ),
+            "a small error page is fetched like any other page"
+        );
+    }
+
+    // Sites decide which page to send by who is asking and what for, so every
+    // fetch says who we are, that we want a page, and in which language.
+    #[tokio::test]
+    async fn says_who_is_asking_and_for_what() {
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::get(|headers: HeaderMap| async move {
+                let header = |name: &str| {
+                    headers
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default()
+                        .to_owned()
+                };
+                (
+                    [("content-type", "text/plain")],
+                    format!(
+                        "{}\n{}\n{}",
+                        header("user-agent"),
+                        header("accept"),
+                        header("accept-language")
+                    ),
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+
+        let Ok(FetchedContent::Html { text, .. }) =
+            fetch_content(&test_outbound_client(), &format!("{base}/")).await
+        else {
+            panic!("expected the headers back");
+        };
+
+        assert_eq!(
+            text,
+            format!(
+                "{}\n{EMBED_ACCEPT}\n{EMBED_ACCEPT_LANGUAGE}",
+                outbound::DEFAULT_USER_AGENT
+            ),
+            "the request should carry our user agent and accept headers"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_out_of_what_robots_txt_disallows() {
+        let base = spawn_test_server(|_base| {
+            vec![
+                (
+                    "/robots.txt",
+                    "text/plain",
+                    b"User-agent: *\nDisallow: /private\n".to_vec(),
+                ),
+                (
+                    "/private",
+                    "text/html",
+                    br#"<meta property="og:title" content="Private">"#.to_vec(),
+                ),
+                (
+                    "/public",
+                    "text/html",
+                    br#"<meta property="og:title" content="Public">"#.to_vec(),
+                ),
+            ]
+        })
+        .await;
+        let client = test_outbound_client()
+            .with_robots(robots::RobotsCache::new(outbound::DEFAULT_USER_AGENT));
+
+        assert_eq!(
+            fetch_content(&client, &format!("{base}/private"))
+                .await
+                .err(),
+            Some(FetchError::Disallowed),
+            "a page robots.txt disallows should not be fetched"
+        );
+        assert!(
+            build_embed(&client, EmbedOptions::default(), &format!("{base}/public"))
+                .await
+                .is_ok(),
+            "a page robots.txt allows should still be embedded"
+        );
+    }
+
+    // A redirect is followed before robots.txt could be asked about where it
+    // leads, so a link that looks allowed must not get an embed of a page that
+    // is not.
+    #[tokio::test]
+    async fn keeps_out_of_what_a_redirect_leads_to() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let port = listener.local_addr().unwrap().port();
+        let router = axum::Router::new()
+            .route(
+                "/robots.txt",
+                get(|| async { "User-agent: *\nDisallow: /private\n" }),
+            )
+            .route(
+                "/go",
+                get(|| async { axum::response::Redirect::temporary("/private") }),
+            )
+            .route(
+                "/private",
+                get(|| async { r#"<meta property="og:title" content="Private">"# }),
+            );
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, router).await;
+        });
+        let client = test_outbound_client()
+            .with_robots(robots::RobotsCache::new(outbound::DEFAULT_USER_AGENT));
+
+        assert_eq!(
+            fetch_content(&client, &format!("http://127.0.0.1:{port}/go"))
+                .await
+                .err(),
+            Some(FetchError::Disallowed),
+            "a redirect to a page robots.txt disallows should not be embedded"
+        );
+    }
+
+    // --- who an upload is attributed to ---
+
+    fn headers_from(pairs: &[(&str, &str)]) -> HeaderMap {
         let mut headers = HeaderMap::new();
         for (name, value) in pairs {
             headers.insert(
@@ -1671,7 +3441,7 @@ mod tests {
 
     fn test_state(secret: &str) -> Mutex<AppState> {
         Mutex::new(AppState {
-            secret_key: secret.as_bytes().to_vec(),
+            secret: ServerSecret::new(secret),
         })
     }
 
@@ -1821,6 +3591,27 @@ mod tests {
         );
     }
 
+    // Each test gets a cache of its own, so none of them is served something
+    // another left behind.
+    fn test_file_cache() -> Extension<Arc<FileCache>> {
+        Extension(Arc::new(FileCache::new(
+            file_cache::DEFAULT_BUDGET_BYTES,
+            file_cache::DEFAULT_MAX_FILE_BYTES,
+        )))
+    }
+
+    // Where stored files live, which a fresh checkout does not have yet.
+    pub(crate) fn create_storage_dir() {
+        for path in [
+            "./var",
+            "./var/lib",
+            "./var/lib/atchat",
+            "./var/lib/atchat/storage",
+        ] {
+            create_dir_if_missing(String::from(path));
+        }
+    }
+
     // Put a file where the endpoint looks for it and ask for it back, so the
     // tests below can read the headers it came with. Each caller passes its own
     // hash, which is the filename, so the two never tread on each other.
@@ -1829,13 +3620,45 @@ mod tests {
         content_type_index: String,
         bytes: &[u8],
     ) -> http::Response<Body> {
-        create_dir_if_missing("./var".to_string());
-        create_dir_if_missing("./var/lib".to_string());
-        create_dir_if_missing("./var/lib/atchat".to_string());
-        create_dir_if_missing("./var/lib/atchat/storage".to_string());
+        get_stored_file_with_query(hash, content_type_index, bytes, FileQuery::default()).await
+    }
+
+    async fn get_stored_file_with_query(
+        hash: &str,
+        content_type_index: String,
+        bytes: &[u8],
+        query: FileQuery,
+    ) -> http::Response<Body> {
+        request_stored_file(
+            hash,
+            content_type_index,
+            bytes,
+            query,
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await
+    }
+
+    async fn request_stored_file(
+        hash: &str,
+        content_type_index: String,
+        bytes: &[u8],
+        query: FileQuery,
+        method: Method,
+        headers: HeaderMap,
+    ) -> http::Response<Body> {
+        create_storage_dir();
         fs::write(filepath(hash), bytes).expect("failed to write the file to serve");
 
-        let response = get_file_endpoint(Path((content_type_index, hash.to_string()))).await;
+        let response = get_file_endpoint(
+            Path((content_type_index, hash.to_owned())),
+            Query(query),
+            method,
+            headers,
+            test_file_cache(),
+        )
+        .await;
 
         let _ = fs::remove_file(filepath(hash));
 
@@ -1970,4 +3793,456 @@ mod tests {
             "with no type declared, this is what stops the browser picking one"
         );
     }
+
+    // --- how a file is handed over ---
+
+    fn content_disposition_of(response: &http::Response<Body>) -> Option<&str> {
+        response
+            .headers()
+            .get("Content-Disposition")
+            .and_then(|value| value.to_str().ok())
+    }
+
+    fn query(filename: Option<&str>, download: Option<&str>) -> FileQuery {
+        FileQuery {
+            filename: filename.map(str::to_owned),
+            download: download.map(str::to_owned),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_file_is_shown_inline_when_nothing_else_is_asked_for() {
+        let response = get_stored_file(
+            "inlineFile",
+            index_of_content_type("video/mp4").to_string(),
+            b"not really a video",
+        )
+        .await;
+
+        assert_eq!(
+            content_disposition_of(&response),
+            Some("inline"),
+            "a link without a filename or download should behave as it always has"
+        );
+    }
+
+    // Without the name in the url, saving a file gives it the hash as a name.
+    #[tokio::test]
+    async fn a_file_saves_under_the_name_in_the_url() {
+        let response = get_stored_file_with_query(
+            "namedFile",
+            index_of_content_type("video/mp4").to_string(),
+            b"not really a video",
+            query(Some("holiday.mp4"), None),
+        )
+        .await;
+
+        assert_eq!(
+            content_disposition_of(&response),
+            Some("inline; filename=\"holiday.mp4\""),
+            "the uploaded name should be offered to the browser"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_file_can_be_forced_to_download() {
+        let response = get_stored_file_with_query(
+            "downloadedFile",
+            index_of_content_type("text/plain; charset=UTF-8").to_string(),
+            b"some notes",
+            query(Some("notes.txt"), Some("1")),
+        )
+        .await;
+
+        assert_eq!(
+            content_disposition_of(&response),
+            Some("attachment; filename=\"notes.txt\""),
+            "download=1 should ask the browser to save the file"
+        );
+    }
+
+    // A header can only hold ASCII, so the real name travels percent encoded in
+    // `filename*` with a readable stand-in beside it for older clients.
+    #[test]
+    fn a_name_that_is_not_ascii_is_encoded_as_utf8() {
+        assert_eq!(
+            content_disposition(&query(Some("café menu.pdf"), Some("1"))),
+            Ok(String::from(
+                "attachment; filename=\"caf_ menu.pdf\"; filename*=UTF-8''caf%C3%A9%20menu.pdf"
+            )),
+            "a non-ASCII name should be sent in filename* with an ASCII fallback"
+        );
+    }
+
+    // Quotes and backslashes would end or escape the quoted string, and some
+    // browsers decode percent signs in it, so none of them go in as they are.
+    #[test]
+    fn characters_that_mean_something_in_the_header_are_kept_out_of_the_fallback() {
+        assert_eq!(
+            content_disposition(&query(Some("a\"b%20.txt"), None)),
+            Ok(String::from(
+                "inline; filename=\"a_b_20.txt\"; filename*=UTF-8''a%22b%2520.txt"
+            )),
+            "the fallback should not carry characters the header gives meaning to"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_filename_that_reaches_outside_a_directory_is_refused() {
+        for filename in [
+            "../../.bashrc",
+            "/etc/passwd",
+            "..\\windows",
+            "..",
+            "",
+            "a\nb",
+        ] {
+            let response = get_stored_file_with_query(
+                "traversalFile",
+                index_of_content_type("text/plain; charset=UTF-8").to_string(),
+                b"some notes",
+                query(Some(filename), Some("1")),
+            )
+            .await;
+
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_REQUEST,
+                "{filename:?} is not a filename and should be refused"
+            );
+        }
+    }
+
+    #[test]
+    fn a_filename_longer_than_a_filesystem_allows_is_refused() {
+        let filename = "a".repeat(MAX_DOWNLOAD_FILENAME_BYTES + 1);
+        assert!(
+            content_disposition(&query(Some(&filename), None)).is_err(),
+            "a name longer than any filesystem accepts should be refused"
+        );
+    }
+
+    #[test]
+    fn only_0_and_1_are_download_values() {
+        assert!(
+            content_disposition(&query(None, Some("yes"))).is_err(),
+            "an unknown download value should be refused rather than guessed at"
+        );
+        assert_eq!(
+            content_disposition(&query(None, Some("0"))),
+            Ok(String::from("inline")),
+            "download=0 should be the same as leaving it out"
+        );
+    }
+
+    // --- conditional requests ---
+
+    async fn body_of(response: http::Response<Body>) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("failed to read the response body")
+            .to_vec()
+    }
+
+    fn header_of<'a>(response: &'a http::Response<Body>, name: &str) -> Option<&'a str> {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    async fn conditional_request(
+        hash: &str,
+        method: Method,
+        headers: &[(&str, &str)],
+    ) -> http::Response<Body> {
+        request_stored_file(
+            hash,
+            index_of_content_type("image/png").to_string(),
+            b"not really a png",
+            FileQuery::default(),
+            method,
+            headers_from(headers),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn a_file_is_tagged_with_its_hash() {
+        let response = conditional_request("taggedFile", Method::GET, &[]).await;
+
+        assert_eq!(
+            header_of(&response, "ETag"),
+            Some("\"taggedFile\""),
+            "the hash already names the bytes, so it should be the tag"
+        );
+        assert!(
+            header_of(&response, "Last-Modified").is_some_and(|date| date.ends_with(" GMT")),
+            "the time the file was stored should be sent as an HTTP date"
+        );
+    }
+
+    // A browser that evicted the file's cache headers but kept the tag, or a
+    // proxy in between, only needs telling that nothing changed.
+    #[tokio::test]
+    async fn a_client_holding_the_tag_is_told_nothing_changed() {
+        for if_none_match in [
+            "\"revalidatedFile\"",
+            "\"other\", W/\"revalidatedFile\"",
+            "*",
+        ] {
+            let response = conditional_request(
+                "revalidatedFile",
+                Method::GET,
+                &[("if-none-match", if_none_match)],
+            )
+            .await;
+
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_MODIFIED,
+                "If-None-Match: {if_none_match} should match the file"
+            );
+            assert_eq!(
+                header_of(&response, "ETag"),
+                Some("\"revalidatedFile\""),
+                "a 304 should still carry the tag it is confirming"
+            );
+            assert!(
+                body_of(response).await.is_empty(),
+                "a 304 should not resend the file"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_client_holding_a_different_tag_is_sent_the_file() {
+        let response = conditional_request(
+            "changedFile",
+            Method::GET,
+            &[("if-none-match", "\"somethingElse\"")],
+        )
+        .await;
+
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "a different tag is a different file"
+        );
+        assert_eq!(
+            body_of(response).await,
+            b"not really a png",
+            "the file should be sent in full"
+        );
+    }
+
+    // If-None-Match is the more precise of the two, so when it says the client
+    // has something else the date must not overrule it.
+    #[tokio::test]
+    async fn the_tag_is_preferred_over_the_date() {
+        let response = conditional_request(
+            "taggedAndDatedFile",
+            Method::GET,
+            &[
+                ("if-none-match", "\"somethingElse\""),
+                ("if-modified-since", "Fri, 01 Jan 2100 00:00:00 GMT"),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "a mismatched tag should be sent the file whatever the date says"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_client_with_only_a_date_is_compared_by_date() {
+        let later = conditional_request(
+            "datedFile",
+            Method::GET,
+            &[("if-modified-since", "Fri, 01 Jan 2100 00:00:00 GMT")],
+        )
+        .await;
+        assert_eq!(
+            later.status(),
+            StatusCode::NOT_MODIFIED,
+            "a copy from after the file was stored is up to date"
+        );
+
+        let earlier = conditional_request(
+            "datedFile",
+            Method::GET,
+            &[("if-modified-since", "Mon, 01 Jan 2001 00:00:00 GMT")],
+        )
+        .await;
+        assert_eq!(
+            earlier.status(),
+            StatusCode::OK,
+            "a copy from before the file was stored is out of date"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_head_request_gets_the_size_and_type_without_the_file() {
+        let response = conditional_request("headFile", Method::HEAD, &[]).await;
+
+        assert_eq!(response.status(), StatusCode::OK, "the file exists");
+        assert_eq!(
+            header_of(&response, "Content-Length"),
+            Some("16"),
+            "the size should come from the file on disk"
+        );
+        assert_eq!(
+            header_of(&response, "Content-Type"),
+            Some("image/png"),
+            "the type should be the one the url asked for"
+        );
+        assert!(
+            body_of(response).await.is_empty(),
+            "a HEAD response has no body"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_thumbnail_has_a_tag_of_its_own() {
+        create_storage_dir();
+        fs::write(thumbnail_filepath("taggedThumbnail"), b"not really a webp")
+            .expect("failed to write the thumbnail to serve");
+
+        let response = get_file_thumbnail_endpoint(
+            Path(String::from("taggedThumbnail")),
+            Method::GET,
+            HeaderMap::new(),
+            test_file_cache(),
+        )
+        .await;
+        let etag = header_of(&response, "ETag").map(str::to_owned);
+
+        let revalidated = get_file_thumbnail_endpoint(
+            Path(String::from("taggedThumbnail")),
+            Method::GET,
+            headers_from(&[("if-none-match", etag.as_deref().unwrap_or_default())]),
+            test_file_cache(),
+        )
+        .await;
+
+        let _ = fs::remove_file(thumbnail_filepath("taggedThumbnail"));
+
+        assert_eq!(
+            etag.as_deref(),
+            Some("\"taggedThumbnail_thumbnail\""),
+            "a thumbnail should not share a tag with the file it was made from"
+        );
+        assert_eq!(
+            revalidated.status(),
+            StatusCode::NOT_MODIFIED,
+            "a thumbnail should be revalidated like any other file"
+        );
+    }
+
+    // --- compression ---
+
+    fn remove_compressed_copies(hash: &str) {
+        for encoding in [compression::Encoding::Brotli, compression::Encoding::Gzip] {
+            let _ = fs::remove_file(format!("{}_{}", filepath(hash), encoding.name()));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_text_file_is_sent_compressed_to_a_client_that_accepts_it() {
+        let log: Vec<u8> = "12:00:00 INFO nothing happened\n".repeat(200).into_bytes();
+
+        let response = request_stored_file(
+            "compressedLog",
+            index_of_content_type("text/plain; charset=UTF-8").to_string(),
+            &log,
+            FileQuery::default(),
+            Method::GET,
+            headers_from(&[("accept-encoding", "gzip, deflate, br")]),
+        )
+        .await;
+        remove_compressed_copies("compressedLog");
+
+        assert_eq!(
+            header_of(&response, "Content-Encoding"),
+            Some("br"),
+            "a log file should be sent compressed"
+        );
+        assert_eq!(
+            header_of(&response, "Vary"),
+            Some("Accept-Encoding"),
+            "caches need to know the response depends on Accept-Encoding"
+        );
+        assert_eq!(
+            header_of(&response, "ETag"),
+            Some("\"compressedLog_br\""),
+            "the compressed copy is different bytes and needs a tag of its own"
+        );
+
+        let mut decompressed: Vec<u8> = Vec::new();
+        std::io::Read::read_to_end(
+            &mut brotli::Decompressor::new(body_of(response).await.as_slice(), 4096),
+            &mut decompressed,
+        )
+        .expect("the body should be valid brotli");
+        assert_eq!(decompressed, log, "the body should decompress to the file");
+    }
+
+    #[tokio::test]
+    async fn a_text_file_is_sent_as_stored_to_a_client_that_does_not_accept_compression() {
+        let log: Vec<u8> = "12:00:00 INFO nothing happened\n".repeat(200).into_bytes();
+
+        let response = request_stored_file(
+            "uncompressedLog",
+            index_of_content_type("text/plain; charset=UTF-8").to_string(),
+            &log,
+            FileQuery::default(),
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await;
+        remove_compressed_copies("uncompressedLog");
+
+        assert!(
+            header_of(&response, "Content-Encoding").is_none(),
+            "a client that did not ask for compression should not get it"
+        );
+        assert_eq!(
+            header_of(&response, "Vary"),
+            Some("Accept-Encoding"),
+            "the response would have differed had the client asked"
+        );
+        assert_eq!(
+            body_of(response).await,
+            log,
+            "the file should be sent as it is"
+        );
+    }
+
+    // Images and video are compressed already, and compressing them again only
+    // spends time making them bigger.
+    #[tokio::test]
+    async fn media_is_never_compressed() {
+        let response = request_stored_file(
+            "neverCompressedImage",
+            index_of_content_type("image/png").to_string(),
+            &make_png(64, 64),
+            FileQuery::default(),
+            Method::GET,
+            headers_from(&[("accept-encoding", "gzip, br")]),
+        )
+        .await;
+        remove_compressed_copies("neverCompressedImage");
+
+        assert!(
+            header_of(&response, "Content-Encoding").is_none(),
+            "an image should be sent as it was stored"
+        );
+        assert!(
+            header_of(&response, "Vary").is_none(),
+            "an image is the same whatever the client accepts"
+        );
+    }
 }
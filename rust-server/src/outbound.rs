@@ -0,0 +1,608 @@
+//! The client every request we send to a url someone else chose goes through.
+//!
+//! Embeds, uploads by url and the backend's custom requests all fetch whatever
+//! url they are handed. Without a check that includes `http://localhost:8000`,
+//! where Lamdera answers RPC calls, `169.254.169.254`, where a cloud host hands
+//! out its credentials, and anything else on the network the server sits in.
+//!
+//! So every host is resolved here before it is connected to, and only public
+//! addresses are let through. Doing it in the resolver means the address that
+//! was checked is the address that is connected to, so a name that resolves to
+//! somewhere public the first time and somewhere private the second gets
+//! nowhere. It also covers every redirect, since each one is resolved afresh.
+//! Urls that give an address rather than a name never reach the resolver, and
+//! are checked on their own before the request and before each redirect.
+
+use crate::robots::RobotsCache;
+use axum::body::Bytes;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Method, RequestBuilder, Url};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+/// Hosts that may be fetched from wherever they resolve to, unless the server
+/// is told otherwise in `OUTBOUND_ALLOWED_HOSTS`. Discord's API is the one host
+/// the backend has to be able to reach, including when it is reached through a
+/// proxy on the local network.
+pub const DEFAULT_ALLOWED_HOSTS: &str = "discord.com";
+
+/// Who we say we are, unless the server is told otherwise in
+/// `OUTBOUND_USER_AGENT`. Sites see a lot of anonymous scraping, and some turn
+/// away anything that does not say what it is, so this names the bot and where
+/// to read about it. The `Mozilla/5.0 (compatible; ...)` around it is how link
+/// preview bots are expected to look, and gets the same page a browser would.
+pub const DEFAULT_USER_AGENT: &str =
+    "Mozilla/5.0 (compatible; AtChatBot/1.0; +https://at-chat.app)";
+
+/// As many redirects as reqwest follows by default.
+const MAX_REDIRECTS: usize = 10;
+
+/// Why a url was not fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blocked {
+    InvalidUrl,
+    /// Only `http` and `https` are fetched, so nothing can be read off disk
+    /// with `file:` or the like.
+    Scheme,
+    /// The host is, or resolves to, an address on our own network.
+    Address(IpAddr),
+}
+
+impl std::fmt::Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUrl => write!(f, "Not a valid url"),
+            Self::Scheme => write!(f, "Only http and https urls can be fetched"),
+            Self::Address(address) => write!(f, "{address} is not a public address"),
+        }
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+#[derive(Clone)]
+pub struct OutboundClient {
+    client: reqwest::Client,
+    allowed_hosts: Arc<Vec<String>>,
+    user_agent: Arc<str>,
+    /// The robots.txt of each site fetched from, when the server is asked to
+    /// respect them.
+    robots: Option<Arc<RobotsCache>>,
+}
+
+impl OutboundClient {
+    /// `allowed_hosts` are fetched from whatever address they resolve to.
+    /// Every other host has to be public. Fails only if the TLS backend cannot
+    /// be set up.
+    pub fn new(allowed_hosts: Vec<String>) -> Result<Self, reqwest::Error> {
+        let allowed_hosts = Arc::new(
+            allowed_hosts
+                .into_iter()
+                .map(|host| host.trim().to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect::<Vec<String>>(),
+        );
+
+        let redirect_hosts = allowed_hosts.clone();
+
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(PublicResolver {
+                allowed_hosts: allowed_hosts.clone(),
+            }))
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("Too many redirects")
+                } else {
+                    match check_url(attempt.url(), &redirect_hosts) {
+                        Ok(()) => attempt.follow(),
+                        Err(blocked) => attempt.error(blocked),
+                    }
+                }
+            }))
+            // A proxy would do the resolving itself, and the checks here would
+            // only ever see the proxy's address.
+            .no_proxy()
+            .build()?;
+
+        Ok(Self {
+            client,
+            allowed_hosts,
+            user_agent: Arc::from(DEFAULT_USER_AGENT),
+            robots: None,
+        })
+    }
+
+    /// The hosts in `OUTBOUND_ALLOWED_HOSTS`, separated by commas, and the
+    /// `User-Agent` in `OUTBOUND_USER_AGENT`. Robots.txt is respected unless
+    /// `OUTBOUND_RESPECT_ROBOTS_TXT` is 0.
+    pub fn from_env() -> Result<Self, reqwest::Error> {
+        let allowed_hosts: String = std::env::var("OUTBOUND_ALLOWED_HOSTS")
+            .unwrap_or_else(|_| DEFAULT_ALLOWED_HOSTS.to_owned());
+        let user_agent: String =
+            std::env::var("OUTBOUND_USER_AGENT").unwrap_or_else(|_| DEFAULT_USER_AGENT.to_owned());
+
+        let client = Self::new(allowed_hosts.split(',').map(String::from).collect())?
+            .with_user_agent(&user_agent);
+
+        if crate::env_u64("OUTBOUND_RESPECT_ROBOTS_TXT", 1) == 0 {
+            Ok(client)
+        } else {
+            Ok(client.with_robots(RobotsCache::new(&user_agent)))
+        }
+    }
+
+    /// Sends every request with this `User-Agent`.
+    pub fn with_user_agent(self, user_agent: &str) -> Self {
+        Self {
+            user_agent: Arc::from(user_agent),
+            ..self
+        }
+    }
+
+    /// Makes [`Self::robots_allow`] go by each site's robots.txt.
+    pub fn with_robots(self, robots: RobotsCache) -> Self {
+        Self {
+            robots: Some(Arc::new(robots)),
+            ..self
+        }
+    }
+
+    /// Whether the site's robots.txt lets us fetch this url. Always true when
+    /// robots.txt is not being respected.
+    pub async fn robots_allow(&self, url: &str) -> bool {
+        match &self.robots {
+            Some(robots) => robots.allows(self, url).await,
+            None => true,
+        }
+    }
+
+    /// Whether the site's robots.txt lets us use the response to a request for
+    /// `url`, wherever its redirects led. Redirects are followed before their
+    /// targets' robots.txt could be fetched, so the page they end on is
+    /// checked once it is reached, and its response is not read if it is
+    /// disallowed.
+    pub async fn robots_allow_response(&self, url: &str, response: &reqwest::Response) -> bool {
+        response.url().as_str() == url || self.robots_allow(response.url().as_str()).await
+    }
+
+    pub fn get(&self, url: &str) -> Result<RequestBuilder, Blocked> {
+        self.request(Method::GET, url)
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> Result<RequestBuilder, Blocked> {
+        let Ok(url2) = Url::parse(url) else {
+            return Err(Blocked::InvalidUrl);
+        };
+
+        check_url(&url2, &self.allowed_hosts)?;
+        Ok(self
+            .client
+            .request(method, url2)
+            .header(reqwest::header::USER_AGENT, self.user_agent.as_ref()))
+    }
+}
+
+/// Whether a request that failed did so because it was led somewhere it is not
+/// allowed to go, either by a redirect or by a name resolving to a private
+/// address.
+pub fn blocked_by(error: &reqwest::Error) -> Option<Blocked> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+
+    while let Some(error2) = source {
+        if let Some(blocked) = error2.downcast_ref::<Blocked>() {
+            return Some(*blocked);
+        }
+        source = error2.source();
+    }
+
+    None
+}
+
+/// Why a response body could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    /// The body is bigger than it was allowed to be.
+    TooLarge,
+    Interrupted,
+}
+
+/// Reads a response body, giving up as soon as it is known to be over `limit`
+/// bytes. That is before any of it is read if the server gives a
+/// `Content-Length`, and otherwise the moment that many bytes have arrived, so
+/// a link to something enormous is never downloaded in full.
+pub async fn read_capped(mut response: reqwest::Response, limit: u64) -> Result<Bytes, ReadError> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Err(ReadError::TooLarge);
+    }
+
+    let mut body: Vec<u8> = Vec::new();
+
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if (body.len() + chunk.len()) as u64 > limit {
+                    return Err(ReadError::TooLarge);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => return Ok(Bytes::from(body)),
+            Err(_) => return Err(ReadError::Interrupted),
+        }
+    }
+}
+
+/// The first `limit` bytes of a response body, or all of it if it is shorter.
+/// Whatever comes after is never downloaded, which is what makes it cheap to
+/// look at the start of a file that might be gigabytes long, even from a
+/// server that ignores `Range` and sends the whole thing.
+pub async fn read_prefix(mut response: reqwest::Response, limit: usize) -> Option<Bytes> {
+    let mut body: Vec<u8> = Vec::new();
+
+    while body.len() < limit {
+        match response.chunk().await.ok()? {
+            Some(chunk) => body.extend_from_slice(&chunk[..chunk.len().min(limit - body.len())]),
+            None => break,
+        }
+    }
+
+    Some(Bytes::from(body))
+}
+
+fn check_url(url: &Url, allowed_hosts: &[String]) -> Result<(), Blocked> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(Blocked::Scheme);
+    }
+
+    let Some(host) = url.host_str() else {
+        return Err(Blocked::InvalidUrl);
+    };
+
+    // Names are checked once they have been resolved.
+    let Ok(address) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        return Ok(());
+    };
+
+    if is_allowed_host(allowed_hosts, host) || is_public(address) {
+        Ok(())
+    } else {
+        Err(Blocked::Address(address))
+    }
+}
+
+fn is_allowed_host(allowed_hosts: &[String], host: &str) -> bool {
+    let host2 = host.trim_start_matches('[').trim_end_matches(']');
+    allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host2))
+}
+
+/// Resolves names the way the system would, then drops every address that is
+/// not public. A name left with no addresses at all fails to resolve.
+struct PublicResolver {
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host: String = name.as_str().to_owned();
+        let is_allowed = is_allowed_host(&self.allowed_hosts, &host);
+
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+
+            let public: Vec<SocketAddr> = addresses
+                .iter()
+                .copied()
+                .filter(|address| is_allowed || is_public(address.ip()))
+                .collect();
+
+            match (public.is_empty(), addresses.first()) {
+                (true, Some(address)) => Err(Blocked::Address(address.ip()).into()),
+                _ => Ok(Box::new(public.into_iter()) as Addrs),
+            }
+        })
+    }
+}
+
+/// Whether an address is out on the internet, as opposed to on this machine,
+/// on the network it sits in, or reserved for something other than hosts.
+pub fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address2) => is_public_v4(address2),
+        IpAddr::V6(address2) => is_public_v6(address2),
+    }
+}
+
+fn is_public_v4(address: Ipv4Addr) -> bool {
+    let [a, b, c, _] = address.octets();
+
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_private()
+        // Includes 169.254.169.254, where cloud hosts serve instance metadata.
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_multicast()
+        || address.is_documentation()
+        // 0.0.0.0/8, which some systems treat as this machine.
+        || a == 0
+        // Carrier-grade NAT, and where Alibaba Cloud serves instance metadata.
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments.
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking.
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved for future use.
+        || a >= 240)
+}
+
+fn is_public_v6(address: Ipv6Addr) -> bool {
+    // An IPv4 address written as IPv6 goes wherever the IPv4 address would.
+    if let Some(address2) = address.to_ipv4_mapped() {
+        return is_public_v4(address2);
+    }
+
+    let segments = address.segments();
+
+    let ipv4_at = |index: usize| {
+        Ipv4Addr::from((u32::from(segments[index]) << 16) | u32::from(segments[index + 1]))
+    };
+
+    // Each of these carries an IPv4 address inside it, and leads to wherever
+    // that address would: NAT64 and its local-use range, and IPv4-compatible
+    // addresses in the last 32 bits, and 6to4 in the 32 bits after 2002:.
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+        || segments[..3] == [0x64, 0xff9b, 1]
+        || segments[..6] == [0, 0, 0, 0, 0, 0]
+    {
+        return is_public_v4(ipv4_at(6));
+    }
+    if segments[0] == 0x2002 {
+        return is_public_v4(ipv4_at(1));
+    }
+    // Teredo gives the server's IPv4 address after 2001:0:, and the client's
+    // with its bits flipped in the last 32 bits.
+    if segments[..2] == [0x2001, 0] {
+        return is_public_v4(ipv4_at(2)) && is_public_v4(!ipv4_at(6));
+    }
+
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_multicast()
+        // Unique local, including fd00:ec2::254 where AWS serves instance
+        // metadata over IPv6.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local.
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation.
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spawn_test_server, test_outbound_client};
+    use axum::response::Redirect;
+
+    fn no_allowed_hosts() -> OutboundClient {
+        OutboundClient::new(Vec::new()).expect("the client should build")
+    }
+
+    // Serves `chunks` chunks of 64 KiB each with no `Content-Length`, as a
+    // server that does not say how much is coming would.
+    async fn spawn_streaming_server(chunks: usize) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let router = axum::Router::new().route(
+            "/",
+            axum::routing::get(move || async move {
+                axum::body::Body::from_stream(futures_util::stream::iter(
+                    (0..chunks).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 64 * 1024]))),
+                ))
+            }),
+        );
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, router).await;
+        });
+        base
+    }
+
+    async fn read_from(url: &str, limit: u64) -> Result<Bytes, ReadError> {
+        let response = test_outbound_client()
+            .get(url)
+            .expect("the stand-in is allowed")
+            .send()
+            .await
+            .expect("the stand-in should answer");
+        read_capped(response, limit).await
+    }
+
+    #[tokio::test]
+    async fn stops_reading_once_the_limit_is_passed() {
+        let base = spawn_streaming_server(32).await;
+
+        assert_eq!(
+            read_from(&base, 1024 * 1024).await.err(),
+            Some(ReadError::TooLarge),
+            "2 MiB streamed with no length given should be cut off at 1 MiB"
+        );
+        assert_eq!(
+            read_from(&base, 2 * 1024 * 1024)
+                .await
+                .map(|body| body.len()),
+            Ok(2 * 1024 * 1024),
+            "a body right at the limit should be read in full"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_only_as_much_of_the_start_as_was_asked_for() {
+        let base = spawn_streaming_server(32).await;
+        let response = test_outbound_client()
+            .get(&base)
+            .expect("the stand-in is allowed")
+            .send()
+            .await
+            .expect("the stand-in should answer");
+
+        assert_eq!(
+            read_prefix(response, 100_000).await.map(|body| body.len()),
+            Some(100_000),
+            "the body should be cut off at the limit, even partway through a chunk"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_body_that_says_it_is_too_large() {
+        let base = spawn_test_server(|_base| vec![("/", "text/plain", vec![0u8; 2048])]).await;
+
+        assert_eq!(
+            read_from(&base, 1024).await.err(),
+            Some(ReadError::TooLarge),
+            "a Content-Length over the limit should be refused"
+        );
+    }
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for address in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00:ec2::254",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::a00:1",
+            "::7f00:1",
+            "::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "2001:0:a00:1::",
+            "2001:0:4136:e378:8000:63bf:f5ff:fffe",
+        ] {
+            assert!(
+                !is_public(address.parse().expect("bad test address")),
+                "{address} is not on the internet and should be blocked"
+            );
+        }
+
+        for address in [
+            "1.1.1.1",
+            "162.159.128.233",
+            "2606:4700::6810:84e5",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+            "2001:0:4136:e378:8000:63bf:fefe:fefe",
+        ] {
+            assert!(
+                is_public(address.parse().expect("bad test address")),
+                "{address} is on the internet and should be allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_urls_that_are_not_web_pages() {
+        let client = no_allowed_hosts();
+
+        assert_eq!(
+            client.get("file:///etc/passwd").err(),
+            Some(Blocked::Scheme),
+            "files on disk should not be readable"
+        );
+        assert_eq!(
+            client.get("not a url").err(),
+            Some(Blocked::InvalidUrl),
+            "something that is not a url should be refused"
+        );
+        assert_eq!(
+            client.get("http://[::1]:8000/").err(),
+            Some(Blocked::Address("::1".parse().unwrap())),
+            "an address given directly should be checked before anything is sent"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_name_that_resolves_to_this_machine() {
+        let base = spawn_test_server(|_base| vec![("/", "text/plain", b"secret".to_vec())]).await;
+        let url = base.replace("127.0.0.1", "localhost");
+
+        let result = no_allowed_hosts()
+            .get(&url)
+            .expect("names are checked when they are resolved")
+            .send()
+            .await;
+
+        assert!(
+            matches!(
+                result.as_ref().map_err(blocked_by),
+                Err(Some(Blocked::Address(_)))
+            ),
+            "localhost resolves to this machine and should be blocked"
+        );
+    }
+
+    // The stand-in is reached as `localhost`, which is allowed, and then sends
+    // the request on to 127.0.0.1, which is not. The redirect is where a url
+    // that looked harmless would have got through before.
+    #[tokio::test]
+    async fn checks_where_a_redirect_leads() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let port = listener.local_addr().unwrap().port();
+        let router = axum::Router::new()
+            .route(
+                "/redirect",
+                axum::routing::get(move || async move {
+                    Redirect::temporary(&format!("http://127.0.0.1:{port}/secret"))
+                }),
+            )
+            .route("/secret", axum::routing::get(|| async { "secret" }));
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, router).await;
+        });
+
+        let client =
+            OutboundClient::new(vec![String::from("localhost")]).expect("the client should build");
+
+        let allowed = client
+            .get(&format!("http://localhost:{port}/secret"))
+            .expect("localhost is allowed")
+            .send()
+            .await;
+        assert!(
+            allowed.is_ok_and(|response| response.status().is_success()),
+            "an allowed host should be reachable wherever it resolves to"
+        );
+
+        let redirected = client
+            .get(&format!("http://localhost:{port}/redirect"))
+            .expect("localhost is allowed")
+            .send()
+            .await;
+        assert_eq!(
+            redirected.as_ref().map_err(blocked_by).err(),
+            Some(Some(Blocked::Address(IpAddr::V4(Ipv4Addr::LOCALHOST)))),
+            "a redirect to a private address should be refused"
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! Reads a site's robots.txt, so that embeds stay out of what it asks crawlers
+//! to stay out of.
+//!
+//! Each site's rules are fetched the first time a link to it is embedded and
+//! kept for a day, which is as long as RFC 9309 lets them be kept. Only the
+//! group for our own name is used if the site has one, and the group for `*`
+//! otherwise.
+//!
+//! Links to one site embedded at the same time, as a message full of them
+//! makes happen, wait on the one fetch of its robots.txt rather than each
+//! making their own.
+//!
+//! A site whose robots.txt is missing lets us fetch anything, and one whose
+//! robots.txt could not be read because the site is down lets us fetch nothing
+//! until it is back, as the RFC asks.
+
+use crate::outbound::{self, OutboundClient};
+use lru::LruCache;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// How long a site's rules are kept.
+const ROBOTS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a site whose rules could not be read is left alone before they are
+/// asked for again.
+const ROBOTS_RETRY_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The most of a robots.txt that is read. The RFC asks for at least 500 KiB to
+/// be read, and whatever comes after it to be ignored.
+const MAX_ROBOTS_BYTES: usize = 500 * 1024;
+
+/// How many sites' rules are kept at once.
+const MAX_HOSTS: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
+
+pub struct RobotsCache {
+    /// The name robots.txt knows us by, in lowercase.
+    product_token: String,
+    entries: Mutex<LruCache<String, CachedRules>>,
+    /// The sites whose robots.txt is being fetched right now, each with what
+    /// the fetch found once it is done.
+    in_flight: Mutex<HashMap<String, Arc<RulesFetch>>>,
+}
+
+/// A fetch of one site's rules, which whoever asks first makes and everyone
+/// else waits on.
+type RulesFetch = OnceCell<Arc<Vec<Rule>>>;
+
+struct CachedRules {
+    rules: Arc<Vec<Rule>>,
+    expires_at: Instant,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl RobotsCache {
+    /// `user_agent` is the `User-Agent` requests are sent with, which the
+    /// name used to find our group in a robots.txt is taken from.
+    pub fn new(user_agent: &str) -> Self {
+        Self {
+            product_token: product_token(user_agent).to_ascii_lowercase(),
+            entries: Mutex::new(LruCache::new(MAX_HOSTS)),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the site lets us fetch this url.
+    pub async fn allows(&self, client: &OutboundClient, url: &str) -> bool {
+        let Ok(url2) = Url::parse(url) else {
+            // Nothing will be fetched from it anyway.
+            return true;
+        };
+
+        let path = match url2.query() {
+            Some(query) => format!("{}?{query}", url2.path()),
+            None => url2.path().to_owned(),
+        };
+
+        if path == "/robots.txt" {
+            return true;
+        }
+
+        let origin = url2.origin().ascii_serialization();
+        let cached: Option<Arc<Vec<Rule>>> = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(&origin)
+            .filter(|cached2| cached2.expires_at > Instant::now())
+            .map(|cached2| cached2.rules.clone());
+
+        let rules = match cached {
+            Some(rules2) => rules2,
+            None => self.fetch_once(client, origin).await,
+        };
+
+        is_allowed(&rules, &path)
+    }
+
+    /// Fetches the site's rules and keeps them, unless they are being fetched
+    /// already, in which case this waits for that fetch instead.
+    async fn fetch_once(&self, client: &OutboundClient, origin: String) -> Arc<Vec<Rule>> {
+        let fetch = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(origin.clone())
+            .or_default()
+            .clone();
+
+        fetch
+            .get_or_init(|| async {
+                let (rules, ttl) = fetch_rules(client, &origin, &self.product_token).await;
+                let rules2 = Arc::new(rules);
+                self.entries.lock().unwrap().put(
+                    origin.clone(),
+                    CachedRules {
+                        rules: rules2.clone(),
+                        expires_at: Instant::now() + ttl,
+                    },
+                );
+                self.in_flight.lock().unwrap().remove(&origin);
+                rules2
+            })
+            .await
+            .clone()
+    }
+}
+
+/// The name a `User-Agent` gives for the program sending it, as in the
+/// `AtChatBot` of `Mozilla/5.0 (compatible; AtChatBot/1.0)`. The `Mozilla/5.0`
+/// many of them start with is not a name at all.
+fn product_token(user_agent: &str) -> &str {
+    user_agent
+        .split(|x: char| x.is_whitespace() || x == ';' || x == '(' || x == ')')
+        .filter_map(|part| part.split_once('/'))
+        .map(|(name, _)| name)
+        .find(|name| !name.is_empty() && !name.eq_ignore_ascii_case("mozilla"))
+        .unwrap_or(user_agent)
+}
+
+/// A site's rules for us, and how long to keep them.
+async fn fetch_rules(
+    client: &OutboundClient,
+    origin: &str,
+    product_token: &str,
+) -> (Vec<Rule>, Duration) {
+    let disallow_all = || {
+        vec![Rule {
+            allow: false,
+            pattern: String::from("/"),
+        }]
+    };
+
+    // A site we may not fetch from at all is refused by the fetch itself, with
+    // a better reason than robots.txt would give.
+    let Ok(request) = client.get(&format!("{origin}/robots.txt")) else {
+        return (Vec::new(), ROBOTS_TTL);
+    };
+
+    let response = match request.timeout(Duration::from_secs(5)).send().await {
+        Ok(response2) => response2,
+        Err(_) => return (disallow_all(), ROBOTS_RETRY_TTL),
+    };
+
+    let status = response.status();
+    if status.is_client_error() {
+        (Vec::new(), ROBOTS_TTL)
+    } else if !status.is_success() {
+        (disallow_all(), ROBOTS_RETRY_TTL)
+    } else {
+        match outbound::read_prefix(response, MAX_ROBOTS_BYTES).await {
+            Some(bytes) => (
+                parse_rules(&String::from_utf8_lossy(&bytes), product_token),
+                ROBOTS_TTL,
+            ),
+            None => (disallow_all(), ROBOTS_RETRY_TTL),
+        }
+    }
+}
+
+/// The rules in a robots.txt that apply to us.
+fn parse_rules(text: &str, product_token: &str) -> Vec<Rule> {
+    let mut ours: Vec<Rule> = Vec::new();
+    let mut everyones: Vec<Rule> = Vec::new();
+    let mut has_our_group = false;
+
+    // A group is one or more `User-agent` lines followed by its rules. These
+    // say which groups the rules being read belong to.
+    let mut group_is_ours = false;
+    let mut group_is_everyones = false;
+    let mut in_rules = false;
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                if in_rules {
+                    group_is_ours = false;
+                    group_is_everyones = false;
+                    in_rules = false;
+                }
+
+                let name = value.split('/').next().unwrap_or_default().trim();
+                if name.eq_ignore_ascii_case(product_token) {
+                    group_is_ours = true;
+                    has_our_group = true;
+                } else if name == "*" {
+                    group_is_everyones = true;
+                }
+            }
+            key2 @ ("allow" | "disallow") => {
+                in_rules = true;
+
+                // An empty `Disallow` disallows nothing, and is only there so
+                // that the group is not empty.
+                if value.is_empty() {
+                    continue;
+                }
+
+                let rule = Rule {
+                    allow: key2 == "allow",
+                    pattern: value.to_owned(),
+                };
+                if group_is_ours {
+                    ours.push(rule.clone());
+                }
+                if group_is_everyones {
+                    everyones.push(rule);
+                }
+            }
+            // Sitemaps, crawl delays and the like belong to no group, and do
+            // not end the one being read.
+            _ => {}
+        }
+    }
+
+    if has_our_group { ours } else { everyones }
+}
+
+/// Whether a path is allowed by a set of rules. The rule with the longest
+/// pattern that matches is the one that counts, and `Allow` wins a tie.
+fn is_allowed(rules: &[Rule], path: &str) -> bool {
+    rules
+        .iter()
+        .filter(|rule| pattern_matches(&rule.pattern, path))
+        .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+        .is_none_or(|rule| rule.allow)
+}
+
+/// Whether a path starts with a pattern, where `*` in the pattern stands for
+/// anything at all and a `$` at its end means the path has to end there too.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern2, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern3) => (pattern3, true),
+        None => (pattern, false),
+    };
+
+    let mut parts = pattern2.split('*');
+    let Some(mut rest) = path.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    for (index, part) in parts.iter().enumerate() {
+        if anchored && index == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(start) => rest = &rest[start + part.len()..],
+            None => return false,
+        }
+    }
+
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spawn_test_server, test_outbound_client};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn finds_our_name_in_a_user_agent() {
+        assert_eq!(
+            product_token("Mozilla/5.0 (compatible; AtChatBot/1.0; +https://at-chat.app)"),
+            "AtChatBot",
+            "the Mozilla prefix is not our name"
+        );
+        assert_eq!(product_token("SomeBot/2"), "SomeBot", "a plain name");
+    }
+
+    #[test]
+    fn uses_our_group_over_everyones() {
+        let text = "
+            # Comments are ignored
+            User-agent: *
+            Disallow: /
+
+            User-agent: OtherBot
+            User-agent: atchatbot/1.0
+            Disallow: /private # until the end of the line
+            Sitemap: https://example.com/sitemap.xml
+            Allow: /private/but-not-this
+
+            User-agent: *
+            Disallow: /everyone
+        ";
+
+        let rules = parse_rules(text, "atchatbot");
+        assert!(is_allowed(&rules, "/public"), "our group allows this");
+        assert!(
+            !is_allowed(&rules, "/private/page"),
+            "our group disallows this"
+        );
+        assert!(
+            is_allowed(&rules, "/private/but-not-this"),
+            "a sitemap line does not end the group"
+        );
+        assert!(
+            is_allowed(&rules, "/everyone"),
+            "everyone's rules do not apply once we have our own"
+        );
+
+        let rules2 = parse_rules(text, "unnamedbot");
+        assert!(
+            !is_allowed(&rules2, "/public"),
+            "without a group of its own a crawler follows everyone's"
+        );
+    }
+
+    #[test]
+    fn the_longest_match_wins() {
+        let rules = parse_rules(
+            "User-agent: *\nDisallow: /docs\nAllow: /docs/public\nDisallow: /*.pdf$\nAllow: /same\nDisallow: /same\nDisallow:\n",
+            "atchatbot",
+        );
+
+        for (path, expected) in [
+            ("/docs/secret", false),
+            ("/docs/public/page", true),
+            ("/file.pdf", false),
+            ("/file.pdf?download", true),
+            ("/same", true),
+            ("/elsewhere", true),
+        ] {
+            assert_eq!(is_allowed(&rules, path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        for (pattern, path, expected) in [
+            ("/a*c", "/abc", true),
+            ("/a*c", "/ab", false),
+            ("/a*c$", "/abcd", false),
+            ("/a*c$", "/abcc", true),
+            ("/a$", "/a", true),
+            ("/a$", "/ab", false),
+            ("*", "/anything", true),
+        ] {
+            assert_eq!(
+                pattern_matches(pattern, path),
+                expected,
+                "{pattern} against {path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_a_sites_rules_once() {
+        let base = spawn_test_server(|_base| {
+            vec![(
+                "/robots.txt",
+                "text/plain",
+                b"User-agent: AtChatBot\nDisallow: /private\n".to_vec(),
+            )]
+        })
+        .await;
+        let missing = spawn_test_server(|_base| Vec::new()).await;
+        let client = test_outbound_client();
+        let robots = RobotsCache::new("Mozilla/5.0 (compatible; AtChatBot/1.0)");
+
+        assert!(
+            robots.allows(&client, &format!("{base}/public")).await,
+            "a page the site allows"
+        );
+        assert!(
+            !robots
+                .allows(&client, &format!("{base}/private/page"))
+                .await,
+            "a page the site disallows"
+        );
+        assert_eq!(
+            robots.entries.lock().unwrap().len(),
+            1,
+            "one site's rules should be kept once"
+        );
+        assert!(
+            robots.allows(&client, &format!("{missing}/anything")).await,
+            "a site without a robots.txt allows everything"
+        );
+        assert!(
+            !robots
+                .allows(&client, "http://127.0.0.1:1/unreachable")
+                .await,
+            "a site that cannot be reached allows nothing"
+        );
+    }
+
+    // Every link in a message to the same site should not each set off a
+    // fetch of its robots.txt. The stand-in takes a moment to answer, so the
+    // second link is asked about while the first fetch is still under way.
+    #[tokio::test]
+    async fn fetches_a_sites_rules_once_however_many_ask_at_once() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test server");
+        let port = listener.local_addr().unwrap().port();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetches2 = fetches.clone();
+        let router = axum::Router::new().route(
+            "/robots.txt",
+            axum::routing::get(move || async move {
+                fetches2.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "User-agent: *\nDisallow: /private\n"
+            }),
+        );
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, router).await;
+        });
+
+        let client = test_outbound_client();
+        let robots = RobotsCache::new("AtChatBot");
+        let base = format!("http://127.0.0.1:{port}");
+
+        let (public, private) = (format!("{base}/public"), format!("{base}/private"));
+        let (first, second) = tokio::join!(
+            robots.allows(&client, &public),
+            robots.allows(&client, &private),
+        );
+        assert!(
+            first && !second,
+            "both should be answered by the same rules"
+        );
+        assert_eq!(
+            fetches.load(Ordering::SeqCst),
+            1,
+            "robots.txt should be fetched once"
+        );
+        assert!(
+            robots.in_flight.lock().unwrap().is_empty(),
+            "nothing should be left waiting once the fetch is done"
+        );
+    }
+}
@@ -0,0 +1,647 @@
+//! The secret the backend and this server share, which `/file/internal/`
+//! requests carry in `x-secret-key` and which this server sends with its own
+//! requests to the backend.
+//!
+//! The backend asks for a new secret and starts using it as soon as it has it,
+//! and this server sends the new one from then on too. Requests the backend
+//! sent with the old one just before can still be on their way, so the old one
+//! also stays accepted for `SERVER_SECRET_GRACE_MINUTES` (60 by default), and
+//! only then stops working. It can be made to stop sooner by finalising the
+//! rotation. Rotating again before then, as when the new secret has leaked
+//! too, stops accepting the old one at once, so only the last two are ever
+//! accepted.
+//!
+//! Rotating, finalising and aborting all need the current secret. The previous
+//! one is only still accepted for requests that were already on their way,
+//! and whoever has it should not be able to undo or cut short the rotation
+//! that replaced it.
+//!
+//! Aborting a rotation goes back to the old secret and answers with it. The
+//! backend has the new one by then, so it has to be given the old one again,
+//! or neither side will accept the other's requests.
+//!
+//! The current secret stays on its own in `secret.txt`, and the rotation going
+//! on, if any, is kept next to it so a restart does not cut it short.
+
+use crate::{AppState, json_response_with_headers, options_endpoint, response_with_headers};
+use axum::routing::{get, post};
+use axum::{Router, extract::State, http::StatusCode, response::Response};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use subtle::{Choice, ConstantTimeEq};
+
+pub const SERVER_SECRET_ROTATION_PATH: &str = "./var/lib/atchat/secret-rotation.json";
+
+/// How long a new secret is, in letters and digits, which is a little over
+/// 380 bits.
+const SECRET_LENGTH: usize = 64;
+
+/// A rotation that has not been finalised or aborted yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingRotation {
+    /// The secret being replaced.
+    previous: String,
+    /// When it started and when the previous secret stops being accepted, in
+    /// seconds since the Unix epoch.
+    started_at: i64,
+    expires_at: i64,
+}
+
+/// What is kept in [`SERVER_SECRET_ROTATION_PATH`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct RotationRecord {
+    pending: Option<PendingRotation>,
+    /// When the secret last changed for good.
+    last_rotated_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RotationStatus {
+    pub rotating: bool,
+    pub started_at: Option<String>,
+    /// When the previous secret stops being accepted.
+    pub expires_at: Option<String>,
+    pub last_rotated_at: Option<String>,
+}
+
+/// Why a rotation could not be started, finalised or aborted.
+#[derive(Debug)]
+pub enum RotationError {
+    NotRotating,
+    Write(std::io::Error),
+}
+
+impl std::fmt::Display for RotationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotRotating => write!(f, "No rotation is going on"),
+            Self::Write(error) => write!(f, "Write failed\n{error:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerSecret {
+    current: String,
+    record: RotationRecord,
+    grace_seconds: i64,
+    secret_path: String,
+    rotation_path: String,
+}
+
+impl ServerSecret {
+    /// A secret that is kept at the usual paths, with no rotation going on.
+    pub fn new(current: &str) -> Self {
+        Self::with_paths(
+            current,
+            crate::SERVER_SECRET_PATH,
+            SERVER_SECRET_ROTATION_PATH,
+        )
+    }
+
+    fn with_paths(current: &str, secret_path: &str, rotation_path: &str) -> Self {
+        Self {
+            current: current.trim().to_owned(),
+            record: RotationRecord::default(),
+            grace_seconds: 60 * 60,
+            secret_path: secret_path.to_owned(),
+            rotation_path: rotation_path.to_owned(),
+        }
+    }
+
+    /// The secret in [`crate::SERVER_SECRET_PATH`], along with the rotation
+    /// that was going on when the server stopped.
+    pub fn load() -> std::io::Result<Self> {
+        let mut secret = Self::load_from(crate::SERVER_SECRET_PATH, SERVER_SECRET_ROTATION_PATH)?;
+        secret.grace_seconds =
+            60 * i64::try_from(crate::env_u64("SERVER_SECRET_GRACE_MINUTES", 60)).unwrap_or(60);
+        Ok(secret)
+    }
+
+    fn load_from(secret_path: &str, rotation_path: &str) -> std::io::Result<Self> {
+        let current = fs::read_to_string(secret_path)?;
+
+        let record: RotationRecord = match fs::read(rotation_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|error| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{rotation_path} could not be read: {error}"),
+                )
+            })?,
+            Err(error) if error.kind() == ErrorKind::NotFound => RotationRecord::default(),
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self {
+            record,
+            ..Self::with_paths(&current, secret_path, rotation_path)
+        })
+    }
+
+    /// The previous secret, while it is still accepted.
+    fn previous(&self, now: i64) -> Option<&str> {
+        self.record
+            .pending
+            .as_ref()
+            .filter(|pending| now < pending.expires_at)
+            .map(|pending| pending.previous.as_str())
+    }
+
+    /// Whether a request carrying `provided` is from the backend. Both secrets
+    /// are always compared, so how long this takes says nothing about which
+    /// one was close.
+    pub fn accepts(&self, provided: &[u8]) -> bool {
+        let previous: Choice = match self.previous(chrono::Utc::now().timestamp()) {
+            Some(previous2) => provided.ct_eq(previous2.as_bytes()),
+            None => Choice::from(0),
+        };
+        (provided.ct_eq(self.current.as_bytes()) | previous).into()
+    }
+
+    /// Whether `provided` is the current secret, for the requests that change
+    /// it, which the previous one is not good enough for.
+    pub fn is_current(&self, provided: &[u8]) -> bool {
+        provided.ct_eq(self.current.as_bytes()).into()
+    }
+
+    /// The secret to send to the backend. It is always the current one, since
+    /// the backend switches to a new secret as soon as it asks for it.
+    pub fn outbound(&self) -> Vec<u8> {
+        self.current.as_bytes().to_vec()
+    }
+
+    /// Makes a new secret and starts accepting it, returning it. A rotation
+    /// that is still going on is finalised first, so the secret it replaced
+    /// stops working straight away.
+    pub fn rotate(&mut self) -> Result<String, RotationError> {
+        let now = chrono::Utc::now().timestamp();
+
+        // A rotation that ran out of time without being finalised went through
+        // all the same.
+        if let Some(pending) = self.record.pending.take() {
+            self.record.last_rotated_at = Some(pending.expires_at.min(now));
+        }
+
+        let new_secret = random_secret();
+        let record = RotationRecord {
+            pending: Some(PendingRotation {
+                previous: self.current.clone(),
+                started_at: now,
+                expires_at: now.saturating_add(self.grace_seconds),
+            }),
+            last_rotated_at: self.record.last_rotated_at,
+        };
+
+        // The rotation is saved first, so that if the server stops in between
+        // the secret it finds is one the rotation still accepts.
+        self.save_record(&record)?;
+        crate::write_atomically(&self.secret_path, new_secret.as_bytes())
+            .map_err(RotationError::Write)?;
+
+        self.record = record;
+        self.current.clone_from(&new_secret);
+        Ok(new_secret)
+    }
+
+    /// Stops accepting the previous secret.
+    pub fn finalise(&mut self) -> Result<(), RotationError> {
+        if self.record.pending.is_none() {
+            return Err(RotationError::NotRotating);
+        }
+
+        let record = RotationRecord {
+            pending: None,
+            last_rotated_at: Some(chrono::Utc::now().timestamp()),
+        };
+        self.save_record(&record)?;
+        self.record = record;
+        Ok(())
+    }
+
+    /// Goes back to the previous secret, if it has not expired yet, and
+    /// returns it.
+    pub fn abort(&mut self) -> Result<String, RotationError> {
+        let Some(previous) = self
+            .previous(chrono::Utc::now().timestamp())
+            .map(str::to_owned)
+        else {
+            return Err(RotationError::NotRotating);
+        };
+
+        let record = RotationRecord {
+            pending: None,
+            last_rotated_at: self.record.last_rotated_at,
+        };
+        crate::write_atomically(&self.secret_path, previous.as_bytes())
+            .map_err(RotationError::Write)?;
+        self.save_record(&record)?;
+
+        self.record = record;
+        self.current.clone_from(&previous);
+        Ok(previous)
+    }
+
+    pub fn status(&self) -> RotationStatus {
+        let time = |seconds: i64| {
+            chrono::DateTime::from_timestamp(seconds, 0).map(|time2| time2.to_rfc3339())
+        };
+        let now = chrono::Utc::now().timestamp();
+        let pending = self
+            .record
+            .pending
+            .as_ref()
+            .filter(|pending2| now < pending2.expires_at);
+
+        RotationStatus {
+            rotating: pending.is_some(),
+            started_at: pending.and_then(|pending2| time(pending2.started_at)),
+            expires_at: pending.and_then(|pending2| time(pending2.expires_at)),
+            last_rotated_at: self
+                .record
+                .last_rotated_at
+                .or_else(|| {
+                    // One that ran out of time counts from when it did.
+                    self.record
+                        .pending
+                        .as_ref()
+                        .filter(|_| pending.is_none())
+                        .map(|pending2| pending2.expires_at)
+                })
+                .and_then(time),
+        }
+    }
+
+    fn save_record(&self, record: &RotationRecord) -> Result<(), RotationError> {
+        crate::write_atomically(
+            &self.rotation_path,
+            serde_json::to_string(record).unwrap().as_bytes(),
+        )
+        .map_err(RotationError::Write)
+    }
+}
+
+const REGENERATE_PATH: &str = "/file/internal/regenerate-server-secret";
+const FINALISE_PATH: &str = "/file/internal/server-secret-rotation/finalise";
+const ABORT_PATH: &str = "/file/internal/server-secret-rotation/abort";
+
+/// Whether a request to `path` changes the secret, and so has to carry the
+/// current one rather than the previous.
+pub fn needs_current_secret(path: &str) -> bool {
+    [REGENERATE_PATH, FINALISE_PATH, ABORT_PATH].contains(&path)
+}
+
+/// The endpoints for changing the secret.
+pub fn routes() -> Router<Arc<Mutex<AppState>>> {
+    Router::new()
+        .route(
+            REGENERATE_PATH,
+            post(regenerate_server_secret_endpoint).options(options_endpoint),
+        )
+        .route(
+            "/file/internal/server-secret-rotation",
+            get(server_secret_rotation_endpoint),
+        )
+        .route(
+            FINALISE_PATH,
+            post(finalise_server_secret_rotation_endpoint).options(options_endpoint),
+        )
+        .route(
+            ABORT_PATH,
+            post(abort_server_secret_rotation_endpoint).options(options_endpoint),
+        )
+}
+
+/// Starts a rotation and answers with the new secret, which is used from now
+/// on. The old one is still accepted until the rotation is finalised, aborted
+/// or runs out of time.
+pub async fn regenerate_server_secret_endpoint(
+    state: State<Arc<Mutex<AppState>>>,
+) -> Response<String> {
+    let result = state.lock().unwrap().secret.rotate();
+    match result {
+        Ok(new_secret) => response_with_headers(StatusCode::OK, new_secret),
+        Err(error) => rotation_error_response(&error),
+    }
+}
+
+pub async fn server_secret_rotation_endpoint(
+    state: State<Arc<Mutex<AppState>>>,
+) -> Response<String> {
+    let status = state.lock().unwrap().secret.status();
+    json_response_with_headers(StatusCode::OK, serde_json::to_string(&status).unwrap())
+}
+
+/// Stops accepting the old secret, once the backend has the new one.
+pub async fn finalise_server_secret_rotation_endpoint(
+    state: State<Arc<Mutex<AppState>>>,
+) -> Response<String> {
+    let result = state.lock().unwrap().secret.finalise();
+    match result {
+        Ok(()) => response_with_headers(StatusCode::OK, String::from("OK")),
+        Err(error) => rotation_error_response(&error),
+    }
+}
+
+/// Goes back to the old secret and throws the new one away, answering with
+/// the old one for the backend to go back to as well.
+pub async fn abort_server_secret_rotation_endpoint(
+    state: State<Arc<Mutex<AppState>>>,
+) -> Response<String> {
+    let result = state.lock().unwrap().secret.abort();
+    match result {
+        Ok(previous) => response_with_headers(StatusCode::OK, previous),
+        Err(error) => rotation_error_response(&error),
+    }
+}
+
+fn rotation_error_response(error: &RotationError) -> Response<String> {
+    let status = match error {
+        RotationError::NotRotating => StatusCode::CONFLICT,
+        RotationError::Write(_) => StatusCode::BAD_REQUEST,
+    };
+    response_with_headers(status, error.to_string())
+}
+
+/// A new secret of letters and digits, which go in a header as they are.
+fn random_secret() -> String {
+    use rand::RngExt;
+
+    rand::rng()
+        .sample_iter(rand::distr::Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where a test keeps its secret, removed when the test is done.
+    struct TestSecret {
+        root: String,
+        secret: ServerSecret,
+    }
+
+    impl TestSecret {
+        fn new(name: &str) -> Self {
+            let root = format!(
+                "{}/atchat-secret-{name}-{}/",
+                std::env::temp_dir().display(),
+                rand::random::<u64>()
+            );
+            fs::create_dir_all(&root).unwrap();
+            fs::write(format!("{root}secret.txt"), "old-secret\n").unwrap();
+
+            let secret = Self::reload(&root);
+            Self { root, secret }
+        }
+
+        fn reload(root: &str) -> ServerSecret {
+            ServerSecret::load_from(
+                &format!("{root}secret.txt"),
+                &format!("{root}secret-rotation.json"),
+            )
+            .unwrap()
+        }
+    }
+
+    impl Drop for TestSecret {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn makes_long_secrets_of_letters_and_digits() {
+        let secret = random_secret();
+        assert_eq!(secret.len(), SECRET_LENGTH, "{secret}");
+        assert!(
+            secret.bytes().all(|byte| byte.is_ascii_alphanumeric()),
+            "only letters and digits should be used: {secret}"
+        );
+        assert_ne!(secret, random_secret(), "each secret should be new");
+    }
+
+    // The backend uses the new secret as soon as it has it, so that is what it
+    // is sent. A request it sent with the old secret just before still gets
+    // through, until the rotation is finalised.
+    #[test]
+    fn accepts_both_secrets_until_the_rotation_is_finalised() {
+        let mut test = TestSecret::new("finalise");
+        let new_secret = test.secret.rotate().unwrap();
+
+        assert!(
+            test.secret.accepts(b"old-secret"),
+            "the old secret should still work"
+        );
+        assert!(
+            test.secret.accepts(new_secret.as_bytes()),
+            "the new one should too"
+        );
+        assert!(!test.secret.accepts(b"other"), "nothing else should");
+        assert_eq!(
+            test.secret.outbound(),
+            new_secret.as_bytes(),
+            "the backend should be sent the secret it has just been given"
+        );
+
+        // The rotation carries on after a restart.
+        let mut reloaded = TestSecret::reload(&test.root);
+        assert!(reloaded.status().rotating, "{:?}", reloaded.status());
+        assert!(
+            reloaded.accepts(b"old-secret"),
+            "the old secret should still work"
+        );
+
+        reloaded.finalise().unwrap();
+        assert!(
+            !reloaded.accepts(b"old-secret"),
+            "the old secret should stop working"
+        );
+        assert!(
+            reloaded.accepts(new_secret.as_bytes()),
+            "the new one should keep working"
+        );
+        assert_eq!(
+            reloaded.outbound(),
+            new_secret.as_bytes(),
+            "the new one should still be sent"
+        );
+
+        let status = TestSecret::reload(&test.root).status();
+        assert!(
+            !status.rotating && status.last_rotated_at.is_some(),
+            "when it was finalised should be kept: {status:?}"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{}secret.txt", test.root)).unwrap(),
+            new_secret,
+            "secret.txt should hold the new secret"
+        );
+    }
+
+    // Rotating again, as when the new secret turns out to have leaked as well,
+    // should not have to wait for the first rotation to run out.
+    #[test]
+    fn rotating_again_stops_accepting_the_oldest_secret() {
+        let mut test = TestSecret::new("again");
+        let first = test.secret.rotate().unwrap();
+        let second = test.secret.rotate().unwrap();
+
+        assert!(
+            !test.secret.accepts(b"old-secret"),
+            "the secret from before the first rotation should stop working"
+        );
+        assert!(
+            test.secret.accepts(first.as_bytes()),
+            "the one it replaced should still work for a while"
+        );
+        assert!(
+            test.secret.is_current(second.as_bytes()) && !test.secret.is_current(first.as_bytes()),
+            "only the newest should be current"
+        );
+        assert_eq!(
+            test.secret.abort().unwrap(),
+            first,
+            "aborting should only go back one rotation"
+        );
+        assert!(
+            !TestSecret::reload(&test.root).accepts(b"old-secret"),
+            "the oldest secret should stay gone after a restart"
+        );
+    }
+
+    // Whoever has the previous secret must not be able to take back or cut
+    // short the rotation that replaced it, though the backend's requests
+    // already on their way with it should still get through.
+    #[tokio::test]
+    async fn only_the_current_secret_changes_the_secret() {
+        let test = TestSecret::new("routes");
+        let state = Arc::new(Mutex::new(AppState {
+            secret: test.secret.clone(),
+        }));
+        let new_secret = state.lock().unwrap().secret.rotate().unwrap();
+
+        let app = routes()
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                crate::require_internal_secret,
+            ))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        let client = reqwest::Client::new();
+        let status = async |method: reqwest::Method, path: &str, secret: &str| {
+            client
+                .request(method, format!("{base}{path}"))
+                .header("x-secret-key", secret)
+                .send()
+                .await
+                .unwrap()
+                .status()
+        };
+
+        assert_eq!(
+            status(
+                reqwest::Method::GET,
+                "/file/internal/server-secret-rotation",
+                "old-secret"
+            )
+            .await,
+            StatusCode::OK,
+            "the previous secret should still be accepted for other requests"
+        );
+        for path in [REGENERATE_PATH, FINALISE_PATH, ABORT_PATH] {
+            assert_eq!(
+                status(reqwest::Method::POST, path, "old-secret").await,
+                StatusCode::FORBIDDEN,
+                "{path} should not take the previous secret"
+            );
+        }
+        assert!(
+            state
+                .lock()
+                .unwrap()
+                .secret
+                .is_current(new_secret.as_bytes()),
+            "the rotation should not have been touched"
+        );
+
+        assert_eq!(
+            status(reqwest::Method::POST, ABORT_PATH, &new_secret).await,
+            StatusCode::OK,
+            "the current secret should be able to abort"
+        );
+    }
+
+    #[test]
+    fn goes_back_to_the_old_secret_when_aborted() {
+        let mut test = TestSecret::new("abort");
+        let new_secret = test.secret.rotate().unwrap();
+
+        assert_eq!(
+            test.secret.abort().unwrap(),
+            "old-secret",
+            "the old secret should be given back for the backend to use again"
+        );
+        assert!(
+            test.secret.accepts(b"old-secret"),
+            "the old secret should work"
+        );
+        assert_eq!(
+            test.secret.outbound(),
+            b"old-secret",
+            "the old secret should be sent again"
+        );
+        assert!(
+            !test.secret.accepts(new_secret.as_bytes()),
+            "the new one should be thrown away"
+        );
+        assert!(
+            matches!(test.secret.abort(), Err(RotationError::NotRotating)),
+            "there should be nothing left to abort"
+        );
+        assert!(
+            TestSecret::reload(&test.root).accepts(b"old-secret"),
+            "the old secret should be back in secret.txt"
+        );
+    }
+
+    #[test]
+    fn stops_accepting_the_old_secret_once_the_grace_period_is_up() {
+        let mut test = TestSecret::new("expire");
+        test.secret.grace_seconds = 0;
+        let new_secret = test.secret.rotate().unwrap();
+
+        assert!(
+            !test.secret.accepts(b"old-secret"),
+            "the old secret should have expired"
+        );
+        assert_eq!(
+            test.secret.outbound(),
+            new_secret.as_bytes(),
+            "the new one is sent"
+        );
+        assert!(
+            matches!(test.secret.abort(), Err(RotationError::NotRotating)),
+            "an expired rotation cannot be undone"
+        );
+
+        let status = test.secret.status();
+        assert!(
+            !status.rotating && status.last_rotated_at.is_some(),
+            "an expired rotation should count as done: {status:?}"
+        );
+        assert!(
+            test.secret.rotate().is_ok(),
+            "another rotation can start once it has expired"
+        );
+    }
+}
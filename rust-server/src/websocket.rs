@@ -129,7 +129,7 @@ pub async fn room_endpoint(
         None => return forbidden("Missing session"),
     };
 
-    let secret_key: Vec<u8> = state.lock().unwrap().secret_key.clone();
+    let secret_key: Vec<u8> = state.lock().unwrap().secret.outbound();
 
     match is_call_allowed(&secret_key, &session_id, &query.client_id, &room_id).await {
         Ok(()) => upgrade.on_upgrade(move |socket| join_room(socket, room_id, rooms)),
@@ -490,7 +490,7 @@ mod tests {
         );
 
         let state = Arc::new(Mutex::new(AppState {
-            secret_key: b"the-secret".to_vec(),
+            secret: crate::server_secret::ServerSecret::new("the-secret"),
         }));
         let router = Router::new()
             .route("/file/websocket/{room_id}", get(room_endpoint))